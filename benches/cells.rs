@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_simple_stack_processor::bench;
+use rust_simple_stack_processor::{ArrayTraps, GasLimit, Opcode, StackMachine};
+
+// Compare runs with and without the simd feature to see what it gains
+fn array_traps(c: &mut Criterion) {
+    let mut group = c.benchmark_group("array_traps");
+    for &len in &[64i64, 4096] {
+        let mut sm = StackMachine::default();
+        sm.trap_handlers.register(Box::new(ArrayTraps::new(0)));
+        let ramp: Vec<i64> = (0..3 * len).collect();
+        sm.load_cells(0, &ramp).unwrap();
+        // ADD ( a b dest len -- ), SUM ( a len -- n ) and DOT ( a b len -- n )
+        for (name, trap_id, stack) in [
+            ("add", 0, vec![0, len, 2 * len, len]),
+            ("sum", 5, vec![0, len]),
+            ("dot", 4, vec![0, len, len]),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, len), &stack, |b, stack| {
+                b.iter(|| {
+                    sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+                    sm.execute_with_stack(0, stack.clone(), GasLimit::Unlimited)
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn cell_moves(c: &mut Criterion) {
+    let workload = bench::cell_heavy(5_000);
+    c.bench_function(workload.name, |b| {
+        b.iter_batched(
+            || workload.machine(),
+            |mut sm| sm.execute(0, GasLimit::Unlimited).unwrap(),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, array_traps, cell_moves);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_simple_stack_processor::bench;
+use rust_simple_stack_processor::GasLimit;
+
+fn dispatch(c: &mut Criterion) {
+    for workload in bench::all() {
+        c.bench_function(workload.name, |b| {
+            b.iter_batched(
+                || workload.machine(),
+                |mut sm| sm.execute(0, GasLimit::Unlimited).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
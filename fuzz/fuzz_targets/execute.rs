@@ -0,0 +1,45 @@
+//! Runs arbitrary programs, which must fail with an error rather than panic or abort.
+//!
+//! Each opcode is a byte, taken modulo the number of opcodes, followed by 8 little-endian
+//! bytes of immediate value for the opcodes that carry one.
+//!
+//! cargo +nightly fuzz run execute
+#![no_main]
+
+use std::convert::{TryFrom, TryInto};
+
+use libfuzzer_sys::fuzz_target;
+use rust_simple_stack_processor::{
+    DecodeOpcodeError, GasLimit, Opcode, StackMachine, OPCODE_COUNT,
+};
+
+fn decode(mut bytes: &[u8]) -> Vec<Opcode> {
+    let mut opcodes = Vec::new();
+    while let Some((&code, rest)) = bytes.split_first() {
+        bytes = rest;
+        let code = code % OPCODE_COUNT;
+        let opcode = match Opcode::try_from((code, None)) {
+            Err(DecodeOpcodeError::MissingImmediate(_)) if bytes.len() >= 8 => {
+                let (immediate, rest) = bytes.split_at(8);
+                bytes = rest;
+                Opcode::try_from((
+                    code,
+                    Some(i64::from_le_bytes(immediate.try_into().unwrap())),
+                ))
+            }
+            decoded => decoded,
+        };
+        match opcode {
+            Ok(opcode) => opcodes.push(opcode),
+            Err(_) => break,
+        }
+    }
+    opcodes
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut sm = StackMachine::default();
+    sm.st.load_program(decode(data));
+    sm.st.set_cell_limit(Some(1 << 16));
+    let _ = sm.execute(0, GasLimit::Limited(10_000));
+});
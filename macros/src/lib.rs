@@ -0,0 +1,429 @@
+//! Procedural macros for rust-simple-stack-processor, use them through the
+//! re-exports in that crate rather than depending on this crate directly.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+/// Jumps that take an offset relative to themselves
+const RELATIVE_JUMPS: &[&str] = &["JR", "JRZ", "JRNZ", "JRC", "JRO"];
+/// Opcodes that take an absolute address
+const ABSOLUTE_JUMPS: &[&str] = &["JMP", "CALL"];
+/// Opcodes that take two immediate operands
+const PAIRED_OPERANDS: &[&str] = &["SPILL", "UNSPILL", "DODOES"];
+
+enum Operand {
+    None,
+    Immediate(i64, Span),
+    /// The two operands of SPILL, UNSPILL and DODOES
+    Pair((i64, Span), (i64, Span)),
+    Label(String, Span),
+}
+
+struct Statement {
+    mnemonic: Ident,
+    operand: Operand,
+}
+
+struct Error {
+    message: String,
+    span: Span,
+}
+
+fn error<T>(message: impl Into<String>, span: Span) -> Result<T, Error> {
+    Err(Error {
+        message: message.into(),
+        span,
+    })
+}
+
+/// Assemble a program at compile time into a `&'static [Opcode]`.
+///
+/// Statements are separated by semicolons, and may be preceded by `label:`.
+/// An operand is either an integer immediate, or `@label`. For the JR family
+/// `@label` is turned into an LDI of the relative offset before the jump, for
+/// JMP and CALL into an LDI of the absolute address, and `LDI @label` pushes
+/// the absolute address, as does `LDPC @label` but relative to the LDPC. SPILL and UNSPILL
+/// take two integer operands, the count and the address, and DODOES the data field and the
+/// behavior. Unknown labels and mnemonics are compile errors.
+///
+/// ```ignore
+/// let code = ssp_asm! { start: LDI 1; JRNZ @start; RET };
+/// ```
+#[proc_macro]
+pub fn ssp_asm(input: TokenStream) -> TokenStream {
+    match assemble(input) {
+        Ok(tokens) => tokens,
+        Err(e) => compile_error(&e.message, e.span),
+    }
+}
+
+fn assemble(input: TokenStream) -> Result<TokenStream, Error> {
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = 0;
+
+    let mut tokens = input.into_iter().peekable();
+    while tokens.peek().is_some() {
+        let mut statement_tokens = Vec::new();
+        for token in tokens.by_ref() {
+            if let TokenTree::Punct(p) = &token {
+                if p.as_char() == ';' {
+                    break;
+                }
+            }
+            statement_tokens.push(token);
+        }
+        if statement_tokens.is_empty() {
+            continue;
+        }
+
+        let mut iter = statement_tokens.into_iter().peekable();
+        // Any number of labels can precede the mnemonic
+        let mnemonic = loop {
+            let ident = match iter.next() {
+                Some(TokenTree::Ident(i)) => i,
+                Some(other) => return error("expected a label or mnemonic", other.span()),
+                None => return error("expected a mnemonic after label", Span::call_site()),
+            };
+            match iter.peek() {
+                Some(TokenTree::Punct(p)) if p.as_char() == ':' => {
+                    iter.next();
+                    if labels.insert(ident.to_string(), address).is_some() {
+                        return error(
+                            format!("label '{}' defined more than once", ident),
+                            ident.span(),
+                        );
+                    }
+                }
+                _ => break ident,
+            }
+        };
+
+        let mut operand = parse_operand(&mut iter)?;
+        if let (Operand::Immediate(value, span), Some(_)) = (&operand, iter.peek()) {
+            match parse_operand(&mut iter)? {
+                Operand::Immediate(second, second_span) => {
+                    operand = Operand::Pair((*value, *span), (second, second_span));
+                }
+                Operand::Label(_, label_span) => {
+                    return error(
+                        format!("{} does not take a label as its second operand", mnemonic),
+                        label_span,
+                    );
+                }
+                Operand::None | Operand::Pair(..) => {}
+            }
+        }
+        if let Some(extra) = iter.next() {
+            return error("unexpected token, expected ';'", extra.span());
+        }
+
+        let name = mnemonic.to_string();
+        address += match operand {
+            Operand::Pair(..) if !PAIRED_OPERANDS.contains(&name.as_str()) => {
+                return error(
+                    format!("{} does not take two operands", name),
+                    mnemonic.span(),
+                );
+            }
+            Operand::Label(..) if name != "LDI" && name != "LDPC" => {
+                if !RELATIVE_JUMPS.contains(&name.as_str())
+                    && !ABSOLUTE_JUMPS.contains(&name.as_str())
+                {
+                    return error(
+                        format!("{} does not take a label operand", name),
+                        mnemonic.span(),
+                    );
+                }
+                2
+            }
+            _ => 1,
+        };
+        statements.push(Statement { mnemonic, operand });
+    }
+
+    let mut body = Vec::new();
+    let mut address = 0;
+    for statement in statements {
+        let name = statement.mnemonic.to_string();
+        match statement.operand {
+            Operand::None => {
+                push_opcode(&mut body, &statement.mnemonic, &[]);
+                address += 1;
+            }
+            Operand::Immediate(value, span) => {
+                push_opcode(&mut body, &statement.mnemonic, &[i64_literal(value, span)]);
+                address += 1;
+            }
+            Operand::Pair(first, second) => {
+                let immediates = [u32_literal(first)?, u32_literal(second)?];
+                push_opcode(&mut body, &statement.mnemonic, &immediates);
+                address += 1;
+            }
+            Operand::Label(label, span) => {
+                let target = match labels.get(&label) {
+                    Some(t) => *t as i64,
+                    None => return error(format!("undefined label '{}'", label), span),
+                };
+                if name == "LDI" || name == "LDPC" {
+                    let value = if name == "LDPC" {
+                        target - address as i64
+                    } else {
+                        target
+                    };
+                    push_opcode(&mut body, &statement.mnemonic, &[i64_literal(value, span)]);
+                    address += 1;
+                    continue;
+                }
+                let value = if RELATIVE_JUMPS.contains(&name.as_str()) {
+                    target - (address as i64 + 1)
+                } else {
+                    target
+                };
+                push_opcode(
+                    &mut body,
+                    &Ident::new("LDI", span),
+                    &[i64_literal(value, span)],
+                );
+                push_opcode(&mut body, &statement.mnemonic, &[]);
+                address += 2;
+            }
+        }
+    }
+
+    let slice = TokenTree::Group(Group::new(Delimiter::Bracket, TokenStream::from_iter(body)));
+    Ok(TokenStream::from_iter(vec![
+        TokenTree::Punct(Punct::new('&', Spacing::Alone)),
+        slice,
+    ]))
+}
+
+fn parse_operand(
+    iter: &mut std::iter::Peekable<std::vec::IntoIter<TokenTree>>,
+) -> Result<Operand, Error> {
+    match iter.next() {
+        None => Ok(Operand::None),
+        Some(TokenTree::Punct(p)) if p.as_char() == '@' => match iter.next() {
+            Some(TokenTree::Ident(label)) => Ok(Operand::Label(label.to_string(), label.span())),
+            _ => error("expected a label name after '@'", p.span()),
+        },
+        Some(TokenTree::Punct(p)) if p.as_char() == '-' => match iter.next() {
+            Some(TokenTree::Literal(l)) => {
+                let value = parse_integer(&l, true)?;
+                Ok(Operand::Immediate(value, l.span()))
+            }
+            _ => error("expected an integer after '-'", p.span()),
+        },
+        Some(TokenTree::Literal(l)) => Ok(Operand::Immediate(parse_integer(&l, false)?, l.span())),
+        Some(other) => error("expected an integer or @label operand", other.span()),
+    }
+}
+
+fn parse_integer(literal: &Literal, negative: bool) -> Result<i64, Error> {
+    let text = literal.to_string().replace('_', "");
+    let text = text.trim_end_matches("i64");
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16)
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        i128::from_str_radix(binary, 2)
+    } else {
+        text.parse::<i128>()
+    };
+    let value = match parsed {
+        Ok(v) if negative => -v,
+        Ok(v) => v,
+        Err(_) => return error("expected an integer literal", literal.span()),
+    };
+    if value < i128::from(i64::MIN) || value > i128::from(i64::MAX) {
+        return error("integer does not fit in an i64", literal.span());
+    }
+    Ok(value as i64)
+}
+
+fn i64_literal(value: i64, span: Span) -> Literal {
+    let mut literal = Literal::i64_suffixed(value);
+    literal.set_span(span);
+    literal
+}
+
+fn u32_literal((value, span): (i64, Span)) -> Result<Literal, Error> {
+    if value < 0 || value > i64::from(u32::MAX) {
+        return error("integer does not fit in a u32", span);
+    }
+    let mut literal = Literal::u32_suffixed(value as u32);
+    literal.set_span(span);
+    Ok(literal)
+}
+
+fn push_opcode(body: &mut Vec<TokenTree>, mnemonic: &Ident, immediates: &[Literal]) {
+    let span = mnemonic.span();
+    for segment in &["rust_simple_stack_processor", "Opcode"] {
+        body.push(TokenTree::Punct(Punct::new(':', Spacing::Joint)));
+        body.push(TokenTree::Punct(Punct::new(':', Spacing::Alone)));
+        body.push(TokenTree::Ident(Ident::new(segment, span)));
+    }
+    body.push(TokenTree::Punct(Punct::new(':', Spacing::Joint)));
+    body.push(TokenTree::Punct(Punct::new(':', Spacing::Alone)));
+    body.push(TokenTree::Ident(mnemonic.clone()));
+    if !immediates.is_empty() {
+        let mut arguments = Vec::new();
+        for literal in immediates {
+            if !arguments.is_empty() {
+                arguments.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+            }
+            arguments.push(TokenTree::Literal(literal.clone()));
+        }
+        body.push(TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(arguments),
+        )));
+    }
+    body.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+}
+
+/// Implement `StackAbi` for a struct with named fields that all implement it, laid out
+/// in declaration order.
+///
+/// ```ignore
+/// #[derive(StackAbi)]
+/// struct Rect { x: i64, y: i64, size: [i64; 2] }
+/// ```
+#[proc_macro_derive(StackAbi)]
+pub fn derive_stack_abi(input: TokenStream) -> TokenStream {
+    match stack_abi(input) {
+        Ok(tokens) => tokens,
+        Err(e) => compile_error(&e.message, e.span),
+    }
+}
+
+fn stack_abi(input: TokenStream) -> Result<TokenStream, Error> {
+    let mut tokens = input.into_iter();
+    // Skip attributes and visibility up to the struct keyword
+    let name = loop {
+        match tokens.next() {
+            Some(TokenTree::Ident(i)) if i.to_string() == "struct" => match tokens.next() {
+                Some(TokenTree::Ident(name)) => break name,
+                _ => return error("expected a struct name", i.span()),
+            },
+            Some(TokenTree::Ident(i)) if i.to_string() == "enum" || i.to_string() == "union" => {
+                return error("StackAbi can only be derived for structs", i.span())
+            }
+            Some(_) => {}
+            None => return error("expected a struct", Span::call_site()),
+        }
+    };
+    let body = match tokens.next() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => g,
+        Some(other) => {
+            return error(
+                "StackAbi can only be derived for structs with named fields and no generics",
+                other.span(),
+            )
+        }
+        None => return error("expected the struct fields", name.span()),
+    };
+
+    // Each field is `attributes visibility name: type,`, split on commas outside of <>
+    let mut fields = Vec::new();
+    let mut field: Vec<TokenTree> = Vec::new();
+    let mut angle_depth = 0;
+    for token in body
+        .stream()
+        .into_iter()
+        .chain(std::iter::once(TokenTree::Punct(Punct::new(
+            ',',
+            Spacing::Alone,
+        ))))
+    {
+        if let TokenTree::Punct(p) = &token {
+            match p.as_char() {
+                '<' => angle_depth += 1,
+                '>' => angle_depth -= 1,
+                ',' if angle_depth == 0 => {
+                    if !field.is_empty() {
+                        fields.push(parse_field(std::mem::take(&mut field))?);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        field.push(token);
+    }
+
+    let abi = "::rust_simple_stack_processor::StackAbi";
+    let mut width = String::from("0");
+    let mut to_values = String::new();
+    let mut from_values = String::new();
+    let mut names = Vec::new();
+    for (field_name, field_type) in &fields {
+        width += &format!(" + <{} as {}>::WIDTH", field_type, abi);
+        to_values += &format!("{}::to_values(&self.{}, out);", abi, field_name);
+        from_values += &format!(
+            "let {name} = <{ty} as {abi}>::from_values(&values[offset..offset + <{ty} as {abi}>::WIDTH]); \
+             offset += <{ty} as {abi}>::WIDTH;",
+            name = field_name,
+            ty = field_type,
+            abi = abi
+        );
+        names.push(field_name.clone());
+    }
+    let output = format!(
+        "impl {abi} for {name} {{ \
+             const WIDTH: usize = {width}; \
+             fn to_values(&self, out: &mut ::std::vec::Vec<i64>) {{ {to_values} }} \
+             #[allow(unused_assignments, unused_mut, unused_variables)] \
+             fn from_values(values: &[i64]) -> Self {{ \
+                 let mut offset = 0; {from_values} {name} {{ {names} }} \
+             }} \
+         }}",
+        abi = abi,
+        name = name,
+        width = width,
+        to_values = to_values,
+        from_values = from_values,
+        names = names.join(", ")
+    );
+    output
+        .parse()
+        .or_else(|_| error("could not derive StackAbi", name.span()))
+}
+
+fn parse_field(tokens: Vec<TokenTree>) -> Result<(String, String), Error> {
+    let colon = tokens
+        .iter()
+        .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ':'));
+    match colon {
+        Some(colon) if colon > 0 => match &tokens[colon - 1] {
+            TokenTree::Ident(name) => {
+                let ty = TokenStream::from_iter(tokens[colon + 1..].iter().cloned());
+                Ok((name.to_string(), ty.to_string()))
+            }
+            other => error("expected a field name", other.span()),
+        },
+        _ => error(
+            "StackAbi can only be derived for structs with named fields",
+            tokens[0].span(),
+        ),
+    }
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let tokens = vec![
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from(TokenTree::Literal(literal)),
+        )),
+    ];
+    TokenStream::from_iter(tokens.into_iter().map(|mut t| {
+        t.set_span(span);
+        t
+    }))
+}
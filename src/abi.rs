@@ -0,0 +1,89 @@
+use super::{ErrorKind, StackKind, StackMachineError, StackMachineState, TrapFrame};
+
+/// A Rust value laid out as WIDTH consecutive i64s, for passing structs to and from traps.
+///
+/// On the number stack the values are pushed in order, so the last field is on top, and in
+/// cells the first field is at the lowest address. Derive it for structs whose fields all
+/// implement StackAbi with `#[derive(StackAbi)]`.
+pub trait StackAbi: Sized {
+    const WIDTH: usize;
+
+    /// Append the values, first field first
+    fn to_values(&self, out: &mut Vec<i64>);
+
+    /// Build from exactly WIDTH values
+    fn from_values(values: &[i64]) -> Self;
+}
+
+impl StackAbi for i64 {
+    const WIDTH: usize = 1;
+
+    fn to_values(&self, out: &mut Vec<i64>) {
+        out.push(*self);
+    }
+
+    fn from_values(values: &[i64]) -> Self {
+        values[0]
+    }
+}
+
+impl<const N: usize> StackAbi for [i64; N] {
+    const WIDTH: usize = N;
+
+    fn to_values(&self, out: &mut Vec<i64>) {
+        out.extend_from_slice(self);
+    }
+
+    fn from_values(values: &[i64]) -> Self {
+        let mut array = [0; N];
+        array.copy_from_slice(values);
+        array
+    }
+}
+
+impl<'a> TrapFrame<'a> {
+    /// Remove a struct passed as arguments
+    pub fn arg_struct<T: StackAbi>(&mut self) -> Result<T, StackMachineError> {
+        let stack = self.state().number_stack_mut();
+        let start = stack
+            .len()
+            .checked_sub(T::WIDTH)
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        let value = T::from_values(&stack[start..]);
+        stack.truncate(start);
+        Ok(value)
+    }
+
+    /// Push a struct as results
+    pub fn ret_struct<T: StackAbi>(&mut self, value: &T) {
+        let mut values = Vec::with_capacity(T::WIDTH);
+        value.to_values(&mut values);
+        self.ret(&values);
+    }
+}
+
+impl StackMachineState {
+    /// Read a struct from the cells starting at address, which must all be allocated
+    pub fn load_struct<T: StackAbi>(&self, address: usize) -> Result<T, StackMachineError> {
+        let range = self.cell_range(address, T::WIDTH)?;
+        Ok(T::from_values(&self.cells[range]))
+    }
+
+    /// Write a struct to the cells starting at address, which must all be allocated, each
+    /// value wrapped to the word size
+    pub fn store_struct<T: StackAbi>(
+        &mut self,
+        address: usize,
+        value: &T,
+    ) -> Result<(), StackMachineError> {
+        let range = self.cell_range(address, T::WIDTH)?;
+        let mut values = Vec::with_capacity(T::WIDTH);
+        value.to_values(&mut values);
+        let word_size = self.word_size;
+        for (cell, value) in self.cells[range.clone()].iter_mut().zip(values) {
+            *cell = word_size.wrap(value);
+        }
+        self.touch_cells(range);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use super::{ErrorKind, StackMachineError};
+
+/// Grow cells to len cells, initialised to 0, unless that would be more than limit or more
+/// than can be allocated
+pub(crate) fn grow_cells(
+    cells: &mut Vec<i64>,
+    len: usize,
+    limit: Option<usize>,
+) -> Result<(), StackMachineError> {
+    if limit.is_some_and(|limit| len > limit) {
+        return Err(ErrorKind::CellLimitExceeded.into());
+    }
+    if len > cells.len() {
+        cells
+            .try_reserve_exact(len - cells.len())
+            .map_err(|_| ErrorKind::CellLimitExceeded)?;
+        cells.resize(len, 0);
+    }
+    Ok(())
+}
+
+/// A live block of cells handed out by NEWCELLS while the cell allocator is enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: usize,
+    pub len: usize,
+}
+
+/// Places NEWCELLS allocations at aligned addresses surrounded by guard cells, and tracks
+/// them so that MOVETOCELLS and MOVEFROMCELLS can refuse to touch cells outside of one
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CellAllocator {
+    alignment: usize,
+    guard_cells: usize,
+    // Each allocation with the first cell reserved for it, including padding and guards
+    allocations: Vec<(usize, Allocation)>,
+}
+
+impl CellAllocator {
+    /// An allocator taking over the cells already allocated as a single allocation
+    pub(crate) fn new(alignment: usize, guard_cells: usize, cells: &[i64]) -> Self {
+        let mut allocator = CellAllocator {
+            alignment: alignment.max(1),
+            guard_cells,
+            allocations: Vec::new(),
+        };
+        allocator.reset(cells);
+        allocator
+    }
+
+    /// Forget every allocation, taking over the cells as a single allocation
+    pub(crate) fn reset(&mut self, cells: &[i64]) {
+        self.allocations.clear();
+        if !cells.is_empty() {
+            self.allocations.push((
+                0,
+                Allocation {
+                    address: 0,
+                    len: cells.len(),
+                },
+            ));
+        }
+    }
+
+    pub(crate) fn allocations(&self) -> impl Iterator<Item = &Allocation> + '_ {
+        self.allocations.iter().map(|(_, allocation)| allocation)
+    }
+
+    /// Grow cells by len usable cells, plus padding and guards, returning the address
+    pub(crate) fn allocate(
+        &mut self,
+        cells: &mut Vec<i64>,
+        len: usize,
+        limit: Option<usize>,
+    ) -> Result<usize, StackMachineError> {
+        let reserved = cells.len();
+        let unaligned = reserved
+            .checked_add(self.guard_cells)
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        let address = unaligned
+            .checked_add((self.alignment - unaligned % self.alignment) % self.alignment)
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        let end = address
+            .checked_add(len)
+            .and_then(|end| end.checked_add(self.guard_cells))
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        grow_cells(cells, end, limit)?;
+        self.allocations
+            .push((reserved, Allocation { address, len }));
+        Ok(address)
+    }
+
+    /// Release the most recent allocation, which must be len cells long
+    pub(crate) fn free(
+        &mut self,
+        cells: &mut Vec<i64>,
+        len: usize,
+    ) -> Result<(), StackMachineError> {
+        match self.allocations.last() {
+            Some((reserved, allocation)) if allocation.len == len => {
+                cells.truncate(*reserved);
+                self.allocations.pop();
+                Ok(())
+            }
+            _ => Err(ErrorKind::InvalidCellOperation.into()),
+        }
+    }
+
+    /// Forget the allocations that no longer fit in len cells
+    pub(crate) fn trim(&mut self, len: usize) {
+        self.allocations
+            .retain(|(_, allocation)| allocation.address + allocation.len <= len);
+    }
+
+    /// Check that the len cells from address all belong to a single allocation
+    pub(crate) fn check(&self, address: usize, len: usize) -> Result<(), StackMachineError> {
+        let index = self
+            .allocations
+            .partition_point(|(_, allocation)| allocation.address + allocation.len <= address);
+        match self.allocations.get(index) {
+            Some((_, allocation))
+                if allocation.address <= address
+                    && address + len <= allocation.address + allocation.len =>
+            {
+                Ok(())
+            }
+            Some((_, allocation)) if allocation.address <= address => {
+                Err(ErrorKind::GuardCellAccess {
+                    address: allocation.address + allocation.len,
+                }
+                .into())
+            }
+            _ => Err(ErrorKind::GuardCellAccess { address }.into()),
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+
+use super::{propagate_constants, validate, AbstractValue, Opcode, ValidationError};
+
+/// Static metrics for a program, see `analyze`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub instruction_count: usize,
+    /// Jumps of any kind, including CALL
+    pub branch_count: usize,
+    pub call_count: usize,
+    pub trap_count: usize,
+    /// Deepest nesting of PUSHLP/DROPLP pairs, in program order
+    pub max_loop_nesting: usize,
+    /// Upper bound on the gas used running the program from address 0, when one can be derived
+    pub worst_case_gas: Option<u64>,
+}
+
+/// Compute static metrics for a program.
+///
+/// A worst case gas bound is only derived when every jump and call has a target
+/// given by the LDI immediately before it, no jump goes backwards, and no
+/// subroutine is recursive, i.e. when the program cannot loop.
+pub fn analyze(opcodes: &[Opcode]) -> Metrics {
+    let mut nesting = 0_usize;
+    let mut max_loop_nesting = 0;
+    for op in opcodes {
+        match op {
+            Opcode::PUSHLP => {
+                nesting += 1;
+                max_loop_nesting = max_loop_nesting.max(nesting);
+            }
+            Opcode::DROPLP => nesting = nesting.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    Metrics {
+        instruction_count: opcodes.len(),
+        branch_count: opcodes.iter().filter(|op| is_branch(op)).count(),
+        call_count: opcodes.iter().filter(|op| **op == Opcode::CALL).count(),
+        trap_count: opcodes
+            .iter()
+            .filter(|op| matches!(op, Opcode::TRAP | Opcode::TRAPID(_)))
+            .count(),
+        max_loop_nesting,
+        worst_case_gas: worst_case_gas(opcodes, 0),
+    }
+}
+
+pub(crate) fn is_branch(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::JMP
+            | Opcode::JR
+            | Opcode::JRZ
+            | Opcode::JRNZ
+            | Opcode::JRC
+            | Opcode::JRO
+            | Opcode::CALL
+            | Opcode::DODOES(..)
+    )
+}
+
+pub(crate) fn is_relative_branch(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::JR | Opcode::JRZ | Opcode::JRNZ | Opcode::JRC | Opcode::JRO
+    )
+}
+
+/// The target of the jump or call at pc, when it is given by the LDI or LDPC immediately
+/// before it, or by the jump itself for DODOES
+pub(crate) fn static_target(opcodes: &[Opcode], pc: usize) -> Option<usize> {
+    let op = opcodes.get(pc)?;
+    if let Opcode::DODOES(_, behavior) = op {
+        return usize::try_from(*behavior).ok();
+    }
+    let value = match opcodes.get(pc.checked_sub(1)?)? {
+        Opcode::LDI(value) => *value,
+        Opcode::LDPC(offset) => i64::try_from(pc - 1).ok()?.checked_add(*offset)?,
+        _ => return None,
+    };
+    if is_relative_branch(op) {
+        usize::try_from(i64::try_from(pc).ok()?.checked_add(value)?).ok()
+    } else if *op == Opcode::JMP || *op == Opcode::CALL {
+        usize::try_from(value).ok()
+    } else {
+        None
+    }
+}
+
+/// A loop of the form `LDI max, LDI start, PUSHLP, body..., INCLP, CMPLOOP, LDI back, JRZ`
+/// whose body leaves its counter alone, so it runs a known number of times
+#[derive(Clone, Copy)]
+struct CountedLoop {
+    latch: usize,
+    iterations: u64,
+}
+
+/// Whether the body of the counted loop from head up to its INCLP at end can only reach
+/// the INCLP with its own counter on top of the loop stack. The loop stack depth is tracked
+/// through the body, so nested loops are fine, but calls, traps, anything touching the
+/// counter, or a backward branch at the loop's own depth are not.
+fn leaves_counter_alone(opcodes: &[Opcode], head: usize, end: usize) -> bool {
+    // Loop stack depth above the counter on reaching each instruction of the body, and end
+    let mut depths = vec![None; end - head + 1];
+    depths[0] = Some(0_usize);
+    let reach = |depths: &mut Vec<Option<usize>>, pc: usize, depth: usize| match depths[pc - head] {
+        Some(known) => known == depth,
+        None => {
+            depths[pc - head] = Some(depth);
+            true
+        }
+    };
+    for pc in head..end {
+        // Only reached through a backward branch, which is refused below
+        let depth = match depths[pc - head] {
+            Some(depth) => depth,
+            None => continue,
+        };
+        let op = &opcodes[pc];
+        let next = match op {
+            Opcode::CALL | Opcode::TRAP | Opcode::TRAPID(_) => return false,
+            Opcode::DROPLP | Opcode::INCLP | Opcode::ADDLP if depth == 0 => return false,
+            Opcode::PUSHLP => Some(depth + 1),
+            Opcode::DROPLP => Some(depth - 1),
+            Opcode::RET | Opcode::JMP | Opcode::DODOES(..) | Opcode::JR => None,
+            _ => Some(depth),
+        };
+        if is_relative_branch(op) || matches!(op, Opcode::JMP | Opcode::DODOES(..)) {
+            let target = match static_target(opcodes, pc) {
+                Some(target) => target,
+                None => return false,
+            };
+            if target <= pc {
+                // Back to code already checked, at the same depth, to loop in a nested loop
+                if depth == 0 || target < head || depths[target - head] != Some(depth) {
+                    return false;
+                }
+            } else if target <= end {
+                if !reach(&mut depths, target, depth) {
+                    return false;
+                }
+            } else if target <= end + 3 {
+                // Skipping the INCLP
+                return false;
+            }
+        }
+        if let Some(next) = next {
+            if !reach(&mut depths, pc + 1, next) {
+                return false;
+            }
+        }
+    }
+    matches!(depths[end - head], None | Some(0))
+}
+
+fn counted_loops(opcodes: &[Opcode]) -> HashMap<usize, CountedLoop> {
+    let mut loops = HashMap::new();
+    for (pc, op) in opcodes.iter().enumerate() {
+        if *op != Opcode::JRZ || pc < 4 {
+            continue;
+        }
+        let head = match static_target(opcodes, pc) {
+            Some(head) if head >= 3 && head + 3 <= pc => head,
+            _ => continue,
+        };
+        if opcodes[pc - 2] != Opcode::CMPLOOP
+            || opcodes[pc - 3] != Opcode::INCLP
+            || opcodes[head - 1] != Opcode::PUSHLP
+        {
+            continue;
+        }
+        let (max, start) = match (&opcodes[head - 3], &opcodes[head - 2]) {
+            (Opcode::LDI(max), Opcode::LDI(start)) => (*max, *start),
+            _ => continue,
+        };
+        if !leaves_counter_alone(opcodes, head, pc - 3) {
+            continue;
+        }
+        // The body always runs at least once
+        let iterations = u64::try_from(i128::from(max) - i128::from(start))
+            .unwrap_or(0)
+            .max(1);
+        loops.insert(
+            head,
+            CountedLoop {
+                latch: pc,
+                iterations,
+            },
+        );
+    }
+    loops
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    Unvisited,
+    InProgress,
+    Done(Option<u64>),
+}
+
+enum Step {
+    /// Execution stops after this instruction
+    Terminal,
+    /// Continues with one of these
+    Next(Vec<usize>),
+    /// Calls the first address, then continues with the second
+    Call(usize, usize),
+    /// Runs a counted loop, whose body costs the given gas per iteration, then continues
+    Loop(u64, usize),
+    /// No bound can be given
+    Unbounded,
+}
+
+struct WorstCase<'a> {
+    opcodes: &'a [Opcode],
+    loops: HashMap<usize, CountedLoop>,
+}
+
+impl<'a> WorstCase<'a> {
+    fn step(&self, pc: usize, entry: usize, stop: Option<usize>) -> Step {
+        let opcodes = self.opcodes;
+        if Some(pc) == stop {
+            return Step::Terminal;
+        }
+        if let Some(counted) = self.loops.get(&pc) {
+            if pc != entry || stop.is_none() {
+                return match self.longest(pc, Some(counted.latch)) {
+                    Some(body) => {
+                        Step::Loop(body.saturating_mul(counted.iterations), counted.latch + 1)
+                    }
+                    None => Step::Unbounded,
+                };
+            }
+        }
+        let target = static_target(opcodes, pc);
+        match &opcodes[pc] {
+            Opcode::RET | Opcode::TRAP | Opcode::TRAPID(_) => Step::Terminal,
+            Opcode::JR | Opcode::JMP | Opcode::DODOES(..) => match target {
+                Some(t) if t > pc => Step::Next(vec![t]),
+                _ => Step::Unbounded,
+            },
+            Opcode::JRZ if self.loops.values().any(|l| l.latch == pc) => Step::Next(vec![pc + 1]),
+            Opcode::JRZ | Opcode::JRNZ | Opcode::JRC | Opcode::JRO => match target {
+                Some(t) if t > pc => Step::Next(vec![pc + 1, t]),
+                _ => Step::Unbounded,
+            },
+            Opcode::CALL => match target {
+                Some(t) => Step::Call(t, pc + 1),
+                None => Step::Unbounded,
+            },
+            _ => Step::Next(vec![pc + 1]),
+        }
+    }
+
+    /// Longest path, in instructions, from entry to the RET that ends it, or to stop
+    fn longest(&self, entry: usize, stop: Option<usize>) -> Option<u64> {
+        let opcodes = self.opcodes;
+        let mut visits = vec![Visit::Unvisited; opcodes.len()];
+        // Running off the end of the program, or jumping outside it, stops execution
+        let done = |visits: &[Visit], pc: usize| -> Option<u64> {
+            match visits.get(pc) {
+                None => Some(0),
+                Some(Visit::Done(cost)) => *cost,
+                Some(_) => None,
+            }
+        };
+
+        let mut stack = vec![entry];
+        while let Some(&pc) = stack.last() {
+            if pc >= opcodes.len() {
+                stack.pop();
+                continue;
+            }
+            match visits[pc] {
+                Visit::Done(_) => {
+                    stack.pop();
+                }
+                Visit::Unvisited => {
+                    visits[pc] = Visit::InProgress;
+                    let dependencies = match self.step(pc, entry, stop) {
+                        Step::Terminal | Step::Unbounded => vec![],
+                        Step::Next(next) => next,
+                        Step::Call(target, next) => vec![target, next],
+                        Step::Loop(_, next) => vec![next],
+                    };
+                    for d in dependencies {
+                        match visits.get(d) {
+                            Some(Visit::Unvisited) => stack.push(d),
+                            // A cycle, only possible through recursion
+                            Some(Visit::InProgress) => visits[pc] = Visit::Done(None),
+                            _ => {}
+                        }
+                    }
+                }
+                Visit::InProgress => {
+                    let cost = match self.step(pc, entry, stop) {
+                        Step::Terminal => Some(1),
+                        Step::Unbounded => None,
+                        Step::Next(next) => next
+                            .iter()
+                            .map(|n| done(&visits, *n))
+                            .try_fold(0_u64, |max, c| c.map(|c| max.max(c)))
+                            .map(|c| c.saturating_add(1)),
+                        Step::Call(target, next) => done(&visits, target)
+                            .and_then(|t| done(&visits, next).map(|n| t.saturating_add(n)))
+                            .map(|c| c.saturating_add(1)),
+                        Step::Loop(body, next) => {
+                            done(&visits, next).map(|n| n.saturating_add(body))
+                        }
+                    };
+                    visits[pc] = Visit::Done(cost);
+                    stack.pop();
+                }
+            }
+        }
+        done(&visits, entry)
+    }
+}
+
+/// Upper bound on the gas used running from an address to the RET that ends it
+pub(crate) fn worst_case_gas(opcodes: &[Opcode], entry: usize) -> Option<u64> {
+    WorstCase {
+        opcodes,
+        loops: counted_loops(opcodes),
+    }
+    .longest(entry, None)
+}
+
+/// Result of estimating the cost of a program before running it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CostEstimate {
+    /// The program is guaranteed to finish within the budget
+    WithinBudget { worst_case_gas: u64 },
+    /// The program may use more than the budget, it can be rejected up front
+    ExceedsBudget { worst_case_gas: u64 },
+    /// No bound could be proven, the program has to be run with a gas limit
+    NeedsMetering,
+}
+
+/// Validate a program, then try to prove it finishes within a gas budget when run from entry.
+///
+/// Loop free code is bounded, as are counted loops written as
+/// `LDI max, LDI start, PUSHLP, body..., INCLP, CMPLOOP, LDI back, JRZ` whose
+/// body does not touch the loop's counter, call subroutines or raise traps.
+pub fn estimate_cost(
+    opcodes: &[Opcode],
+    entry: usize,
+    gas_budget: u64,
+) -> Result<CostEstimate, ValidationError> {
+    validate(opcodes)?;
+    Ok(match worst_case_gas(opcodes, entry) {
+        Some(worst_case_gas) if worst_case_gas <= gas_budget => {
+            CostEstimate::WithinBudget { worst_case_gas }
+        }
+        Some(worst_case_gas) => CostEstimate::ExceedsBudget { worst_case_gas },
+        None => CostEstimate::NeedsMetering,
+    })
+}
+
+/// The traps a program can raise, see `trap_usage`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrapUsage {
+    pub trap_ids: BTreeSet<i64>,
+    /// Reachable TRAPs whose trap id is not a constant, so could be anything
+    pub dynamic_traps: Vec<usize>,
+}
+
+impl TrapUsage {
+    /// Whether every trap the program can raise is listed in trap_ids
+    pub fn is_complete(&self) -> bool {
+        self.dynamic_traps.is_empty()
+    }
+
+    /// Whether the program can only raise traps for which allowed returns true
+    pub fn only_uses(&self, allowed: impl Fn(i64) -> bool) -> bool {
+        self.is_complete() && self.trap_ids.iter().all(|&trap_id| allowed(trap_id))
+    }
+}
+
+/// Find the trap ids a program run from address 0 can raise, from TRAPID immediates and
+/// from TRAPs whose id `propagate_constants` finds is always the same, so that a host can
+/// refuse programs using traps it has not approved before running them
+pub fn trap_usage(opcodes: &[Opcode]) -> TrapUsage {
+    let analysis = propagate_constants(opcodes);
+    let mut usage = TrapUsage::default();
+    for (pc, op) in opcodes.iter().enumerate() {
+        if !analysis.is_reachable(pc) {
+            continue;
+        }
+        match op {
+            Opcode::TRAPID(trap_id) => {
+                usage.trap_ids.insert(*trap_id);
+            }
+            Opcode::TRAP => match analysis.stack_at(pc).and_then(<[_]>::last) {
+                Some(AbstractValue::Known(trap_id)) => {
+                    usage.trap_ids.insert(*trap_id);
+                }
+                _ => usage.dynamic_traps.push(pc),
+            },
+            _ => {}
+        }
+    }
+    usage
+}
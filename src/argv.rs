@@ -0,0 +1,62 @@
+use std::convert::TryFrom;
+
+use super::{
+    ErrorKind, HandleTrap, StackMachine, StackMachineError, StackMachineState, TrapHandled,
+};
+
+impl StackMachine {
+    /// Set the arguments of the following runs, for programs to read with `ArgvTraps` as a
+    /// process reads its command line
+    pub fn set_argv(&mut self, args: &[i64]) {
+        self.st.argv = args.to_vec();
+    }
+}
+
+impl StackMachineState {
+    /// The arguments set with `StackMachine::set_argv`
+    pub fn argv(&self) -> &[i64] {
+        &self.argv
+    }
+}
+
+/// Traps reading the arguments the host sets with `StackMachine::set_argv`:
+///
+/// - first_trap_id, ARGC ( -- n ) the number of arguments
+/// - first_trap_id + 1, ARGN ( i -- n ) argument i, counting from 0
+///
+/// Asking for an argument outside of 0 to ARGC - 1 gives a NoSuchArgument error
+pub struct ArgvTraps {
+    first_trap_id: i64,
+}
+
+impl ArgvTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        ArgvTraps { first_trap_id }
+    }
+}
+
+impl HandleTrap for ArgvTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id.checked_sub(self.first_trap_id) {
+            Some(0) => {
+                let argc = i64::try_from(st.argv.len())?;
+                st.push_number(argc);
+            }
+            Some(1) => {
+                let index = st.pop_number()?;
+                let argc = st.argv.len();
+                let arg = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| st.argv.get(i).copied())
+                    .ok_or(ErrorKind::NoSuchArgument { index, argc })?;
+                st.push_number(arg);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
@@ -0,0 +1,137 @@
+use super::dictionary::cell_index;
+use super::simd;
+use super::{ErrorKind, HandleTrap, StackMachineError, StackMachineState, TrapHandled};
+
+/// Traps running loops over arrays of cells natively, so numeric programs use the gas of a
+/// single TRAP for a whole inner loop. Arrays are given by the address of their first cell,
+/// matrices are stored a row at a time, and arithmetic wraps to the word size like ADD and
+/// MUL:
+///
+/// - first_trap_id, ADD ( a b dest len -- ) dest = a + b
+/// - first_trap_id + 1, SUB ( a b dest len -- ) dest = a - b
+/// - first_trap_id + 2, MUL ( a b dest len -- ) dest = a * b, element by element
+/// - first_trap_id + 3, SCALE ( a n dest len -- ) dest = a * n
+/// - first_trap_id + 4, DOT ( a b len -- n ) the dot product of a and b
+/// - first_trap_id + 5, SUM ( a len -- n )
+/// - first_trap_id + 6, RAMP ( dest len start step -- ) `dest[i] = start + i * step`
+/// - first_trap_id + 7, MATMUL ( a b dest rows inner columns -- ) dest = a * b, where a is
+///   rows by inner and b is inner by columns
+///
+/// ADD, SUB and SUM use SIMD instructions with the `simd` feature on x86_64.
+/// The results are written after every value has been read, so dest may overlap the
+/// arrays it is computed from. Cells that have not been allocated, or are outside of a
+/// single allocation while the cell allocator is enabled, give an error
+pub struct ArrayTraps {
+    first_trap_id: i64,
+}
+
+impl ArrayTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        ArrayTraps { first_trap_id }
+    }
+}
+
+impl StackMachineState {
+    // The values of the len cells from address
+    fn read_array(&self, address: i64, len: usize) -> Result<&[i64], StackMachineError> {
+        let range = self.cell_range(cell_index(address)?, len)?;
+        Ok(&self.cells[range])
+    }
+
+    fn write_array(&mut self, address: i64, values: &[i64]) -> Result<(), StackMachineError> {
+        let range = self.cell_range(cell_index(address)?, values.len())?;
+        let word_size = self.word_size;
+        for (cell, value) in self.cells[range.clone()].iter_mut().zip(values) {
+            *cell = word_size.wrap(*value);
+        }
+        self.touch_cells(range);
+        Ok(())
+    }
+
+    // Apply a kernel to the arrays a and b, in ( a b dest len -- )
+    fn zip_arrays(
+        &mut self,
+        kernel: fn(&[i64], &[i64]) -> Vec<i64>,
+    ) -> Result<(), StackMachineError> {
+        let [a, b, dest, len] = self.pop_n()?;
+        let len = cell_index(len)?;
+        let values = kernel(self.read_array(a, len)?, self.read_array(b, len)?);
+        self.write_array(dest, &values)
+    }
+}
+
+// The number of elements in a rows by columns matrix
+fn matrix_len(rows: usize, columns: usize) -> Result<usize, StackMachineError> {
+    rows.checked_mul(columns)
+        .ok_or_else(|| ErrorKind::InvalidCellOperation.into())
+}
+
+impl HandleTrap for ArrayTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id.checked_sub(self.first_trap_id) {
+            Some(0) => st.zip_arrays(simd::add)?,
+            Some(1) => st.zip_arrays(simd::sub)?,
+            Some(2) => st.zip_arrays(simd::mul)?,
+            Some(3) => {
+                let [a, n, dest, len] = st.pop_n()?;
+                let values: Vec<i64> = st
+                    .read_array(a, cell_index(len)?)?
+                    .iter()
+                    .map(|x| x.wrapping_mul(n))
+                    .collect();
+                st.write_array(dest, &values)?;
+            }
+            Some(4) => {
+                let [a, b, len] = st.pop_n()?;
+                let len = cell_index(len)?;
+                let dot = st
+                    .read_array(a, len)?
+                    .iter()
+                    .zip(st.read_array(b, len)?)
+                    .fold(0i64, |sum, (x, y)| sum.wrapping_add(x.wrapping_mul(*y)));
+                st.push_number(dot);
+            }
+            Some(5) => {
+                let [a, len] = st.pop_n()?;
+                let sum = simd::sum(st.read_array(a, cell_index(len)?)?);
+                st.push_number(sum);
+            }
+            Some(6) => {
+                let [dest, len, start, step] = st.pop_n()?;
+                let len = cell_index(len)?;
+                // Check the cells before making a ramp as long as len
+                st.cell_range(cell_index(dest)?, len)?;
+                let values: Vec<i64> = (0..len)
+                    .map(|i| start.wrapping_add((i as i64).wrapping_mul(step)))
+                    .collect();
+                st.write_array(dest, &values)?;
+            }
+            Some(7) => {
+                let [a, b, dest, rows, inner, columns] = st.pop_n()?;
+                let (rows, inner, columns) =
+                    (cell_index(rows)?, cell_index(inner)?, cell_index(columns)?);
+                let len = matrix_len(rows, columns)?;
+                st.cell_range(cell_index(dest)?, len)?;
+                let a = st.read_array(a, matrix_len(rows, inner)?)?;
+                let b = st.read_array(b, matrix_len(inner, columns)?)?;
+                let mut values = Vec::with_capacity(len);
+                for row in 0..rows {
+                    for column in 0..columns {
+                        values.push((0..inner).fold(0i64, |sum, k| {
+                            sum.wrapping_add(
+                                a[row * inner + k].wrapping_mul(b[k * columns + column]),
+                            )
+                        }));
+                    }
+                }
+                st.write_array(dest, &values)?;
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::{Library, Opcode, SymbolTable};
+
+/// Error returned when a program refers to labels that cannot be resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssembleError {
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleError::UndefinedLabel(l) => write!(f, "undefined label '{}'", l),
+            AssembleError::DuplicateLabel(l) => write!(f, "label '{}' defined more than once", l),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+enum Fixup {
+    Absolute,
+    Relative,
+    PcRelative,
+}
+
+/// Builds a program, resolving references to labels once all of them are known.
+///
+/// A label marks the address of the next opcode added. References are emitted
+/// as LDI opcodes holding either the absolute address of the label (for JMP and
+/// CALL), or the offset from the following opcode to the label (for the JR family).
+/// `ldpc` references are LDPC opcodes holding the offset from the LDPC itself, for
+/// code that works wherever it is loaded.
+#[derive(Default)]
+pub struct ProgramBuilder {
+    opcodes: Vec<Opcode>,
+    labels: HashMap<String, usize>,
+    duplicate_label: Option<String>,
+    fixups: Vec<(usize, String, Fixup)>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    pub fn op(&mut self, opcode: Opcode) -> &mut Self {
+        self.opcodes.push(opcode);
+        self
+    }
+
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        if self
+            .labels
+            .insert(name.to_string(), self.opcodes.len())
+            .is_some()
+            && self.duplicate_label.is_none()
+        {
+            self.duplicate_label = Some(name.to_string());
+        }
+        self
+    }
+
+    /// Push the absolute address of a label
+    pub fn ldi_address(&mut self, label: &str) -> &mut Self {
+        self.fixups
+            .push((self.opcodes.len(), label.to_string(), Fixup::Absolute));
+        self.op(Opcode::LDI(0))
+    }
+
+    /// Push the offset of a label relative to the opcode after this one
+    pub fn ldi_relative(&mut self, label: &str) -> &mut Self {
+        self.fixups
+            .push((self.opcodes.len(), label.to_string(), Fixup::Relative));
+        self.op(Opcode::LDI(0))
+    }
+
+    /// Push the address of a label as an LDPC, relative to the LDPC itself
+    pub fn ldpc(&mut self, label: &str) -> &mut Self {
+        self.fixups
+            .push((self.opcodes.len(), label.to_string(), Fixup::PcRelative));
+        self.op(Opcode::LDPC(0))
+    }
+
+    /// Build the program, along with a symbol table holding every label, including labels
+    /// that alias another
+    pub fn build_with_symbols(self) -> Result<(Vec<Opcode>, SymbolTable), AssembleError> {
+        let mut symbols = SymbolTable::new();
+        for (name, address) in &self.labels {
+            symbols.insert(*address, name);
+        }
+        Ok((self.build()?, symbols))
+    }
+
+    /// Build the program as a library exporting every label, with a relocation for each
+    /// absolute address it pushes
+    pub fn build_library(self) -> Result<Library, AssembleError> {
+        let relocations = self
+            .fixups
+            .iter()
+            .filter(|(_, _, fixup)| matches!(fixup, Fixup::Absolute))
+            .map(|(index, _, _)| *index)
+            .collect();
+        let (opcodes, exports) = self.build_with_symbols()?;
+        Ok(Library {
+            opcodes,
+            exports,
+            relocations,
+        })
+    }
+
+    pub fn build(self) -> Result<Vec<Opcode>, AssembleError> {
+        if let Some(label) = self.duplicate_label {
+            return Err(AssembleError::DuplicateLabel(label));
+        }
+        let mut opcodes = self.opcodes;
+        for (index, label, fixup) in self.fixups {
+            let target = *self
+                .labels
+                .get(&label)
+                .ok_or(AssembleError::UndefinedLabel(label))?;
+            // Addresses are bounded by the length of the program, so they always fit
+            let target = i64::try_from(target).unwrap_or(i64::MAX);
+            let here = i64::try_from(index).unwrap_or(i64::MAX);
+            opcodes[index] = match fixup {
+                Fixup::Absolute => Opcode::LDI(target),
+                Fixup::Relative => Opcode::LDI(target - (here + 1)),
+                Fixup::PcRelative => Opcode::LDPC(target - here),
+            };
+        }
+        Ok(opcodes)
+    }
+}
+
+/// Write a program as a list of mnemonics, producing a `Vec<Opcode>`.
+///
+/// Opcodes are separated by commas, immediate values follow the mnemonic,
+/// e.g. `LDI 5` or `SPILL 2 16`.
+/// `name:` defines a label at the next opcode, `LDI @name` pushes the address
+/// of a label and `LDI %name` pushes its offset relative to the following opcode,
+/// which is what JR, JRZ and JRNZ expect. `LDPC @name` pushes the address of a
+/// label too, but is encoded relative to itself so the code can be loaded anywhere.
+///
+/// ```
+/// use rust_simple_stack_processor::{program, Opcode};
+///
+/// let opcodes = program![
+///     LDI 1,
+///     LDI %done,
+///     JRNZ,
+///     LDI 2,
+///     done:
+///     RET,
+/// ];
+/// assert_eq!(
+///     opcodes,
+///     vec![Opcode::LDI(1), Opcode::LDI(2), Opcode::JRNZ, Opcode::LDI(2), Opcode::RET]
+/// );
+/// ```
+///
+/// Panics if a label is undefined or defined twice. Each opcode is one level
+/// of macro recursion, so very long programs may need a higher `recursion_limit`.
+#[macro_export]
+macro_rules! program {
+    (@item $b:ident;) => {};
+    (@item $b:ident; $label:ident : $($rest:tt)*) => {
+        $b.label(stringify!($label));
+        $crate::program!(@item $b; $($rest)*);
+    };
+    (@item $b:ident; LDI @ $label:ident $(, $($rest:tt)*)?) => {
+        $b.ldi_address(stringify!($label));
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    (@item $b:ident; LDPC @ $label:ident $(, $($rest:tt)*)?) => {
+        $b.ldpc(stringify!($label));
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    (@item $b:ident; LDI % $label:ident $(, $($rest:tt)*)?) => {
+        $b.ldi_relative(stringify!($label));
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    (@item $b:ident; $op:ident $(, $($rest:tt)*)?) => {
+        $b.op($crate::Opcode::$op);
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    (@item $b:ident; $op:ident $n:literal $address:literal $(, $($rest:tt)*)?) => {
+        $b.op($crate::Opcode::$op($n, $address));
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    (@item $b:ident; $op:ident $immediate:expr $(, $($rest:tt)*)?) => {
+        $b.op($crate::Opcode::$op($immediate));
+        $crate::program!(@item $b; $($($rest)*)?);
+    };
+    ($($body:tt)*) => {{
+        let mut builder = $crate::ProgramBuilder::new();
+        $crate::program!(@item builder; $($body)*);
+        builder
+            .build()
+            .unwrap_or_else(|e| panic!("invalid program: {}", e))
+    }};
+}
@@ -0,0 +1,156 @@
+//! Representative workloads for measuring interpreter performance.
+//!
+//! These back the criterion benches in `benches/`, and can be used directly
+//! to compare dispatch changes or catch performance regressions.
+
+use std::time::{Duration, Instant};
+
+use super::{GasLimit, Opcode, StackMachine, StackMachineError};
+use crate::program;
+
+/// A program together with the inputs it needs to run
+pub struct Workload {
+    pub name: &'static str,
+    pub opcodes: Vec<Opcode>,
+    pub number_stack: Vec<i64>,
+}
+
+impl Workload {
+    /// A machine loaded with the workload, ready to execute from address 0
+    pub fn machine(&self) -> StackMachine {
+        let mut sm = StackMachine::default();
+        sm.st.load_program(self.opcodes.clone());
+        sm.st.set_number_stack(self.number_stack.clone());
+        sm
+    }
+
+    /// Run the workload to completion, returning the gas it used
+    pub fn run(&self) -> Result<u64, StackMachineError> {
+        let mut sm = self.machine();
+        sm.execute(0, GasLimit::Unlimited)?;
+        Ok(sm.st.gas_used())
+    }
+
+    /// Average wall clock time of running the workload, excluding machine setup
+    pub fn measure(&self, runs: u32) -> Result<Duration, StackMachineError> {
+        let mut total = Duration::default();
+        for _ in 0..runs {
+            let mut sm = self.machine();
+            let start = Instant::now();
+            sm.execute(0, GasLimit::Unlimited)?;
+            total += start.elapsed();
+        }
+        Ok(total / runs.max(1))
+    }
+}
+
+/// A tight loop of arithmetic on the number stack
+pub fn arithmetic_loop(iterations: i64) -> Workload {
+    Workload {
+        name: "arithmetic_loop",
+        opcodes: program![
+            LDI 0,
+            SWAP,
+            top:
+            DUP,
+            LDI %done,
+            JRZ,
+            DUP,
+            GtR,
+            ADD,
+            LDI 3,
+            MUL,
+            LDI 65535,
+            AND,
+            RGt,
+            LDI -1,
+            ADD,
+            LDI %top,
+            JR,
+            done:
+            DROP,
+            RET,
+        ],
+        number_stack: vec![iterations],
+    }
+}
+
+/// A loop that spends most of its time calling and returning from a subroutine
+pub fn call_heavy(iterations: i64) -> Workload {
+    Workload {
+        name: "call_heavy",
+        opcodes: program![
+            LDI 0,
+            SWAP,
+            top:
+            DUP,
+            LDI %done,
+            JRZ,
+            SWAP,
+            LDI @increment,
+            CALL,
+            SWAP,
+            LDI -1,
+            ADD,
+            LDI %top,
+            JR,
+            done:
+            DROP,
+            RET,
+            increment:
+            LDI @add_one,
+            CALL,
+            RET,
+            add_one:
+            LDI 1,
+            ADD,
+            RET,
+        ],
+        number_stack: vec![iterations],
+    }
+}
+
+/// A loop that moves blocks of cells back and forth
+pub fn cell_heavy(iterations: i64) -> Workload {
+    Workload {
+        name: "cell_heavy",
+        opcodes: program![
+            LDI 64,
+            NEWCELLS,
+            DROP,
+            top:
+            DUP,
+            LDI %done,
+            JRZ,
+            LDI 0,
+            LDI 32,
+            MOVEFROMCELLS,
+            LDI 32,
+            LDI 32,
+            MOVETOCELLS,
+            LDI 32,
+            LDI 32,
+            MOVEFROMCELLS,
+            LDI 0,
+            LDI 32,
+            MOVETOCELLS,
+            LDI -1,
+            ADD,
+            LDI %top,
+            JR,
+            done:
+            DROP,
+            RET,
+        ],
+        number_stack: vec![iterations],
+    }
+}
+
+/// All of the workloads, each sized to run for a comparable number of instructions
+pub fn all() -> Vec<Workload> {
+    vec![
+        arithmetic_loop(10_000),
+        call_heavy(10_000),
+        cell_heavy(5_000),
+    ]
+}
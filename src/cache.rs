@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{fold_constants, validate, Opcode, ValidationError, WordSize};
+
+/// 64-bit FNV-1a, which unlike the std hashers gives the same hash in every process and
+/// on every architecture
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    pub(crate) fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A hash of a program's numeric encoding, the same in every process and on every architecture
+pub fn content_hash(opcodes: &[Opcode]) -> u64 {
+    let mut hasher = StableHasher::new();
+    for opcode in opcodes {
+        let (code, immediate) = opcode.encode();
+        hasher.write(&[code]);
+        if let Some(immediate) = immediate {
+            hasher.write(&immediate.to_le_bytes());
+        }
+    }
+    hasher.finish()
+}
+
+struct CacheEntry {
+    source: Vec<Opcode>,
+    entry_points: Vec<usize>,
+    word_size: WordSize,
+    flags: bool,
+    program: Arc<[Opcode]>,
+}
+
+impl CacheEntry {
+    fn matches(
+        &self,
+        opcodes: &[Opcode],
+        entry_points: &[usize],
+        word_size: WordSize,
+        flags: bool,
+    ) -> bool {
+        self.source == opcodes
+            && self.entry_points == entry_points
+            && self.word_size == word_size
+            && self.flags == flags
+    }
+}
+
+/// Validated and constant folded programs, keyed by the content hash of the source and the
+/// entry points, word size and flags setting they were folded for, so a program that is
+/// run again and again is only prepared once.
+///
+/// The prepared programs are shared, ready for `load_shared_program` on a machine with the
+/// same word size and flags setting, and to be entered at one of the same entry points, see
+/// `fold_constants`. A hit compares the source in full, so programs whose hashes collide
+/// are never confused
+#[derive(Default)]
+pub struct ProgramCache {
+    entries: HashMap<u64, Vec<CacheEntry>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ProgramCache {
+    pub fn new() -> Self {
+        ProgramCache::default()
+    }
+
+    /// The prepared form of a program for a machine with word_size, and with flags set when
+    /// its flags register is enabled, validating and folding it first if it is not cached.
+    /// Programs that fail validation are not cached.
+    ///
+    /// The prepared program only behaves as the original when execution starts at one of
+    /// entry_points, so every address it may be run from has to be listed, such as those
+    /// given to `run_plan` or `call_within_trap`, dictionary words and library exports
+    pub fn prepare(
+        &mut self,
+        opcodes: &[Opcode],
+        entry_points: &[usize],
+        word_size: WordSize,
+        flags: bool,
+    ) -> Result<Arc<[Opcode]>, ValidationError> {
+        let hash = content_hash(opcodes);
+        if let Some(entry) = self.entries.get(&hash).and_then(|entries| {
+            entries
+                .iter()
+                .find(|entry| entry.matches(opcodes, entry_points, word_size, flags))
+        }) {
+            self.hits += 1;
+            return Ok(entry.program.clone());
+        }
+        self.misses += 1;
+        validate(opcodes)?;
+        let program: Arc<[Opcode]> = fold_constants(opcodes, entry_points, word_size, flags).into();
+        self.entries.entry(hash).or_default().push(CacheEntry {
+            source: opcodes.to_vec(),
+            entry_points: entry_points.to_vec(),
+            word_size,
+            flags,
+            program: program.clone(),
+        });
+        Ok(program)
+    }
+
+    /// Whether a program is cached for a set of entry points, word size and flags setting
+    pub fn contains(
+        &self,
+        opcodes: &[Opcode],
+        entry_points: &[usize],
+        word_size: WordSize,
+        flags: bool,
+    ) -> bool {
+        self.entries
+            .get(&content_hash(opcodes))
+            .is_some_and(|entries| {
+                entries
+                    .iter()
+                    .any(|entry| entry.matches(opcodes, entry_points, word_size, flags))
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Calls to `prepare` answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Calls to `prepare` that had to prepare the program
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
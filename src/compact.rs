@@ -0,0 +1,203 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::analysis::{is_branch, is_relative_branch, static_target};
+use super::{Opcode, StackMachine, SymbolTable};
+
+/// Reasons `StackMachine::compact_code` leaves the program as it was
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactError {
+    /// The program is frozen
+    Frozen,
+    /// A run is paused, with addresses on its return stack
+    Paused,
+    /// Kept code at pc jumps or calls to an address that is not a constant given by the
+    /// instruction before it, so the code it can reach is not known
+    DynamicJump { pc: usize },
+    /// Kept code at pc refers to an address past the end of the program
+    TargetOutOfRange { pc: usize },
+}
+
+impl fmt::Display for CompactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactError::Frozen => write!(f, "program is frozen"),
+            CompactError::Paused => write!(f, "a run is paused"),
+            CompactError::DynamicJump { pc } => {
+                write!(f, "jump or call at {} does not have a constant target", pc)
+            }
+            CompactError::TargetOutOfRange { pc } => {
+                write!(f, "address used at {} is past the end of the program", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompactError {}
+
+/// Where `StackMachine::compact_code` moved the code it kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compaction {
+    // The new address of every old address up to and including the end of the program
+    addresses: Vec<Option<usize>>,
+    /// Instructions removed
+    pub removed: usize,
+}
+
+impl Compaction {
+    /// The address old code moved to, None if it was removed
+    pub fn new_address(&self, address: usize) -> Option<usize> {
+        self.addresses.get(address).copied().flatten()
+    }
+}
+
+// Jumps that never carry on to the next instruction
+fn ends_segment(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::RET | Opcode::JMP | Opcode::JR | Opcode::DODOES(..)
+    )
+}
+
+impl StackMachine {
+    /// Remove the code that can no longer run, such as the old definitions of redefined
+    /// words or code that has been run once and is not needed again, moving the code that is
+    /// left down and rewriting the addresses that refer to it.
+    ///
+    /// The program is split into segments at address 0, every dictionary definition and
+    /// export, and roots. The segments of the newest definition of each word, the exports
+    /// and the roots are kept, with every segment they jump, call, fall through or point
+    /// into with LDPC. Jumps and calls must have constant targets, given by the LDI or LDPC
+    /// before them, and addresses kept anywhere else, such as in cells, are not rewritten.
+    /// Dictionary definitions, exports and breakpoints in removed code are dropped
+    pub fn compact_code(&mut self, roots: &[usize]) -> Result<Compaction, CompactError> {
+        if self.st.is_code_frozen() {
+            return Err(CompactError::Frozen);
+        }
+        if self.st.paused {
+            return Err(CompactError::Paused);
+        }
+        let opcodes = self.st.opcodes();
+        let len = opcodes.len();
+
+        let mut live = roots.to_vec();
+        live.extend(self.st.exports.iter().map(|(address, _)| address));
+        let mut starts: BTreeSet<usize> = live.iter().copied().collect();
+        starts.insert(0);
+        if let Some(dictionary) = &self.st.dictionary {
+            let mut newest = HashMap::new();
+            for (name, address) in dictionary.iter() {
+                starts.insert(address);
+                newest.insert(name, address);
+            }
+            live.extend(newest.values());
+        }
+        starts.retain(|&address| address < len);
+        let segment_of = |address: usize| {
+            let start = *starts.range(..=address).next_back().unwrap_or(&0);
+            let end = starts.range(address + 1..).next().copied().unwrap_or(len);
+            (start, end)
+        };
+
+        // Mark the kept segments, and the constant each kept jump or LDPC refers to
+        let mut kept = vec![false; len];
+        let mut targets = HashMap::new();
+        let mut pending: Vec<usize> = live.into_iter().filter(|&a| a < len).collect();
+        while let Some(address) = pending.pop() {
+            let (start, end) = segment_of(address);
+            if kept[start] {
+                continue;
+            }
+            kept[start..end].iter_mut().for_each(|kept| *kept = true);
+            for pc in start..end {
+                let target = match &opcodes[pc] {
+                    Opcode::LDPC(offset) => i64::try_from(pc)
+                        .ok()
+                        .and_then(|pc| pc.checked_add(*offset))
+                        .and_then(|target| usize::try_from(target).ok()),
+                    Opcode::DODOES(_, behavior) => usize::try_from(*behavior).ok(),
+                    op if is_branch(op) => {
+                        // The LDI or LDPC before a segment start is not run on the way in
+                        let constant = pc > start
+                            && match opcodes[pc - 1] {
+                                Opcode::LDI(_) => true,
+                                Opcode::LDPC(_) => !is_relative_branch(op),
+                                _ => false,
+                            };
+                        match static_target(opcodes, pc) {
+                            Some(target) if constant => Some(target),
+                            _ => return Err(CompactError::DynamicJump { pc }),
+                        }
+                    }
+                    _ => continue,
+                };
+                match target {
+                    Some(target) if target <= len => {
+                        targets.insert(pc, target);
+                        pending.push(target);
+                    }
+                    _ => return Err(CompactError::TargetOutOfRange { pc }),
+                }
+            }
+            if end < len && !ends_segment(&opcodes[end - 1]) {
+                pending.push(end);
+            }
+        }
+
+        let mut addresses = Vec::with_capacity(len + 1);
+        let mut next = 0;
+        for &kept in &kept {
+            addresses.push(if kept { Some(next) } else { None });
+            next += kept as usize;
+        }
+        addresses.push(Some(next));
+        let moved = |address: usize| addresses[address].unwrap();
+        let offset = |from: usize, to: usize| moved(to) as i64 - moved(from) as i64;
+
+        let mut compacted: Vec<Opcode> = Vec::with_capacity(next);
+        for (pc, op) in opcodes.iter().enumerate().filter(|(pc, _)| kept[*pc]) {
+            let op = match (op, targets.get(&pc)) {
+                (Opcode::LDPC(_), Some(&target)) => Opcode::LDPC(offset(pc, target)),
+                (Opcode::DODOES(data, _), Some(&target)) => {
+                    let behavior = u32::try_from(moved(target))
+                        .map_err(|_| CompactError::TargetOutOfRange { pc })?;
+                    Opcode::DODOES(*data, behavior)
+                }
+                (op, Some(&target)) => {
+                    if let Some(Opcode::LDI(_)) = compacted.last() {
+                        let value = if is_relative_branch(op) {
+                            offset(pc, target)
+                        } else {
+                            moved(target) as i64
+                        };
+                        *compacted.last_mut().unwrap() = Opcode::LDI(value);
+                    }
+                    op.clone()
+                }
+                (op, None) => op.clone(),
+            };
+            compacted.push(op);
+        }
+
+        let removed = len - compacted.len();
+        self.st.load_program(compacted);
+        if let Some(dictionary) = &mut self.st.dictionary {
+            dictionary.relocate(|address| addresses.get(address).copied().flatten());
+        }
+        let mut exports = SymbolTable::new();
+        for (address, name) in self.st.exports.iter() {
+            if let Some(address) = addresses.get(address).copied().flatten() {
+                exports.insert(address, name);
+            }
+        }
+        self.st.exports = exports;
+        self.st.breakpoints = self
+            .st
+            .breakpoints
+            .iter()
+            .filter_map(|&address| addresses.get(address).copied().flatten())
+            .collect();
+        Ok(Compaction { addresses, removed })
+    }
+}
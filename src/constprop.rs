@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use super::{greatest_common_divisor, integer_square_root, Opcode, WordSize};
+
+/// What is known about a value on the number stack at some point in the program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AbstractValue {
+    Known(i64),
+    Unknown,
+}
+
+/// Result of propagating constants through a program, see `propagate_constants`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConstantAnalysis {
+    /// Absolute target of every reachable jump or call whose target is a known constant,
+    /// keyed by the pc of the jump, targets may lie outside the program
+    pub jump_targets: BTreeMap<usize, i64>,
+    /// Conditional branches that always, or never, jump
+    pub constant_branches: BTreeMap<usize, bool>,
+    /// Arithmetic instructions whose result is always the same, keyed by pc
+    pub constant_results: BTreeMap<usize, i64>,
+    /// Set when some reachable jump has a target that could not be resolved, in which case
+    /// every instruction is treated as a possible jump target
+    pub has_dynamic_jumps: bool,
+    stacks: Vec<Option<Vec<AbstractValue>>>,
+}
+
+impl ConstantAnalysis {
+    /// False for instructions that can never be reached from address 0
+    pub fn is_reachable(&self, pc: usize) -> bool {
+        matches!(self.stacks.get(pc), Some(Some(_)))
+    }
+
+    /// The known top of the number stack on entry to pc, bottom first, values below it are unknown
+    pub fn stack_at(&self, pc: usize) -> Option<&[AbstractValue]> {
+        self.stacks.get(pc)?.as_deref()
+    }
+}
+
+fn join(a: &[AbstractValue], b: &[AbstractValue]) -> Vec<AbstractValue> {
+    let len = a.len().min(b.len());
+    a[a.len() - len..]
+        .iter()
+        .zip(&b[b.len() - len..])
+        .map(|(x, y)| if x == y { *x } else { AbstractValue::Unknown })
+        .collect()
+}
+
+struct Outcome {
+    stack: Vec<AbstractValue>,
+    /// Where execution continues, with the stack to continue with when it differs
+    successors: Vec<(usize, Option<Vec<AbstractValue>>)>,
+    jump_target: Option<AbstractValue>,
+    branch_taken: Option<bool>,
+    result: Option<i64>,
+}
+
+fn offset(pc: usize, value: AbstractValue) -> AbstractValue {
+    match value {
+        AbstractValue::Known(v) => match i64::try_from(pc).ok().and_then(|p| p.checked_add(v)) {
+            Some(t) => AbstractValue::Known(t),
+            None => AbstractValue::Unknown,
+        },
+        AbstractValue::Unknown => AbstractValue::Unknown,
+    }
+}
+
+/// Result of a single result opcode given its arguments, TOS first, as a machine with
+/// word_size computes it, None when it would fail
+pub(crate) fn evaluate(op: &Opcode, args: &[i64], word_size: WordSize) -> Option<i64> {
+    let (x, y) = (*args.first()?, args.get(1).copied());
+    let result = match op {
+        Opcode::CMPZ => Some(if x == 0 { -1 } else { 0 }),
+        Opcode::CMPNZ => Some(if x == 0 { 0 } else { -1 }),
+        Opcode::NOT => Some(if x == 0 { 1 } else { 0 }),
+        Opcode::ISQRT => i64::try_from(integer_square_root(u64::try_from(x).ok()?)).ok(),
+        Opcode::ADD => Some(x.wrapping_add(y?)),
+        Opcode::SUB => Some(x.wrapping_sub(y?)),
+        Opcode::MUL => Some(x.wrapping_mul(y?)),
+        Opcode::DIV if x != 0 => Some(y?.wrapping_div(x)),
+        Opcode::AND => Some(x & y?),
+        Opcode::ADDSAT => Some(word_size.saturate(x.saturating_add(y?))),
+        Opcode::SUBSAT => Some(word_size.saturate(x.saturating_sub(y?))),
+        Opcode::MULSAT => Some(word_size.saturate(x.saturating_mul(y?))),
+        Opcode::IPOW => word_size
+            .check(y?.checked_pow(u32::try_from(x).ok()?)?)
+            .ok(),
+        Opcode::GCD => word_size
+            .check(
+                i64::try_from(greatest_common_divisor(x.unsigned_abs(), y?.unsigned_abs())).ok()?,
+            )
+            .ok(),
+        _ => None,
+    };
+    result.map(|value| word_size.wrap(value))
+}
+
+fn transfer(
+    opcodes: &[Opcode],
+    pc: usize,
+    mut stack: Vec<AbstractValue>,
+    word_size: WordSize,
+) -> Outcome {
+    let pop = |stack: &mut Vec<AbstractValue>| stack.pop().unwrap_or(AbstractValue::Unknown);
+    let mut outcome = Outcome {
+        stack: vec![],
+        successors: vec![],
+        jump_target: None,
+        branch_taken: None,
+        result: None,
+    };
+    let next = pc + 1;
+    let op = &opcodes[pc];
+    match op {
+        Opcode::LDI(v) => {
+            stack.push(AbstractValue::Known(word_size.wrap(*v)));
+            outcome.successors.push((next, None));
+        }
+        Opcode::DODOES(data, behavior) => {
+            stack.push(AbstractValue::Known(word_size.wrap(i64::from(*data))));
+            outcome.jump_target = Some(AbstractValue::Known(i64::from(*behavior)));
+        }
+        Opcode::LDPC(offset) => {
+            let address = i64::try_from(pc)
+                .ok()
+                .and_then(|pc| pc.checked_add(*offset));
+            stack.push(address.map_or(AbstractValue::Unknown, |address| {
+                AbstractValue::Known(word_size.wrap(address))
+            }));
+            outcome.successors.push((next, None));
+        }
+        Opcode::RET | Opcode::TRAP | Opcode::TRAPID(_) => {}
+        Opcode::JMP => outcome.jump_target = Some(pop(&mut stack)),
+        Opcode::JR => {
+            let value = pop(&mut stack);
+            outcome.jump_target = Some(offset(pc, value));
+        }
+        Opcode::CALL => {
+            outcome.jump_target = Some(pop(&mut stack));
+            // Nothing is known about the stack once the subroutine returns
+            outcome.successors.push((next, Some(vec![])));
+        }
+        Opcode::JRZ | Opcode::JRNZ => {
+            let value = pop(&mut stack);
+            let target = offset(pc, value);
+            let taken = match pop(&mut stack) {
+                AbstractValue::Known(flag) => Some((flag == 0) == (*op == Opcode::JRZ)),
+                AbstractValue::Unknown => None,
+            };
+            outcome.branch_taken = taken;
+            if taken != Some(true) {
+                outcome.successors.push((next, None));
+            }
+            if taken != Some(false) {
+                outcome.jump_target = Some(target);
+            }
+        }
+        Opcode::JRC | Opcode::JRO => {
+            let value = pop(&mut stack);
+            outcome.jump_target = Some(offset(pc, value));
+            outcome.successors.push((next, None));
+        }
+        Opcode::DUP => {
+            let x = pop(&mut stack);
+            stack.extend_from_slice(&[x, x]);
+            outcome.successors.push((next, None));
+        }
+        Opcode::SWAP => {
+            let x = pop(&mut stack);
+            let y = pop(&mut stack);
+            stack.extend_from_slice(&[x, y]);
+            outcome.successors.push((next, None));
+        }
+        Opcode::DUP2 => {
+            let x = pop(&mut stack);
+            let y = pop(&mut stack);
+            stack.extend_from_slice(&[y, x, y, x]);
+            outcome.successors.push((next, None));
+        }
+        Opcode::SWAP2 | Opcode::OVER2 => {
+            let x4 = pop(&mut stack);
+            let x3 = pop(&mut stack);
+            let x2 = pop(&mut stack);
+            let x1 = pop(&mut stack);
+            if *op == Opcode::SWAP2 {
+                stack.extend_from_slice(&[x3, x4, x1, x2]);
+            } else {
+                stack.extend_from_slice(&[x1, x2, x3, x4, x1, x2]);
+            }
+            outcome.successors.push((next, None));
+        }
+        Opcode::MOVETOCELLS | Opcode::MOVEFROMCELLS => {
+            let count = pop(&mut stack);
+            let _address = pop(&mut stack);
+            match (count, op) {
+                (AbstractValue::Known(n), Opcode::MOVETOCELLS) if n >= 0 => {
+                    let n = usize::try_from(n).unwrap_or(usize::MAX);
+                    stack.truncate(stack.len().saturating_sub(n));
+                }
+                (AbstractValue::Known(n), _) if (0..=1024).contains(&n) => {
+                    stack.resize(stack.len() + n as usize, AbstractValue::Unknown);
+                }
+                _ => stack.clear(),
+            }
+            outcome.successors.push((next, None));
+        }
+        Opcode::NEXTIN => {
+            // One or two values are pushed, so nothing on the stack is known afterwards
+            stack.clear();
+            outcome.successors.push((next, None));
+        }
+        Opcode::SPILL(n, _) => {
+            let n = usize::try_from(*n).unwrap_or(usize::MAX);
+            stack.truncate(stack.len().saturating_sub(n));
+            outcome.successors.push((next, None));
+        }
+        Opcode::UNSPILL(n, _) => {
+            match usize::try_from(*n) {
+                Ok(n) if n <= 1024 => stack.resize(stack.len() + n, AbstractValue::Unknown),
+                _ => stack.clear(),
+            }
+            outcome.successors.push((next, None));
+        }
+        _ => {
+            let metadata = op.metadata();
+            let args: Vec<AbstractValue> = (0..metadata.pops).map(|_| pop(&mut stack)).collect();
+            let known: Option<Vec<i64>> = args
+                .iter()
+                .map(|a| match a {
+                    AbstractValue::Known(v) => Some(*v),
+                    AbstractValue::Unknown => None,
+                })
+                .collect();
+            let result = if metadata.pushes == 1 {
+                known.and_then(|k| evaluate(op, &k, word_size))
+            } else {
+                None
+            };
+            outcome.result = result;
+            for _ in 0..metadata.pushes {
+                stack.push(result.map_or(AbstractValue::Unknown, AbstractValue::Known));
+            }
+            outcome.successors.push((next, None));
+        }
+    }
+
+    if let Some(AbstractValue::Known(target)) = outcome.jump_target {
+        if let Ok(target) = usize::try_from(target) {
+            outcome.successors.push((target, None));
+        }
+    }
+    outcome.stack = stack;
+    outcome
+}
+
+/// Propagate constants from LDI instructions through arithmetic, stack shuffling and
+/// branches, starting at address 0 with nothing known about the number stack.
+///
+/// This resolves jump targets computed by constant expressions, finds branches
+/// whose direction never changes, and finds arithmetic that can be folded.
+/// Subroutines are assumed to leave nothing known on the stack when they return.
+pub fn propagate_constants(opcodes: &[Opcode]) -> ConstantAnalysis {
+    propagate_constants_from(opcodes, 0, WordSize::Bits64)
+}
+
+/// As propagate_constants, for a run starting at entry on a machine with word_size
+pub(crate) fn propagate_constants_from(
+    opcodes: &[Opcode],
+    entry: usize,
+    word_size: WordSize,
+) -> ConstantAnalysis {
+    let mut analysis = ConstantAnalysis {
+        stacks: vec![None; opcodes.len()],
+        ..ConstantAnalysis::default()
+    };
+    if entry >= opcodes.len() {
+        return analysis;
+    }
+
+    let mut worklist: BTreeSet<usize> = BTreeSet::new();
+    analysis.stacks[entry] = Some(vec![]);
+    worklist.insert(entry);
+    while let Some(pc) = worklist.iter().next().copied() {
+        worklist.remove(&pc);
+        let stack = match &analysis.stacks[pc] {
+            Some(stack) => stack.clone(),
+            None => continue,
+        };
+        let outcome = transfer(opcodes, pc, stack, word_size);
+        if outcome.jump_target == Some(AbstractValue::Unknown) && !analysis.has_dynamic_jumps {
+            // Anywhere could be jumped to, with anything on the stack
+            analysis.has_dynamic_jumps = true;
+            for target in 0..opcodes.len() {
+                analysis.stacks[target] = Some(vec![]);
+                worklist.insert(target);
+            }
+        }
+        let Outcome {
+            stack: after,
+            successors,
+            ..
+        } = outcome;
+        for (successor, stack) in successors {
+            if successor >= opcodes.len() {
+                continue;
+            }
+            let incoming = stack.unwrap_or_else(|| after.clone());
+            let joined = match &analysis.stacks[successor] {
+                Some(existing) => join(existing, &incoming),
+                None => incoming,
+            };
+            if analysis.stacks[successor].as_ref() != Some(&joined) {
+                analysis.stacks[successor] = Some(joined);
+                worklist.insert(successor);
+            }
+        }
+    }
+
+    for pc in 0..opcodes.len() {
+        if let Some(stack) = analysis.stacks[pc].clone() {
+            let outcome = transfer(opcodes, pc, stack, word_size);
+            if let Some(AbstractValue::Known(target)) = outcome.jump_target {
+                analysis.jump_targets.insert(pc, target);
+            }
+            if let Some(taken) = outcome.branch_taken {
+                analysis.constant_branches.insert(pc, taken);
+            }
+            if let Some(result) = outcome.result {
+                analysis.constant_results.insert(pc, result);
+            }
+        }
+    }
+    analysis
+}
+
+/// Replace arithmetic with constant results by an LDI of the result, turning the LDIs that
+/// fed it into NOPs. Addresses do not change, so the result needs no relocation.
+///
+/// The program is analysed from each of entry_points, and nothing that one of them or a
+/// jump lands inside is folded, so the folded program only behaves as the original when
+/// entered at one of them.
+///
+/// The results are those of a machine with word_size, and with flags set when its flags
+/// register is enabled, in which case ADD, SUB and MUL are not folded so that they still
+/// set the flags JRC and JRO read. The folded program only behaves as the original on such
+/// a machine
+pub fn fold_constants(
+    opcodes: &[Opcode],
+    entry_points: &[usize],
+    word_size: WordSize,
+    flags: bool,
+) -> Vec<Opcode> {
+    let mut folded = opcodes.to_vec();
+    loop {
+        let mut targets: BTreeSet<usize> = entry_points.iter().copied().collect();
+        let mut constant_results = BTreeMap::new();
+        for &entry in entry_points.iter().filter(|&&entry| entry < folded.len()) {
+            let analysis = propagate_constants_from(&folded, entry, word_size);
+            if analysis.has_dynamic_jumps {
+                return folded;
+            }
+            targets.extend(
+                analysis
+                    .jump_targets
+                    .values()
+                    .filter_map(|t| usize::try_from(*t).ok()),
+            );
+            constant_results.extend(analysis.constant_results);
+        }
+        let mut changed = false;
+        for (pc, result) in &constant_results {
+            let pops = usize::from(folded[*pc].metadata().pops);
+            let sets_flags = matches!(folded[*pc], Opcode::ADD | Opcode::SUB | Opcode::MUL);
+            if pops == 0 || *pc < pops || (flags && sets_flags) {
+                continue;
+            }
+            let inputs = *pc - pops..*pc;
+            let foldable = inputs.clone().all(|i| matches!(folded[i], Opcode::LDI(_)))
+                && (inputs.start + 1..=*pc).all(|i| !targets.contains(&i));
+            if foldable {
+                for i in inputs {
+                    folded[i] = Opcode::NOP;
+                }
+                folded[*pc] = Opcode::LDI(*result);
+                changed = true;
+            }
+        }
+        if !changed {
+            return folded;
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use super::{LoopFrame, StackMachineState};
+
+/// Where a run is and its stacks, which can be set aside while other code runs on the same
+/// program and cells and then put back, as a Forth EVALUATE interprets freshly compiled
+/// code in the middle of a running program
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Context {
+    pub pc: usize,
+    pub number_stack: Vec<i64>,
+    pub scratch_stack: Vec<i64>,
+    pub return_stack: Vec<usize>,
+    pub loop_stack: Vec<LoopFrame>,
+    return_fence: usize,
+}
+
+// The deprecated fields are the storage behind the stacks
+#[allow(deprecated)]
+impl StackMachineState {
+    /// A copy of the context
+    pub fn save_context(&self) -> Context {
+        Context {
+            pc: self.pc,
+            number_stack: self.number_stack.clone(),
+            scratch_stack: self.scratch_stack.clone(),
+            return_stack: self.return_stack.clone(),
+            loop_stack: self.loop_stack.clone(),
+            return_fence: self.return_fence,
+        }
+    }
+
+    /// Move the context out, leaving empty stacks at pc 0
+    pub fn take_context(&mut self) -> Context {
+        self.restore_context(Context::default())
+    }
+
+    /// Replace the context, returning the one it replaces
+    pub fn restore_context(&mut self, context: Context) -> Context {
+        Context {
+            pc: std::mem::replace(&mut self.pc, context.pc),
+            number_stack: std::mem::replace(&mut self.number_stack, context.number_stack),
+            scratch_stack: std::mem::replace(&mut self.scratch_stack, context.scratch_stack),
+            return_stack: std::mem::replace(&mut self.return_stack, context.return_stack),
+            loop_stack: std::mem::replace(&mut self.loop_stack, context.loop_stack),
+            return_fence: std::mem::replace(&mut self.return_fence, context.return_fence),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::convert::TryFrom;
+
+use super::{ErrorKind, ExecutionReport, StackKind, StackMachineError, StackMachineState};
+
+/// Tuples of types the top values of the number stack convert to, for `results`.
+///
+/// Implemented for tuples of up to eight types that implement `TryFrom<i64>`, the first
+/// type taking the deepest of the values and the last TOS
+pub trait FromStackValues: Sized {
+    /// The number of values the tuple takes
+    const LEN: usize;
+
+    /// Convert LEN values, deepest first, giving a ConversionFailed error for the first
+    /// that does not fit its type, or a StackUnderflow error if there are not LEN values
+    fn from_stack_values(values: &[i64]) -> Result<Self, StackMachineError>;
+}
+
+// Convert the value at position in the results to T
+fn convert<T: TryFrom<i64>>(value: i64, position: usize) -> Result<T, StackMachineError> {
+    T::try_from(value).map_err(|_| ErrorKind::ConversionFailed { position, value }.into())
+}
+
+macro_rules! tuple_from_stack_values {
+    ($len:expr; $($t:ident $position:tt),+) => {
+        impl<$($t: TryFrom<i64>),+> FromStackValues for ($($t,)+) {
+            const LEN: usize = $len;
+
+            fn from_stack_values(values: &[i64]) -> Result<Self, StackMachineError> {
+                if values.len() != Self::LEN {
+                    return Err(ErrorKind::StackUnderflow(StackKind::Number).into());
+                }
+                Ok(($(convert::<$t>(values[$position], $position)?,)+))
+            }
+        }
+    };
+}
+
+tuple_from_stack_values!(1; A 0);
+tuple_from_stack_values!(2; A 0, B 1);
+tuple_from_stack_values!(3; A 0, B 1, C 2);
+tuple_from_stack_values!(4; A 0, B 1, C 2, D 3);
+tuple_from_stack_values!(5; A 0, B 1, C 2, D 3, E 4);
+tuple_from_stack_values!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+tuple_from_stack_values!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple_from_stack_values!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+impl StackMachineState {
+    /// Pop TOS converted to T, leaving it on the stack if it does not fit
+    pub fn pop_typed<T: TryFrom<i64>>(&mut self) -> Result<T, StackMachineError> {
+        let value = *self
+            .number_stack()
+            .last()
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        let converted = convert(value, 0)?;
+        self.pop_number()?;
+        Ok(converted)
+    }
+
+    /// The top values of the number stack converted to the types of a tuple, without
+    /// popping them, such as `st.results::<(u8, u32, i32)>()` for a program leaving three
+    pub fn results<R: FromStackValues>(&self) -> Result<R, StackMachineError> {
+        let stack = self.number_stack();
+        R::from_stack_values(&stack[stack.len().saturating_sub(R::LEN)..])
+    }
+}
+
+impl ExecutionReport {
+    /// The top values the run left converted to the types of a tuple, as
+    /// `StackMachineState::results`
+    pub fn results<R: FromStackValues>(&self) -> Result<R, StackMachineError> {
+        R::from_stack_values(self.return_values(R::LEN)?)
+    }
+}
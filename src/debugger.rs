@@ -0,0 +1,149 @@
+use std::fmt;
+
+use super::validate::validate_from;
+use super::{
+    validate, CallChain, ErrorKind, ExitStatus, GasLimit, Opcode, StackMachine, StackMachineError,
+    SymbolTable, ValidationError,
+};
+
+/// Reasons `StackMachine::patch` refuses to change the program
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    /// The opcodes to replace run past the end of the program
+    OutOfRange { start: usize, len: usize },
+    /// The patched program would not pass `validate`
+    Invalid(ValidationError),
+    /// The program is frozen
+    Frozen,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::OutOfRange { start, len } => write!(
+                f,
+                "patching {} opcodes at {} runs past the end of the program",
+                len, start
+            ),
+            PatchError::Invalid(e) => write!(f, "patched program is invalid: {}", e),
+            PatchError::Frozen => write!(f, "program is frozen"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatchError::Invalid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl StackMachine {
+    /// Stop execution before running the instruction at pc
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.st.breakpoints.insert(pc);
+    }
+
+    /// Returns false if there was no breakpoint at pc
+    pub fn clear_breakpoint(&mut self, pc: usize) -> bool {
+        self.st.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.st.breakpoints.iter().copied()
+    }
+
+    /// Where the most recent run is paused, None if it ran to completion or failed
+    pub fn paused_at(&self) -> Option<usize> {
+        if self.st.paused {
+            Some(self.st.pc)
+        } else {
+            None
+        }
+    }
+
+    /// The calls in progress where the most recent run is paused, named by symbols, None if
+    /// it is not paused
+    pub fn paused_call_chain(&self, symbols: &SymbolTable) -> Option<CallChain> {
+        self.paused_at().map(|_| self.st.call_chain(symbols))
+    }
+
+    /// Start a run that is paused before its first instruction, so that it can be single
+    /// stepped with step, or carried on with resume
+    pub fn start_paused(&mut self, starting_point: usize) {
+        self.start_run(starting_point);
+        self.st.paused = true;
+    }
+
+    /// Run the next instruction of a paused run, leaving it paused at the one after unless
+    /// the run finished. The gas limit covers the whole run, as for resume
+    pub fn step(&mut self, gas_limit: GasLimit) -> Result<ExitStatus, StackMachineError> {
+        if !self.st.paused {
+            return Err(ErrorKind::NotPaused.into());
+        }
+        self.st.single_step = true;
+        let result = self.run_until_stopped(gas_limit);
+        self.st.single_step = false;
+        result
+    }
+
+    /// Carry on a paused run to the end or the next breakpoint, running the instruction it
+    /// is paused at first.
+    /// The gas limit covers the whole run, including the gas used before it stopped
+    pub fn resume(&mut self, gas_limit: GasLimit) -> Result<ExitStatus, StackMachineError> {
+        if !self.st.paused {
+            return Err(ErrorKind::NotPaused.into());
+        }
+        self.run_until_stopped(gas_limit)
+    }
+
+    /// Replace the opcodes from start with replacement, keeping every address the same, so
+    /// that a program stopped at a breakpoint can be edited and then resumed.
+    ///
+    /// The patched program must pass `validate`, so jumps and calls with constant targets
+    /// still land inside it, otherwise the program is left as it was. A shared program is
+    /// copied first, the other machines sharing it are not affected
+    pub fn patch(&mut self, start: usize, replacement: &[Opcode]) -> Result<(), PatchError> {
+        if self.st.is_code_frozen() {
+            return Err(PatchError::Frozen);
+        }
+        let end = start
+            .checked_add(replacement.len())
+            .filter(|&end| end <= self.st.opcodes().len())
+            .ok_or(PatchError::OutOfRange {
+                start,
+                len: replacement.len(),
+            })?;
+        let mut patched = self.st.opcodes().to_vec();
+        patched[start..end].clone_from_slice(replacement);
+        validate(&patched).map_err(PatchError::Invalid)?;
+        self.st.load_program(patched);
+        Ok(())
+    }
+
+    /// Add code to the end of the program, returning the address it starts at, for
+    /// interactive compilation where words are defined and run one after another. Every
+    /// address in the program stays where it was.
+    ///
+    /// Only the new code is validated, run from its first instruction, so it can call and
+    /// jump into the code before it. Code that fails is not added
+    #[allow(deprecated)]
+    pub fn append_code(&mut self, segment: &[Opcode]) -> Result<usize, PatchError> {
+        if self.st.is_code_frozen() {
+            return Err(PatchError::Frozen);
+        }
+        let base = self.st.opcodes().len();
+        if self.st.shared_opcodes.is_some() {
+            let program = self.st.opcodes().to_vec();
+            self.st.load_program(program);
+        }
+        self.st.opcodes.extend_from_slice(segment);
+        if let Err(e) = validate_from(&self.st.opcodes, base) {
+            self.st.opcodes.truncate(base);
+            return Err(PatchError::Invalid(e));
+        }
+        Ok(base)
+    }
+}
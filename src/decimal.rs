@@ -0,0 +1,183 @@
+use std::convert::TryFrom;
+
+use super::{ErrorKind, StackMachineError, WordSize};
+
+/// How DDIV and DRESCALE round a result that does not fit the exponent it is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// To the nearest, halves to the even neighbour, as banks round
+    #[default]
+    HalfEven,
+    /// To the nearest, halves away from zero, as taught in school
+    HalfUp,
+    /// Drop the digits, rounding towards zero
+    TowardZero,
+    /// Towards negative infinity
+    Floor,
+    /// Towards positive infinity
+    Ceiling,
+}
+
+/// A decimal on the number stack, its value is mantissa * 10^exponent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Decimal {
+    pub(crate) mantissa: i64,
+    pub(crate) exponent: i64,
+}
+
+impl Decimal {
+    pub(crate) fn new(mantissa: i64, exponent: i64) -> Self {
+        Decimal { mantissa, exponent }
+    }
+}
+
+// More digits than any i64 has, dividing one by a larger power of ten rounds the same way
+const MAX_DIGITS: i128 = 20;
+
+fn overflow() -> StackMachineError {
+    ErrorKind::NumericOverflow.into()
+}
+
+// 10^n, None when it does not fit in an i128
+fn power_of_ten(n: i128) -> Option<i128> {
+    10i128.checked_pow(u32::try_from(n).ok()?)
+}
+
+// mantissa * 10^digits
+fn scale_up(mantissa: i64, digits: i128) -> Result<i128, StackMachineError> {
+    if mantissa == 0 {
+        return Ok(0);
+    }
+    power_of_ten(digits)
+        .and_then(|scale| scale.checked_mul(i128::from(mantissa)))
+        .ok_or_else(overflow)
+}
+
+// The mantissa of the decimal at the smaller exponent to
+fn scale_down(decimal: Decimal, to: i64) -> Result<i128, StackMachineError> {
+    scale_up(
+        decimal.mantissa,
+        i128::from(decimal.exponent) - i128::from(to),
+    )
+}
+
+// numerator / denominator rounded to an integer, the denominator is not zero
+fn divide_rounded(numerator: i128, denominator: i128, mode: RoundingMode) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = (numerator % denominator).unsigned_abs();
+    if remainder == 0 {
+        return quotient;
+    }
+    let negative = (numerator < 0) != (denominator < 0);
+    let away = if negative { quotient - 1 } else { quotient + 1 };
+    // Compare the remainder with the rest of the denominator, so nothing can overflow
+    let rest = denominator.unsigned_abs() - remainder;
+    let round_away = match mode {
+        RoundingMode::TowardZero => false,
+        RoundingMode::Floor => negative,
+        RoundingMode::Ceiling => !negative,
+        RoundingMode::HalfUp => remainder >= rest,
+        RoundingMode::HalfEven => remainder > rest || (remainder == rest && quotient % 2 != 0),
+    };
+    if round_away {
+        away
+    } else {
+        quotient
+    }
+}
+
+fn finish(
+    mantissa: i128,
+    exponent: i64,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    let mantissa = i64::try_from(mantissa).map_err(|_| overflow())?;
+    Ok(Decimal::new(
+        word_size.check(mantissa)?,
+        word_size.check(exponent)?,
+    ))
+}
+
+/// a + b exactly, at the smaller of their exponents
+pub(crate) fn add(
+    a: Decimal,
+    b: Decimal,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    let exponent = a.exponent.min(b.exponent);
+    let mantissa = scale_down(a, exponent)?
+        .checked_add(scale_down(b, exponent)?)
+        .ok_or_else(overflow)?;
+    finish(mantissa, exponent, word_size)
+}
+
+/// a - b exactly, at the smaller of their exponents
+pub(crate) fn sub(
+    a: Decimal,
+    b: Decimal,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    let negated = Decimal::new(b.mantissa.checked_neg().ok_or_else(overflow)?, b.exponent);
+    add(a, negated, word_size)
+}
+
+/// a * b exactly, at the sum of their exponents
+pub(crate) fn mul(
+    a: Decimal,
+    b: Decimal,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    let mantissa = i128::from(a.mantissa) * i128::from(b.mantissa);
+    let exponent = a.exponent.checked_add(b.exponent).ok_or_else(overflow)?;
+    finish(mantissa, exponent, word_size)
+}
+
+/// a / b rounded to the exponent of a
+pub(crate) fn div(
+    a: Decimal,
+    b: Decimal,
+    mode: RoundingMode,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    if b.mantissa == 0 {
+        return Err(ErrorKind::DivisionByZero.into());
+    }
+    // a / b = (a.mantissa / b.mantissa) * 10^(a.exponent - b.exponent), so at the exponent
+    // of a the mantissa is a.mantissa * 10^-b.exponent / b.mantissa
+    let (numerator, denominator) = if b.exponent <= 0 {
+        let digits = -i128::from(b.exponent);
+        (scale_up(a.mantissa, digits)?, i128::from(b.mantissa))
+    } else if i128::from(b.exponent) < MAX_DIGITS {
+        let scale = power_of_ten(i128::from(b.exponent)).unwrap();
+        (i128::from(a.mantissa), scale * i128::from(b.mantissa))
+    } else {
+        // The quotient is less than a tenth, which rounds the same as any other
+        let sign = a.mantissa.signum() * b.mantissa.signum();
+        (i128::from(sign), 10)
+    };
+    finish(
+        divide_rounded(numerator, denominator, mode),
+        a.exponent,
+        word_size,
+    )
+}
+
+/// The decimal at exponent, rounded when it loses digits
+pub(crate) fn rescale(
+    decimal: Decimal,
+    exponent: i64,
+    mode: RoundingMode,
+    word_size: WordSize,
+) -> Result<Decimal, StackMachineError> {
+    let mantissa = if exponent <= decimal.exponent {
+        scale_down(decimal, exponent)?
+    } else {
+        let digits = (i128::from(exponent) - i128::from(decimal.exponent)).min(MAX_DIGITS);
+        divide_rounded(
+            i128::from(decimal.mantissa),
+            power_of_ten(digits).unwrap(),
+            mode,
+        )
+    };
+    finish(mantissa, exponent, word_size)
+}
@@ -0,0 +1,68 @@
+use super::{ErrorKind, GasLimit, StackMachine, StackMachineError, StackMachineState, WordSize};
+
+/// Settings under which every machine running the same program from the same state computes
+/// bit-identical results, whatever platform it is on, for hosts that replicate machines.
+///
+/// While a profile is set every run is limited to its gas limit, even when given a higher or
+/// unlimited one, and fails with a NondeterministicFeature error if the word size or cell
+/// limit no longer match the profile, or if trap handler statistics or trap time budgets,
+/// which measure wall-clock time, are enabled. The cell limit is a u32 so that it means the
+/// same on 32-bit platforms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeterministicProfile {
+    pub gas_limit: u64,
+    pub cell_limit: u32,
+    pub word_size: WordSize,
+}
+
+impl DeterministicProfile {
+    pub fn new(gas_limit: u64, cell_limit: u32) -> Self {
+        DeterministicProfile {
+            gas_limit,
+            cell_limit,
+            word_size: WordSize::default(),
+        }
+    }
+
+    /// The smaller of the profile's gas limit and gas_limit, keeping a soft limit
+    pub(crate) fn limit_gas(&self, gas_limit: GasLimit) -> GasLimit {
+        match gas_limit {
+            GasLimit::Limited(limit) if limit < self.gas_limit => gas_limit,
+            GasLimit::Soft { soft, hard } => GasLimit::Soft {
+                soft,
+                hard: hard.min(self.gas_limit),
+            },
+            _ => GasLimit::Limited(self.gas_limit),
+        }
+    }
+
+    pub(crate) fn check(&self, st: &StackMachineState) -> Result<(), StackMachineError> {
+        if st.word_size != self.word_size
+            || st.cell_limit() != Some(self.cell_limit as usize)
+            || st.trap_handler_stats.is_some()
+            || st.trap_budgets.values().any(|budget| budget.time.is_some())
+        {
+            return Err(ErrorKind::NondeterministicFeature.into());
+        }
+        Ok(())
+    }
+}
+
+impl StackMachine {
+    /// Set the word size and cell limit of the profile, turn off trap handler statistics,
+    /// and enforce the profile on every run until it is cleared
+    pub fn set_deterministic_profile(&mut self, profile: DeterministicProfile) {
+        self.st.word_size = profile.word_size;
+        self.st.set_cell_limit(Some(profile.cell_limit as usize));
+        self.disable_trap_handler_stats();
+        self.st.deterministic = Some(profile);
+    }
+
+    pub fn clear_deterministic_profile(&mut self) {
+        self.st.deterministic = None;
+    }
+
+    pub fn deterministic_profile(&self) -> Option<DeterministicProfile> {
+        self.st.deterministic
+    }
+}
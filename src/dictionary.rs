@@ -0,0 +1,150 @@
+use std::convert::TryFrom;
+
+use super::{
+    ErrorKind, HandleTrap, StackMachine, StackMachineError, StackMachineState, TrapHandled,
+};
+
+/// Word names and their entry addresses, in the order they were defined, so that newer
+/// definitions hide older ones with the same name, as in Forth
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dictionary {
+    words: Vec<(String, usize)>,
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Dictionary::default()
+    }
+
+    /// Define a word, hiding any older definition of the name
+    pub fn create(&mut self, name: &str, address: usize) {
+        self.words.push((name.to_string(), address));
+    }
+
+    /// The entry address of the newest definition of a word
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.words
+            .iter()
+            .rev()
+            .find(|(word, _)| word == name)
+            .map(|(_, address)| *address)
+    }
+
+    /// Remove the newest definition of a word and every word defined after it, returning
+    /// false if the word is not defined
+    pub fn forget(&mut self, name: &str) -> bool {
+        match self.words.iter().rposition(|(word, _)| word == name) {
+            Some(index) => {
+                self.words.truncate(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The definitions, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.words
+            .iter()
+            .map(|(word, address)| (word.as_str(), *address))
+    }
+
+    /// Move every definition to the address moved gives for it, forgetting the ones it
+    /// gives None for
+    pub(crate) fn relocate(&mut self, moved: impl Fn(usize) -> Option<usize>) {
+        self.words = std::mem::take(&mut self.words)
+            .into_iter()
+            .filter_map(|(word, address)| moved(address).map(|address| (word, address)))
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
+impl StackMachine {
+    /// Give the machine an empty dictionary, for the host and `DictionaryTraps` to share
+    pub fn enable_dictionary(&mut self) {
+        self.st.dictionary = Some(Dictionary::new());
+    }
+
+    pub fn disable_dictionary(&mut self) {
+        self.st.dictionary = None;
+    }
+}
+
+impl StackMachineState {
+    /// The dictionary, None if it is not enabled
+    pub fn dictionary(&self) -> Option<&Dictionary> {
+        self.dictionary.as_ref()
+    }
+
+    pub fn dictionary_mut(&mut self) -> Option<&mut Dictionary> {
+        self.dictionary.as_mut()
+    }
+}
+
+/// Traps giving programs the dictionary, with names passed as an address and a length
+/// of cells holding one character each:
+///
+/// - first_trap_id, CREATE ( entry address length -- )
+/// - first_trap_id + 1, FIND ( address length -- entry true | false )
+/// - first_trap_id + 2, FORGET ( address length -- flag )
+///
+/// They give a DictionaryNotEnabled error when the machine has no dictionary
+pub struct DictionaryTraps {
+    first_trap_id: i64,
+}
+
+impl DictionaryTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        DictionaryTraps { first_trap_id }
+    }
+}
+
+impl HandleTrap for DictionaryTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        let trap = match trap_id.checked_sub(self.first_trap_id) {
+            Some(trap @ 0..=2) => trap,
+            _ => return Ok(TrapHandled::NotHandled),
+        };
+        if st.dictionary.is_none() {
+            return Err(ErrorKind::DictionaryNotEnabled.into());
+        }
+        let [address, length] = st.pop_n()?;
+        let name = st.read_string(cell_index(address)?, cell_index(length)?)?;
+        match trap {
+            0 => {
+                let entry = usize::try_from(st.pop_number()?)?;
+                if let Some(dictionary) = st.dictionary.as_mut() {
+                    dictionary.create(&name, entry);
+                }
+            }
+            1 => match st.dictionary.as_ref().and_then(|d| d.find(&name)) {
+                Some(entry) => {
+                    st.push_number(i64::try_from(entry)?);
+                    st.push_number(-1);
+                }
+                None => st.push_number(0),
+            },
+            _ => {
+                let forgotten = st.dictionary.as_mut().is_some_and(|d| d.forget(&name));
+                st.push_number(if forgotten { -1 } else { 0 });
+            }
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+pub(crate) fn cell_index(value: i64) -> Result<usize, StackMachineError> {
+    usize::try_from(value).map_err(|_| ErrorKind::InvalidCellOperation.into())
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::ops::Range;
+
+use super::{StackMachine, StackMachineState};
+
+/// How much of the machine `StackMachine::dump` shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpOptions {
+    /// Instructions shown before the pc
+    pub code_before: usize,
+    /// Instructions shown after the pc
+    pub code_after: usize,
+    /// Values shown from the top of each stack
+    pub stack_depth: usize,
+    /// Recently touched cells shown, when they are tracked
+    pub cells: usize,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        DumpOptions {
+            code_before: 3,
+            code_after: 4,
+            stack_depth: 8,
+            cells: 8,
+        }
+    }
+}
+
+/// The addresses of the cells most recently read or written by instructions, newest first
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TouchedCells {
+    capacity: usize,
+    addresses: VecDeque<usize>,
+}
+
+impl TouchedCells {
+    fn new(capacity: usize) -> Self {
+        TouchedCells {
+            capacity,
+            addresses: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub(crate) fn record(&mut self, range: Range<usize>) {
+        let start = range.end.saturating_sub(self.capacity).max(range.start);
+        for address in start..range.end {
+            if let Some(index) = self.addresses.iter().position(|&a| a == address) {
+                self.addresses.remove(index);
+            }
+            if self.addresses.len() == self.capacity {
+                self.addresses.pop_back();
+            }
+            self.addresses.push_front(address);
+        }
+    }
+}
+
+impl StackMachineState {
+    // Remember the cells an instruction touched, when they are tracked
+    pub(crate) fn touch_cells(&mut self, range: Range<usize>) {
+        if let Some(touched) = self.touched_cells.as_mut() {
+            touched.record(range);
+        }
+    }
+}
+
+// The top of a stack, top first, and how many values there are in it
+fn write_stack<T>(
+    report: &mut String,
+    name: &str,
+    stack: &[T],
+    depth: usize,
+    show: impl Fn(&T) -> String,
+) {
+    let _ = write!(report, "{} ({}):", name, stack.len());
+    for value in stack.iter().rev().take(depth) {
+        let _ = write!(report, " {}", show(value));
+    }
+    if stack.len() > depth {
+        report.push_str(" ...");
+    }
+    report.push('\n');
+}
+
+impl StackMachine {
+    /// Track the last capacity cells read or written by MOVETOCELLS, MOVEFROMCELLS, SPILL
+    /// and UNSPILL, for `dump` to show
+    pub fn enable_touched_cells(&mut self, capacity: usize) {
+        self.st.touched_cells = Some(TouchedCells::new(capacity));
+    }
+
+    pub fn disable_touched_cells(&mut self) {
+        self.st.touched_cells = None;
+    }
+
+    /// The addresses of the cells most recently touched, newest first, None if they are
+    /// not tracked
+    pub fn touched_cells(&self) -> Option<Vec<usize>> {
+        self.st
+            .touched_cells
+            .as_ref()
+            .map(|touched| touched.addresses.iter().copied().collect())
+    }
+
+    /// A report of the machine for people to read, such as when a test fails: the code
+    /// around the pc, the top of every stack with the top first, the call chain when there
+    /// are exports to name it, the recently touched cells when they are tracked, and the gas
+    /// and steps used
+    pub fn dump(&self, options: &DumpOptions) -> String {
+        let st = &self.st;
+        let mut report = String::new();
+        let _ = write!(
+            report,
+            "pc {}, gas used {}, steps {}",
+            st.pc, st.gas_used, st.steps
+        );
+        if st.paused {
+            report.push_str(", paused");
+        }
+        report.push('\n');
+
+        let opcodes = st.opcodes();
+        let first = st.pc.saturating_sub(options.code_before);
+        let last = st.pc.saturating_add(options.code_after).saturating_add(1);
+        for (pc, opcode) in opcodes.iter().enumerate().take(last).skip(first) {
+            let marker = if pc == st.pc { "=>" } else { "  " };
+            let _ = write!(report, "{} {:>5}  {}", marker, pc, opcode);
+            if let Some(name) = st.exports.name_at(pc) {
+                let _ = write!(report, "  ; {}", name);
+            }
+            report.push('\n');
+        }
+        if st.pc >= opcodes.len() {
+            let _ = writeln!(report, "=> {:>5}  (end of program)", st.pc);
+        }
+
+        let depth = options.stack_depth;
+        write_stack(
+            &mut report,
+            "number stack",
+            st.number_stack(),
+            depth,
+            i64::to_string,
+        );
+        write_stack(
+            &mut report,
+            "scratch stack",
+            st.scratch_stack(),
+            depth,
+            i64::to_string,
+        );
+        write_stack(
+            &mut report,
+            "return stack",
+            &st.return_stack,
+            depth,
+            usize::to_string,
+        );
+        write_stack(&mut report, "loop stack", &st.loop_stack, depth, |frame| {
+            format!("{}/{}", frame.index, frame.limit)
+        });
+        if !st.exports.is_empty() {
+            let _ = writeln!(report, "calls: {}", st.call_chain(&st.exports));
+        }
+
+        if let Some(touched) = &st.touched_cells {
+            let _ = write!(report, "cells ({}):", st.cells.len());
+            for &address in touched.addresses.iter().take(options.cells) {
+                match st.cells.get(address) {
+                    Some(value) => {
+                        let _ = write!(report, " [{}]={}", address, value);
+                    }
+                    None => {
+                        let _ = write!(report, " [{}] freed", address);
+                    }
+                }
+            }
+            report.push('\n');
+        }
+        report
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use super::{ErrorKind, ExitStatus, GasLimit, Opcode, StackMachine, StackMachineError};
+
+/// Limits shared by every machine of an `Engine`, None for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineLimits {
+    /// Gas used by all the runs of all the machines
+    pub gas: Option<u64>,
+    /// Heap bytes held by all the machines and programs at once, as `memory_stats` counts.
+    /// Only cells are capped while a run goes, the stacks are checked once it stops
+    pub memory: Option<usize>,
+}
+
+/// A program added to an `Engine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProgramId(usize);
+
+/// A machine spawned by an `Engine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MachineId(usize);
+
+/// Error returned by an `Engine`
+#[derive(Debug)]
+pub enum EngineError {
+    UnknownProgram(ProgramId),
+    UnknownMachine(MachineId),
+    /// The machines have used all of the engine's gas
+    GasBudgetExhausted,
+    /// The machines and programs hold more memory than the engine allows
+    MemoryBudgetExceeded,
+    /// The run failed for a reason of its own
+    Machine(StackMachineError),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::UnknownProgram(id) => write!(f, "no program {}", id.0),
+            EngineError::UnknownMachine(id) => write!(f, "no machine {}", id.0),
+            EngineError::GasBudgetExhausted => write!(f, "engine gas budget exhausted"),
+            EngineError::MemoryBudgetExceeded => write!(f, "engine memory budget exceeded"),
+            EngineError::Machine(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Machine(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Owns shared programs and the machines running them, charging every run against gas and
+/// memory budgets for all of them together, so a host running many scripts can limit what
+/// they use between them.
+///
+/// A run is given the smaller of the gas asked for and the gas left in the budget, and
+/// cells up to the memory left. Running out of either because of the budget gives a
+/// GasBudgetExhausted or MemoryBudgetExceeded error instead of the machine's own error.
+///
+/// The memory budget only caps cells during a run. The stacks and the output queue are not
+/// capped, so a run can grow them past the budget, and that is only found when the run
+/// stops, as a MemoryBudgetExceeded error with the machine still holding the memory. Bound
+/// them with the gas limit, which limits how many values a run can push, or remove the
+/// machine once it goes over
+#[derive(Default)]
+pub struct Engine {
+    limits: EngineLimits,
+    programs: Vec<Arc<[Opcode]>>,
+    machines: Vec<Option<StackMachine>>,
+    gas_used: u64,
+    /// Runs waiting for `execute_all_parallel`
+    #[cfg(feature = "rayon")]
+    pub(crate) queued: Vec<(MachineId, usize, GasLimit)>,
+}
+
+// A limit of at most cap gas, and whether the cap lowered it
+fn cap_gas(gas_limit: GasLimit, cap: u64) -> (GasLimit, bool) {
+    match gas_limit {
+        GasLimit::Limited(limit) if limit <= cap => (gas_limit, false),
+        GasLimit::Soft { soft, hard } if hard <= cap => (GasLimit::Soft { soft, hard }, false),
+        GasLimit::Soft { soft, .. } => (GasLimit::Soft { soft, hard: cap }, true),
+        _ => (GasLimit::Limited(cap), true),
+    }
+}
+
+impl Engine {
+    pub fn new(limits: EngineLimits) -> Self {
+        Engine {
+            limits,
+            ..Engine::default()
+        }
+    }
+
+    pub fn limits(&self) -> EngineLimits {
+        self.limits
+    }
+
+    /// Add a program for machines to share, it counts towards the memory budget once
+    pub fn add_program(&mut self, opcodes: impl Into<Vec<Opcode>>) -> ProgramId {
+        self.programs.push(opcodes.into().into());
+        ProgramId(self.programs.len() - 1)
+    }
+
+    pub fn program(&self, id: ProgramId) -> Option<&Arc<[Opcode]>> {
+        self.programs.get(id.0)
+    }
+
+    /// A new machine with the program loaded
+    pub fn spawn(&mut self, program: ProgramId) -> Result<MachineId, EngineError> {
+        let opcodes = self
+            .program(program)
+            .ok_or(EngineError::UnknownProgram(program))?
+            .clone();
+        let mut sm = StackMachine::default();
+        sm.st.load_shared_program(opcodes);
+        self.machines.push(Some(sm));
+        Ok(MachineId(self.machines.len() - 1))
+    }
+
+    pub fn machine(&self, id: MachineId) -> Option<&StackMachine> {
+        self.machines.get(id.0).and_then(Option::as_ref)
+    }
+
+    /// The machine, to set up its stacks and trap handlers or read its results
+    pub fn machine_mut(&mut self, id: MachineId) -> Option<&mut StackMachine> {
+        self.machines.get_mut(id.0).and_then(Option::as_mut)
+    }
+
+    /// Take a machine out of the engine, its memory no longer counts towards the budget
+    pub fn remove(&mut self, id: MachineId) -> Option<StackMachine> {
+        self.machines.get_mut(id.0).and_then(Option::take)
+    }
+
+    /// The machines in the engine
+    pub fn machines(&self) -> impl Iterator<Item = MachineId> + '_ {
+        self.machines
+            .iter()
+            .enumerate()
+            .filter(|(_, sm)| sm.is_some())
+            .map(|(index, _)| MachineId(index))
+    }
+
+    /// Gas charged to the budget by every run so far
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    /// Gas left in the budget, None when there is no gas limit
+    pub fn gas_remaining(&self) -> Option<u64> {
+        self.limits
+            .gas
+            .map(|limit| limit.saturating_sub(self.gas_used))
+    }
+
+    pub(crate) fn charge_gas(&mut self, gas: u64) {
+        self.gas_used = self.gas_used.saturating_add(gas);
+    }
+
+    /// Heap bytes held by the programs and machines, counting each shared program once
+    pub fn memory_used(&self) -> usize {
+        let programs: usize = self
+            .programs
+            .iter()
+            .map(|program| program.len() * size_of::<Opcode>())
+            .sum();
+        let machines: usize = self
+            .machines
+            .iter()
+            .flatten()
+            .map(|sm| {
+                let stats = sm.memory_stats();
+                if stats.program_shared {
+                    stats.total() - stats.program
+                } else {
+                    stats.total()
+                }
+            })
+            .sum();
+        programs + machines
+    }
+
+    /// Run a machine from starting_point, as `StackMachine::execute`
+    pub fn execute(
+        &mut self,
+        id: MachineId,
+        starting_point: usize,
+        gas_limit: GasLimit,
+    ) -> Result<ExitStatus, EngineError> {
+        self.run(id, gas_limit, |sm, gas_limit| {
+            sm.execute(starting_point, gas_limit)
+        })
+    }
+
+    /// Carry on a paused run, as `StackMachine::resume`
+    pub fn resume(
+        &mut self,
+        id: MachineId,
+        gas_limit: GasLimit,
+    ) -> Result<ExitStatus, EngineError> {
+        self.run(id, gas_limit, StackMachine::resume)
+    }
+
+    fn run(
+        &mut self,
+        id: MachineId,
+        gas_limit: GasLimit,
+        run: impl FnOnce(&mut StackMachine, GasLimit) -> Result<ExitStatus, StackMachineError>,
+    ) -> Result<ExitStatus, EngineError> {
+        if self.machine(id).is_none() {
+            return Err(EngineError::UnknownMachine(id));
+        }
+        let memory_left = match self.limits.memory {
+            Some(limit) => Some(
+                limit
+                    .checked_sub(self.memory_used())
+                    .ok_or(EngineError::MemoryBudgetExceeded)?,
+            ),
+            None => None,
+        };
+        let gas_left = self.gas_remaining();
+        if gas_left == Some(0) {
+            return Err(EngineError::GasBudgetExhausted);
+        }
+        let sm = self.machines[id.0].as_mut().unwrap();
+
+        // A run is charged for the gas it uses, which a resumed run counts from its start
+        let gas_before = if sm.paused_at().is_some() {
+            sm.st.gas_used()
+        } else {
+            0
+        };
+        let (gas_limit, gas_capped) = match gas_left {
+            Some(left) => cap_gas(gas_limit, gas_before.saturating_add(left)),
+            None => (gas_limit, false),
+        };
+        let cell_limit = sm.st.cell_limit();
+        let mut cells_capped = false;
+        if let Some(left) = memory_left {
+            let cells = sm.st.cells().len() + left / size_of::<i64>();
+            if cell_limit.is_none_or(|limit| cells < limit) {
+                sm.st.set_cell_limit(Some(cells));
+                cells_capped = true;
+            }
+        }
+
+        let result = run(sm, gas_limit);
+        sm.st.set_cell_limit(cell_limit);
+        let gas_used = sm.st.gas_used().saturating_sub(gas_before);
+        self.charge_gas(gas_used);
+
+        match result {
+            Err(e) if gas_capped && e.kind() == ErrorKind::RanOutOfGas => {
+                Err(EngineError::GasBudgetExhausted)
+            }
+            Err(e) if cells_capped && e.kind() == ErrorKind::CellLimitExceeded => {
+                Err(EngineError::MemoryBudgetExceeded)
+            }
+            Err(e) => Err(EngineError::Machine(e)),
+            Ok(_)
+                if self
+                    .limits
+                    .memory
+                    .is_some_and(|limit| self.memory_used() > limit) =>
+            {
+                Err(EngineError::MemoryBudgetExceeded)
+            }
+            Ok(status) => Ok(status),
+        }
+    }
+}
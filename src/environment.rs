@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{ErrorKind, HandleTrap, StackMachineError, StackMachineState, TrapHandled};
+
+/// A value in a machine's environment, see `StackMachineState::set_env`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvValue {
+    Number(i64),
+    /// Read by programs as the id of the interned string
+    String(Arc<str>),
+}
+
+impl From<i64> for EnvValue {
+    fn from(n: i64) -> Self {
+        EnvValue::Number(n)
+    }
+}
+
+impl From<&str> for EnvValue {
+    fn from(s: &str) -> Self {
+        EnvValue::String(s.into())
+    }
+}
+
+impl From<String> for EnvValue {
+    fn from(s: String) -> Self {
+        EnvValue::String(s.into())
+    }
+}
+
+impl StackMachineState {
+    /// Set a value in the environment, for programs to read with `EnvTraps`, returning the
+    /// value it replaces
+    pub fn set_env(&mut self, key: &str, value: impl Into<EnvValue>) -> Option<EnvValue> {
+        self.environment.insert(key.to_string(), value.into())
+    }
+
+    pub fn env(&self, key: &str) -> Option<&EnvValue> {
+        self.environment.get(key)
+    }
+
+    pub fn remove_env(&mut self, key: &str) -> Option<EnvValue> {
+        self.environment.remove(key)
+    }
+
+    pub fn environment(&self) -> &HashMap<String, EnvValue> {
+        &self.environment
+    }
+
+    pub fn clear_env(&mut self) {
+        self.environment.clear();
+    }
+}
+
+/// Traps reading the environment the host sets with `StackMachineState::set_env`, so a
+/// program can be given its configuration by name, with keys passed as the ids of interned
+/// strings:
+///
+/// - first_trap_id, GETENV ( key -- value true | false ) a string is pushed as the id of
+///   the interned string
+/// - first_trap_id + 1, ENVKIND ( key -- n ) 0 when the key is not set, 1 for a number and
+///   2 for a string
+///
+/// A key that is not interned gives an UnknownString error
+pub struct EnvTraps {
+    first_trap_id: i64,
+}
+
+impl EnvTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        EnvTraps { first_trap_id }
+    }
+}
+
+impl HandleTrap for EnvTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        let trap = match trap_id.checked_sub(self.first_trap_id) {
+            Some(trap @ 0..=1) => trap,
+            _ => return Ok(TrapHandled::NotHandled),
+        };
+        let id = st.pop_number()?;
+        let key = st.string(id).ok_or(ErrorKind::UnknownString { id })?;
+        let value = st.environment.get(key).cloned();
+        match (trap, value) {
+            (0, Some(value)) => {
+                let value = match value {
+                    EnvValue::Number(n) => n,
+                    EnvValue::String(s) => st.intern(&s),
+                };
+                st.push_number(value);
+                st.push_number(-1);
+            }
+            (0, None) => st.push_number(0),
+            (_, value) => st.push_number(match value {
+                None => 0,
+                Some(EnvValue::Number(_)) => 1,
+                Some(EnvValue::String(_)) => 2,
+            }),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
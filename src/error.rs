@@ -0,0 +1,276 @@
+use std::error::Error;
+use std::fmt;
+use std::num::TryFromIntError;
+
+use super::{unwind, CallChain, Opcode, SymbolTable};
+
+/// The stacks of the machine, for errors that name one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StackKind {
+    Number,
+    Scratch,
+    Return,
+}
+
+impl fmt::Display for StackKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackKind::Number => write!(f, "number"),
+            StackKind::Scratch => write!(f, "scratch"),
+            StackKind::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// What went wrong, new kinds may be added in any release so matches need a wildcard arm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    Unknown,
+    NumericOverflow,
+    DivisionByZero,
+    StackUnderflow(StackKind),
+    /// A loop instruction needed the frame this many from the innermost, but the loop
+    /// stack only held depth frames
+    LoopStackUnderflow {
+        frame: usize,
+        depth: usize,
+    },
+    InvalidCellOperation,
+    /// A cell operation touched a cell outside of the cell allocator's allocations
+    GuardCellAccess {
+        address: usize,
+    },
+    /// No trap handler handled the trap
+    UnhandledTrap {
+        trap_id: i64,
+    },
+    /// The machine's trap permissions do not allow the trap
+    PermissionDenied {
+        trap_id: i64,
+    },
+    /// A quota of the machine's trap permissions was used up
+    QuotaExceeded {
+        quota: &'static str,
+    },
+    RanOutOfGas,
+    FlagsNotEnabled,
+    /// An error from the host, returned by a trap handler, available from `source`
+    Host,
+    /// resume or step was called without a paused run
+    NotPaused,
+    /// The program changed while it was frozen
+    CodeModified,
+    /// Allocating cells would go over the cell limit, or the host could not allocate them
+    CellLimitExceeded,
+    /// The pc is past the end of the program
+    PcOutOfRange,
+    /// A run ran more instructions than its step limit
+    StepLimitExceeded,
+    /// A feature that could make runs differ between machines is enabled under a
+    /// deterministic profile
+    NondeterministicFeature,
+    /// A dictionary trap was raised on a machine without a dictionary
+    DictionaryNotEnabled,
+    /// Writing to the machine's writer failed, the io::Error is available from `source`
+    IoError,
+    /// An ASSERT at pc popped a false flag
+    AssertionFailed {
+        pc: usize,
+        message_id: i64,
+    },
+    /// A string trap was given an id that is not in the string table
+    UnknownString {
+        id: i64,
+    },
+    /// A file trap was given a handle that is not open
+    BadFileHandle {
+        handle: i64,
+    },
+    /// The handlers of a trap took longer or used more gas than its budget
+    TrapBudgetExceeded {
+        trap_id: i64,
+    },
+    /// ARGN asked for an argument past the argc set with `set_argv`
+    NoSuchArgument {
+        index: i64,
+        argc: usize,
+    },
+    /// A value read by `pop_typed` or `results` did not fit the type asked for, position
+    /// counting from the first value of the results
+    ConversionFailed {
+        position: usize,
+        value: i64,
+    },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Unknown => write!(f, "unknown error"),
+            ErrorKind::NumericOverflow => write!(f, "numeric overflow"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::StackUnderflow(stack) => write!(f, "{} stack underflow", stack),
+            ErrorKind::LoopStackUnderflow { frame, depth } => write!(
+                f,
+                "loop stack underflow, needed frame {} with {} on the loop stack",
+                frame, depth
+            ),
+            ErrorKind::InvalidCellOperation => write!(f, "invalid cell operation"),
+            ErrorKind::GuardCellAccess { address } => {
+                write!(f, "cell {} is not in an allocation", address)
+            }
+            ErrorKind::UnhandledTrap { trap_id } => write!(f, "unhandled trap {}", trap_id),
+            ErrorKind::PermissionDenied { trap_id } => {
+                write!(f, "trap {} is not permitted", trap_id)
+            }
+            ErrorKind::QuotaExceeded { quota } => write!(f, "{} quota exceeded", quota),
+            ErrorKind::RanOutOfGas => write!(f, "ran out of gas"),
+            ErrorKind::FlagsNotEnabled => write!(f, "flags are not enabled"),
+            ErrorKind::Host => write!(f, "host error"),
+            ErrorKind::NotPaused => write!(f, "not paused at a breakpoint"),
+            ErrorKind::CodeModified => write!(f, "frozen program was modified"),
+            ErrorKind::CellLimitExceeded => write!(f, "cell limit exceeded"),
+            ErrorKind::PcOutOfRange => write!(f, "pc out of range"),
+            ErrorKind::StepLimitExceeded => write!(f, "step limit exceeded"),
+            ErrorKind::NondeterministicFeature => {
+                write!(
+                    f,
+                    "nondeterministic feature enabled under a deterministic profile"
+                )
+            }
+            ErrorKind::DictionaryNotEnabled => write!(f, "dictionary is not enabled"),
+            ErrorKind::IoError => write!(f, "i/o error"),
+            ErrorKind::AssertionFailed { pc, message_id } => {
+                write!(f, "assertion {} failed at {}", message_id, pc)
+            }
+            ErrorKind::UnknownString { id } => write!(f, "no string has id {}", id),
+            ErrorKind::BadFileHandle { handle } => write!(f, "file handle {} is not open", handle),
+            ErrorKind::TrapBudgetExceeded { trap_id } => {
+                write!(f, "handlers of trap {} went over their budget", trap_id)
+            }
+            ErrorKind::NoSuchArgument { index, argc } => {
+                write!(f, "no argument {} of {}", index, argc)
+            }
+            ErrorKind::ConversionFailed { position, value } => write!(
+                f,
+                "value {} at position {} does not fit the type asked for",
+                value, position
+            ),
+        }
+    }
+}
+
+/// An error stopping execution, with the instruction that raised it when it came from a run.
+///
+/// Errors are usually built from an `ErrorKind`, which `?` does automatically, and matched
+/// through `kind`.
+#[derive(Debug)]
+pub struct StackMachineError {
+    kind: ErrorKind,
+    location: Option<(usize, Opcode)>,
+    /// The return stack when the error stopped the run
+    return_stack: Option<Box<[usize]>>,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl StackMachineError {
+    /// Wrap a host error so that a trap handler can return it
+    pub fn host(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        StackMachineError {
+            kind: ErrorKind::Host,
+            location: None,
+            return_stack: None,
+            source: Some(error.into()),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Address of the instruction that raised the error
+    pub fn pc(&self) -> Option<usize> {
+        self.location.as_ref().map(|(pc, _)| *pc)
+    }
+
+    /// The instruction that raised the error
+    pub fn opcode(&self) -> Option<&Opcode> {
+        self.location.as_ref().map(|(_, opcode)| opcode)
+    }
+
+    /// The calls in progress when the error was raised, named by symbols, or None when it
+    /// did not come from a run
+    pub fn call_chain(&self, symbols: &SymbolTable) -> Option<CallChain> {
+        let pc = self.pc()?;
+        let return_stack = self.return_stack.as_deref().unwrap_or_default();
+        Some(unwind(return_stack, pc, symbols))
+    }
+
+    /// Record where the error was raised, unless it already says
+    pub(crate) fn at(mut self, pc: usize, opcode: Option<&Opcode>) -> Self {
+        if self.location.is_none() {
+            self.location = opcode.map(|op| (pc, op.clone()));
+        }
+        self
+    }
+
+    /// Record the return stack of the run the error stopped, unless it already has one
+    pub(crate) fn with_return_stack(mut self, return_stack: &[usize]) -> Self {
+        if self.return_stack.is_none() {
+            self.return_stack = Some(return_stack.into());
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for StackMachineError {
+    fn from(kind: ErrorKind) -> Self {
+        StackMachineError {
+            kind,
+            location: None,
+            return_stack: None,
+            source: None,
+        }
+    }
+}
+
+impl From<TryFromIntError> for StackMachineError {
+    fn from(_err: TryFromIntError) -> StackMachineError {
+        ErrorKind::NumericOverflow.into()
+    }
+}
+
+impl From<std::io::Error> for StackMachineError {
+    fn from(error: std::io::Error) -> StackMachineError {
+        StackMachineError {
+            kind: ErrorKind::IoError,
+            location: None,
+            return_stack: None,
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+impl fmt::Display for StackMachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", self.kind, source)?,
+            None => write!(f, "{}", self.kind)?,
+        }
+        if let Some((pc, opcode)) = &self.location {
+            write!(f, " at {} ({})", pc, opcode)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for StackMachineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.source {
+            Some(e) => Some(e.as_ref()),
+            None => None,
+        }
+    }
+}
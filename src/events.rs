@@ -0,0 +1,65 @@
+use super::Opcode;
+
+/// Something that happened while a program ran, delivered to every `EventSink`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// An instruction finished, gas_used includes it. The instruction that ends a run,
+    /// the final RET or a handled TRAP, uses no gas and is not retired
+    InstructionRetired {
+        pc: usize,
+        opcode: Opcode,
+        gas_used: u64,
+    },
+    /// A jump or conditional branch changed the pc
+    BranchTaken {
+        from: usize,
+        to: usize,
+    },
+    /// A TRAP is about to be passed to the trap handlers
+    TrapRaised {
+        pc: usize,
+        trap_id: i64,
+    },
+    CallEntered {
+        from: usize,
+        target: usize,
+    },
+    /// A RET to a caller, to is the address execution continues at
+    CallReturned {
+        from: usize,
+        to: usize,
+    },
+}
+
+/// Receives the events of every run of the machine it is added to
+pub trait EventSink {
+    fn event(&mut self, event: &Event);
+}
+
+/// Collects events in memory
+impl EventSink for Vec<Event> {
+    fn event(&mut self, event: &Event) {
+        self.push(event.clone());
+    }
+}
+
+/// A value logged by LOG, level is LOG's immediate value, by convention 1 for errors up to
+/// 5 for tracing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: i64,
+    pub pc: usize,
+    pub value: i64,
+}
+
+/// Receives what programs log with LOG
+pub trait LogSink {
+    fn log(&mut self, record: &LogRecord);
+}
+
+/// Collects log records in memory
+impl LogSink for Vec<LogRecord> {
+    fn log(&mut self, record: &LogRecord) {
+        self.push(*record);
+    }
+}
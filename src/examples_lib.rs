@@ -0,0 +1,110 @@
+//! Canonical example programs.
+//!
+//! Each function returns a subroutine that starts at address 0 and ends with
+//! RET, taking its arguments from and leaving its results on the number stack.
+//! They are used in documentation, benchmarks and as regression fixtures.
+
+use super::Opcode;
+use crate::program;
+
+/// ( n -- n! )
+pub fn factorial() -> Vec<Opcode> {
+    program![
+        LDI 1,
+        SWAP,
+        top:
+        DUP,
+        LDI %done,
+        JRZ,
+        DUP,
+        GtR,
+        MUL,
+        RGt,
+        LDI -1,
+        ADD,
+        LDI %top,
+        JR,
+        done:
+        DROP,
+        RET,
+    ]
+}
+
+/// ( n -- fib(n) ), where fib(0) is 0 and fib(1) is 1
+pub fn fibonacci() -> Vec<Opcode> {
+    program![
+        GtR,
+        LDI 0,
+        LDI 1,
+        top:
+        RGt,
+        DUP,
+        GtR,
+        LDI %done,
+        JRZ,
+        RGt,
+        LDI -1,
+        ADD,
+        GtR,
+        DUP,
+        GtR,
+        ADD,
+        RGt,
+        SWAP,
+        LDI %top,
+        JR,
+        done:
+        DROP,
+        RGt,
+        DROP,
+        RET,
+    ]
+}
+
+/// ( src dst n -- ) copy n cells starting at src to dst, the regions may overlap
+pub fn memcpy() -> Vec<Opcode> {
+    program![
+        DUP,
+        LDI %empty,
+        JRZ,
+        DUP,
+        GtR,
+        SWAP,
+        GtR,
+        MOVEFROMCELLS,
+        RGt,
+        RGt,
+        MOVETOCELLS,
+        RET,
+        empty:
+        DROP,
+        DROP,
+        DROP,
+        RET,
+    ]
+}
+
+/// ( addr -- len ) count the cells from addr up to the first zero cell
+pub fn string_length() -> Vec<Opcode> {
+    program![
+        LDI 0,
+        SWAP,
+        top:
+        DUP,
+        LDI 1,
+        MOVEFROMCELLS,
+        LDI %done,
+        JRZ,
+        LDI 1,
+        ADD,
+        SWAP,
+        LDI 1,
+        ADD,
+        SWAP,
+        LDI %top,
+        JR,
+        done:
+        DROP,
+        RET,
+    ]
+}
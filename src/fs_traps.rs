@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use super::dictionary::cell_index;
+use super::{ErrorKind, HandleTrap, StackMachineError, StackMachineState, TrapHandled};
+
+/// What a program may do with a file it is allowed to open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAccess {
+    Read,
+    /// Created if it does not exist, and emptied when it is opened
+    Write,
+    /// Created if it does not exist, and written at the end
+    Append,
+}
+
+/// Traps giving programs the files the host allows, and nothing else. A program names a
+/// file by the id of an interned string, which the host maps to a path, and uses the
+/// handle it is given to read and write it. Bytes are one to a cell:
+///
+/// - first_trap_id, OPEN ( name -- handle true | false ) false if the name is not allowed
+/// - first_trap_id + 1, READ ( handle address length -- count ) count is 0 at the end
+/// - first_trap_id + 2, WRITE ( handle address length -- )
+/// - first_trap_id + 3, CLOSE ( handle -- )
+///
+/// A handle that is not open gives a BadFileHandle error, and reading a file opened for
+/// writing or writing one opened for reading an IoError
+#[derive(Default)]
+pub struct FileTraps {
+    first_trap_id: i64,
+    allowed: HashMap<String, (PathBuf, FileAccess)>,
+    // Indexed by handle - 1, None once closed
+    handles: Vec<Option<File>>,
+}
+
+impl FileTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        FileTraps {
+            first_trap_id,
+            ..FileTraps::default()
+        }
+    }
+
+    /// Allow programs to open the file at path with the name
+    pub fn allow(mut self, name: &str, path: impl Into<PathBuf>, access: FileAccess) -> Self {
+        self.allowed.insert(name.to_string(), (path.into(), access));
+        self
+    }
+
+    /// The number of files open
+    pub fn open_files(&self) -> usize {
+        self.handles.iter().filter(|file| file.is_some()).count()
+    }
+
+    /// Close every file, the handles programs hold are no longer open
+    pub fn close_all(&mut self) {
+        self.handles.clear();
+    }
+
+    fn open(&mut self, name: &str) -> Result<Option<i64>, StackMachineError> {
+        let (path, access) = match self.allowed.get(name) {
+            Some(allowed) => allowed,
+            None => return Ok(None),
+        };
+        let file = match access {
+            FileAccess::Read => File::open(path)?,
+            FileAccess::Write => File::create(path)?,
+            FileAccess::Append => OpenOptions::new().append(true).create(true).open(path)?,
+        };
+        let index = match self.handles.iter().position(Option::is_none) {
+            Some(index) => {
+                self.handles[index] = Some(file);
+                index
+            }
+            None => {
+                self.handles.push(Some(file));
+                self.handles.len() - 1
+            }
+        };
+        Ok(Some(i64::try_from(index + 1)?))
+    }
+
+    // The slot of a handle, which must be open
+    fn slot(&mut self, handle: i64) -> Result<&mut Option<File>, StackMachineError> {
+        let handles = &mut self.handles;
+        usize::try_from(handle)
+            .ok()
+            .and_then(|handle| handle.checked_sub(1))
+            .and_then(move |index| handles.get_mut(index))
+            .filter(|slot| slot.is_some())
+            .ok_or_else(|| ErrorKind::BadFileHandle { handle }.into())
+    }
+
+    fn file(&mut self, handle: i64) -> Result<&mut File, StackMachineError> {
+        self.slot(handle)?
+            .as_mut()
+            .ok_or_else(|| ErrorKind::BadFileHandle { handle }.into())
+    }
+}
+
+impl HandleTrap for FileTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id.checked_sub(self.first_trap_id) {
+            Some(0) => {
+                let id = st.pop_number()?;
+                let name = st
+                    .string(id)
+                    .ok_or(ErrorKind::UnknownString { id })?
+                    .to_string();
+                match self.open(&name)? {
+                    Some(handle) => {
+                        st.push_number(handle);
+                        st.push_number(-1);
+                    }
+                    None => st.push_number(0),
+                }
+            }
+            Some(1) => {
+                let [handle, address, length] = st.pop_n()?;
+                let (address, length) = (cell_index(address)?, cell_index(length)?);
+                let range = st.cell_range(address, length)?;
+                let mut bytes = vec![0; length];
+                let count = self.file(handle)?.read(&mut bytes)?;
+                for (cell, byte) in st.cells[range].iter_mut().zip(&bytes[..count]) {
+                    *cell = i64::from(*byte);
+                }
+                st.push_number(i64::try_from(count)?);
+            }
+            Some(2) => {
+                let [handle, address, length] = st.pop_n()?;
+                let (address, length) = (cell_index(address)?, cell_index(length)?);
+                let range = st.cell_range(address, length)?;
+                let bytes = st.cells[range]
+                    .iter()
+                    .map(|&cell| u8::try_from(cell))
+                    .collect::<Result<Vec<u8>, _>>()?;
+                self.file(handle)?.write_all(&bytes)?;
+            }
+            Some(3) => {
+                let handle = st.pop_number()?;
+                *self.slot(handle)? = None;
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
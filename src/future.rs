@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{ExitStatus, GasLimit, StackMachine, StackMachineError};
+
+/// Gas a `StackMachineFuture` runs for each time it is polled, unless given another slice
+const DEFAULT_GAS_SLICE: u64 = 10_000;
+
+/// A run of a machine as a future, running a slice of gas each time it is polled and then
+/// yielding to the executor, so a long script does not block the thread it runs on.
+///
+/// The future finishes with the result of the run, as `StackMachine::execute` would return
+/// it, including a Yielded status when the run pauses for its own reasons such as a READIN
+/// waiting for input or a soft gas limit. A machine is not Send, so with a multi-threaded
+/// runtime such as tokio run the future on a local task set
+pub struct StackMachineFuture<'a> {
+    sm: &'a mut StackMachine,
+    starting_point: usize,
+    gas_limit: GasLimit,
+    slice: u64,
+    started: bool,
+}
+
+impl<'a> StackMachineFuture<'a> {
+    /// Run for slice gas each time the future is polled, instead of 10,000
+    pub fn with_slice(mut self, slice: u64) -> Self {
+        self.slice = slice.max(1);
+        self
+    }
+}
+
+impl StackMachine {
+    /// Execute from starting_point as a future that runs a slice of gas per poll
+    pub fn execute_async(
+        &mut self,
+        starting_point: usize,
+        gas_limit: GasLimit,
+    ) -> StackMachineFuture<'_> {
+        StackMachineFuture {
+            sm: self,
+            starting_point,
+            gas_limit,
+            slice: DEFAULT_GAS_SLICE,
+            started: false,
+        }
+    }
+}
+
+impl Future for StackMachineFuture<'_> {
+    type Output = Result<ExitStatus, StackMachineError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (soft, hard) = this.gas_limit.soft_and_hard();
+        // Pause with a soft limit once the slice is used, after a soft limit of the run's own
+        let gas_used = if this.started {
+            this.sm.st.gas_used()
+        } else {
+            0
+        };
+        let end = gas_used.saturating_add(this.slice - 1);
+        let slice = GasLimit::Soft {
+            soft: soft.unwrap_or(u64::MAX).min(end),
+            hard,
+        };
+        let result = if this.started {
+            this.sm.resume(slice)
+        } else {
+            this.started = true;
+            this.sm.execute(this.starting_point, slice)
+        };
+        let gas_used = this.sm.st.gas_used();
+        match result {
+            Ok(ExitStatus::Yielded)
+                if gas_used > end && soft.is_none_or(|soft| gas_used <= soft) =>
+            {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
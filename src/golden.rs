@@ -0,0 +1,251 @@
+//! Golden file tests, comparing the final state of a run against a JSON file, or the
+//! instructions it ran against a trace file.
+//!
+//! Set `UPDATE_GOLDEN=1` to write the files from the current results instead of
+//! checking them, then review the diff.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Event, EventSink, ExitStatus, GasLimit, Opcode, StackMachine, StackMachineError};
+
+/// The final state of a run, as stored in a golden file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expectation {
+    pub number_stack: Vec<i64>,
+    pub scratch_stack: Vec<i64>,
+    pub cells: Vec<i64>,
+    pub gas_used: u64,
+    /// The error the run stopped with, as displayed
+    pub error: Option<String>,
+}
+
+impl Expectation {
+    pub fn from_run(sm: &StackMachine, result: &Result<ExitStatus, StackMachineError>) -> Self {
+        Expectation {
+            number_stack: sm.st.number_stack().to_vec(),
+            scratch_stack: sm.st.scratch_stack().to_vec(),
+            cells: sm.st.cells.clone(),
+            gas_used: sm.st.gas_used(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoldenMode {
+    /// Compare against the file, which must exist
+    Check,
+    /// Overwrite the file with the actual results
+    Update,
+}
+
+impl GoldenMode {
+    /// Update when the UPDATE_GOLDEN environment variable is set to anything but 0
+    pub fn from_env() -> Self {
+        match std::env::var("UPDATE_GOLDEN") {
+            Ok(v) if v != "0" && !v.is_empty() => GoldenMode::Update,
+            _ => GoldenMode::Check,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Mismatch {
+        path: PathBuf,
+        expected: Box<Expectation>,
+        actual: Box<Expectation>,
+    },
+    /// A line of a trace file is not an address and an opcode, line counts from 1
+    BadTraceLine {
+        path: PathBuf,
+        line: usize,
+    },
+    /// The traces differ first at index, None where one of them has ended
+    TraceMismatch {
+        path: PathBuf,
+        index: usize,
+        expected: Option<(usize, Opcode)>,
+        actual: Option<(usize, Opcode)>,
+    },
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            GoldenError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            GoldenError::Mismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} does not match, rerun with UPDATE_GOLDEN=1 if the change is intended\nexpected: {:?}\nactual:   {:?}",
+                path.display(),
+                expected,
+                actual
+            ),
+            GoldenError::BadTraceLine { path, line } => {
+                write!(f, "{}:{}: expected an address and an opcode", path.display(), line)
+            }
+            GoldenError::TraceMismatch {
+                path,
+                index,
+                expected,
+                actual,
+            } => {
+                let show = |step: &Option<(usize, Opcode)>| match step {
+                    Some((pc, opcode)) => format!("{} {}", pc, opcode),
+                    None => "end of trace".to_string(),
+                };
+                write!(
+                    f,
+                    "{} does not match at instruction {}, rerun with UPDATE_GOLDEN=1 if the change is intended\nexpected: {}\nactual:   {}",
+                    path.display(),
+                    index,
+                    show(expected),
+                    show(actual)
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+/// Compare the result of a run with a golden file, or write it in update mode
+pub fn check_golden(
+    sm: &StackMachine,
+    result: &Result<ExitStatus, StackMachineError>,
+    path: impl AsRef<Path>,
+    mode: GoldenMode,
+) -> Result<(), GoldenError> {
+    let path = path.as_ref();
+    let actual = Expectation::from_run(sm, result);
+
+    if mode == GoldenMode::Update {
+        let mut json = serde_json::to_string_pretty(&actual)
+            .map_err(|e| GoldenError::Parse(path.to_path_buf(), e))?;
+        json.push('\n');
+        return fs::write(path, json).map_err(|e| GoldenError::Io(path.to_path_buf(), e));
+    }
+
+    let text = fs::read_to_string(path).map_err(|e| GoldenError::Io(path.to_path_buf(), e))?;
+    let expected: Expectation =
+        serde_json::from_str(&text).map_err(|e| GoldenError::Parse(path.to_path_buf(), e))?;
+    if expected != actual {
+        return Err(GoldenError::Mismatch {
+            path: path.to_path_buf(),
+            expected: Box::new(expected),
+            actual: Box::new(actual),
+        });
+    }
+    Ok(())
+}
+
+/// Run a program from address 0 on the given number stack and check it against a golden file
+pub fn run_golden(
+    opcodes: &[Opcode],
+    number_stack: &[i64],
+    gas_limit: GasLimit,
+    path: impl AsRef<Path>,
+    mode: GoldenMode,
+) -> Result<(), GoldenError> {
+    let mut sm = StackMachine::default();
+    sm.st.load_program(opcodes);
+    sm.st.set_number_stack(number_stack.to_vec());
+    let result = sm.execute(0, gas_limit);
+    check_golden(&sm, &result, path, mode)
+}
+
+// Collects the instructions retired by a run
+struct TraceRecorder(Rc<RefCell<Vec<(usize, Opcode)>>>);
+
+impl EventSink for TraceRecorder {
+    fn event(&mut self, event: &Event) {
+        if let Event::InstructionRetired { pc, opcode, .. } = event {
+            self.0.borrow_mut().push((*pc, opcode.clone()));
+        }
+    }
+}
+
+/// Run from starting_point, returning every instruction retired with its address, in the
+/// order they ran. The RET or handled TRAP that ends a run is not retired, so it is not
+/// in the trace
+pub fn record_trace(
+    sm: &mut StackMachine,
+    starting_point: usize,
+    gas_limit: GasLimit,
+) -> (Vec<(usize, Opcode)>, Result<ExitStatus, StackMachineError>) {
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    sm.event_sinks.push(Box::new(TraceRecorder(trace.clone())));
+    let result = sm.execute(starting_point, gas_limit);
+    sm.event_sinks.pop();
+    let trace = trace.replace(Vec::new());
+    (trace, result)
+}
+
+/// Compare a trace with a trace file, or write it in update mode. The file has a line for
+/// each instruction, its address and then the opcode as displayed, such as `3 LDI -5`
+pub fn check_trace(
+    trace: &[(usize, Opcode)],
+    path: impl AsRef<Path>,
+    mode: GoldenMode,
+) -> Result<(), GoldenError> {
+    let path = path.as_ref();
+    if mode == GoldenMode::Update {
+        let text: String = trace
+            .iter()
+            .map(|(pc, opcode)| format!("{} {}\n", pc, opcode))
+            .collect();
+        return fs::write(path, text).map_err(|e| GoldenError::Io(path.to_path_buf(), e));
+    }
+
+    let text = fs::read_to_string(path).map_err(|e| GoldenError::Io(path.to_path_buf(), e))?;
+    let mut expected = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let step = line.split_once(' ').and_then(|(pc, opcode)| {
+            Some((pc.parse::<usize>().ok()?, opcode.parse::<Opcode>().ok()?))
+        });
+        expected.push(step.ok_or_else(|| GoldenError::BadTraceLine {
+            path: path.to_path_buf(),
+            line: index + 1,
+        })?);
+    }
+    let index = (0..expected.len().max(trace.len())).find(|&i| expected.get(i) != trace.get(i));
+    match index {
+        Some(index) => Err(GoldenError::TraceMismatch {
+            path: path.to_path_buf(),
+            index,
+            expected: expected.get(index).cloned(),
+            actual: trace.get(index).cloned(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Run a program from address 0 on the given number stack and check the instructions it
+/// runs against a trace file
+pub fn run_trace_golden(
+    opcodes: &[Opcode],
+    number_stack: &[i64],
+    gas_limit: GasLimit,
+    path: impl AsRef<Path>,
+    mode: GoldenMode,
+) -> Result<(), GoldenError> {
+    let mut sm = StackMachine::default();
+    sm.st.load_program(opcodes);
+    sm.st.set_number_stack(number_stack.to_vec());
+    let (trace, _) = record_trace(&mut sm, 0, gas_limit);
+    check_trace(&trace, path, mode)
+}
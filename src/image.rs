@@ -0,0 +1,206 @@
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+use super::allocator::grow_cells;
+use super::{DecodeOpcodeError, ErrorKind, Opcode, StackMachine, StackMachineError};
+
+/// The first bytes of every encoded image
+pub const IMAGE_MAGIC: [u8; 4] = *b"SSPI";
+
+/// The version of the image encoding written by `Image::to_bytes`
+pub const IMAGE_VERSION: u8 = 1;
+
+/// Error returned when decoding an image fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The image ended in the middle of a value
+    Truncated,
+    /// A length does not fit in a usize on this machine
+    TooLarge,
+    Opcode(DecodeOpcodeError),
+    /// Bytes were left over after the last data segment
+    TrailingBytes,
+    /// The image does not have the expected checksum
+    ChecksumMismatch,
+    /// The image signature did not verify
+    BadSignature,
+    /// A string in a session is not valid UTF-8
+    InvalidString,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::BadMagic => write!(f, "not an image, bad magic number"),
+            ImageError::UnsupportedVersion(v) => write!(f, "unsupported image version {}", v),
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::TooLarge => write!(f, "image is too large for this machine"),
+            ImageError::Opcode(e) => write!(f, "invalid opcode in image: {}", e),
+            ImageError::TrailingBytes => write!(f, "trailing bytes after the image"),
+            ImageError::ChecksumMismatch => write!(f, "image checksum does not match"),
+            ImageError::BadSignature => write!(f, "image signature is not valid"),
+            ImageError::InvalidString => write!(f, "string is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Opcode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Cells to initialise before a program runs, such as lookup tables and constant arrays
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSegment {
+    pub offset: usize,
+    pub values: Vec<i64>,
+}
+
+impl DataSegment {
+    pub fn new(offset: usize, values: impl Into<Vec<i64>>) -> Self {
+        DataSegment {
+            offset,
+            values: values.into(),
+        }
+    }
+}
+
+/// A program together with the data segments it expects in the cells
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    pub opcodes: Vec<Opcode>,
+    pub data: Vec<DataSegment>,
+}
+
+impl Image {
+    pub fn new(opcodes: impl Into<Vec<Opcode>>) -> Self {
+        Image {
+            opcodes: opcodes.into(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Add a data segment, later segments overwrite earlier ones where they overlap
+    pub fn with_data(mut self, offset: usize, values: impl Into<Vec<i64>>) -> Self {
+        self.data.push(DataSegment::new(offset, values));
+        self
+    }
+
+    /// Encode the image, the same on every architecture.
+    ///
+    /// The layout is `IMAGE_MAGIC`, the `IMAGE_VERSION` byte, the number of opcodes, each
+    /// opcode as its opcode number followed by the immediate value for opcodes that carry
+    /// one, then the number of data segments, each as its offset, the number of values and
+    /// the values. Counts and offsets are u64 and values i64, all little-endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = IMAGE_MAGIC.to_vec();
+        bytes.push(IMAGE_VERSION);
+        bytes.extend_from_slice(&(self.opcodes.len() as u64).to_le_bytes());
+        for opcode in &self.opcodes {
+            let (code, immediate) = opcode.encode();
+            bytes.push(code);
+            if let Some(immediate) = immediate {
+                bytes.extend_from_slice(&immediate.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        for segment in &self.data {
+            bytes.extend_from_slice(&(segment.offset as u64).to_le_bytes());
+            bytes.extend_from_slice(&(segment.values.len() as u64).to_le_bytes());
+            for value in &segment.values {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Decode an image written by `to_bytes`, images of any other version are refused
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut reader = Reader { bytes };
+        if reader.take(IMAGE_MAGIC.len())? != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let version = reader.take(1)?[0];
+        if version != IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let mut image = Image::default();
+        for _ in 0..reader.usize()? {
+            let code = reader.take(1)?[0];
+            let opcode = match Opcode::try_from((code, None)) {
+                Err(DecodeOpcodeError::MissingImmediate(_)) => {
+                    Opcode::try_from((code, Some(reader.i64()?)))
+                }
+                decoded => decoded,
+            };
+            image.opcodes.push(opcode.map_err(ImageError::Opcode)?);
+        }
+        for _ in 0..reader.usize()? {
+            let offset = reader.usize()?;
+            let mut values = Vec::new();
+            for _ in 0..reader.usize()? {
+                values.push(reader.i64()?);
+            }
+            image.data.push(DataSegment { offset, values });
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(ImageError::TrailingBytes);
+        }
+        Ok(image)
+    }
+}
+
+// Reads the little-endian values of an image from the front of the bytes left
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], ImageError> {
+        if self.bytes.len() < n {
+            return Err(ImageError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64, ImageError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, ImageError> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| ImageError::TooLarge)
+    }
+}
+
+impl StackMachine {
+    /// Copy values into the cells starting at offset, allocating cells initialised to 0
+    /// when there are not enough. Cells allocated here are not allocations of the cell allocator
+    pub fn load_cells(&mut self, offset: usize, values: &[i64]) -> Result<(), StackMachineError> {
+        let end = offset
+            .checked_add(values.len())
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        grow_cells(&mut self.st.cells, end, self.st.cell_limit)?;
+        self.st.cells[offset..end].copy_from_slice(values);
+        Ok(())
+    }
+
+    /// Load the program of an image and then its data segments, in order
+    pub fn load_image(&mut self, image: &Image) -> Result<(), StackMachineError> {
+        self.st.load_program(image.opcodes.clone());
+        for segment in &image.data {
+            self.load_cells(segment.offset, &segment.values)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use super::{Image, ImageError};
+
+/// The expected checksum of an encoded image, checked before it is decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Crc32(u32),
+    #[cfg(feature = "sha2")]
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    pub fn crc32(bytes: &[u8]) -> Self {
+        Checksum::Crc32(crc32(bytes))
+    }
+
+    #[cfg(feature = "sha2")]
+    pub fn sha256(bytes: &[u8]) -> Self {
+        use sha2::Digest;
+        Checksum::Sha256(sha2::Sha256::digest(bytes).into())
+    }
+
+    /// Whether bytes have this checksum, computed with the same algorithm
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        match self {
+            Checksum::Crc32(_) => Checksum::crc32(bytes) == *self,
+            #[cfg(feature = "sha2")]
+            Checksum::Sha256(_) => Checksum::sha256(bytes) == *self,
+        }
+    }
+}
+
+// CRC-32 as used by zlib and PNG, reflected with polynomial 0xEDB88320
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+impl Image {
+    /// Decode an image, refusing it unless the bytes have the expected checksum
+    pub fn from_bytes_checked(bytes: &[u8], checksum: &Checksum) -> Result<Self, ImageError> {
+        if !checksum.matches(bytes) {
+            return Err(ImageError::ChecksumMismatch);
+        }
+        Image::from_bytes(bytes)
+    }
+
+    /// Decode an image, refusing it unless signature is an ed25519 signature of the bytes
+    /// by the holder of key
+    #[cfg(feature = "ed25519")]
+    pub fn from_bytes_signed(
+        bytes: &[u8],
+        signature: &ed25519_dalek::Signature,
+        key: &ed25519_dalek::VerifyingKey,
+    ) -> Result<Self, ImageError> {
+        key.verify_strict(bytes, signature)
+            .map_err(|_| ImageError::BadSignature)?;
+        Image::from_bytes(bytes)
+    }
+}
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{ErrorKind, HandleTrap, StackMachineError, StackMachineState, TrapHandled};
+
+/// Where `KvTraps` keeps its values, such as a database or a file, so that they outlive
+/// a run, or the machine
+pub trait KvBackend {
+    fn get(&mut self, key: &str) -> Result<Option<i64>, StackMachineError>;
+    fn set(&mut self, key: &str, value: i64) -> Result<(), StackMachineError>;
+    /// Returns false if there was no value
+    fn remove(&mut self, key: &str) -> Result<bool, StackMachineError>;
+}
+
+/// Keeps values in memory, for as long as the handler
+impl KvBackend for HashMap<String, i64> {
+    fn get(&mut self, key: &str) -> Result<Option<i64>, StackMachineError> {
+        Ok(HashMap::get(self, key).copied())
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> Result<(), StackMachineError> {
+        self.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, StackMachineError> {
+        Ok(HashMap::remove(self, key).is_some())
+    }
+}
+
+/// A backend the host keeps a handle on, to read and change the values between runs
+impl<B: KvBackend> KvBackend for Rc<RefCell<B>> {
+    fn get(&mut self, key: &str) -> Result<Option<i64>, StackMachineError> {
+        self.borrow_mut().get(key)
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> Result<(), StackMachineError> {
+        self.borrow_mut().set(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, StackMachineError> {
+        self.borrow_mut().remove(key)
+    }
+}
+
+/// Traps giving programs named values that persist between runs, with keys passed as the
+/// ids of interned strings:
+///
+/// - first_trap_id, GET ( key -- value true | false )
+/// - first_trap_id + 1, SET ( value key -- )
+/// - first_trap_id + 2, DELETE ( key -- flag ) false if there was no value
+///
+/// A key that is not interned gives an UnknownString error
+pub struct KvTraps<B = HashMap<String, i64>> {
+    first_trap_id: i64,
+    backend: B,
+}
+
+impl KvTraps {
+    /// Traps keeping their values in memory
+    pub fn new(first_trap_id: i64) -> Self {
+        KvTraps::with_backend(first_trap_id, HashMap::new())
+    }
+}
+
+impl<B: KvBackend> KvTraps<B> {
+    pub fn with_backend(first_trap_id: i64, backend: B) -> Self {
+        KvTraps {
+            first_trap_id,
+            backend,
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+}
+
+impl<B: KvBackend> HandleTrap for KvTraps<B> {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        let trap = match trap_id.checked_sub(self.first_trap_id) {
+            Some(trap @ 0..=2) => trap,
+            _ => return Ok(TrapHandled::NotHandled),
+        };
+        let id = st.pop_number()?;
+        let key = st.string(id).ok_or(ErrorKind::UnknownString { id })?;
+        match trap {
+            0 => match self.backend.get(key)? {
+                Some(value) => {
+                    st.push_number(value);
+                    st.push_number(-1);
+                }
+                None => st.push_number(0),
+            },
+            1 => {
+                let key = key.to_string();
+                let value = st.pop_number()?;
+                self.backend.set(&key, value)?;
+            }
+            _ => {
+                let removed = self.backend.remove(key)?;
+                st.push_number(if removed { -1 } else { 0 });
+            }
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
@@ -1,30 +1,308 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::TryFrom;
-use std::num::TryFromIntError;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
+// Lets the procedural macros refer to this crate by name from inside it too
+extern crate self as rust_simple_stack_processor;
+
+mod abi;
+mod allocator;
+mod analysis;
+mod argv;
+mod array;
+mod assembler;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod cache;
+mod compact;
+mod constprop;
+mod context;
+mod convert;
+mod debugger;
+mod decimal;
+mod deterministic;
+mod dictionary;
+mod dump;
+mod engine;
+mod environment;
+mod error;
+mod events;
+pub mod examples_lib;
+#[cfg(feature = "fs-traps")]
+mod fs_traps;
+mod future;
+#[cfg(feature = "golden")]
+pub mod golden;
+mod image;
+mod integrity;
+mod kv;
+mod library;
+mod lockstep;
+mod memory;
+mod opcode;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod permissions;
+mod plan;
+mod profiler;
+mod reentrant;
+mod report;
+mod sandbox;
+mod session;
+mod simd;
+mod statistics;
+mod strings;
+mod symbols;
+#[cfg(feature = "symexec")]
+pub mod symexec;
+#[cfg(feature = "proptest")]
+pub mod testing;
 #[cfg(test)]
 mod tests;
+mod trap_frame;
+mod unwind;
+mod validate;
+
+pub use abi::StackAbi;
+pub use allocator::Allocation;
+use allocator::{grow_cells, CellAllocator};
+pub use analysis::{analyze, estimate_cost, trap_usage, CostEstimate, Metrics, TrapUsage};
+pub use argv::ArgvTraps;
+pub use array::ArrayTraps;
+pub use assembler::{AssembleError, ProgramBuilder};
+use cache::StableHasher;
+pub use cache::{content_hash, ProgramCache};
+pub use compact::{CompactError, Compaction};
+pub use constprop::{fold_constants, propagate_constants, AbstractValue, ConstantAnalysis};
+pub use context::Context;
+pub use convert::FromStackValues;
+pub use debugger::PatchError;
+use decimal::Decimal;
+pub use decimal::RoundingMode;
+pub use deterministic::DeterministicProfile;
+pub use dictionary::{Dictionary, DictionaryTraps};
+pub use dump::DumpOptions;
+use dump::TouchedCells;
+pub use engine::{Engine, EngineError, EngineLimits, MachineId, ProgramId};
+pub use environment::{EnvTraps, EnvValue};
+pub use error::{ErrorKind, StackKind, StackMachineError};
+pub use events::{Event, EventSink, LogRecord, LogSink};
+#[cfg(feature = "fs-traps")]
+pub use fs_traps::{FileAccess, FileTraps};
+pub use future::StackMachineFuture;
+pub use image::{DataSegment, Image, ImageError, IMAGE_MAGIC, IMAGE_VERSION};
+pub use integrity::Checksum;
+pub use kv::{KvBackend, KvTraps};
+pub use library::{Library, LinkError};
+pub use lockstep::{run_lockstep, Divergence};
+pub use memory::MemoryStats;
+pub use opcode::{
+    instruction_set_json, DecodeOpcodeError, GasClass, OpcodeMetadata, ParseOpcodeError,
+    OPCODE_COUNT,
+};
+#[cfg(feature = "rayon")]
+pub use parallel::EngineReport;
+pub use permissions::{TrapBudget, TrapPermissions};
+pub use plan::{PlanError, PlanStep};
+pub use profiler::{CallEdge, CallProfile, CallTargetGas, FunctionProfile};
+use profiler::{CallGasTracker, CallGraphProfiler};
+pub use reentrant::call_within_trap;
+pub use report::ExecutionReport;
+pub use rust_simple_stack_processor_macros::{ssp_asm, StackAbi};
+pub use sandbox::{SandboxLimits, SandboxTrap};
+pub use session::{Session, SESSION_MAGIC, SESSION_VERSION};
+use statistics::StatisticsRecorder;
+pub use statistics::{ExecutionStatistics, HighWaterMarks, TrapHandlerStats};
+pub use strings::{StringTable, StringTraps};
+pub use symbols::SymbolTable;
+pub use trap_frame::TrapFrame;
+pub use unwind::{unwind, CallChain, CallFrame};
+pub use validate::{validate, ValidationError};
+
+/// Why a run stopped without an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitStatus {
+    /// The program ran off the end
+    Halted,
+    /// The program returned with RET on an empty return stack
+    Returned,
+    /// A trap with this id was handled, which ends the run
+    TrapExit(i64),
+    /// The run is paused at the breakpoint at this pc
+    BreakpointHit(usize),
+    /// The run is paused after running the instruction it was asked to step, at a READIN
+    /// waiting for input, or after crossing a soft gas limit
+    Yielded,
+}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GasLimit {
     Unlimited,
     Limited(u64),
+    /// Pause the run with a Yielded status once it has used more than soft gas, so the host
+    /// can decide whether to grant more and resume with a higher limit, and fail it with
+    /// RanOutOfGas once it has used more than hard
+    Soft {
+        soft: u64,
+        hard: u64,
+    },
+}
+
+impl GasLimit {
+    // The soft limit, if any, and the hard limit, u64::MAX when unlimited
+    pub(crate) fn soft_and_hard(self) -> (Option<u64>, u64) {
+        match self {
+            GasLimit::Unlimited => (None, u64::MAX),
+            GasLimit::Limited(hard) => (None, hard),
+            GasLimit::Soft { soft, hard } => (Some(soft), hard),
+        }
+    }
+}
+
+/// How the run loop meters gas, the loop is compiled once for each policy so that an
+/// unlimited run has no check at all
+trait GasPolicy {
+    fn exhausted(&self, gas_used: u64) -> bool;
+
+    fn soft_limit_crossed(&self, _gas_used: u64) -> bool {
+        false
+    }
+}
+
+struct Unmetered;
+
+impl GasPolicy for Unmetered {
+    #[inline(always)]
+    fn exhausted(&self, _gas_used: u64) -> bool {
+        false
+    }
+}
+
+struct Metered(u64);
+
+impl GasPolicy for Metered {
+    #[inline(always)]
+    fn exhausted(&self, gas_used: u64) -> bool {
+        gas_used > self.0
+    }
 }
 
-#[derive(Debug)]
-pub enum StackMachineError {
-    UnkownError,
-    NumericOverflow,
-    NumberStackUnderflow,
-    LoopStackUnderflow,
-    ScratchStackUnderflow,
-    InvalidCellOperation,
-    UnhandledTrap,
-    RanOutOfGas,
+struct SoftMetered {
+    soft: u64,
+    hard: u64,
 }
 
-impl From<TryFromIntError> for StackMachineError {
-    fn from(err: TryFromIntError) -> StackMachineError {
-        match err {
-            _ => StackMachineError::NumericOverflow,
+impl GasPolicy for SoftMetered {
+    #[inline(always)]
+    fn exhausted(&self, gas_used: u64) -> bool {
+        gas_used > self.hard
+    }
+
+    #[inline(always)]
+    fn soft_limit_crossed(&self, gas_used: u64) -> bool {
+        gas_used > self.soft
+    }
+}
+
+/// Width of the values the machine computes with.
+///
+/// In `Bits32` mode every value pushed onto the number stack is truncated
+/// to 32 bits and sign extended, so arithmetic wraps (or saturates, or fails
+/// for the checked opcodes) exactly as it would on a 32-bit Forth system.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WordSize {
+    #[default]
+    Bits64,
+    Bits32,
+}
+
+impl WordSize {
+    pub fn min_value(self) -> i64 {
+        match self {
+            WordSize::Bits64 => i64::MIN,
+            WordSize::Bits32 => i64::from(i32::MIN),
+        }
+    }
+
+    pub fn max_value(self) -> i64 {
+        match self {
+            WordSize::Bits64 => i64::MAX,
+            WordSize::Bits32 => i64::from(i32::MAX),
+        }
+    }
+
+    /// Truncate a value to the word size, wrapping around on overflow
+    pub fn wrap(self, x: i64) -> i64 {
+        match self {
+            WordSize::Bits64 => x,
+            WordSize::Bits32 => i64::from(x as i32),
+        }
+    }
+
+    /// Clamp a value to the range representable in the word size
+    pub fn saturate(self, x: i64) -> i64 {
+        x.max(self.min_value()).min(self.max_value())
+    }
+
+    /// Fail with NumericOverflow if a value is not representable in the word size
+    pub fn check(self, x: i64) -> Result<i64, StackMachineError> {
+        if x < self.min_value() || x > self.max_value() {
+            return Err(ErrorKind::NumericOverflow.into());
+        }
+        Ok(x)
+    }
+}
+
+/// Condition flags, updated by ADD, SUB and MUL when the flags register is enabled.
+///
+/// `carry` is the unsigned carry (or borrow for SUB) out of the word,
+/// `overflow` is signed overflow, `zero` and `negative` describe the wrapped result.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Flags {
+    pub carry: bool,
+    pub overflow: bool,
+    pub zero: bool,
+    pub negative: bool,
+}
+
+#[derive(Clone, Copy)]
+enum FlagOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+macro_rules! flags_for_width {
+    ($op:expr,$x:expr,$y:expr,$signed:ty,$unsigned:ty) => {{
+        let (x, y) = ($x as $signed, $y as $signed);
+        let (result, overflow) = match $op {
+            FlagOp::Add => x.overflowing_add(y),
+            FlagOp::Sub => x.overflowing_sub(y),
+            FlagOp::Mul => x.overflowing_mul(y),
+        };
+        let (_, carry) = match $op {
+            FlagOp::Add => (x as $unsigned).overflowing_add(y as $unsigned),
+            FlagOp::Sub => (x as $unsigned).overflowing_sub(y as $unsigned),
+            FlagOp::Mul => (x as $unsigned).overflowing_mul(y as $unsigned),
+        };
+        Flags {
+            carry,
+            overflow,
+            zero: result == 0,
+            negative: result < 0,
+        }
+    }};
+}
+
+impl Flags {
+    fn compute(word_size: WordSize, op: FlagOp, x: i64, y: i64) -> Flags {
+        match word_size {
+            WordSize::Bits64 => flags_for_width!(op, x, y, i64, u64),
+            WordSize::Bits32 => flags_for_width!(op, x, y, i32, u32),
         }
     }
 }
@@ -43,9 +321,13 @@ pub trait HandleTrap {
     ) -> Result<TrapHandled, StackMachineError>;
 }
 
+type TrapFn<'a> =
+    dyn Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a;
+
 pub struct TrapHandler<'a> {
-    handled_trap: i64,
-    to_run: Box<dyn Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a>,
+    /// None to be called for every trap
+    handled_trap: Option<i64>,
+    to_run: Box<TrapFn<'a>>,
 }
 
 impl<'a> TrapHandler<'a> {
@@ -54,7 +336,18 @@ impl<'a> TrapHandler<'a> {
         C: Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a,
     {
         TrapHandler {
-            handled_trap,
+            handled_trap: Some(handled_trap),
+            to_run: Box::new(f),
+        }
+    }
+
+    /// A handler called with every trap id, for use as a fallback
+    pub fn any<C>(f: C) -> TrapHandler<'a>
+    where
+        C: Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a,
+    {
+        TrapHandler {
+            handled_trap: None,
             to_run: Box::new(f),
         }
     }
@@ -66,14 +359,82 @@ impl<'a> HandleTrap for TrapHandler<'a> {
         trap_number: i64,
         st: &mut StackMachineState,
     ) -> Result<TrapHandled, StackMachineError> {
-        if trap_number == self.handled_trap {
-            return (self.to_run)(self.handled_trap, st);
+        match self.handled_trap {
+            Some(handled_trap) if handled_trap != trap_number => Ok(TrapHandled::NotHandled),
+            _ => (self.to_run)(trap_number, st),
         }
-        Ok(TrapHandled::NotHandled)
+    }
+}
+
+/// Identifies a handler registered with `TrapHandlers`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerId(u64);
+
+/// The trap handlers of a machine, consulted in registration order until one handles the trap
+#[derive(Default)]
+pub struct TrapHandlers {
+    handlers: Vec<(HandlerId, Box<dyn HandleTrap>)>,
+    next_id: u64,
+    fallback: Option<Box<dyn HandleTrap>>,
+}
+
+impl TrapHandlers {
+    /// Add a handler after the existing ones
+    pub fn register(&mut self, handler: Box<dyn HandleTrap>) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        self.handlers.push((id, handler));
+        id
+    }
+
+    /// The same as `register`
+    pub fn push(&mut self, handler: Box<dyn HandleTrap>) -> HandlerId {
+        self.register(handler)
+    }
+
+    /// Remove a handler, returning it, or None if it is not registered
+    pub fn unregister(&mut self, id: HandlerId) -> Option<Box<dyn HandleTrap>> {
+        let index = self.handlers.iter().position(|(i, _)| *i == id)?;
+        Some(self.handlers.remove(index).1)
+    }
+
+    /// Swap in a new handler in the same position, returning the old one,
+    /// or None (dropping the new one) if id is not registered
+    pub fn replace(
+        &mut self,
+        id: HandlerId,
+        handler: Box<dyn HandleTrap>,
+    ) -> Option<Box<dyn HandleTrap>> {
+        let (_, slot) = self.handlers.iter_mut().find(|(i, _)| *i == id)?;
+        Some(std::mem::replace(slot, handler))
+    }
+
+    /// Install a handler that is consulted after all the registered ones, returning the
+    /// previous fallback. A trap it does not handle either is an UnhandledTrap error
+    pub fn set_fallback(&mut self, handler: Box<dyn HandleTrap>) -> Option<Box<dyn HandleTrap>> {
+        self.fallback.replace(handler)
+    }
+
+    pub fn take_fallback(&mut self) -> Option<Box<dyn HandleTrap>> {
+        self.fallback.take()
+    }
+
+    /// Ids of the registered handlers, in the order they are consulted
+    pub fn ids(&self) -> impl Iterator<Item = HandlerId> + '_ {
+        self.handlers.iter().map(|(id, _)| *id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Opcode {
     JMP,
     JR,
@@ -114,68 +475,496 @@ pub enum Opcode {
     NEWCELLS,
     MOVETOCELLS,
     MOVEFROMCELLS,
+    ADDSAT,
+    SUBSAT,
+    MULSAT,
+    ISQRT,
+    GCD,
+    IPOW,
+    JRC,
+    JRO,
+    FMDIVMOD,
+    FREECELLS,
+    HERE,
+    ALLOT,
+    SPILL(u32, u32),
+    UNSPILL(u32, u32),
+    LDPC(i64),
+    DODOES(u32, u32),
+    TRAPID(i64),
+    READIN,
+    WRITEOUT,
+    NEXTIN,
+    WRITEBYTE,
+    WRITECELL,
+    LOG(i64),
+    ASSERT(i64),
+    DADD,
+    DSUB,
+    DMUL,
+    DDIV,
+    DRESCALE,
 }
 
+/// Everything about a machine but its trap handlers and event sinks.
+///
+/// Clones are independent, so a state can be snapshotted and restored or forked. Two states
+/// are equal when the program, stacks, cells, cell allocations, pc, gas used, word size and
+/// flags are, whatever instrumentation is enabled
+#[derive(Clone, Default)]
+#[allow(deprecated)]
 pub struct StackMachineState {
+    #[deprecated(note = "use number_stack(), push_number() and pop_number()")]
     pub number_stack: Vec<i64>,
+    #[deprecated(note = "use scratch_stack(), push_scratch() and pop_scratch()")]
     pub scratch_stack: Vec<i64>,
     return_stack: Vec<usize>,
-    // current index, max_index
-    loop_stack: Vec<(i64, i64)>,
+    /// RET returns from the run when the return stack is this deep
+    return_fence: usize,
+    loop_stack: Vec<LoopFrame>,
     cells: Vec<i64>,
+    /// Places and checks cell allocations, None (the default) when it is disabled
+    allocator: Option<CellAllocator>,
+    /// The most cells a program can allocate, None (the default) for no limit
+    cell_limit: Option<usize>,
+    /// Enforced on every run when it is set
+    deterministic: Option<DeterministicProfile>,
+    /// Word names for the host and `DictionaryTraps`, None (the default) when it is disabled
+    dictionary: Option<Dictionary>,
+    /// The traps that may be raised, None (the default) for every trap
+    trap_permissions: Option<TrapPermissions>,
+    /// Limits on the handlers of each trap
+    trap_budgets: HashMap<i64, TrapBudget>,
+    /// Values for READIN, first in first out
+    input_queue: VecDeque<i64>,
+    /// Values written by WRITEOUT, oldest first
+    output_queue: VecDeque<i64>,
+    /// Interned strings, for the host and program to refer to by id
+    strings: StringTable,
+    /// Values set by the host for `EnvTraps`
+    environment: HashMap<String, EnvValue>,
+    /// Arguments set by the host for `ArgvTraps`
+    argv: Vec<i64>,
+    #[deprecated(note = "use opcodes() and load_program()")]
     pub opcodes: Vec<Opcode>,
+    /// A program shared with other machines, used instead of opcodes when it is loaded
+    shared_opcodes: Option<Arc<[Opcode]>>,
+    /// The program as it was frozen, which the loaded program must still be
+    frozen_opcodes: Option<Arc<[Opcode]>>,
+    /// The words of the libraries loaded with load_library
+    exports: SymbolTable,
     pc: usize,
     gas_used: u64,
+    /// Instructions run in the current run
+    steps: u64,
+    /// The most instructions a run can run, None (the default) for no limit
+    step_limit: Option<u64>,
+    pub word_size: WordSize,
+    /// The flags register, None (the default) when it is disabled
+    pub flags: Option<Flags>,
+    /// How DDIV and DRESCALE round
+    pub rounding_mode: RoundingMode,
+    statistics: Option<StatisticsRecorder>,
+    profiler: Option<CallGraphProfiler>,
+    high_water_marks: Option<HighWaterMarks>,
+    call_gas: Option<CallGasTracker>,
+    trap_handler_stats: Option<BTreeMap<HandlerId, TrapHandlerStats>>,
+    breakpoints: BTreeSet<usize>,
+    /// The cells most recently touched, None (the default) when they are not tracked
+    touched_cells: Option<TouchedCells>,
+    /// Stopped at a breakpoint, with the instruction at pc still to run
+    paused: bool,
+    /// Stop before every instruction, as if each one had a breakpoint
+    single_step: bool,
 }
 
-impl Default for StackMachineState {
-    fn default() -> Self {
-        StackMachineState {
-            number_stack: Vec::new(),
-            scratch_stack: Vec::new(),
-            return_stack: Vec::new(),
-            loop_stack: Vec::new(),
-            cells: Vec::new(),
-            opcodes: Vec::new(),
-            pc: 0,
-            gas_used: 0,
+// The deprecated fields are the storage behind these accessors
+#[allow(deprecated)]
+impl StackMachineState {
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    /// Instructions run in the most recent run, which unlike gas always count one each
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Limit the instructions a run can run, whatever gas they use, running more gives a
+    /// StepLimitExceeded error
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    pub fn step_limit(&self) -> Option<u64> {
+        self.step_limit
+    }
+
+    /// The number stack, TOS last
+    pub fn number_stack(&self) -> &[i64] {
+        &self.number_stack
+    }
+
+    /// Push a value, wrapped to the word size
+    pub fn push_number(&mut self, value: i64) {
+        let value = self.word_size.wrap(value);
+        self.number_stack.push(value);
+    }
+
+    pub fn pop_number(&mut self) -> Result<i64, StackMachineError> {
+        self.number_stack
+            .pop()
+            .ok_or_else(|| ErrorKind::StackUnderflow(StackKind::Number).into())
+    }
+
+    /// Pop TOS, the same as pop_number, for trap handlers
+    pub fn pop(&mut self) -> Result<i64, StackMachineError> {
+        self.pop_number()
+    }
+
+    /// Pop the top N values, deepest first so they read in the order they were pushed.
+    /// Nothing is popped when there are fewer than N
+    pub fn pop_n<const N: usize>(&mut self) -> Result<[i64; N], StackMachineError> {
+        let first = self
+            .number_stack
+            .len()
+            .checked_sub(N)
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        let mut values = [0; N];
+        values.copy_from_slice(&self.number_stack[first..]);
+        self.number_stack.truncate(first);
+        Ok(values)
+    }
+
+    /// Push values in order, so the last is TOS, each wrapped to the word size
+    pub fn push_all(&mut self, values: &[i64]) {
+        for &value in values {
+            self.push_number(value);
         }
     }
+
+    /// TOS, without removing it
+    pub fn peek_number(&self) -> Option<i64> {
+        self.number_stack.last().copied()
+    }
+
+    /// Replace the number stack, TOS last
+    pub fn set_number_stack(&mut self, values: Vec<i64>) {
+        self.number_stack = values;
+    }
+
+    /// Move the number stack out without copying it, leaving it empty
+    pub fn take_number_stack(&mut self) -> Vec<i64> {
+        std::mem::take(&mut self.number_stack)
+    }
+
+    pub(crate) fn number_stack_mut(&mut self) -> &mut Vec<i64> {
+        &mut self.number_stack
+    }
+
+    /// The scratch stack, top last
+    pub fn scratch_stack(&self) -> &[i64] {
+        &self.scratch_stack
+    }
+
+    pub fn push_scratch(&mut self, value: i64) {
+        self.scratch_stack.push(value);
+    }
+
+    pub fn pop_scratch(&mut self) -> Result<i64, StackMachineError> {
+        self.scratch_stack
+            .pop()
+            .ok_or_else(|| ErrorKind::StackUnderflow(StackKind::Scratch).into())
+    }
+
+    pub fn peek_scratch(&self) -> Option<i64> {
+        self.scratch_stack.last().copied()
+    }
+
+    /// The program
+    pub fn opcodes(&self) -> &[Opcode] {
+        match &self.shared_opcodes {
+            Some(shared) => shared,
+            None => &self.opcodes,
+        }
+    }
+
+    /// Replace the program
+    pub fn load_program(&mut self, opcodes: impl Into<Vec<Opcode>>) {
+        self.opcodes = opcodes.into();
+        self.shared_opcodes = None;
+    }
+
+    /// Replace the program with one that can be shared by many machines without copying it
+    pub fn load_shared_program(&mut self, opcodes: Arc<[Opcode]>) {
+        self.opcodes = Vec::new();
+        self.shared_opcodes = Some(opcodes);
+    }
+
+    /// The program, shared, so it can be loaded into other machines with `load_shared_program`.
+    /// A program that is not shared yet is moved into a new Arc first
+    pub fn share_program(&mut self) -> Arc<[Opcode]> {
+        let shared = match self.shared_opcodes.take() {
+            Some(shared) => shared,
+            None => std::mem::take(&mut self.opcodes).into(),
+        };
+        self.shared_opcodes = Some(shared.clone());
+        shared
+    }
+
+    /// The cells allocated with NEWCELLS
+    pub fn cells(&self) -> &[i64] {
+        &self.cells
+    }
+
+    /// Move the cells out without copying them, leaving none allocated
+    pub fn take_cells(&mut self) -> Vec<i64> {
+        if let Some(allocator) = self.allocator.as_mut() {
+            allocator.trim(0);
+        }
+        std::mem::take(&mut self.cells)
+    }
+
+    /// Limit the number of cells, allocating more gives a CellLimitExceeded error.
+    /// Cells that are already allocated are kept
+    pub fn set_cell_limit(&mut self, limit: Option<usize>) {
+        self.cell_limit = limit;
+    }
+
+    pub fn cell_limit(&self) -> Option<usize> {
+        self.cell_limit
+    }
+
+    /// Release every cell from `len` onwards, and the memory they used,
+    /// does nothing if fewer than `len` cells are allocated
+    pub fn trim_cells(&mut self, len: usize) {
+        self.cells.truncate(len);
+        self.cells.shrink_to_fit();
+        if let Some(allocator) = self.allocator.as_mut() {
+            allocator.trim(self.cells.len());
+        }
+    }
+
+    /// The values waiting to be read by READIN, push_back to add one
+    pub fn input_queue_mut(&mut self) -> &mut VecDeque<i64> {
+        &mut self.input_queue
+    }
+
+    pub fn output_queue(&self) -> &VecDeque<i64> {
+        &self.output_queue
+    }
+
+    /// Remove and return everything written by WRITEOUT, oldest first
+    pub fn take_output(&mut self) -> Vec<i64> {
+        self.output_queue.drain(..).collect()
+    }
+
+    /// The string held in length cells from address, one character to a cell
+    pub fn read_string(&self, address: usize, length: usize) -> Result<String, StackMachineError> {
+        let end = address
+            .checked_add(length)
+            .filter(|&end| end <= self.cells.len())
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        self.cells[address..end]
+            .iter()
+            .map(|&cell| {
+                u32::try_from(cell)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| ErrorKind::InvalidCellOperation.into())
+            })
+            .collect()
+    }
+
+    // The cells SPILL and UNSPILL move, which must all have been allocated
+    fn spill_range(&self, n: u32, address: u32) -> Result<Range<usize>, StackMachineError> {
+        self.cell_range(usize::try_from(address)?, usize::try_from(n)?)
+    }
+
+    /// The len cells from address, checking that they have all been allocated
+    pub(crate) fn cell_range(
+        &self,
+        address: usize,
+        len: usize,
+    ) -> Result<Range<usize>, StackMachineError> {
+        let end = address
+            .checked_add(len)
+            .filter(|&end| end <= self.cells.len())
+            .ok_or(ErrorKind::InvalidCellOperation)?;
+        if let (Some(allocator), true) = (&self.allocator, len > 0) {
+            allocator.check(address, len)?;
+        }
+        Ok(address..end)
+    }
+
+    /// Protect the program from changes, a run gives a CodeModified error if the program is
+    /// not the one frozen when it starts or when a trap handler returns. The program is shared
+    /// to freeze it, so loading or patching another one, or changing the opcodes field, is seen
+    pub fn freeze_code(&mut self) {
+        self.frozen_opcodes = Some(self.share_program());
+    }
+
+    pub fn thaw_code(&mut self) {
+        self.frozen_opcodes = None;
+    }
+
+    pub fn is_code_frozen(&self) -> bool {
+        self.frozen_opcodes.is_some()
+    }
+
+    fn check_frozen_code(&self) -> Result<(), StackMachineError> {
+        match (&self.frozen_opcodes, &self.shared_opcodes) {
+            (None, _) => Ok(()),
+            (Some(frozen), Some(shared))
+                if Arc::ptr_eq(frozen, shared) && self.opcodes.is_empty() =>
+            {
+                Ok(())
+            }
+            _ => Err(ErrorKind::CodeModified.into()),
+        }
+    }
+
+    /// A hash of the stacks, cells, pc and gas used, the same in every process and on every
+    /// architecture, so that machines that should be in step can be compared cheaply
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        for section in &[&self.number_stack, &self.scratch_stack, &self.cells] {
+            hasher.write_u64(section.len() as u64);
+            for &value in section.iter() {
+                hasher.write_u64(value as u64);
+            }
+        }
+        hasher.write_u64(self.return_stack.len() as u64);
+        for &address in &self.return_stack {
+            hasher.write_u64(address as u64);
+        }
+        hasher.write_u64(self.loop_stack.len() as u64);
+        for frame in &self.loop_stack {
+            hasher.write_u64(frame.index as u64);
+            hasher.write_u64(frame.limit as u64);
+        }
+        hasher.write_u64(self.pc as u64);
+        hasher.write_u64(self.gas_used);
+        hasher.finish()
+    }
+
+    /// The return addresses of the calls in progress, innermost last
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    /// The loop stack, innermost loop last
+    pub fn loop_stack(&self) -> &[LoopFrame] {
+        &self.loop_stack
+    }
+
+    /// Start a loop, as PUSHLP does, for trap handlers implementing their own iteration
+    pub fn push_loop(&mut self, frame: LoopFrame) {
+        self.loop_stack.push(frame);
+    }
+
+    /// Remove the innermost loop, as DROPLP does
+    pub fn pop_loop(&mut self) -> Result<LoopFrame, StackMachineError> {
+        let depth = self.loop_stack.len();
+        self.loop_stack
+            .pop()
+            .ok_or_else(|| ErrorKind::LoopStackUnderflow { frame: 0, depth }.into())
+    }
+
+    /// The loop frame this many from the innermost, with the LoopStackUnderflow error the
+    /// loop opcodes give when there is no such frame
+    pub fn loop_frame(&mut self, frame: usize) -> Result<&mut LoopFrame, StackMachineError> {
+        let depth = self.loop_stack.len();
+        depth
+            .checked_sub(frame + 1)
+            .map(move |index| &mut self.loop_stack[index])
+            .ok_or_else(|| ErrorKind::LoopStackUnderflow { frame, depth }.into())
+    }
 }
 
-impl StackMachineState {
-    pub fn gas_used(&self) -> u64 {
-        self.gas_used
+#[allow(deprecated)]
+impl PartialEq for StackMachineState {
+    fn eq(&self, other: &Self) -> bool {
+        self.number_stack == other.number_stack
+            && self.scratch_stack == other.scratch_stack
+            && self.return_stack == other.return_stack
+            && self.loop_stack == other.loop_stack
+            && self.cells == other.cells
+            && self.allocator == other.allocator
+            && self.opcodes() == other.opcodes()
+            && self.pc == other.pc
+            && self.gas_used == other.gas_used
+            && self.word_size == other.word_size
+            && self.flags == other.flags
+            && self.rounding_mode == other.rounding_mode
     }
 }
 
-pub struct StackMachine {
-    pub st: StackMachineState,
-    pub trap_handlers: Vec<Box<dyn HandleTrap>>,
+/// A loop on the loop stack, counting index up towards limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopFrame {
+    pub index: i64,
+    pub limit: i64,
 }
 
-impl Default for StackMachine {
-    fn default() -> StackMachine {
-        StackMachine {
-            st: StackMachineState::default(),
-            trap_handlers: Vec::new(),
-        }
+impl LoopFrame {
+    pub fn new(index: i64, limit: i64) -> Self {
+        LoopFrame { index, limit }
+    }
+
+    /// True once the index has reached the limit, as tested by CMPLOOP
+    pub fn is_finished(&self) -> bool {
+        self.index >= self.limit
+    }
+
+    /// Move the index on, wrapping to the word size
+    pub fn advance(&mut self, by: i64, word_size: WordSize) {
+        self.index = word_size.wrap(self.index.wrapping_add(by));
     }
 }
 
+#[derive(Default)]
+pub struct StackMachine {
+    pub st: StackMachineState,
+    pub trap_handlers: TrapHandlers,
+    pub event_sinks: Vec<Box<dyn EventSink>>,
+    /// Values for NEXTIN
+    input_stream: Option<Box<dyn Iterator<Item = i64>>>,
+    /// Where WRITEBYTE and WRITECELL write to
+    writer: Option<BufWriter<Box<dyn Write>>>,
+    /// Where LOG sends its records
+    log_sink: Option<Box<dyn LogSink>>,
+    on_start: Option<StartHook>,
+    on_exit: Option<ExitHook>,
+}
+
+/// A host callback run with the state at the start of every execute
+pub type StartHook = Box<dyn FnMut(&mut StackMachineState) -> Result<(), StackMachineError>>;
+
+/// A host callback run with the state and the result whenever a run ends
+pub type ExitHook = Box<
+    dyn FnMut(
+        &mut StackMachineState,
+        &Result<ExitStatus, StackMachineError>,
+    ) -> Result<(), StackMachineError>,
+>;
+
 macro_rules! pop_number_stack {
     ($variable:ident) => {
         $variable
             .st
             .number_stack
             .pop()
-            .ok_or(StackMachineError::NumberStackUnderflow)?
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?
     };
 }
 
 macro_rules! push_number_stack {
     ($variable:ident,$expr:expr) => {
-        $variable.st.number_stack.push($expr);
+        $variable
+            .st
+            .number_stack
+            .push($variable.st.word_size.wrap($expr))
     };
 }
 
@@ -185,13 +974,13 @@ macro_rules! pop_scratch_stack {
             .st
             .scratch_stack
             .pop()
-            .ok_or(StackMachineError::ScratchStackUnderflow)?
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Scratch))?
     };
 }
 
 macro_rules! push_scratch_stack {
     ($variable:ident,$expr:expr) => {
-        $variable.st.scratch_stack.push($expr);
+        $variable.st.scratch_stack.push($expr)
     };
 }
 
@@ -201,43 +990,564 @@ macro_rules! last_scratch_stack {
             .st
             .scratch_stack
             .last()
-            .ok_or(StackMachineError::ScratchStackUnderflow)?
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Scratch))?
+    };
+}
+
+macro_rules! update_flags {
+    ($variable:ident,$op:expr,$x:expr,$y:expr) => {
+        if $variable.st.flags.is_some() {
+            $variable.st.flags = Some(Flags::compute($variable.st.word_size, $op, $x, $y));
+        }
     };
 }
 
+// The run loop works on the stacks directly
+#[allow(deprecated)]
 impl StackMachine {
+    /// Start collecting ExecutionStatistics, they are reset at the start of every execute
+    pub fn enable_statistics(&mut self) {
+        self.st.statistics = Some(StatisticsRecorder::default());
+    }
+
+    pub fn disable_statistics(&mut self) {
+        self.st.statistics = None;
+    }
+
+    /// Statistics for the most recent run, None if they are not enabled
+    pub fn statistics(&self) -> Option<ExecutionStatistics> {
+        self.st.statistics.as_ref().map(StatisticsRecorder::export)
+    }
+
+    /// Start attributing gas to call frames, the profile is reset at the start of every execute
+    pub fn enable_profiler(&mut self) {
+        self.st.profiler = Some(CallGraphProfiler::default());
+    }
+
+    pub fn disable_profiler(&mut self) {
+        self.st.profiler = None;
+    }
+
+    /// Call graph profile of the most recent run, None if profiling is not enabled
+    pub fn profile(&self) -> Option<CallProfile> {
+        self.st.profiler.as_ref().map(CallGraphProfiler::export)
+    }
+
+    /// Start tracking the maximum depth of each stack and the peak cell count, they are reset at
+    /// the start of every execute
+    pub fn enable_high_water_marks(&mut self) {
+        self.st.high_water_marks = Some(HighWaterMarks::default());
+    }
+
+    pub fn disable_high_water_marks(&mut self) {
+        self.st.high_water_marks = None;
+    }
+
+    /// High water marks of the most recent run, None if tracking is not enabled
+    pub fn high_water_marks(&self) -> Option<HighWaterMarks> {
+        self.st.high_water_marks
+    }
+
+    /// Start counting calls, errors and time for each trap handler, they are reset at the start
+    /// of every execute
+    pub fn enable_trap_handler_stats(&mut self) {
+        self.st.trap_handler_stats = Some(BTreeMap::new());
+    }
+
+    pub fn disable_trap_handler_stats(&mut self) {
+        self.st.trap_handler_stats = None;
+    }
+
+    /// Statistics for each registered trap handler in the most recent run, None if they are
+    /// not enabled
+    pub fn trap_handler_stats(&self) -> Option<BTreeMap<HandlerId, TrapHandlerStats>> {
+        self.st.trap_handler_stats.as_ref().map(|stats| {
+            self.trap_handlers
+                .ids()
+                .map(|id| (id, stats.get(&id).copied().unwrap_or_default()))
+                .collect()
+        })
+    }
+
+    /// Place every NEWCELLS allocation at an address that is a multiple of alignment, with
+    /// guard_cells unusable cells either side, and keep track of the allocations.
+    ///
+    /// While enabled MOVETOCELLS and MOVEFROMCELLS give a GuardCellAccess error when the cells
+    /// they touch are not all in the same allocation, and FREECELLS must free exactly the most
+    /// recent allocation. The cells already allocated become a single allocation
+    pub fn enable_cell_allocator(&mut self, alignment: usize, guard_cells: usize) {
+        self.st.allocator = Some(CellAllocator::new(alignment, guard_cells, &self.st.cells));
+    }
+
+    pub fn disable_cell_allocator(&mut self) {
+        self.st.allocator = None;
+    }
+
+    /// The live cell allocations, lowest address first, None if the cell allocator is not enabled
+    pub fn cell_allocations(&self) -> Option<Vec<Allocation>> {
+        self.st
+            .allocator
+            .as_ref()
+            .map(|allocator| allocator.allocations().copied().collect())
+    }
+
+    /// Give NEXTIN a stream of values to read, such as host data too large to queue up front
+    pub fn attach_input_stream(&mut self, stream: impl Iterator<Item = i64> + 'static) {
+        self.input_stream = Some(Box::new(stream));
+    }
+
+    /// Remove the input stream, returning what is left of it
+    pub fn detach_input_stream(&mut self) -> Option<Box<dyn Iterator<Item = i64>>> {
+        self.input_stream.take()
+    }
+
+    /// Send the records of LOG to sink, replacing any earlier sink
+    pub fn set_log_sink(&mut self, sink: impl LogSink + 'static) {
+        self.log_sink = Some(Box::new(sink));
+    }
+
+    /// Remove the log sink
+    pub fn take_log_sink(&mut self) -> Option<Box<dyn LogSink>> {
+        self.log_sink.take()
+    }
+
+    /// Run hook at the start of every execute, before the first instruction, replacing any
+    /// earlier hook, such as to seed the input queue. An error from it fails the execute
+    /// without running anything
+    pub fn on_start(
+        &mut self,
+        hook: impl FnMut(&mut StackMachineState) -> Result<(), StackMachineError> + 'static,
+    ) {
+        self.on_start = Some(Box::new(hook));
+    }
+
+    /// Run hook whenever a run ends, rather than pausing, with its result, replacing any
+    /// earlier hook, such as to harvest the output queue or check invariants. An error from
+    /// it fails a run that succeeded, the error of a run that failed is kept
+    pub fn on_exit(
+        &mut self,
+        hook: impl FnMut(
+                &mut StackMachineState,
+                &Result<ExitStatus, StackMachineError>,
+            ) -> Result<(), StackMachineError>
+            + 'static,
+    ) {
+        self.on_exit = Some(Box::new(hook));
+    }
+
+    /// Remove the start and exit hooks
+    pub fn clear_hooks(&mut self) {
+        self.on_start = None;
+        self.on_exit = None;
+    }
+
+    /// Send what WRITEBYTE and WRITECELL write to writer, buffered and flushed at the end
+    /// of every run
+    pub fn set_writer(&mut self, writer: impl Write + 'static) {
+        self.writer = Some(BufWriter::new(Box::new(writer)));
+    }
+
+    /// Flush and remove the writer
+    pub fn take_writer(&mut self) -> Result<Option<Box<dyn Write>>, StackMachineError> {
+        match self.writer.take() {
+            Some(writer) => Ok(Some(writer.into_inner().map_err(|e| e.into_error())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write out anything still buffered for the writer
+    pub fn flush_writer(&mut self) -> Result<(), StackMachineError> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<Box<dyn Write>>, StackMachineError> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no writer is set").into())
+    }
+
+    /// Record the gas used at each CALL alongside the return stack, so that the gas used by
+    /// every CALL target can be reported without a symbol table, reset at the start of every
+    /// execute
+    pub fn enable_call_gas(&mut self) {
+        self.st.call_gas = Some(CallGasTracker::default());
+    }
+
+    pub fn disable_call_gas(&mut self) {
+        self.st.call_gas = None;
+    }
+
+    /// Gas used by each CALL target in the most recent run, including the code it called,
+    /// None if call gas recording is not enabled
+    pub fn gas_by_call_target(&self) -> Option<BTreeMap<usize, CallTargetGas>> {
+        self.st.call_gas.as_ref().map(|c| c.totals().clone())
+    }
+
     /// JR(*) is relative from the JR(*) instruction,
     /// 0 would jump back onto the JR instruction
     /// -1 Would jump back to the instruction before the JR(*}) instruction
     /// 1 Would jump to the instruction after the JR(*) instruction
     ///
-    /// TRAPs always have a numeric code on the number stack to define which TRAP is being called
+    /// TRAPs always have a numeric code on the number stack to define which TRAP is being called,
+    /// TRAPID raises the trap given by its immediate value instead
+    ///
+    /// READIN pushes the next value of the input queue, pausing the run with a Yielded status
+    /// when it is empty so that the host can add more and resume, WRITEOUT appends TOS to
+    /// the output queue
+    ///
+    /// NEXTIN pushes the next value of the input stream and then true, or only false when
+    /// the stream has ended or none is attached
+    ///
+    /// WRITEBYTE writes TOS, which must be from 0 to 255, as a byte to the machine's writer,
+    /// WRITECELL writes TOS as 8 little-endian bytes
+    ///
+    /// LOG(level) pops TOS and sends it to the log sink with the level and pc, such as the
+    /// address of a string for the host to read. Without a log sink the value is dropped
+    ///
+    /// ASSERT(message_id) pops a flag and fails with AssertionFailed when it is false
     ///
     /// CMPLOOP
     /// pushes 1 on the stack if the loop counter is greater than or equal to the max
     /// pushes 0 on the stack if the loop counter is less than the max
+    ///
+    /// ADDSAT, SUBSAT and MULSAT behave like ADD, SUB and MUL but clamp the
+    /// result to i64::MIN/i64::MAX instead of overflowing
+    ///
+    /// ISQRT pushes the integer square root (rounded down) of TOS
+    /// GCD pushes the greatest common divisor of the top two values, always non-negative
+    /// IPOW raises the second value on the stack to the power of TOS,
+    /// negative exponents and results that do not fit give a NumericOverflow
+    ///
+    /// With a WordSize of Bits32 every value pushed to the number stack is wrapped
+    /// to 32 bits, the saturating opcodes clamp to the 32-bit range, and the checked
+    /// opcodes give a NumericOverflow when the result does not fit in 32 bits
+    ///
+    /// When the flags register is enabled (st.flags is Some) ADD, SUB and MUL update it,
+    /// JRC and JRO are relative jumps taken when the carry or overflow flag is set,
+    /// they give a FlagsNotEnabled error when the flags register is disabled
+    ///
+    /// DIV divides the second value on the stack by TOS, truncating towards zero (SM/REM)
+    /// FMDIVMOD divides the same way but floors the quotient (FM/MOD), it pushes the
+    /// remainder and then the quotient, the remainder takes the sign of the divisor
+    ///
+    /// NEWCELLS allocates TOS cells, initialised to 0, and pushes the address of the first one
+    /// FREECELLS releases the last TOS cells allocated, so cells are freed in the reverse
+    /// order they were allocated in
+    ///
+    /// HERE pushes the data-space pointer, the address just past the last cell, and ALLOT
+    /// moves it by TOS, allocating cells initialised to 0 or releasing them when TOS is
+    /// negative. The cells ALLOT adds are not allocations of the cell allocator
+    ///
+    /// SPILL n address moves the top n values of the number stack to the n cells from
+    /// address, keeping their order so that the deepest goes to address, and UNSPILL n
+    /// address pushes them back, so a compiler can save a stack around a call in one
+    /// instruction each way
+    ///
+    /// LDPC offset pushes its own address plus offset, so code that finds its subroutines and
+    /// data with it runs unchanged wherever it is loaded
+    ///
+    /// DODOES data behavior pushes the address of a data field and jumps to the behavior
+    /// shared by every word of a CREATE ... DOES> defining word, so a word made by one is
+    /// just this instruction, and the behavior's RET returns to the word's caller
+    ///
+    /// The decimal opcodes work on decimals pushed as a mantissa and then an exponent, with
+    /// the value mantissa * 10^exponent, for exact decimal arithmetic such as money.
+    /// DADD, DSUB and DMUL add, subtract and multiply the top two decimals exactly, DSUB
+    /// subtracting the top one from the one below. DDIV divides the lower decimal by the top
+    /// one, and DRESCALE pops an exponent and moves the decimal below it to that exponent,
+    /// both rounding with st.rounding_mode. Results that do not fit give a NumericOverflow
+    ///
+    /// The ExitStatus says why the run stopped, a run that goes past the last instruction
+    /// halts, one that jumps further gives a PcOutOfRange error
     pub fn execute(
         &mut self,
         starting_point: usize,
         gas_limit: GasLimit,
-    ) -> Result<(), StackMachineError> {
+    ) -> Result<ExitStatus, StackMachineError> {
+        self.start_run(starting_point);
+        if let Some(hook) = self.on_start.as_mut() {
+            hook(&mut self.st)?;
+        }
+        self.run_until_stopped(gas_limit)
+    }
+
+    /// Reset the pc, gas and instrumentation for a new run
+    fn start_run(&mut self, starting_point: usize) {
         self.st.gas_used = 0;
+        self.st.steps = 0;
         self.st.pc = starting_point;
+        self.st.paused = false;
+        if let Some(statistics) = self.st.statistics.as_mut() {
+            *statistics = StatisticsRecorder::default();
+        }
+        if let Some(profiler) = self.st.profiler.as_mut() {
+            profiler.start(starting_point, 0);
+        }
+        if let Some(call_gas) = self.st.call_gas.as_mut() {
+            *call_gas = CallGasTracker::default();
+        }
+        if let Some(stats) = self.st.trap_handler_stats.as_mut() {
+            stats.clear();
+        }
+        if self.st.high_water_marks.is_some() {
+            let mut marks = HighWaterMarks::default();
+            marks.update(&self.st);
+            self.st.high_water_marks = Some(marks);
+        }
+    }
+
+    /// Run from the pc until the program returns, fails or reaches a breakpoint
+    fn run_until_stopped(&mut self, gas_limit: GasLimit) -> Result<ExitStatus, StackMachineError> {
+        let mut gas_limit = gas_limit;
+        let mut checked = self.st.check_frozen_code();
+        if let Some(profile) = self.st.deterministic {
+            gas_limit = profile.limit_gas(gas_limit);
+            checked = checked.and_then(|_| profile.check(&self.st));
+        }
+        let result = checked
+            .and_then(|_| match gas_limit {
+                GasLimit::Unlimited => self.run(Unmetered),
+                GasLimit::Limited(limit) => self.run(Metered(limit)),
+                GasLimit::Soft { soft, hard } => self.run(SoftMetered { soft, hard }),
+            })
+            .map_err(|e| {
+                let pc = self.st.pc;
+                e.at(pc, self.st.opcodes().get(pc))
+                    .with_return_stack(&self.st.return_stack)
+            });
+        if self.st.paused {
+            return result;
+        }
+
+        if let Some(profiler) = self.st.profiler.as_mut() {
+            profiler.finish(self.st.gas_used);
+        }
+        if let Some(call_gas) = self.st.call_gas.as_mut() {
+            call_gas.finish(self.st.gas_used);
+        }
+        self.update_high_water_marks();
+        let flushed = self.flush_writer();
+        let result = result.and_then(|status| flushed.map(|_| status));
+        match self.on_exit.as_mut() {
+            Some(hook) => {
+                let checked = hook(&mut self.st, &result);
+                result.and_then(|status| checked.map(|_| status))
+            }
+            None => result,
+        }
+    }
+
+    /// Run with the number stack set to initial_number_stack, returning the final number
+    /// stack, which is moved out of the machine. The other stacks are left as they were
+    pub fn execute_with_stack(
+        &mut self,
+        starting_point: usize,
+        initial_number_stack: Vec<i64>,
+        gas_limit: GasLimit,
+    ) -> Result<Vec<i64>, StackMachineError> {
+        self.st.number_stack = initial_number_stack;
+        self.execute(starting_point, gas_limit)?;
+        Ok(self.st.take_number_stack())
+    }
+
+    /// The state of the machine, dropping its trap handlers and event sinks, so that the
+    /// results of a run can be moved out without copying them
+    pub fn into_state(self) -> StackMachineState {
+        self.st
+    }
+
+    fn update_high_water_marks(&mut self) {
+        if let Some(mut marks) = self.st.high_water_marks {
+            marks.update(&self.st);
+            self.st.high_water_marks = Some(marks);
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        for sink in self.event_sinks.iter_mut() {
+            sink.event(&event);
+        }
+    }
+
+    fn emit_retired(&mut self, current_pc: usize, opcode: &Opcode, pc_reset: bool) {
+        let (from, to) = (current_pc, self.st.pc);
+        self.emit(Event::InstructionRetired {
+            pc: current_pc,
+            opcode: opcode.clone(),
+            gas_used: self.st.gas_used,
+        });
+        match opcode {
+            Opcode::CALL => self.emit(Event::CallEntered { from, target: to }),
+            Opcode::RET => self.emit(Event::CallReturned { from, to }),
+            _ if pc_reset && analysis::is_branch(opcode) => {
+                self.emit(Event::BranchTaken { from, to })
+            }
+            _ => {}
+        }
+    }
+
+    /// True if anything needs to see every step, none of these can change during a run
+    fn instrumented(&self) -> bool {
+        self.st.statistics.is_some()
+            || self.st.profiler.is_some()
+            || self.st.high_water_marks.is_some()
+            || self.st.call_gas.is_some()
+            || !self.event_sinks.is_empty()
+    }
+
+    /// Instrumentation after an instruction, once the pc and gas have been updated
+    fn record_step(&mut self, current_pc: usize, opcode: &Opcode, pc_reset: bool) {
+        self.update_high_water_marks();
+        if let Some(statistics) = self.st.statistics.as_mut() {
+            statistics.record_branch(opcode, pc_reset);
+        }
+        if let Some(profiler) = self.st.profiler.as_mut() {
+            match opcode {
+                Opcode::CALL => profiler.enter(self.st.pc, self.st.gas_used),
+                Opcode::RET => profiler.leave(self.st.gas_used),
+                _ => {}
+            }
+        }
+        if let Some(call_gas) = self.st.call_gas.as_mut() {
+            match opcode {
+                Opcode::CALL => call_gas.call(self.st.pc, self.st.gas_used),
+                Opcode::RET => call_gas.ret(self.st.gas_used),
+                _ => {}
+            }
+        }
+        if !self.event_sinks.is_empty() {
+            self.emit_retired(current_pc, opcode, pc_reset);
+        }
+    }
+
+    fn run<G: GasPolicy>(&mut self, gas: G) -> Result<ExitStatus, StackMachineError> {
+        // Counted in a local so that the loop can keep it in a register
+        let mut steps = self.st.steps;
+        let result = self.run_counting(gas, &mut steps);
+        self.st.steps = steps;
+        result
+    }
+
+    // Run the handlers for a trap, which always ends the run
+    fn raise_trap(
+        &mut self,
+        current_pc: usize,
+        trap_id: i64,
+    ) -> Result<ExitStatus, StackMachineError> {
+        if let Some(statistics) = self.st.statistics.as_mut() {
+            statistics.record_trap(trap_id);
+        }
+        self.emit(Event::TrapRaised {
+            pc: current_pc,
+            trap_id,
+        });
+        if let Some(permissions) = self.st.trap_permissions.as_mut() {
+            permissions.check(trap_id)?;
+        }
+        let budget = self.st.trap_budgets.get(&trap_id).copied();
+        let budget_started = budget.map(|_| (Instant::now(), self.st.gas_used));
+        let check_budget = |st: &StackMachineState| match (budget, budget_started) {
+            (Some(budget), Some((started, gas_used))) => {
+                budget.check(trap_id, started.elapsed(), st.gas_used - gas_used)
+            }
+            _ => Ok(()),
+        };
+        for (id, h) in self.trap_handlers.handlers.iter_mut() {
+            let started = self.st.trap_handler_stats.as_ref().map(|_| Instant::now());
+            let result = h.handle_trap(trap_id, &mut self.st);
+            self.st.check_frozen_code()?;
+            if let (Some(stats), Some(started)) = (self.st.trap_handler_stats.as_mut(), started) {
+                stats
+                    .entry(*id)
+                    .or_default()
+                    .record(&result, started.elapsed());
+            }
+            check_budget(&self.st)?;
+            if let TrapHandled::Handled = result? {
+                return Ok(ExitStatus::TrapExit(trap_id));
+            }
+        }
+        if let Some(fallback) = self.trap_handlers.fallback.as_mut() {
+            let result = fallback.handle_trap(trap_id, &mut self.st);
+            self.st.check_frozen_code()?;
+            check_budget(&self.st)?;
+            if let TrapHandled::Handled = result? {
+                return Ok(ExitStatus::TrapExit(trap_id));
+            }
+        }
+        Err(ErrorKind::UnhandledTrap { trap_id }.into())
+    }
+
+    // Raise a trap, then fail the run if the gas its handlers charged to it, such as for a
+    // sandboxed child or a call_within_trap, took it over its gas limit
+    fn raise_metered_trap<G: GasPolicy>(
+        &mut self,
+        current_pc: usize,
+        opcode: &Opcode,
+        trap_id: i64,
+        gas: &G,
+    ) -> Result<ExitStatus, StackMachineError> {
+        let status = self.raise_trap(current_pc, trap_id)?;
+        if gas.exhausted(self.st.gas_used) {
+            return Err(
+                StackMachineError::from(ErrorKind::RanOutOfGas).at(current_pc, Some(opcode))
+            );
+        }
+        Ok(status)
+    }
+
+    fn run_counting<G: GasPolicy>(
+        &mut self,
+        gas: G,
+        steps: &mut u64,
+    ) -> Result<ExitStatus, StackMachineError> {
+        let instrumented = self.instrumented();
+        let has_breakpoints = !self.st.breakpoints.is_empty() || self.st.single_step;
+        let step_limit = self.st.step_limit.unwrap_or(u64::MAX);
+        // A resumed run starts on the breakpoint it stopped at
+        let mut resuming = std::mem::take(&mut self.st.paused);
         loop {
             let mut pc_reset = false;
-            match self.st.opcodes[self.st.pc] {
+            let current_pc = self.st.pc;
+            if has_breakpoints {
+                if !resuming && (self.st.single_step || self.st.breakpoints.contains(&current_pc)) {
+                    self.st.paused = true;
+                    if self.st.breakpoints.contains(&current_pc) {
+                        return Ok(ExitStatus::BreakpointHit(current_pc));
+                    }
+                    return Ok(ExitStatus::Yielded);
+                }
+                resuming = false;
+            }
+            let opcode = match self.st.opcodes().get(current_pc) {
+                Some(opcode) => opcode.clone(),
+                None if current_pc == self.st.opcodes().len() => return Ok(ExitStatus::Halted),
+                None => return Err(ErrorKind::PcOutOfRange.into()),
+            };
+            if instrumented {
+                if let Some(statistics) = self.st.statistics.as_mut() {
+                    statistics.record_instruction(&opcode);
+                }
+            }
+            match opcode {
                 Opcode::JMP => {
-                    self.st.pc = usize::try_from(pop_number_stack!(self)).unwrap();
+                    self.st.pc = usize::try_from(pop_number_stack!(self))
+                        .map_err(|_| ErrorKind::PcOutOfRange)?;
                     pc_reset = true;
                 }
                 Opcode::JR => {
-                    let new_offset = i64::try_from(self.st.pc)? + pop_number_stack!(self);
-                    self.st.pc = usize::try_from(new_offset).unwrap();
+                    let offset = pop_number_stack!(self);
+                    self.st.pc = relative_target(current_pc, offset)?;
                     pc_reset = true;
                 }
                 Opcode::CALL => {
+                    let target = usize::try_from(pop_number_stack!(self))
+                        .map_err(|_| ErrorKind::PcOutOfRange)?;
                     self.st.return_stack.push(self.st.pc + 1);
-                    self.st.pc = usize::try_from(pop_number_stack!(self))?;
+                    self.st.pc = target;
                     pc_reset = true;
                 }
                 Opcode::CMPZ => {
@@ -257,30 +1567,104 @@ impl StackMachine {
                     }
                 }
                 Opcode::JRZ => {
-                    let new_offset = i64::try_from(self.st.pc)? + pop_number_stack!(self);
+                    let offset = pop_number_stack!(self);
                     let x = pop_number_stack!(self);
                     if x == 0 {
-                        self.st.pc = usize::try_from(new_offset).unwrap();
+                        self.st.pc = relative_target(current_pc, offset)?;
                         pc_reset = true;
                     }
                 }
                 Opcode::JRNZ => {
-                    let new_offset = i64::try_from(self.st.pc)? + pop_number_stack!(self);
+                    let offset = pop_number_stack!(self);
                     let x = pop_number_stack!(self);
                     if x != 0 {
-                        self.st.pc = usize::try_from(new_offset).unwrap();
+                        self.st.pc = relative_target(current_pc, offset)?;
+                        pc_reset = true;
+                    }
+                }
+                Opcode::JRC => {
+                    let offset = pop_number_stack!(self);
+                    let flags = self.st.flags.ok_or(ErrorKind::FlagsNotEnabled)?;
+                    if flags.carry {
+                        self.st.pc = relative_target(current_pc, offset)?;
+                        pc_reset = true;
+                    }
+                }
+                Opcode::JRO => {
+                    let offset = pop_number_stack!(self);
+                    let flags = self.st.flags.ok_or(ErrorKind::FlagsNotEnabled)?;
+                    if flags.overflow {
+                        self.st.pc = relative_target(current_pc, offset)?;
                         pc_reset = true;
                     }
                 }
                 Opcode::LDI(x) => push_number_stack!(self, x),
+                Opcode::READIN => match self.st.input_queue.pop_front() {
+                    Some(value) => push_number_stack!(self, value),
+                    None => {
+                        self.st.paused = true;
+                        return Ok(ExitStatus::Yielded);
+                    }
+                },
+                Opcode::NEXTIN => match self.input_stream.as_mut().and_then(Iterator::next) {
+                    Some(value) => {
+                        push_number_stack!(self, value);
+                        self.st.number_stack.push(-1);
+                    }
+                    None => self.st.number_stack.push(0),
+                },
+                Opcode::WRITEBYTE => {
+                    let byte = u8::try_from(pop_number_stack!(self))?;
+                    self.writer()?.write_all(&[byte])?;
+                }
+                Opcode::WRITECELL => {
+                    let value = pop_number_stack!(self);
+                    self.writer()?.write_all(&value.to_le_bytes())?;
+                }
+                Opcode::LOG(level) => {
+                    let value = pop_number_stack!(self);
+                    if let Some(sink) = self.log_sink.as_mut() {
+                        sink.log(&LogRecord {
+                            level,
+                            pc: current_pc,
+                            value,
+                        });
+                    }
+                }
+                Opcode::ASSERT(message_id) => {
+                    if pop_number_stack!(self) == 0 {
+                        return Err(ErrorKind::AssertionFailed {
+                            pc: current_pc,
+                            message_id,
+                        }
+                        .into());
+                    }
+                }
+                Opcode::WRITEOUT => {
+                    let value = pop_number_stack!(self);
+                    self.st.output_queue.push_back(value);
+                }
+                Opcode::DODOES(data, behavior) => {
+                    push_number_stack!(self, i64::from(data));
+                    self.st.pc = usize::try_from(behavior)?;
+                    pc_reset = true;
+                }
+                Opcode::LDPC(offset) => {
+                    let address = i64::try_from(current_pc)?
+                        .checked_add(offset)
+                        .ok_or(ErrorKind::NumericOverflow)?;
+                    push_number_stack!(self, address);
+                }
                 Opcode::DROP => {
                     let _ = pop_number_stack!(self);
                 }
                 Opcode::RET => {
-                    match self.st.return_stack.pop() {
-                        None => return Ok(()),
-                        Some(oldpc) => self.st.pc = oldpc,
-                    };
+                    if self.st.return_stack.len() <= self.st.return_fence {
+                        return Ok(ExitStatus::Returned);
+                    }
+                    if let Some(oldpc) = self.st.return_stack.pop() {
+                        self.st.pc = oldpc;
+                    }
                     pc_reset = true;
                 }
                 Opcode::GtR => {
@@ -318,22 +1702,28 @@ impl StackMachine {
                 Opcode::ADD => {
                     let x = pop_number_stack!(self);
                     let y = pop_number_stack!(self);
-                    push_number_stack!(self, x + y);
+                    update_flags!(self, FlagOp::Add, x, y);
+                    push_number_stack!(self, x.wrapping_add(y));
                 }
                 Opcode::SUB => {
                     let x = pop_number_stack!(self);
                     let y = pop_number_stack!(self);
-                    push_number_stack!(self, x - y);
+                    update_flags!(self, FlagOp::Sub, x, y);
+                    push_number_stack!(self, x.wrapping_sub(y));
                 }
                 Opcode::MUL => {
                     let x = pop_number_stack!(self);
                     let y = pop_number_stack!(self);
-                    push_number_stack!(self, x * y);
+                    update_flags!(self, FlagOp::Mul, x, y);
+                    push_number_stack!(self, x.wrapping_mul(y));
                 }
                 Opcode::DIV => {
                     let x = pop_number_stack!(self);
                     let y = pop_number_stack!(self);
-                    push_number_stack!(self, y / x);
+                    if x == 0 {
+                        return Err(ErrorKind::DivisionByZero.into());
+                    }
+                    push_number_stack!(self, y.wrapping_div(x));
                 }
                 Opcode::NOT => {
                     let x = pop_number_stack!(self);
@@ -388,76 +1778,40 @@ impl StackMachine {
                 }
                 Opcode::TRAP => {
                     let trap_id = pop_number_stack!(self);
-                    for h in self.trap_handlers.iter_mut() {
-                        if let TrapHandled::Handled = h.handle_trap(trap_id, &mut self.st)? {
-                            return Ok(());
-                        }
-                    }
-                    return Err(StackMachineError::UnhandledTrap);
+                    return self.raise_metered_trap(current_pc, &opcode, trap_id, &gas);
+                }
+                Opcode::TRAPID(trap_id) => {
+                    return self.raise_metered_trap(current_pc, &opcode, trap_id, &gas)
                 }
                 Opcode::NOP => {}
                 Opcode::PUSHLP => {
-                    let current_index = pop_number_stack!(self);
-                    let max_index = pop_number_stack!(self);
-                    self.st.loop_stack.push((current_index, max_index));
+                    let index = pop_number_stack!(self);
+                    let limit = pop_number_stack!(self);
+                    self.st.loop_stack.push(LoopFrame::new(index, limit));
+                }
+                Opcode::INCLP => {
+                    let word_size = self.st.word_size;
+                    self.st.loop_frame(0)?.advance(1, word_size);
                 }
-                Opcode::INCLP => match self.st.loop_stack.last_mut() {
-                    Some((current_index, _max_index)) => {
-                        *current_index += 1;
-                    }
-                    None => {
-                        return Err(StackMachineError::LoopStackUnderflow);
-                    }
-                },
                 Opcode::ADDLP => {
                     let increment = pop_number_stack!(self);
-
-                    match self.st.loop_stack.last_mut() {
-                        Some((current_index, _max_index)) => {
-                            *current_index += increment;
-                        }
-                        None => {
-                            return Err(StackMachineError::LoopStackUnderflow);
-                        }
-                    }
+                    let word_size = self.st.word_size;
+                    self.st.loop_frame(0)?.advance(increment, word_size);
                 }
                 Opcode::GETLP => {
-                    let (current_index, _max_index) = self
-                        .st
-                        .loop_stack
-                        .last()
-                        .ok_or(StackMachineError::LoopStackUnderflow)?;
-                    self.st.number_stack.push(*current_index);
+                    let index = self.st.loop_frame(0)?.index;
+                    self.st.number_stack.push(index);
                 }
                 Opcode::GETLP2 => {
-                    if self.st.loop_stack.len() < 2 {
-                        return Err(StackMachineError::LoopStackUnderflow);
-                    }
-                    let (current_index, _max_index) = self
-                        .st
-                        .loop_stack
-                        .get(self.st.loop_stack.len() - 2)
-                        .ok_or(StackMachineError::LoopStackUnderflow)?;
-                    self.st.number_stack.push(*current_index);
+                    let index = self.st.loop_frame(1)?.index;
+                    self.st.number_stack.push(index);
                 }
                 Opcode::DROPLP => {
-                    let _x = self
-                        .st
-                        .loop_stack
-                        .pop()
-                        .ok_or(StackMachineError::LoopStackUnderflow)?;
+                    self.st.pop_loop()?;
                 }
                 Opcode::CMPLOOP => {
-                    let (current_index, max_index) = self
-                        .st
-                        .loop_stack
-                        .last()
-                        .ok_or(StackMachineError::LoopStackUnderflow)?;
-                    if *current_index >= *max_index {
-                        self.st.number_stack.push(1);
-                    } else {
-                        self.st.number_stack.push(0);
-                    }
+                    let finished = self.st.loop_frame(0)?.is_finished();
+                    self.st.number_stack.push(if finished { 1 } else { 0 });
                 }
                 Opcode::AND => {
                     let x = pop_number_stack!(self);
@@ -466,35 +1820,199 @@ impl StackMachine {
                 }
                 Opcode::NEWCELLS => {
                     let num_cells = usize::try_from(pop_number_stack!(self))
-                        .map_err(|_| StackMachineError::InvalidCellOperation)?;
-                    let newaddress = self.st.cells.len();
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
+                    let limit = self.st.cell_limit;
+                    let newaddress = match self.st.allocator.as_mut() {
+                        Some(allocator) => {
+                            allocator.allocate(&mut self.st.cells, num_cells, limit)?
+                        }
+                        None => {
+                            let newaddress = self.st.cells.len();
+                            let end = newaddress
+                                .checked_add(num_cells)
+                                .ok_or(ErrorKind::InvalidCellOperation)?;
+                            grow_cells(&mut self.st.cells, end, limit)?;
+                            newaddress
+                        }
+                    };
+                    push_number_stack!(self, i64::try_from(newaddress)?);
+                }
+                Opcode::FREECELLS => {
+                    let num_cells = usize::try_from(pop_number_stack!(self))
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
+                    match self.st.allocator.as_mut() {
+                        Some(allocator) => allocator.free(&mut self.st.cells, num_cells)?,
+                        None => {
+                            let len = self
+                                .st
+                                .cells
+                                .len()
+                                .checked_sub(num_cells)
+                                .ok_or(ErrorKind::InvalidCellOperation)?;
+                            self.st.trim_cells(len);
+                        }
+                    }
+                }
+                Opcode::HERE => {
+                    push_number_stack!(self, i64::try_from(self.st.cells.len())?);
+                }
+                Opcode::ALLOT => {
+                    let here = i64::try_from(self.st.cells.len())?;
+                    let here = here
+                        .checked_add(pop_number_stack!(self))
+                        .and_then(|here| usize::try_from(here).ok())
+                        .ok_or(ErrorKind::InvalidCellOperation)?;
+                    if here < self.st.cells.len() {
+                        self.st.trim_cells(here);
+                    } else {
+                        grow_cells(&mut self.st.cells, here, self.st.cell_limit)?;
+                    }
+                }
+                Opcode::SPILL(n, address) => {
+                    let range = self.st.spill_range(n, address)?;
+                    let first = self
+                        .st
+                        .number_stack
+                        .len()
+                        .checked_sub(range.len())
+                        .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+                    self.st.cells[range.clone()].copy_from_slice(&self.st.number_stack[first..]);
+                    self.st.number_stack.truncate(first);
+                    self.st.touch_cells(range);
+                }
+                Opcode::UNSPILL(n, address) => {
+                    let range = self.st.spill_range(n, address)?;
                     self.st
-                        .cells
-                        .resize_with(newaddress + num_cells, Default::default);
+                        .number_stack
+                        .extend_from_slice(&self.st.cells[range.clone()]);
+                    self.st.touch_cells(range);
                 }
                 Opcode::MOVETOCELLS => {
                     let num_cells = usize::try_from(pop_number_stack!(self))
-                        .map_err(|_| StackMachineError::InvalidCellOperation)?;
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
                     let address = usize::try_from(pop_number_stack!(self))
-                        .map_err(|_| StackMachineError::InvalidCellOperation)?;
-                    if num_cells < 1 || self.st.cells.len() < address + num_cells {
-                        return Err(StackMachineError::InvalidCellOperation);
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
+                    let end = cell_range_end(&self.st.cells, address, num_cells)?;
+                    if let Some(allocator) = &self.st.allocator {
+                        allocator.check(address, num_cells)?;
                     }
-                    for i in address..address + num_cells {
-                        self.st.cells[i] = pop_number_stack!(self);
+                    // TOS goes to address, the values below it to the cells after
+                    let stack = &mut self.st.number_stack;
+                    let first = stack.len().saturating_sub(num_cells);
+                    for (cell, value) in self.st.cells[address..end]
+                        .iter_mut()
+                        .zip(stack[first..].iter().rev())
+                    {
+                        *cell = *value;
                     }
+                    let moved = stack.len() - first;
+                    stack.truncate(first);
+                    if moved < num_cells {
+                        return Err(ErrorKind::StackUnderflow(StackKind::Number).into());
+                    }
+                    self.st.touch_cells(address..end);
                 }
                 Opcode::MOVEFROMCELLS => {
                     let num_cells = usize::try_from(pop_number_stack!(self))
-                        .map_err(|_| StackMachineError::InvalidCellOperation)?;
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
                     let address = usize::try_from(pop_number_stack!(self))
-                        .map_err(|_| StackMachineError::InvalidCellOperation)?;
-                    if num_cells < 1 || self.st.cells.len() < address + num_cells {
-                        return Err(StackMachineError::InvalidCellOperation);
+                        .map_err(|_| ErrorKind::InvalidCellOperation)?;
+                    let end = cell_range_end(&self.st.cells, address, num_cells)?;
+                    if let Some(allocator) = &self.st.allocator {
+                        allocator.check(address, num_cells)?;
+                    }
+                    let word_size = self.st.word_size;
+                    self.st.number_stack.extend(
+                        self.st.cells[address..end]
+                            .iter()
+                            .rev()
+                            .map(|&value| word_size.wrap(value)),
+                    );
+                    self.st.touch_cells(address..end);
+                }
+                Opcode::ADDSAT => {
+                    let x = pop_number_stack!(self);
+                    let y = pop_number_stack!(self);
+                    push_number_stack!(self, self.st.word_size.saturate(x.saturating_add(y)));
+                }
+                Opcode::SUBSAT => {
+                    let x = pop_number_stack!(self);
+                    let y = pop_number_stack!(self);
+                    push_number_stack!(self, self.st.word_size.saturate(x.saturating_sub(y)));
+                }
+                Opcode::MULSAT => {
+                    let x = pop_number_stack!(self);
+                    let y = pop_number_stack!(self);
+                    push_number_stack!(self, self.st.word_size.saturate(x.saturating_mul(y)));
+                }
+                Opcode::FMDIVMOD => {
+                    let divisor = pop_number_stack!(self);
+                    let dividend = pop_number_stack!(self);
+                    if divisor == 0 {
+                        return Err(ErrorKind::DivisionByZero.into());
                     }
-                    for i in (address..address + num_cells).rev() {
-                        push_number_stack!(self, self.st.cells[i]);
+                    let mut quotient = dividend
+                        .checked_div(divisor)
+                        .ok_or(ErrorKind::NumericOverflow)?;
+                    let mut remainder = dividend % divisor;
+                    if remainder != 0 && (remainder < 0) != (divisor < 0) {
+                        quotient -= 1;
+                        remainder += divisor;
                     }
+                    push_number_stack!(self, remainder);
+                    push_number_stack!(self, self.st.word_size.check(quotient)?);
+                }
+                Opcode::ISQRT => {
+                    let x = u64::try_from(pop_number_stack!(self))?;
+                    push_number_stack!(self, i64::try_from(integer_square_root(x))?);
+                }
+                Opcode::GCD => {
+                    let x = pop_number_stack!(self);
+                    let y = pop_number_stack!(self);
+                    push_number_stack!(
+                        self,
+                        self.st
+                            .word_size
+                            .check(i64::try_from(greatest_common_divisor(
+                                x.unsigned_abs(),
+                                y.unsigned_abs()
+                            ))?)?
+                    );
+                }
+                Opcode::DADD | Opcode::DSUB | Opcode::DMUL | Opcode::DDIV => {
+                    let [m1, e1, m2, e2] = self.st.pop_n()?;
+                    let (a, b) = (Decimal::new(m1, e1), Decimal::new(m2, e2));
+                    let word_size = self.st.word_size;
+                    let result = match opcode {
+                        Opcode::DADD => decimal::add(a, b, word_size)?,
+                        Opcode::DSUB => decimal::sub(a, b, word_size)?,
+                        Opcode::DMUL => decimal::mul(a, b, word_size)?,
+                        _ => decimal::div(a, b, self.st.rounding_mode, word_size)?,
+                    };
+                    push_number_stack!(self, result.mantissa);
+                    push_number_stack!(self, result.exponent);
+                }
+                Opcode::DRESCALE => {
+                    let [mantissa, exponent, target] = self.st.pop_n()?;
+                    let result = decimal::rescale(
+                        Decimal::new(mantissa, exponent),
+                        target,
+                        self.st.rounding_mode,
+                        self.st.word_size,
+                    )?;
+                    push_number_stack!(self, result.mantissa);
+                    push_number_stack!(self, result.exponent);
+                }
+                Opcode::IPOW => {
+                    let exponent = u32::try_from(pop_number_stack!(self))?;
+                    let base = pop_number_stack!(self);
+                    push_number_stack!(
+                        self,
+                        self.st.word_size.check(
+                            base.checked_pow(exponent)
+                                .ok_or(ErrorKind::NumericOverflow)?
+                        )?
+                    );
                 }
             };
             if !pc_reset {
@@ -502,12 +2020,69 @@ impl StackMachine {
             }
 
             self.st.gas_used += 1;
+            *steps += 1;
 
-            if let GasLimit::Limited(x) = gas_limit {
-                if self.st.gas_used > x {
-                    return Err(StackMachineError::RanOutOfGas);
-                }
+            if instrumented {
+                self.record_step(current_pc, &opcode, pc_reset);
+            }
+
+            if gas.exhausted(self.st.gas_used) {
+                return Err(
+                    StackMachineError::from(ErrorKind::RanOutOfGas).at(current_pc, Some(&opcode))
+                );
+            }
+            if *steps > step_limit {
+                return Err(StackMachineError::from(ErrorKind::StepLimitExceeded)
+                    .at(current_pc, Some(&opcode)));
+            }
+            if gas.soft_limit_crossed(self.st.gas_used) {
+                self.st.paused = true;
+                return Ok(ExitStatus::Yielded);
             }
         }
     }
 }
+
+// The address a relative jump at pc lands on, a jump before address 0 is out of range
+fn relative_target(pc: usize, offset: i64) -> Result<usize, StackMachineError> {
+    i64::try_from(pc)?
+        .checked_add(offset)
+        .and_then(|target| usize::try_from(target).ok())
+        .ok_or_else(|| ErrorKind::PcOutOfRange.into())
+}
+
+// The end of the num_cells cells from address that MOVETOCELLS and MOVEFROMCELLS move,
+// which must be at least one and all allocated
+fn cell_range_end(
+    cells: &[i64],
+    address: usize,
+    num_cells: usize,
+) -> Result<usize, StackMachineError> {
+    address
+        .checked_add(num_cells)
+        .filter(|&end| num_cells > 0 && end <= cells.len())
+        .ok_or_else(|| ErrorKind::InvalidCellOperation.into())
+}
+
+pub(crate) fn integer_square_root(x: u64) -> u64 {
+    if x < 2 {
+        return x;
+    }
+    // Newton's method, starting from a value known to be above the root
+    let mut current = x;
+    let mut next = (current + x / current) / 2;
+    while next < current {
+        current = next;
+        next = (current + x / current) / 2;
+    }
+    current
+}
+
+pub(crate) fn greatest_common_divisor(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
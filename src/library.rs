@@ -0,0 +1,115 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::validate::validate_from;
+use super::{Opcode, StackMachineState, SymbolTable, ValidationError};
+
+/// A segment of code that can be appended to the program of a machine, even while it runs
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Library {
+    /// The code, assembled as if it were loaded at address 0
+    pub opcodes: Vec<Opcode>,
+    /// The words the library provides, by their addresses in opcodes
+    pub exports: SymbolTable,
+    /// The indexes of the LDI opcodes that hold an address in opcodes, which are moved along
+    /// with the code when it is loaded
+    pub relocations: Vec<usize>,
+}
+
+/// Reasons `StackMachineState::load_library` refuses a library
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    /// A word of the library is already exported by one loaded earlier
+    DuplicateExport(String),
+    /// A relocation is not an LDI opcode of the library, or its address overflows
+    BadRelocation(usize),
+    /// An export is not an address in the library
+    BadExport(String),
+    /// The relocated code would not pass `validate`, run from its start or any export
+    Invalid(ValidationError),
+    /// The program is frozen
+    Frozen,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateExport(name) => write!(f, "'{}' is already exported", name),
+            LinkError::BadRelocation(index) => write!(f, "bad relocation at {}", index),
+            LinkError::BadExport(name) => write!(f, "export '{}' is outside the library", name),
+            LinkError::Invalid(e) => write!(f, "linked library is invalid: {}", e),
+            LinkError::Frozen => write!(f, "program is frozen"),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LinkError::Invalid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl StackMachineState {
+    /// Append a library to the program, returning the address it was loaded at. Its
+    /// relocations are moved to that address and its exports added to `exports`.
+    ///
+    /// The code is appended in place, as `append_code` does, so loading does not copy the
+    /// program unless it is shared. The relocated code is validated from its start and from
+    /// each export, so it can call into the code before it.
+    ///
+    /// Nothing is loaded unless the whole library links, and trap handlers can load
+    /// libraries, so a program can include new words as it runs
+    #[allow(deprecated)]
+    pub fn load_library(&mut self, library: &Library) -> Result<usize, LinkError> {
+        if self.is_code_frozen() {
+            return Err(LinkError::Frozen);
+        }
+        let base = self.opcodes().len();
+        for (address, name) in library.exports.iter() {
+            if address > library.opcodes.len() {
+                return Err(LinkError::BadExport(name.to_string()));
+            }
+            if self.exports.address_of(name).is_some() {
+                return Err(LinkError::DuplicateExport(name.to_string()));
+            }
+        }
+        let mut opcodes = library.opcodes.clone();
+        let offset = i64::try_from(base).unwrap_or(i64::MAX);
+        for &index in &library.relocations {
+            match opcodes.get_mut(index) {
+                Some(Opcode::LDI(address)) => {
+                    *address = address
+                        .checked_add(offset)
+                        .ok_or(LinkError::BadRelocation(index))?
+                }
+                _ => return Err(LinkError::BadRelocation(index)),
+            }
+        }
+
+        if self.shared_opcodes.is_some() {
+            let program = self.opcodes().to_vec();
+            self.load_program(program);
+        }
+        self.opcodes.extend(opcodes);
+        let validated = std::iter::once(0)
+            .chain(library.exports.iter().map(|(address, _)| address))
+            .filter(|&address| address < library.opcodes.len())
+            .try_for_each(|address| validate_from(&self.opcodes, base + address));
+        if let Err(e) = validated {
+            self.opcodes.truncate(base);
+            return Err(LinkError::Invalid(e));
+        }
+        for (address, name) in library.exports.iter() {
+            self.exports.insert(base + address, name);
+        }
+        Ok(base)
+    }
+
+    /// The words exported by the libraries loaded so far
+    pub fn exports(&self) -> &SymbolTable {
+        &self.exports
+    }
+}
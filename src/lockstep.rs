@@ -0,0 +1,51 @@
+use super::{ErrorKind, GasLimit, StackMachine};
+
+/// The first instruction at which two machines run in lockstep stopped agreeing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Instructions each machine ran before the one that diverged
+    pub step: u64,
+    /// The pc of each machine before the instruction that diverged
+    pub pcs: (usize, usize),
+    /// The state hash of each machine after it
+    pub state_hashes: (u64, u64),
+    /// How each machine's run ended, when the instruction ended one of them with an error
+    pub errors: (Option<ErrorKind>, Option<ErrorKind>),
+}
+
+/// Run two machines from starting_point one instruction at a time, comparing their state
+/// hashes after every instruction, and return the first instruction after which they differ.
+///
+/// Machines that finish together, or fail together with the same kind of error, have not
+/// diverged. The gas limit applies to each machine
+pub fn run_lockstep(
+    a: &mut StackMachine,
+    b: &mut StackMachine,
+    starting_point: usize,
+    gas_limit: GasLimit,
+) -> Option<Divergence> {
+    a.start_paused(starting_point);
+    b.start_paused(starting_point);
+    let mut step = 0;
+    loop {
+        let pcs = (a.st.pc, b.st.pc);
+        let errors = (
+            a.step(gas_limit).err().map(|e| e.kind()),
+            b.step(gas_limit).err().map(|e| e.kind()),
+        );
+        let state_hashes = (a.st.state_hash(), b.st.state_hash());
+        let running = (a.paused_at().is_some(), b.paused_at().is_some());
+        if state_hashes.0 != state_hashes.1 || errors.0 != errors.1 || running.0 != running.1 {
+            return Some(Divergence {
+                step,
+                pcs,
+                state_hashes,
+                errors,
+            });
+        }
+        if !running.0 {
+            return None;
+        }
+        step += 1;
+    }
+}
@@ -0,0 +1,58 @@
+use std::mem::size_of;
+
+use super::{LoopFrame, Opcode, StackMachine};
+
+/// Heap bytes held by a machine, see `StackMachine::memory_stats`.
+///
+/// Each figure is the capacity allocated, which can be more than the values in use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryStats {
+    pub number_stack: usize,
+    pub scratch_stack: usize,
+    pub return_stack: usize,
+    pub loop_stack: usize,
+    pub cells: usize,
+    pub program: usize,
+    /// The program is shared with other machines, so adding up the program of every machine
+    /// counts it more than once
+    pub program_shared: bool,
+}
+
+impl MemoryStats {
+    /// Every figure added up, including a shared program
+    pub fn total(&self) -> usize {
+        self.stacks() + self.cells + self.program
+    }
+
+    /// The four stacks added up
+    pub fn stacks(&self) -> usize {
+        self.number_stack + self.scratch_stack + self.return_stack + self.loop_stack
+    }
+}
+
+fn bytes<T>(capacity: usize) -> usize {
+    capacity.saturating_mul(size_of::<T>())
+}
+
+impl StackMachine {
+    /// The heap bytes used by each stack, the cells and the program, for hosts running many
+    /// machines to monitor and cap what they use between runs
+    #[allow(deprecated)]
+    pub fn memory_stats(&self) -> MemoryStats {
+        let st = &self.st;
+        let (program, program_shared) = match &st.shared_opcodes {
+            Some(shared) => (bytes::<Opcode>(shared.len()), true),
+            None => (bytes::<Opcode>(st.opcodes.capacity()), false),
+        };
+        MemoryStats {
+            number_stack: bytes::<i64>(st.number_stack.capacity()),
+            scratch_stack: bytes::<i64>(st.scratch_stack.capacity()),
+            return_stack: bytes::<usize>(st.return_stack.capacity()),
+            loop_stack: bytes::<LoopFrame>(st.loop_stack.capacity()),
+            cells: bytes::<i64>(st.cells.capacity()),
+            program,
+            program_shared,
+        }
+    }
+}
@@ -0,0 +1,723 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use super::Opcode;
+
+/// Error returned when parsing an Opcode from its textual form fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseOpcodeError {
+    Empty,
+    UnknownMnemonic(String),
+    MissingImmediate,
+    InvalidImmediate(String),
+    UnexpectedOperand(String),
+}
+
+impl fmt::Display for ParseOpcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseOpcodeError::Empty => write!(f, "empty instruction"),
+            ParseOpcodeError::UnknownMnemonic(m) => write!(f, "unknown mnemonic '{}'", m),
+            ParseOpcodeError::MissingImmediate => write!(f, "missing immediate value"),
+            ParseOpcodeError::InvalidImmediate(v) => write!(f, "invalid immediate value '{}'", v),
+            ParseOpcodeError::UnexpectedOperand(v) => write!(f, "unexpected operand '{}'", v),
+        }
+    }
+}
+
+impl std::error::Error for ParseOpcodeError {}
+
+const ALL_VARIANTS: [Opcode; OPCODE_COUNT as usize] = [
+    Opcode::JMP,
+    Opcode::JR,
+    Opcode::JRZ,
+    Opcode::JRNZ,
+    Opcode::CALL,
+    Opcode::CMPZ,
+    Opcode::CMPNZ,
+    Opcode::LDI(0),
+    Opcode::DROP,
+    Opcode::SWAP,
+    Opcode::SWAP2,
+    Opcode::RET,
+    Opcode::ADD,
+    Opcode::SUB,
+    Opcode::MUL,
+    Opcode::DIV,
+    Opcode::NOT,
+    Opcode::DUP,
+    Opcode::DUP2,
+    Opcode::TRAP,
+    Opcode::NOP,
+    Opcode::PUSHLP,
+    Opcode::INCLP,
+    Opcode::ADDLP,
+    Opcode::GETLP,
+    Opcode::GETLP2,
+    Opcode::DROPLP,
+    Opcode::CMPLOOP,
+    Opcode::OVER2,
+    Opcode::GtR,
+    Opcode::RGt,
+    Opcode::RAt,
+    Opcode::GtR2,
+    Opcode::RGt2,
+    Opcode::RAt2,
+    Opcode::AND,
+    Opcode::NEWCELLS,
+    Opcode::MOVETOCELLS,
+    Opcode::MOVEFROMCELLS,
+    Opcode::ADDSAT,
+    Opcode::SUBSAT,
+    Opcode::MULSAT,
+    Opcode::ISQRT,
+    Opcode::GCD,
+    Opcode::IPOW,
+    Opcode::JRC,
+    Opcode::JRO,
+    Opcode::FMDIVMOD,
+    Opcode::FREECELLS,
+    Opcode::HERE,
+    Opcode::ALLOT,
+    Opcode::SPILL(0, 0),
+    Opcode::UNSPILL(0, 0),
+    Opcode::LDPC(0),
+    Opcode::DODOES(0, 0),
+    Opcode::TRAPID(0),
+    Opcode::READIN,
+    Opcode::WRITEOUT,
+    Opcode::NEXTIN,
+    Opcode::WRITEBYTE,
+    Opcode::WRITECELL,
+    Opcode::LOG(0),
+    Opcode::ASSERT(0),
+    Opcode::DADD,
+    Opcode::DSUB,
+    Opcode::DMUL,
+    Opcode::DDIV,
+    Opcode::DRESCALE,
+];
+
+/// Broad grouping of opcodes by the kind of work they do, for gas accounting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GasClass {
+    /// Moves values between the number stack and the scratch stack, or pushes constants
+    Stack,
+    Arithmetic,
+    /// Jumps, calls and returns
+    Control,
+    /// Loop stack manipulation
+    Loop,
+    /// Cell allocation and transfers
+    Memory,
+    /// Calls out to trap handlers
+    Host,
+}
+
+/// Static description of an opcode.
+///
+/// `pops` and `pushes` describe the effect on the number stack. When
+/// `variable_stack_effect` is set they only cover the fixed part of the effect,
+/// e.g. MOVETOCELLS pops a further count of values given by one of its arguments,
+/// and a TRAP may do anything its handler chooses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeMetadata {
+    pub mnemonic: &'static str,
+    pub immediates: u8,
+    pub pops: u8,
+    pub pushes: u8,
+    pub variable_stack_effect: bool,
+    pub gas_class: GasClass,
+}
+
+macro_rules! metadata {
+    ($mnemonic:expr, $immediates:expr, $pops:expr, $pushes:expr, $class:ident) => {
+        OpcodeMetadata {
+            mnemonic: $mnemonic,
+            immediates: $immediates,
+            pops: $pops,
+            pushes: $pushes,
+            variable_stack_effect: false,
+            gas_class: GasClass::$class,
+        }
+    };
+    ($mnemonic:expr, $immediates:expr, $pops:expr, $pushes:expr, $class:ident, variable) => {
+        OpcodeMetadata {
+            variable_stack_effect: true,
+            ..metadata!($mnemonic, $immediates, $pops, $pushes, $class)
+        }
+    };
+}
+
+impl Opcode {
+    pub fn metadata(&self) -> OpcodeMetadata {
+        match self {
+            Opcode::JMP => metadata!("JMP", 0, 1, 0, Control),
+            Opcode::JR => metadata!("JR", 0, 1, 0, Control),
+            Opcode::JRZ => metadata!("JRZ", 0, 2, 0, Control),
+            Opcode::JRNZ => metadata!("JRNZ", 0, 2, 0, Control),
+            Opcode::CALL => metadata!("CALL", 0, 1, 0, Control),
+            Opcode::CMPZ => metadata!("CMPZ", 0, 1, 1, Arithmetic),
+            Opcode::CMPNZ => metadata!("CMPNZ", 0, 1, 1, Arithmetic),
+            Opcode::LDI(_) => metadata!("LDI", 1, 0, 1, Stack),
+            Opcode::DROP => metadata!("DROP", 0, 1, 0, Stack),
+            Opcode::SWAP => metadata!("SWAP", 0, 2, 2, Stack),
+            Opcode::SWAP2 => metadata!("SWAP2", 0, 4, 4, Stack),
+            Opcode::RET => metadata!("RET", 0, 0, 0, Control),
+            Opcode::ADD => metadata!("ADD", 0, 2, 1, Arithmetic),
+            Opcode::SUB => metadata!("SUB", 0, 2, 1, Arithmetic),
+            Opcode::MUL => metadata!("MUL", 0, 2, 1, Arithmetic),
+            Opcode::DIV => metadata!("DIV", 0, 2, 1, Arithmetic),
+            Opcode::NOT => metadata!("NOT", 0, 1, 1, Arithmetic),
+            Opcode::DUP => metadata!("DUP", 0, 1, 2, Stack),
+            Opcode::DUP2 => metadata!("DUP2", 0, 2, 4, Stack),
+            Opcode::TRAP => metadata!("TRAP", 0, 1, 0, Host, variable),
+            Opcode::NOP => metadata!("NOP", 0, 0, 0, Stack),
+            Opcode::PUSHLP => metadata!("PUSHLP", 0, 2, 0, Loop),
+            Opcode::INCLP => metadata!("INCLP", 0, 0, 0, Loop),
+            Opcode::ADDLP => metadata!("ADDLP", 0, 1, 0, Loop),
+            Opcode::GETLP => metadata!("GETLP", 0, 0, 1, Loop),
+            Opcode::GETLP2 => metadata!("GETLP2", 0, 0, 1, Loop),
+            Opcode::DROPLP => metadata!("DROPLP", 0, 0, 0, Loop),
+            Opcode::CMPLOOP => metadata!("CMPLOOP", 0, 0, 1, Loop),
+            Opcode::OVER2 => metadata!("OVER2", 0, 4, 6, Stack),
+            Opcode::GtR => metadata!("GtR", 0, 1, 0, Stack),
+            Opcode::RGt => metadata!("RGt", 0, 0, 1, Stack),
+            Opcode::RAt => metadata!("RAt", 0, 0, 1, Stack),
+            Opcode::GtR2 => metadata!("GtR2", 0, 2, 0, Stack),
+            Opcode::RGt2 => metadata!("RGt2", 0, 0, 2, Stack),
+            Opcode::RAt2 => metadata!("RAt2", 0, 0, 2, Stack),
+            Opcode::AND => metadata!("AND", 0, 2, 1, Arithmetic),
+            Opcode::NEWCELLS => metadata!("NEWCELLS", 0, 1, 1, Memory),
+            Opcode::MOVETOCELLS => metadata!("MOVETOCELLS", 0, 2, 0, Memory, variable),
+            Opcode::MOVEFROMCELLS => metadata!("MOVEFROMCELLS", 0, 2, 0, Memory, variable),
+            Opcode::ADDSAT => metadata!("ADDSAT", 0, 2, 1, Arithmetic),
+            Opcode::SUBSAT => metadata!("SUBSAT", 0, 2, 1, Arithmetic),
+            Opcode::MULSAT => metadata!("MULSAT", 0, 2, 1, Arithmetic),
+            Opcode::ISQRT => metadata!("ISQRT", 0, 1, 1, Arithmetic),
+            Opcode::GCD => metadata!("GCD", 0, 2, 1, Arithmetic),
+            Opcode::IPOW => metadata!("IPOW", 0, 2, 1, Arithmetic),
+            Opcode::JRC => metadata!("JRC", 0, 1, 0, Control),
+            Opcode::JRO => metadata!("JRO", 0, 1, 0, Control),
+            Opcode::FMDIVMOD => metadata!("FMDIVMOD", 0, 2, 2, Arithmetic),
+            Opcode::FREECELLS => metadata!("FREECELLS", 0, 1, 0, Memory),
+            Opcode::HERE => metadata!("HERE", 0, 0, 1, Memory),
+            Opcode::ALLOT => metadata!("ALLOT", 0, 1, 0, Memory),
+            Opcode::SPILL(..) => metadata!("SPILL", 2, 0, 0, Memory, variable),
+            Opcode::UNSPILL(..) => metadata!("UNSPILL", 2, 0, 0, Memory, variable),
+            Opcode::LDPC(_) => metadata!("LDPC", 1, 0, 1, Stack),
+            Opcode::DODOES(..) => metadata!("DODOES", 2, 0, 1, Control),
+            Opcode::TRAPID(_) => metadata!("TRAPID", 1, 0, 0, Host, variable),
+            Opcode::READIN => metadata!("READIN", 0, 0, 1, Host),
+            Opcode::WRITEOUT => metadata!("WRITEOUT", 0, 1, 0, Host),
+            Opcode::NEXTIN => metadata!("NEXTIN", 0, 0, 1, Host, variable),
+            Opcode::WRITEBYTE => metadata!("WRITEBYTE", 0, 1, 0, Host),
+            Opcode::WRITECELL => metadata!("WRITECELL", 0, 1, 0, Host),
+            Opcode::LOG(_) => metadata!("LOG", 1, 1, 0, Host),
+            Opcode::ASSERT(_) => metadata!("ASSERT", 1, 1, 0, Control),
+            Opcode::DADD => metadata!("DADD", 0, 4, 2, Arithmetic),
+            Opcode::DSUB => metadata!("DSUB", 0, 4, 2, Arithmetic),
+            Opcode::DMUL => metadata!("DMUL", 0, 4, 2, Arithmetic),
+            Opcode::DDIV => metadata!("DDIV", 0, 4, 2, Arithmetic),
+            Opcode::DRESCALE => metadata!("DRESCALE", 0, 3, 2, Arithmetic),
+        }
+    }
+
+    /// Every opcode variant, in encoding order, opcodes carrying an immediate value use 0
+    pub fn all_variants() -> &'static [Opcode] {
+        &ALL_VARIANTS
+    }
+
+    /// The stable mnemonic of the opcode, without any immediate value
+    pub fn mnemonic(&self) -> &'static str {
+        self.metadata().mnemonic
+    }
+
+    /// A one line description of what the opcode does
+    pub fn description(&self) -> &'static str {
+        match self {
+            Opcode::JMP => "Jump to the address on TOS",
+            Opcode::JR => "Jump by the offset on TOS, relative to the JR",
+            Opcode::JRZ => {
+                "Pop an offset and then a value, jumping by the offset when the value is 0"
+            }
+            Opcode::JRNZ => {
+                "Pop an offset and then a value, jumping by the offset when the value is not 0"
+            }
+            Opcode::CALL => {
+                "Push the address after the CALL to the return stack and jump to the address on TOS"
+            }
+            Opcode::CMPZ => "Replace TOS with -1 if it is 0, otherwise 0",
+            Opcode::CMPNZ => "Replace TOS with -1 if it is not 0, otherwise 0",
+            Opcode::LDI(_) => "Push the immediate value",
+            Opcode::DROP => "Discard TOS",
+            Opcode::SWAP => "Swap the top two values",
+            Opcode::SWAP2 => "Swap the top two pairs of values",
+            Opcode::RET => {
+                "Return to the address on the return stack, or from the run when it is empty"
+            }
+            Opcode::ADD => "Replace the top two values with their sum",
+            Opcode::SUB => "Replace the top two values with TOS minus the value below it",
+            Opcode::MUL => "Replace the top two values with their product",
+            Opcode::DIV => "Divide the second value by TOS, truncating towards zero",
+            Opcode::NOT => "Replace TOS with 1 if it is 0, otherwise 0",
+            Opcode::DUP => "Duplicate TOS",
+            Opcode::DUP2 => "Duplicate the top two values",
+            Opcode::TRAP => "Raise the trap whose id is on TOS",
+            Opcode::NOP => "Do nothing",
+            Opcode::PUSHLP => "Pop an index and then a limit and push them to the loop stack",
+            Opcode::INCLP => "Add 1 to the index of the innermost loop",
+            Opcode::ADDLP => "Add TOS to the index of the innermost loop",
+            Opcode::GETLP => "Push the index of the innermost loop",
+            Opcode::GETLP2 => "Push the index of the loop enclosing the innermost one",
+            Opcode::DROPLP => "Discard the innermost loop",
+            Opcode::CMPLOOP => "Push 1 if the innermost loop has reached its limit, otherwise 0",
+            Opcode::OVER2 => "Copy the second pair of values over the top pair",
+            Opcode::GtR => "Move TOS to the scratch stack",
+            Opcode::RGt => "Move the top of the scratch stack to the number stack",
+            Opcode::RAt => "Copy the top of the scratch stack to the number stack",
+            Opcode::GtR2 => "Move the top two values to the scratch stack",
+            Opcode::RGt2 => "Move the top two values of the scratch stack to the number stack",
+            Opcode::RAt2 => "Copy the top two values of the scratch stack to the number stack",
+            Opcode::AND => "Replace the top two values with their bitwise and",
+            Opcode::NEWCELLS => {
+                "Allocate TOS cells initialised to 0 and push the address of the first"
+            }
+            Opcode::MOVETOCELLS => {
+                "Pop a count and an address and move that many values to the cells from the address"
+            }
+            Opcode::MOVEFROMCELLS => {
+                "Pop a count and an address and push the values of that many cells from the address"
+            }
+            Opcode::ADDSAT => "Add the top two values, clamping instead of overflowing",
+            Opcode::SUBSAT => "Subtract like SUB, clamping instead of overflowing",
+            Opcode::MULSAT => "Multiply the top two values, clamping instead of overflowing",
+            Opcode::ISQRT => "Replace TOS with its integer square root, rounded down",
+            Opcode::GCD => "Replace the top two values with their greatest common divisor",
+            Opcode::IPOW => "Raise the second value to the power of TOS",
+            Opcode::JRC => "Jump by the offset on TOS when the carry flag is set",
+            Opcode::JRO => "Jump by the offset on TOS when the overflow flag is set",
+            Opcode::FMDIVMOD => {
+                "Divide the second value by TOS, flooring, and push the remainder and the quotient"
+            }
+            Opcode::FREECELLS => "Release the last TOS cells allocated",
+            Opcode::HERE => "Push the address just past the last cell",
+            Opcode::ALLOT => "Grow the cells by TOS, or shrink them when it is negative",
+            Opcode::SPILL(..) => "Move the top count values to the cells from the address",
+            Opcode::UNSPILL(..) => "Push the count values of the cells from the address",
+            Opcode::LDPC(_) => "Push the address of the LDPC plus the offset",
+            Opcode::DODOES(..) => "Push the data field address and jump to the behavior",
+            Opcode::TRAPID(_) => "Raise the trap given by the immediate value",
+            Opcode::READIN => "Push the next value of the input queue, yielding when it is empty",
+            Opcode::WRITEOUT => "Append TOS to the output queue",
+            Opcode::NEXTIN => {
+                "Push the next value of the input stream and -1, or only 0 when it has ended"
+            }
+            Opcode::WRITEBYTE => "Write TOS as a byte to the writer",
+            Opcode::WRITECELL => "Write TOS as 8 little-endian bytes to the writer",
+            Opcode::LOG(_) => "Send TOS to the log sink with the level and pc",
+            Opcode::ASSERT(_) => "Pop a flag and fail with the message id when it is 0",
+            Opcode::DADD => "Add the top two decimals exactly",
+            Opcode::DSUB => "Subtract the top decimal from the one below exactly",
+            Opcode::DMUL => "Multiply the top two decimals exactly",
+            Opcode::DDIV => {
+                "Divide the lower decimal by the top one, rounding to the lower one's exponent"
+            }
+            Opcode::DRESCALE => "Move the decimal below TOS to the exponent on TOS, rounding",
+        }
+    }
+}
+
+/// The instruction set as JSON, for assemblers, syntax highlighters and hardware
+/// implementations to be generated from.
+///
+/// The object has the `opcode_count` and the `opcodes` in encoding order, each with its
+/// `mnemonic`, opcode number as `code`, number of `immediates`, the values it `pops` and
+/// `pushes`, whether it has a `variable_stack_effect`, its `gas_class` and `description`
+pub fn instruction_set_json() -> String {
+    let opcodes: Vec<String> = Opcode::all_variants()
+        .iter()
+        .map(|opcode| {
+            let metadata = opcode.metadata();
+            format!(
+                concat!(
+                    "{{\"mnemonic\":{},\"code\":{},\"immediates\":{},\"pops\":{},",
+                    "\"pushes\":{},\"variable_stack_effect\":{},\"gas_class\":{},",
+                    "\"description\":{}}}"
+                ),
+                json_string(metadata.mnemonic),
+                opcode.encode().0,
+                metadata.immediates,
+                metadata.pops,
+                metadata.pushes,
+                metadata.variable_stack_effect,
+                json_string(&format!("{:?}", metadata.gas_class)),
+                json_string(opcode.description()),
+            )
+        })
+        .collect();
+    format!(
+        "{{\"opcode_count\":{},\"opcodes\":[{}]}}",
+        OPCODE_COUNT,
+        opcodes.join(",")
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Opcodes are written as their mnemonic, followed by the immediate values for the
+/// opcodes that carry them, each after a single space, e.g. `ADD`, `LDI -5` or `SPILL 2 16`
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::LDI(x)
+            | Opcode::LDPC(x)
+            | Opcode::TRAPID(x)
+            | Opcode::LOG(x)
+            | Opcode::ASSERT(x) => {
+                write!(f, "{} {}", self.mnemonic(), x)
+            }
+            Opcode::SPILL(x, y) | Opcode::UNSPILL(x, y) | Opcode::DODOES(x, y) => {
+                write!(f, "{} {} {}", self.mnemonic(), x, y)
+            }
+            _ => f.write_str(self.mnemonic()),
+        }
+    }
+}
+
+// The immediate value at index in the operands of an opcode
+fn parse_immediate<T: FromStr>(operands: &[&str], index: usize) -> Result<T, ParseOpcodeError> {
+    let value = operands
+        .get(index)
+        .ok_or(ParseOpcodeError::MissingImmediate)?;
+    value
+        .parse()
+        .map_err(|_| ParseOpcodeError::InvalidImmediate(value.to_string()))
+}
+
+/// Parses the form produced by Display, any amount of whitespace may separate
+/// the mnemonic and its immediate values
+impl FromStr for Opcode {
+    type Err = ParseOpcodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let mnemonic = parts.next().ok_or(ParseOpcodeError::Empty)?;
+        let operands: Vec<&str> = parts.collect();
+
+        let opcode = match mnemonic {
+            "LDI" => Opcode::LDI(parse_immediate(&operands, 0)?),
+            "LDPC" => Opcode::LDPC(parse_immediate(&operands, 0)?),
+            "TRAPID" => Opcode::TRAPID(parse_immediate(&operands, 0)?),
+            "LOG" => Opcode::LOG(parse_immediate(&operands, 0)?),
+            "ASSERT" => Opcode::ASSERT(parse_immediate(&operands, 0)?),
+            "DODOES" => Opcode::DODOES(
+                parse_immediate(&operands, 0)?,
+                parse_immediate(&operands, 1)?,
+            ),
+            "SPILL" => Opcode::SPILL(
+                parse_immediate(&operands, 0)?,
+                parse_immediate(&operands, 1)?,
+            ),
+            "UNSPILL" => Opcode::UNSPILL(
+                parse_immediate(&operands, 0)?,
+                parse_immediate(&operands, 1)?,
+            ),
+            "JMP" => Opcode::JMP,
+            "JR" => Opcode::JR,
+            "JRZ" => Opcode::JRZ,
+            "JRNZ" => Opcode::JRNZ,
+            "CALL" => Opcode::CALL,
+            "CMPZ" => Opcode::CMPZ,
+            "CMPNZ" => Opcode::CMPNZ,
+            "DROP" => Opcode::DROP,
+            "SWAP" => Opcode::SWAP,
+            "SWAP2" => Opcode::SWAP2,
+            "RET" => Opcode::RET,
+            "ADD" => Opcode::ADD,
+            "SUB" => Opcode::SUB,
+            "MUL" => Opcode::MUL,
+            "DIV" => Opcode::DIV,
+            "NOT" => Opcode::NOT,
+            "DUP" => Opcode::DUP,
+            "DUP2" => Opcode::DUP2,
+            "TRAP" => Opcode::TRAP,
+            "NOP" => Opcode::NOP,
+            "PUSHLP" => Opcode::PUSHLP,
+            "INCLP" => Opcode::INCLP,
+            "ADDLP" => Opcode::ADDLP,
+            "GETLP" => Opcode::GETLP,
+            "GETLP2" => Opcode::GETLP2,
+            "DROPLP" => Opcode::DROPLP,
+            "CMPLOOP" => Opcode::CMPLOOP,
+            "OVER2" => Opcode::OVER2,
+            "GtR" => Opcode::GtR,
+            "RGt" => Opcode::RGt,
+            "RAt" => Opcode::RAt,
+            "GtR2" => Opcode::GtR2,
+            "RGt2" => Opcode::RGt2,
+            "RAt2" => Opcode::RAt2,
+            "AND" => Opcode::AND,
+            "NEWCELLS" => Opcode::NEWCELLS,
+            "MOVETOCELLS" => Opcode::MOVETOCELLS,
+            "MOVEFROMCELLS" => Opcode::MOVEFROMCELLS,
+            "ADDSAT" => Opcode::ADDSAT,
+            "SUBSAT" => Opcode::SUBSAT,
+            "MULSAT" => Opcode::MULSAT,
+            "ISQRT" => Opcode::ISQRT,
+            "GCD" => Opcode::GCD,
+            "IPOW" => Opcode::IPOW,
+            "JRC" => Opcode::JRC,
+            "JRO" => Opcode::JRO,
+            "FMDIVMOD" => Opcode::FMDIVMOD,
+            "FREECELLS" => Opcode::FREECELLS,
+            "HERE" => Opcode::HERE,
+            "ALLOT" => Opcode::ALLOT,
+            "READIN" => Opcode::READIN,
+            "WRITEOUT" => Opcode::WRITEOUT,
+            "NEXTIN" => Opcode::NEXTIN,
+            "WRITEBYTE" => Opcode::WRITEBYTE,
+            "WRITECELL" => Opcode::WRITECELL,
+            "DADD" => Opcode::DADD,
+            "DSUB" => Opcode::DSUB,
+            "DMUL" => Opcode::DMUL,
+            "DDIV" => Opcode::DDIV,
+            "DRESCALE" => Opcode::DRESCALE,
+            _ => return Err(ParseOpcodeError::UnknownMnemonic(mnemonic.to_string())),
+        };
+
+        match operands.get(usize::from(opcode.metadata().immediates)) {
+            Some(operand) => Err(ParseOpcodeError::UnexpectedOperand(operand.to_string())),
+            None => Ok(opcode),
+        }
+    }
+}
+
+/// Error returned when a numeric encoding does not describe a valid Opcode
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeOpcodeError {
+    UnknownCode(u8),
+    MissingImmediate(u8),
+    UnexpectedImmediate(u8),
+}
+
+impl fmt::Display for DecodeOpcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeOpcodeError::UnknownCode(c) => write!(f, "unknown opcode {}", c),
+            DecodeOpcodeError::MissingImmediate(c) => {
+                write!(f, "opcode {} requires an immediate value", c)
+            }
+            DecodeOpcodeError::UnexpectedImmediate(c) => {
+                write!(f, "opcode {} does not take an immediate value", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeOpcodeError {}
+
+/// Number of opcodes defined by the numeric encoding, codes run from 0 to OPCODE_COUNT - 1
+pub const OPCODE_COUNT: u8 = 68;
+
+impl Opcode {
+    /// The stable numeric encoding of the opcode: an opcode number and the
+    /// immediate value for the opcodes that carry one.
+    ///
+    /// Opcode numbers are assigned in declaration order, starting with JMP at 0,
+    /// and are never reused or renumbered; new opcodes are given the next free number.
+    /// SPILL and UNSPILL carry their count in the high 32 bits of the immediate value
+    /// and their address in the low 32 bits, DODOES its data field and behavior the same way.
+    pub fn encode(&self) -> (u8, Option<i64>) {
+        match self {
+            Opcode::JMP => (0, None),
+            Opcode::JR => (1, None),
+            Opcode::JRZ => (2, None),
+            Opcode::JRNZ => (3, None),
+            Opcode::CALL => (4, None),
+            Opcode::CMPZ => (5, None),
+            Opcode::CMPNZ => (6, None),
+            Opcode::LDI(x) => (7, Some(*x)),
+            Opcode::DROP => (8, None),
+            Opcode::SWAP => (9, None),
+            Opcode::SWAP2 => (10, None),
+            Opcode::RET => (11, None),
+            Opcode::ADD => (12, None),
+            Opcode::SUB => (13, None),
+            Opcode::MUL => (14, None),
+            Opcode::DIV => (15, None),
+            Opcode::NOT => (16, None),
+            Opcode::DUP => (17, None),
+            Opcode::DUP2 => (18, None),
+            Opcode::TRAP => (19, None),
+            Opcode::NOP => (20, None),
+            Opcode::PUSHLP => (21, None),
+            Opcode::INCLP => (22, None),
+            Opcode::ADDLP => (23, None),
+            Opcode::GETLP => (24, None),
+            Opcode::GETLP2 => (25, None),
+            Opcode::DROPLP => (26, None),
+            Opcode::CMPLOOP => (27, None),
+            Opcode::OVER2 => (28, None),
+            Opcode::GtR => (29, None),
+            Opcode::RGt => (30, None),
+            Opcode::RAt => (31, None),
+            Opcode::GtR2 => (32, None),
+            Opcode::RGt2 => (33, None),
+            Opcode::RAt2 => (34, None),
+            Opcode::AND => (35, None),
+            Opcode::NEWCELLS => (36, None),
+            Opcode::MOVETOCELLS => (37, None),
+            Opcode::MOVEFROMCELLS => (38, None),
+            Opcode::ADDSAT => (39, None),
+            Opcode::SUBSAT => (40, None),
+            Opcode::MULSAT => (41, None),
+            Opcode::ISQRT => (42, None),
+            Opcode::GCD => (43, None),
+            Opcode::IPOW => (44, None),
+            Opcode::JRC => (45, None),
+            Opcode::JRO => (46, None),
+            Opcode::FMDIVMOD => (47, None),
+            Opcode::FREECELLS => (48, None),
+            Opcode::HERE => (49, None),
+            Opcode::ALLOT => (50, None),
+            Opcode::SPILL(n, address) => (51, Some(pack_immediates(*n, *address))),
+            Opcode::UNSPILL(n, address) => (52, Some(pack_immediates(*n, *address))),
+            Opcode::LDPC(offset) => (53, Some(*offset)),
+            Opcode::DODOES(data, behavior) => (54, Some(pack_immediates(*data, *behavior))),
+            Opcode::TRAPID(trap_id) => (55, Some(*trap_id)),
+            Opcode::READIN => (56, None),
+            Opcode::WRITEOUT => (57, None),
+            Opcode::NEXTIN => (58, None),
+            Opcode::WRITEBYTE => (59, None),
+            Opcode::WRITECELL => (60, None),
+            Opcode::LOG(level) => (61, Some(*level)),
+            Opcode::ASSERT(message_id) => (62, Some(*message_id)),
+            Opcode::DADD => (63, None),
+            Opcode::DSUB => (64, None),
+            Opcode::DMUL => (65, None),
+            Opcode::DDIV => (66, None),
+            Opcode::DRESCALE => (67, None),
+        }
+    }
+}
+
+fn pack_immediates(high: u32, low: u32) -> i64 {
+    ((u64::from(high) << 32) | u64::from(low)) as i64
+}
+
+fn unpack_immediates(immediate: i64) -> (u32, u32) {
+    let immediate = immediate as u64;
+    ((immediate >> 32) as u32, immediate as u32)
+}
+
+impl From<&Opcode> for (u8, Option<i64>) {
+    fn from(opcode: &Opcode) -> Self {
+        opcode.encode()
+    }
+}
+
+impl TryFrom<(u8, Option<i64>)> for Opcode {
+    type Error = DecodeOpcodeError;
+
+    fn try_from(encoded: (u8, Option<i64>)) -> Result<Self, Self::Error> {
+        match encoded {
+            (7, Some(x)) => Ok(Opcode::LDI(x)),
+            (7, None) => Err(DecodeOpcodeError::MissingImmediate(7)),
+            (0, None) => Ok(Opcode::JMP),
+            (1, None) => Ok(Opcode::JR),
+            (2, None) => Ok(Opcode::JRZ),
+            (3, None) => Ok(Opcode::JRNZ),
+            (4, None) => Ok(Opcode::CALL),
+            (5, None) => Ok(Opcode::CMPZ),
+            (6, None) => Ok(Opcode::CMPNZ),
+            (8, None) => Ok(Opcode::DROP),
+            (9, None) => Ok(Opcode::SWAP),
+            (10, None) => Ok(Opcode::SWAP2),
+            (11, None) => Ok(Opcode::RET),
+            (12, None) => Ok(Opcode::ADD),
+            (13, None) => Ok(Opcode::SUB),
+            (14, None) => Ok(Opcode::MUL),
+            (15, None) => Ok(Opcode::DIV),
+            (16, None) => Ok(Opcode::NOT),
+            (17, None) => Ok(Opcode::DUP),
+            (18, None) => Ok(Opcode::DUP2),
+            (19, None) => Ok(Opcode::TRAP),
+            (20, None) => Ok(Opcode::NOP),
+            (21, None) => Ok(Opcode::PUSHLP),
+            (22, None) => Ok(Opcode::INCLP),
+            (23, None) => Ok(Opcode::ADDLP),
+            (24, None) => Ok(Opcode::GETLP),
+            (25, None) => Ok(Opcode::GETLP2),
+            (26, None) => Ok(Opcode::DROPLP),
+            (27, None) => Ok(Opcode::CMPLOOP),
+            (28, None) => Ok(Opcode::OVER2),
+            (29, None) => Ok(Opcode::GtR),
+            (30, None) => Ok(Opcode::RGt),
+            (31, None) => Ok(Opcode::RAt),
+            (32, None) => Ok(Opcode::GtR2),
+            (33, None) => Ok(Opcode::RGt2),
+            (34, None) => Ok(Opcode::RAt2),
+            (35, None) => Ok(Opcode::AND),
+            (36, None) => Ok(Opcode::NEWCELLS),
+            (37, None) => Ok(Opcode::MOVETOCELLS),
+            (38, None) => Ok(Opcode::MOVEFROMCELLS),
+            (39, None) => Ok(Opcode::ADDSAT),
+            (40, None) => Ok(Opcode::SUBSAT),
+            (41, None) => Ok(Opcode::MULSAT),
+            (42, None) => Ok(Opcode::ISQRT),
+            (43, None) => Ok(Opcode::GCD),
+            (44, None) => Ok(Opcode::IPOW),
+            (45, None) => Ok(Opcode::JRC),
+            (46, None) => Ok(Opcode::JRO),
+            (47, None) => Ok(Opcode::FMDIVMOD),
+            (48, None) => Ok(Opcode::FREECELLS),
+            (49, None) => Ok(Opcode::HERE),
+            (50, None) => Ok(Opcode::ALLOT),
+            (51, Some(x)) => {
+                let (n, address) = unpack_immediates(x);
+                Ok(Opcode::SPILL(n, address))
+            }
+            (52, Some(x)) => {
+                let (n, address) = unpack_immediates(x);
+                Ok(Opcode::UNSPILL(n, address))
+            }
+            (53, Some(x)) => Ok(Opcode::LDPC(x)),
+            (54, Some(x)) => {
+                let (data, behavior) = unpack_immediates(x);
+                Ok(Opcode::DODOES(data, behavior))
+            }
+            (55, Some(x)) => Ok(Opcode::TRAPID(x)),
+            (56, None) => Ok(Opcode::READIN),
+            (57, None) => Ok(Opcode::WRITEOUT),
+            (58, None) => Ok(Opcode::NEXTIN),
+            (59, None) => Ok(Opcode::WRITEBYTE),
+            (60, None) => Ok(Opcode::WRITECELL),
+            (61, Some(x)) => Ok(Opcode::LOG(x)),
+            (62, Some(x)) => Ok(Opcode::ASSERT(x)),
+            (63, None) => Ok(Opcode::DADD),
+            (64, None) => Ok(Opcode::DSUB),
+            (65, None) => Ok(Opcode::DMUL),
+            (66, None) => Ok(Opcode::DDIV),
+            (67, None) => Ok(Opcode::DRESCALE),
+            (code @ (51..=55 | 61 | 62), None) => Err(DecodeOpcodeError::MissingImmediate(code)),
+            (code, Some(_)) if code < OPCODE_COUNT => {
+                Err(DecodeOpcodeError::UnexpectedImmediate(code))
+            }
+            (code, _) => Err(DecodeOpcodeError::UnknownCode(code)),
+        }
+    }
+}
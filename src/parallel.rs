@@ -0,0 +1,225 @@
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rayon::prelude::*;
+
+use super::{
+    Engine, EngineError, ErrorKind, ExitStatus, GasLimit, MachineId, StackMachine,
+    StackMachineState,
+};
+
+/// Gas a parallel run takes from the engine's budget at a time
+const GAS_SLICE: u64 = 1024;
+
+/// The outcome of one of the runs of `Engine::execute_all_parallel`
+#[derive(Debug)]
+pub struct EngineReport {
+    pub machine: MachineId,
+    pub result: Result<ExitStatus, EngineError>,
+    /// Gas charged to the engine for the run
+    pub gas_used: u64,
+}
+
+// Take up to amount gas from what is left, returning how much was taken
+fn reserve(gas_left: &AtomicU64, amount: u64) -> u64 {
+    match gas_left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+        Some(left - left.min(amount))
+    }) {
+        Ok(left) => left.min(amount),
+        Err(_) => 0,
+    }
+}
+
+// Run a queued machine with the gas reserved for it, taking more from the budget a slice
+// at a time. The run pauses with a soft limit at the end of each slice to take another, and
+// gives back what it reserved but did not use when it stops
+fn run_sliced(
+    sm: &mut StackMachine,
+    starting_point: usize,
+    gas_limit: GasLimit,
+    gas_left: &AtomicU64,
+    mut reserved: u64,
+) -> Result<ExitStatus, EngineError> {
+    let (soft, hard) = gas_limit.soft_and_hard();
+    let mut started = false;
+    let result = loop {
+        // Pause on reaching the end of the slice, or fail there if the budget has no more
+        let slice = GasLimit::Soft {
+            soft: soft.unwrap_or(u64::MAX).min(reserved.saturating_sub(1)),
+            hard: hard.min(reserved),
+        };
+        let result = if started {
+            sm.resume(slice)
+        } else {
+            started = true;
+            sm.execute(starting_point, slice)
+        };
+        let gas_used = sm.st.gas_used();
+        match result {
+            Ok(ExitStatus::Yielded) if soft.is_some_and(|soft| gas_used > soft) => {
+                break Ok(ExitStatus::Yielded)
+            }
+            Ok(ExitStatus::Yielded) if gas_used >= reserved => {
+                let more = reserve(gas_left, GAS_SLICE);
+                if more == 0 {
+                    break Err(EngineError::GasBudgetExhausted);
+                }
+                reserved += more;
+            }
+            Err(e) if e.kind() == ErrorKind::RanOutOfGas && gas_used <= hard => {
+                break Err(EngineError::GasBudgetExhausted)
+            }
+            result => break result.map_err(EngineError::Machine),
+        }
+    };
+    gas_left.fetch_add(reserved.saturating_sub(sm.st.gas_used()), Ordering::SeqCst);
+    result
+}
+
+// A queued run, with the machine's own cell limit when the budget lowered it
+struct QueuedRun {
+    id: MachineId,
+    st: StackMachineState,
+    starting_point: usize,
+    gas_limit: GasLimit,
+    own_cell_limit: Option<Option<usize>>,
+}
+
+// Run a queued machine on a worker thread, setting up a machine around its state, and
+// return the state, the result and the gas it used
+fn run_queued(
+    run: QueuedRun,
+    gas_left: Option<&AtomicU64>,
+    setup: &(impl Fn(&mut StackMachine) + Sync),
+) -> (StackMachineState, Result<ExitStatus, EngineError>, u64) {
+    let mut sm = StackMachine {
+        st: run.st,
+        ..StackMachine::default()
+    };
+    setup(&mut sm);
+    let result = match gas_left {
+        Some(gas_left) => {
+            // One more than hard, so that running out of the machine's own gas is its own error
+            let (_, hard) = run.gas_limit.soft_and_hard();
+            match reserve(gas_left, GAS_SLICE.min(hard.saturating_add(1))) {
+                0 => return (sm.st, Err(EngineError::GasBudgetExhausted), 0),
+                reserved => run_sliced(
+                    &mut sm,
+                    run.starting_point,
+                    run.gas_limit,
+                    gas_left,
+                    reserved,
+                ),
+            }
+        }
+        None => sm
+            .execute(run.starting_point, run.gas_limit)
+            .map_err(EngineError::Machine),
+    };
+    let result = match result {
+        Err(EngineError::Machine(e))
+            if run.own_cell_limit.is_some() && e.kind() == ErrorKind::CellLimitExceeded =>
+        {
+            Err(EngineError::MemoryBudgetExceeded)
+        }
+        result => result,
+    };
+    if let Some(cell_limit) = run.own_cell_limit {
+        sm.st.set_cell_limit(cell_limit);
+    }
+    let gas_used = sm.st.gas_used();
+    (sm.st, result, gas_used)
+}
+
+impl Engine {
+    /// Queue a run of a machine from starting_point, for `execute_all_parallel`, replacing
+    /// a run of the machine already queued
+    pub fn queue(
+        &mut self,
+        id: MachineId,
+        starting_point: usize,
+        gas_limit: GasLimit,
+    ) -> Result<(), EngineError> {
+        if self.machine(id).is_none() {
+            return Err(EngineError::UnknownMachine(id));
+        }
+        self.queued.retain(|(queued, ..)| *queued != id);
+        self.queued.push((id, starting_point, gas_limit));
+        Ok(())
+    }
+
+    /// Run every queued machine across rayon's thread pool, returning a report of each run
+    /// in the order they were queued, such as when applying a script to every record of a
+    /// file.
+    ///
+    /// Trap handlers, event sinks and hooks cannot move between threads, so each run has a
+    /// fresh machine around the queued machine's state, which setup is called with first to
+    /// add what the run needs. The runs take gas from the engine's budget a slice at a time,
+    /// and the memory left is split evenly between them as cell limits. Unlike
+    /// `Engine::execute`, the stacks are neither capped nor checked once the runs stop
+    pub fn execute_all_parallel(
+        &mut self,
+        setup: impl Fn(&mut StackMachine) + Sync,
+    ) -> Vec<EngineReport> {
+        let queued = std::mem::take(&mut self.queued);
+        let memory_left = self
+            .limits()
+            .memory
+            .map(|limit| limit.saturating_sub(self.memory_used()));
+        let gas_left = self.gas_remaining().map(AtomicU64::new);
+
+        let mut runs = Vec::with_capacity(queued.len());
+        let mut reports = Vec::with_capacity(queued.len());
+        for &(id, starting_point, gas_limit) in &queued {
+            match self.machine_mut(id) {
+                Some(sm) => {
+                    let mut st = std::mem::take(&mut sm.st);
+                    let mut own_cell_limit = None;
+                    if let Some(left) = memory_left {
+                        let cells = st.cells().len() + left / queued.len() / size_of::<i64>();
+                        if st.cell_limit().is_none_or(|limit| cells < limit) {
+                            own_cell_limit = Some(st.cell_limit());
+                            st.set_cell_limit(Some(cells));
+                        }
+                    }
+                    runs.push(QueuedRun {
+                        id,
+                        st,
+                        starting_point,
+                        gas_limit,
+                        own_cell_limit,
+                    });
+                }
+                _ => reports.push(EngineReport {
+                    machine: id,
+                    result: Err(EngineError::UnknownMachine(id)),
+                    gas_used: 0,
+                }),
+            }
+        }
+
+        let finished: Vec<_> = runs
+            .into_par_iter()
+            .map(|run| {
+                let id = run.id;
+                let (st, result, gas_used) = run_queued(run, gas_left.as_ref(), &setup);
+                (id, st, result, gas_used)
+            })
+            .collect();
+
+        for (id, st, result, gas_used) in finished {
+            self.charge_gas(gas_used);
+            if let Some(sm) = self.machine_mut(id) {
+                sm.st = st;
+            }
+            reports.push(EngineReport {
+                machine: id,
+                result,
+                gas_used,
+            });
+        }
+        let order = |id: MachineId| queued.iter().position(|(queued, ..)| *queued == id);
+        reports.sort_by_key(|report| order(report.machine));
+        reports
+    }
+}
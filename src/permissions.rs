@@ -0,0 +1,197 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use super::{ErrorKind, StackMachineError, StackMachineState};
+
+/// The trap ids a machine may raise. A TRAP or TRAPID of any other id fails with a
+/// PermissionDenied error before any handler sees it, so third party scripts can be run
+/// with only the traps they need.
+///
+/// Quotas limit how much a machine can use of a category of traps, or of a resource that
+/// handlers charge for with `StackMachineState::charge`, such as bytes written. Going over
+/// one gives a QuotaExceeded error. Usage builds up over every run until `reset_usage`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrapPermissions {
+    allowed: Vec<RangeInclusive<i64>>,
+    quotas: Vec<Quota>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Quota {
+    name: &'static str,
+    /// The traps charged one each time they are raised, None for a resource
+    trap_ids: Option<RangeInclusive<i64>>,
+    limit: u64,
+    used: u64,
+}
+
+impl Quota {
+    fn charge(&mut self, amount: u64) -> Result<(), StackMachineError> {
+        match self.used.checked_add(amount) {
+            Some(used) if used <= self.limit => {
+                self.used = used;
+                Ok(())
+            }
+            _ => Err(ErrorKind::QuotaExceeded { quota: self.name }.into()),
+        }
+    }
+}
+
+impl TrapPermissions {
+    /// Permissions allowing no traps at all
+    pub fn new() -> Self {
+        TrapPermissions::default()
+    }
+
+    pub fn allow(mut self, trap_id: i64) -> Self {
+        self.allowed.push(trap_id..=trap_id);
+        self
+    }
+
+    pub fn allow_range(mut self, trap_ids: RangeInclusive<i64>) -> Self {
+        self.allowed.push(trap_ids);
+        self
+    }
+
+    /// Allow the traps in a category to be raised at most limit times between them
+    pub fn limit_traps(
+        mut self,
+        name: &'static str,
+        trap_ids: RangeInclusive<i64>,
+        limit: u64,
+    ) -> Self {
+        self.quotas.push(Quota {
+            name,
+            trap_ids: Some(trap_ids),
+            limit,
+            used: 0,
+        });
+        self
+    }
+
+    /// Allow handlers to charge at most limit of a resource
+    pub fn limit_resource(mut self, name: &'static str, limit: u64) -> Self {
+        self.quotas.push(Quota {
+            name,
+            trap_ids: None,
+            limit,
+            used: 0,
+        });
+        self
+    }
+
+    pub fn permits(&self, trap_id: i64) -> bool {
+        self.allowed.iter().any(|range| range.contains(&trap_id))
+    }
+
+    /// How much of a quota has been used, None if there is no quota with that name
+    pub fn usage(&self, name: &str) -> Option<u64> {
+        self.quotas
+            .iter()
+            .find(|quota| quota.name == name)
+            .map(|quota| quota.used)
+    }
+
+    pub fn reset_usage(&mut self) {
+        for quota in &mut self.quotas {
+            quota.used = 0;
+        }
+    }
+
+    pub(crate) fn check(&mut self, trap_id: i64) -> Result<(), StackMachineError> {
+        if !self.permits(trap_id) {
+            return Err(ErrorKind::PermissionDenied { trap_id }.into());
+        }
+        for quota in &mut self.quotas {
+            if quota
+                .trap_ids
+                .as_ref()
+                .is_some_and(|trap_ids| trap_ids.contains(&trap_id))
+            {
+                quota.charge(1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long the handlers of a trap may take and how much gas they may use, such as by
+/// calling back into the program, each time it is raised. Handlers cannot be interrupted,
+/// so one that goes over is caught when it returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrapBudget {
+    pub time: Option<Duration>,
+    pub gas: Option<u64>,
+}
+
+impl TrapBudget {
+    pub fn time(time: Duration) -> Self {
+        TrapBudget {
+            time: Some(time),
+            gas: None,
+        }
+    }
+
+    pub fn gas(gas: u64) -> Self {
+        TrapBudget {
+            time: None,
+            gas: Some(gas),
+        }
+    }
+
+    pub(crate) fn check(
+        &self,
+        trap_id: i64,
+        elapsed: Duration,
+        gas: u64,
+    ) -> Result<(), StackMachineError> {
+        if self.time.is_some_and(|time| elapsed > time) || self.gas.is_some_and(|limit| gas > limit)
+        {
+            return Err(ErrorKind::TrapBudgetExceeded { trap_id }.into());
+        }
+        Ok(())
+    }
+}
+
+impl StackMachineState {
+    /// Limit the handlers of a trap, a handler that goes over the budget fails the run
+    /// with TrapBudgetExceeded, even if it handled the trap. A time budget is not allowed
+    /// under a deterministic profile
+    pub fn set_trap_budget(&mut self, trap_id: i64, budget: TrapBudget) {
+        self.trap_budgets.insert(trap_id, budget);
+    }
+
+    pub fn remove_trap_budget(&mut self, trap_id: i64) -> Option<TrapBudget> {
+        self.trap_budgets.remove(&trap_id)
+    }
+
+    pub fn trap_budget(&self, trap_id: i64) -> Option<TrapBudget> {
+        self.trap_budgets.get(&trap_id).copied()
+    }
+
+    /// Restrict the traps that can be raised, None (the default) allows every trap
+    pub fn set_trap_permissions(&mut self, permissions: Option<TrapPermissions>) {
+        self.trap_permissions = permissions;
+    }
+
+    pub fn trap_permissions(&self) -> Option<&TrapPermissions> {
+        self.trap_permissions.as_ref()
+    }
+
+    pub fn trap_permissions_mut(&mut self) -> Option<&mut TrapPermissions> {
+        self.trap_permissions.as_mut()
+    }
+
+    /// Charge amount to the resource quota called name, for trap handlers. Does nothing when
+    /// there is no such quota
+    pub fn charge(&mut self, name: &str, amount: u64) -> Result<(), StackMachineError> {
+        let quotas = self
+            .trap_permissions
+            .iter_mut()
+            .flat_map(|permissions| permissions.quotas.iter_mut());
+        for quota in quotas.filter(|quota| quota.name == name) {
+            quota.charge(amount)?;
+        }
+        Ok(())
+    }
+}
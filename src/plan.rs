@@ -0,0 +1,72 @@
+use std::fmt;
+
+use super::{ExitStatus, GasLimit, StackMachine, StackMachineError};
+
+/// How one entry of a plan run by `StackMachine::run_plan` finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlanStep {
+    pub entry: usize,
+    pub status: ExitStatus,
+    /// Gas used by this entry alone
+    pub gas_used: u64,
+}
+
+/// An entry of a plan that failed, index is its position in the plan
+#[derive(Debug)]
+pub struct PlanError {
+    pub index: usize,
+    pub entry: usize,
+    /// The entries that finished before it
+    pub completed: Vec<PlanStep>,
+    pub error: StackMachineError,
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "plan entry {} at {} failed: {}",
+            self.index, self.entry, self.error
+        )
+    }
+}
+
+impl std::error::Error for PlanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl StackMachine {
+    /// Run each entry point in turn with its own gas limit, such as the init, main and
+    /// teardown phases of a program, keeping the stacks and cells from one to the next.
+    ///
+    /// The plan stops at the first entry that fails, or that pauses at a breakpoint or
+    /// READIN, which is then the last step returned
+    pub fn run_plan(&mut self, plan: &[(usize, GasLimit)]) -> Result<Vec<PlanStep>, PlanError> {
+        let mut steps = Vec::with_capacity(plan.len());
+        for (index, &(entry, gas_limit)) in plan.iter().enumerate() {
+            match self.execute(entry, gas_limit) {
+                Ok(status) => {
+                    steps.push(PlanStep {
+                        entry,
+                        status,
+                        gas_used: self.st.gas_used(),
+                    });
+                    if self.st.paused {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    return Err(PlanError {
+                        index,
+                        entry,
+                        completed: steps,
+                        error,
+                    })
+                }
+            }
+        }
+        Ok(steps)
+    }
+}
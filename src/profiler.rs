@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::{CallFrame, SymbolTable};
+
+/// Gas attributed to one called address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub address: usize,
+    pub calls: u64,
+    /// Gas used by the function and everything it called
+    pub inclusive_gas: u64,
+    /// Gas used by the function's own instructions
+    pub exclusive_gas: u64,
+}
+
+/// Number of calls from one address to another
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallEdge {
+    pub caller: usize,
+    pub callee: usize,
+    pub calls: u64,
+}
+
+/// Call graph profile of a run, the entry point counts as a function called once
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallProfile {
+    pub functions: Vec<FunctionProfile>,
+    pub edges: Vec<CallEdge>,
+}
+
+impl CallProfile {
+    pub fn function(&self, address: usize) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|f| f.address == address)
+    }
+
+    /// A table of functions sorted by inclusive gas, followed by the call graph edges, each
+    /// function named as its frame is in a `CallChain`
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let name = |address: usize| CallFrame::new(address, symbols).to_string();
+        let mut functions: Vec<&FunctionProfile> = self.functions.iter().collect();
+        functions.sort_by(|a, b| {
+            b.inclusive_gas
+                .cmp(&a.inclusive_gas)
+                .then(a.address.cmp(&b.address))
+        });
+
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{:<24} {:>10} {:>12} {:>12}",
+            "function", "calls", "inclusive", "exclusive"
+        );
+        for f in functions {
+            let _ = writeln!(
+                report,
+                "{:<24} {:>10} {:>12} {:>12}",
+                name(f.address),
+                f.calls,
+                f.inclusive_gas,
+                f.exclusive_gas
+            );
+        }
+        let _ = writeln!(report, "call graph:");
+        for edge in &self.edges {
+            let _ = writeln!(
+                report,
+                "  {} -> {} ({} calls)",
+                name(edge.caller),
+                name(edge.callee),
+                edge.calls
+            );
+        }
+        report
+    }
+}
+
+#[derive(Clone)]
+struct Frame {
+    address: usize,
+    gas_at_entry: u64,
+    child_gas: u64,
+}
+
+/// Tracks call frames while running, see `StackMachine::enable_profiler`
+#[derive(Clone, Default)]
+pub(crate) struct CallGraphProfiler {
+    frames: Vec<Frame>,
+    functions: BTreeMap<usize, FunctionProfile>,
+    edges: BTreeMap<(usize, usize), u64>,
+}
+
+impl CallGraphProfiler {
+    pub(crate) fn start(&mut self, address: usize, gas_used: u64) {
+        *self = CallGraphProfiler::default();
+        self.enter(address, gas_used);
+    }
+
+    pub(crate) fn enter(&mut self, address: usize, gas_used: u64) {
+        if let Some(caller) = self.frames.last() {
+            *self.edges.entry((caller.address, address)).or_insert(0) += 1;
+        }
+        self.functions
+            .entry(address)
+            .or_insert_with(|| FunctionProfile {
+                address,
+                ..FunctionProfile::default()
+            })
+            .calls += 1;
+        self.frames.push(Frame {
+            address,
+            gas_at_entry: gas_used,
+            child_gas: 0,
+        });
+    }
+
+    pub(crate) fn leave(&mut self, gas_used: u64) {
+        if let Some(frame) = self.frames.pop() {
+            let inclusive = gas_used - frame.gas_at_entry;
+            let function = self
+                .functions
+                .get_mut(&frame.address)
+                .expect("every frame has a function entry");
+            function.inclusive_gas += inclusive;
+            function.exclusive_gas += inclusive - frame.child_gas;
+            if let Some(parent) = self.frames.last_mut() {
+                parent.child_gas += inclusive;
+            }
+        }
+    }
+
+    /// Close every frame that is still open, when a run ends
+    pub(crate) fn finish(&mut self, gas_used: u64) {
+        while !self.frames.is_empty() {
+            self.leave(gas_used);
+        }
+    }
+
+    pub(crate) fn export(&self) -> CallProfile {
+        CallProfile {
+            functions: self.functions.values().cloned().collect(),
+            edges: self
+                .edges
+                .iter()
+                .map(|((caller, callee), calls)| CallEdge {
+                    caller: *caller,
+                    callee: *callee,
+                    calls: *calls,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Gas used by the calls to one CALL target, including everything they called
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallTargetGas {
+    pub calls: u64,
+    pub gas: u64,
+}
+
+/// Records the gas used at entry alongside each return stack entry, see
+/// `StackMachine::enable_call_gas`
+#[derive(Clone, Default)]
+pub(crate) struct CallGasTracker {
+    entries: Vec<(usize, u64)>,
+    totals: BTreeMap<usize, CallTargetGas>,
+}
+
+impl CallGasTracker {
+    pub(crate) fn call(&mut self, target: usize, gas_used: u64) {
+        self.entries.push((target, gas_used));
+    }
+
+    pub(crate) fn ret(&mut self, gas_used: u64) {
+        if let Some((target, gas_at_entry)) = self.entries.pop() {
+            let total = self.totals.entry(target).or_default();
+            total.calls += 1;
+            total.gas += gas_used - gas_at_entry;
+        }
+    }
+
+    pub(crate) fn finish(&mut self, gas_used: u64) {
+        while !self.entries.is_empty() {
+            self.ret(gas_used);
+        }
+    }
+
+    pub(crate) fn totals(&self) -> &BTreeMap<usize, CallTargetGas> {
+        &self.totals
+    }
+}
@@ -0,0 +1,53 @@
+use super::{
+    ExitStatus, GasLimit, Metered, StackMachine, StackMachineError, StackMachineState,
+    TrapHandlers, Unmetered,
+};
+
+/// Run the subroutine at pc from inside a trap handler, on the same stacks and cells, and
+/// return to the handler when it returns, so that host functions can take callbacks.
+///
+/// The return stack is fenced at its current depth, so the subroutine returns to the handler
+/// with a RET that would otherwise pop an address pushed before the trap, and anything it
+/// leaves above the fence is dropped. The gas limit applies to the subroutine alone, but
+/// its gas counts towards the run that raised the trap, and it cannot pause, so only the
+/// hard part of a soft limit applies. The subroutine runs without trap
+/// handlers or breakpoints, so a trap it raises gives an UnhandledTrap error
+pub fn call_within_trap(
+    st: &mut StackMachineState,
+    pc: usize,
+    gas_limit: GasLimit,
+) -> Result<ExitStatus, StackMachineError> {
+    let trap_pc = st.pc;
+    let fence = st.return_stack.len();
+    let outer_fence = std::mem::replace(&mut st.return_fence, fence);
+    let breakpoints = std::mem::take(&mut st.breakpoints);
+    st.pc = pc;
+
+    let mut machine = StackMachine {
+        st: std::mem::take(st),
+        trap_handlers: TrapHandlers::default(),
+        event_sinks: Vec::new(),
+        input_stream: None,
+        writer: None,
+        log_sink: None,
+        on_start: None,
+        on_exit: None,
+    };
+    let result = match gas_limit {
+        GasLimit::Unlimited => machine.run(Unmetered),
+        GasLimit::Limited(limit) | GasLimit::Soft { hard: limit, .. } => {
+            machine.run(Metered(machine.st.gas_used.saturating_add(limit)))
+        }
+    }
+    .map_err(|e| {
+        let pc = machine.st.pc;
+        e.at(pc, machine.st.opcodes().get(pc))
+    });
+
+    *st = machine.st;
+    st.return_stack.truncate(fence);
+    st.return_fence = outer_fence;
+    st.breakpoints = breakpoints;
+    st.pc = trap_pc;
+    result
+}
@@ -0,0 +1,74 @@
+use std::convert::TryFrom;
+
+use super::{ErrorKind, ExitStatus, GasLimit, StackKind, StackMachine, StackMachineError};
+
+/// What a run left for the host, see `StackMachine::execute_with_report`.
+///
+/// By convention a program leaves its results on top of the number stack, the last result
+/// on top, so the host reads them with `return_values` or converts the top one with the
+/// `return_` helpers instead of popping them by hand. A program that ends like a process
+/// leaves its exit code on top, 0 for success, for `exit_code`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionReport {
+    pub status: ExitStatus,
+    pub gas_used: u64,
+    /// The number stack the run left, TOS last
+    pub number_stack: Vec<i64>,
+}
+
+impl ExecutionReport {
+    /// The top n values of the number stack, in the order they were pushed, or a
+    /// StackUnderflow error if the run left fewer
+    pub fn return_values(&self, n: usize) -> Result<&[i64], StackMachineError> {
+        let first = self
+            .number_stack
+            .len()
+            .checked_sub(n)
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        Ok(&self.number_stack[first..])
+    }
+
+    /// The value on top of the number stack
+    pub fn return_value(&self) -> Result<i64, StackMachineError> {
+        Ok(self.return_values(1)?[0])
+    }
+
+    /// The value on top as a flag, where any value but 0 is true as JRNZ takes it
+    pub fn return_bool(&self) -> Result<bool, StackMachineError> {
+        Ok(self.return_value()? != 0)
+    }
+
+    /// The value on top as a u32, or a NumericOverflow error if it is out of range
+    pub fn return_u32(&self) -> Result<u32, StackMachineError> {
+        Ok(u32::try_from(self.return_value()?)?)
+    }
+
+    /// The value on top as an exit code, or a NumericOverflow error if it is out of range
+    pub fn exit_code(&self) -> Result<i32, StackMachineError> {
+        Ok(i32::try_from(self.return_value()?)?)
+    }
+
+    /// True when the exit code on top is 0
+    pub fn succeeded(&self) -> bool {
+        matches!(self.exit_code(), Ok(0))
+    }
+}
+
+impl StackMachine {
+    /// Run with the number stack set to initial_number_stack, as `execute_with_stack`,
+    /// reporting why the run stopped and the gas it used along with the number stack
+    pub fn execute_with_report(
+        &mut self,
+        starting_point: usize,
+        initial_number_stack: Vec<i64>,
+        gas_limit: GasLimit,
+    ) -> Result<ExecutionReport, StackMachineError> {
+        self.st.set_number_stack(initial_number_stack);
+        let status = self.execute(starting_point, gas_limit)?;
+        Ok(ExecutionReport {
+            status,
+            gas_used: self.st.gas_used(),
+            number_stack: self.st.take_number_stack(),
+        })
+    }
+}
@@ -0,0 +1,115 @@
+use std::convert::TryFrom;
+
+use super::{
+    ErrorKind, GasLimit, HandleTrap, StackKind, StackMachine, StackMachineError, StackMachineState,
+    TrapHandled,
+};
+
+/// Limits for the child machines a `SandboxTrap` spawns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxLimits {
+    pub gas_limit: u64,
+    pub step_limit: u64,
+    pub cell_limit: usize,
+    pub max_inputs: usize,
+    pub max_results: usize,
+    /// How many more levels of sandboxes a child can spawn, 0 for none
+    pub max_depth: u32,
+}
+
+/// A trap that runs a subroutine of the program in a child machine with its own stacks and
+/// cells and stricter limits, so that a script can run untrusted sub-scripts.
+///
+/// The program pushes the inputs, their count, the address of the subroutine and the number
+/// of results it wants, then raises the trap. The child starts with the inputs on its number
+/// stack, and when it returns without an error and with enough values the last ones are
+/// pushed as the results followed by true (-1), otherwise only false (0) is pushed.
+///
+/// ( inputs... n_inputs address n_results -- results... true | false )
+///
+/// The gas the child uses counts towards the run that raised the trap, so the parent's gas
+/// limit and trap budgets cover it.
+///
+/// A child can spawn sandboxes of its own with the same trap id, under the same limits,
+/// until max_depth is used up. Children have the trap permissions of their parent, and
+/// share its quotas
+pub struct SandboxTrap {
+    trap_id: i64,
+    limits: SandboxLimits,
+}
+
+impl SandboxTrap {
+    pub fn new(trap_id: i64, limits: SandboxLimits) -> Self {
+        SandboxTrap { trap_id, limits }
+    }
+
+    // The results, None if the child failed or asked for too much
+    fn run_child(
+        &self,
+        st: &mut StackMachineState,
+        inputs: Vec<i64>,
+        address: usize,
+        n_results: usize,
+    ) -> Option<Vec<i64>> {
+        if inputs.len() > self.limits.max_inputs || n_results > self.limits.max_results {
+            return None;
+        }
+        let mut child = StackMachine::default();
+        child.st.load_shared_program(st.share_program());
+        child.st.word_size = st.word_size;
+        child.st.rounding_mode = st.rounding_mode;
+        child.st.set_cell_limit(Some(self.limits.cell_limit));
+        child.st.set_step_limit(Some(self.limits.step_limit));
+        child.st.trap_permissions = st.trap_permissions.clone();
+        if let Some(max_depth) = self.limits.max_depth.checked_sub(1) {
+            let limits = SandboxLimits {
+                max_depth,
+                ..self.limits
+            };
+            child
+                .trap_handlers
+                .register(Box::new(SandboxTrap::new(self.trap_id, limits)));
+        }
+        let results =
+            child.execute_with_stack(address, inputs, GasLimit::Limited(self.limits.gas_limit));
+        // Quotas and gas used by the child count against the parent
+        st.trap_permissions = child.st.trap_permissions.take();
+        st.gas_used = st.gas_used.saturating_add(child.st.gas_used());
+        let mut results = results.ok()?;
+        let first = results.len().checked_sub(n_results)?;
+        Some(results.split_off(first))
+    }
+}
+
+impl HandleTrap for SandboxTrap {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        if trap_id != self.trap_id {
+            return Ok(TrapHandled::NotHandled);
+        }
+        let [n_inputs, address, n_results] = st.pop_n()?;
+        let n_inputs = usize::try_from(n_inputs)?;
+        let address = usize::try_from(address)?;
+        let n_results = usize::try_from(n_results)?;
+        let first = st
+            .number_stack()
+            .len()
+            .checked_sub(n_inputs)
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        let inputs = st.number_stack_mut().split_off(first);
+
+        match self.run_child(st, inputs, address, n_results) {
+            Some(results) => {
+                for result in results {
+                    st.push_number(result);
+                }
+                st.push_number(-1);
+            }
+            None => st.push_number(0),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
@@ -0,0 +1,119 @@
+use super::image::Reader;
+use super::{Dictionary, ImageError, StackMachine, StringTable};
+
+/// The first bytes of every encoded session
+pub const SESSION_MAGIC: [u8; 4] = *b"SSPS";
+
+/// The version of the session encoding written by `Session::to_bytes`
+pub const SESSION_VERSION: u8 = 1;
+
+/// What an interactive environment needs to resume a user's workspace, the cells, the
+/// dictionary and the interned strings, without the program or the stacks of a run
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Session {
+    pub cells: Vec<i64>,
+    pub dictionary: Option<Dictionary>,
+    pub strings: StringTable,
+}
+
+impl Session {
+    /// Encode the session, the same on every architecture.
+    ///
+    /// The layout is `SESSION_MAGIC`, the `SESSION_VERSION` byte, the number of cells and
+    /// the cells, a byte that is 1 when there is a dictionary followed by the number of
+    /// words and each word as its name and entry address, then the number of strings and
+    /// the strings, oldest first. Strings are their length in bytes then their UTF-8.
+    /// Counts and addresses are u64 and cells i64, all little-endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = SESSION_MAGIC.to_vec();
+        bytes.push(SESSION_VERSION);
+        bytes.extend_from_slice(&(self.cells.len() as u64).to_le_bytes());
+        for cell in &self.cells {
+            bytes.extend_from_slice(&cell.to_le_bytes());
+        }
+        match &self.dictionary {
+            Some(dictionary) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(dictionary.len() as u64).to_le_bytes());
+                for (name, address) in dictionary.iter() {
+                    write_string(&mut bytes, name);
+                    bytes.extend_from_slice(&(address as u64).to_le_bytes());
+                }
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&(self.strings.len() as u64).to_le_bytes());
+        for (_, string) in self.strings.iter() {
+            write_string(&mut bytes, string);
+        }
+        bytes
+    }
+
+    /// Decode a session written by `to_bytes`, sessions of any other version are refused
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut reader = Reader { bytes };
+        if reader.take(SESSION_MAGIC.len())? != SESSION_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let version = reader.take(1)?[0];
+        if version != SESSION_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let mut session = Session::default();
+        for _ in 0..reader.usize()? {
+            session.cells.push(reader.i64()?);
+        }
+        if reader.take(1)?[0] != 0 {
+            let mut dictionary = Dictionary::new();
+            for _ in 0..reader.usize()? {
+                let name = read_string(&mut reader)?;
+                dictionary.create(&name, reader.usize()?);
+            }
+            session.dictionary = Some(dictionary);
+        }
+        for _ in 0..reader.usize()? {
+            let string = read_string(&mut reader)?;
+            session.strings.intern(&string);
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(ImageError::TrailingBytes);
+        }
+        Ok(session)
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(&(string.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn read_string(reader: &mut Reader<'_>) -> Result<String, ImageError> {
+    let length = reader.usize()?;
+    let bytes = reader.take(length)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| ImageError::InvalidString)
+}
+
+impl StackMachine {
+    /// The cells, dictionary and interned strings
+    pub fn save_session(&self) -> Session {
+        Session {
+            cells: self.st.cells.clone(),
+            dictionary: self.st.dictionary.clone(),
+            strings: self.st.strings.clone(),
+        }
+    }
+
+    /// Replace the cells, dictionary and interned strings with those of a session, leaving
+    /// the program and the stacks as they are. With the cell allocator enabled the cells
+    /// become a single allocation
+    pub fn restore_session(&mut self, session: Session) {
+        self.st.cells = session.cells;
+        if let Some(allocator) = self.st.allocator.as_mut() {
+            allocator.reset(&self.st.cells);
+        }
+        self.st.dictionary = session.dictionary;
+        self.st.strings = session.strings;
+    }
+}
@@ -0,0 +1,152 @@
+//! Kernels for the bulk cell operations, using SIMD instructions when the `simd` feature is
+//! enabled on x86_64 and plain loops everywhere else. Every kernel wraps on overflow, and
+//! gives the same results whichever way it runs.
+
+/// a + b, element by element, over the shorter of the two
+pub(crate) fn add(a: &[i64], b: &[i64]) -> Vec<i64> {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        x86::zip(a, b, x86::Op::Add)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar_zip(a, b, i64::wrapping_add)
+    }
+}
+
+/// a - b, element by element, over the shorter of the two
+pub(crate) fn sub(a: &[i64], b: &[i64]) -> Vec<i64> {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        x86::zip(a, b, x86::Op::Sub)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar_zip(a, b, i64::wrapping_sub)
+    }
+}
+
+/// a * b, element by element, over the shorter of the two. x86_64 has no 64-bit multiply
+/// before AVX-512, so this is always a plain loop
+pub(crate) fn mul(a: &[i64], b: &[i64]) -> Vec<i64> {
+    scalar_zip(a, b, i64::wrapping_mul)
+}
+
+/// The sum of the values
+pub(crate) fn sum(values: &[i64]) -> i64 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        x86::sum(values)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar_sum(values)
+    }
+}
+
+fn scalar_zip(a: &[i64], b: &[i64], f: fn(i64, i64) -> i64) -> Vec<i64> {
+    a.iter().zip(b).map(|(&x, &y)| f(x, y)).collect()
+}
+
+fn scalar_sum(values: &[i64]) -> i64 {
+    values.iter().fold(0, |sum, &x| sum.wrapping_add(x))
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    pub(super) enum Op {
+        Add,
+        Sub,
+    }
+
+    pub(super) fn zip(a: &[i64], b: &[i64], op: Op) -> Vec<i64> {
+        let len = a.len().min(b.len());
+        let mut out = vec![0; len];
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 was detected, and the slices are all len long
+            unsafe { zip_avx2(&a[..len], &b[..len], &mut out, op) };
+        } else {
+            // SAFETY: SSE2 is part of x86_64, and the slices are all len long
+            unsafe { zip_sse2(&a[..len], &b[..len], &mut out, op) };
+        }
+        out
+    }
+
+    pub(super) fn sum(values: &[i64]) -> i64 {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 was detected
+            unsafe { sum_avx2(values) }
+        } else {
+            // SAFETY: SSE2 is part of x86_64
+            unsafe { sum_sse2(values) }
+        }
+    }
+
+    fn finish(a: &[i64], b: &[i64], out: &mut [i64], op: Op) {
+        for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) {
+            *o = match op {
+                Op::Add => x.wrapping_add(y),
+                Op::Sub => x.wrapping_sub(y),
+            };
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn zip_avx2(a: &[i64], b: &[i64], out: &mut [i64], op: Op) {
+        let lanes = a.len() / 4 * 4;
+        for i in (0..lanes).step_by(4) {
+            let x = _mm256_loadu_si256(a.as_ptr().add(i) as *const __m256i);
+            let y = _mm256_loadu_si256(b.as_ptr().add(i) as *const __m256i);
+            let r = match op {
+                Op::Add => _mm256_add_epi64(x, y),
+                Op::Sub => _mm256_sub_epi64(x, y),
+            };
+            _mm256_storeu_si256(out.as_mut_ptr().add(i) as *mut __m256i, r);
+        }
+        finish(&a[lanes..], &b[lanes..], &mut out[lanes..], op);
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn zip_sse2(a: &[i64], b: &[i64], out: &mut [i64], op: Op) {
+        let lanes = a.len() / 2 * 2;
+        for i in (0..lanes).step_by(2) {
+            let x = _mm_loadu_si128(a.as_ptr().add(i) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(i) as *const __m128i);
+            let r = match op {
+                Op::Add => _mm_add_epi64(x, y),
+                Op::Sub => _mm_sub_epi64(x, y),
+            };
+            _mm_storeu_si128(out.as_mut_ptr().add(i) as *mut __m128i, r);
+        }
+        finish(&a[lanes..], &b[lanes..], &mut out[lanes..], op);
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_avx2(values: &[i64]) -> i64 {
+        let lanes = values.len() / 4 * 4;
+        let mut total = _mm256_setzero_si256();
+        for i in (0..lanes).step_by(4) {
+            let x = _mm256_loadu_si256(values.as_ptr().add(i) as *const __m256i);
+            total = _mm256_add_epi64(total, x);
+        }
+        let mut parts = [0i64; 4];
+        _mm256_storeu_si256(parts.as_mut_ptr() as *mut __m256i, total);
+        super::scalar_sum(&parts).wrapping_add(super::scalar_sum(&values[lanes..]))
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum_sse2(values: &[i64]) -> i64 {
+        let lanes = values.len() / 2 * 2;
+        let mut total = _mm_setzero_si128();
+        for i in (0..lanes).step_by(2) {
+            let x = _mm_loadu_si128(values.as_ptr().add(i) as *const __m128i);
+            total = _mm_add_epi64(total, x);
+        }
+        let mut parts = [0i64; 2];
+        _mm_storeu_si128(parts.as_mut_ptr() as *mut __m128i, total);
+        super::scalar_sum(&parts).wrapping_add(super::scalar_sum(&values[lanes..]))
+    }
+}
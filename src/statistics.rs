@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::{Opcode, StackMachineError, StackMachineState, TrapHandled, OPCODE_COUNT};
+
+/// Statistics about a single run of the machine, see `StackMachine::enable_statistics`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecutionStatistics {
+    pub total_instructions: u64,
+    /// Number of times each opcode was executed, keyed by mnemonic, opcodes that never ran
+    /// are omitted
+    pub instructions_by_opcode: BTreeMap<String, u64>,
+    /// Conditional branches (JRZ, JRNZ, JRC, JRO) that jumped
+    pub branches_taken: u64,
+    /// Conditional branches that fell through to the next instruction
+    pub branches_not_taken: u64,
+    /// Number of times each trap id was raised
+    pub traps: BTreeMap<i64, u64>,
+}
+
+/// Cheap counters updated while running, turned into ExecutionStatistics on demand
+#[derive(Debug, Clone)]
+pub(crate) struct StatisticsRecorder {
+    opcode_counts: [u64; OPCODE_COUNT as usize],
+    branches_taken: u64,
+    branches_not_taken: u64,
+    traps: BTreeMap<i64, u64>,
+}
+
+impl Default for StatisticsRecorder {
+    fn default() -> Self {
+        StatisticsRecorder {
+            opcode_counts: [0; OPCODE_COUNT as usize],
+            branches_taken: 0,
+            branches_not_taken: 0,
+            traps: BTreeMap::new(),
+        }
+    }
+}
+
+impl StatisticsRecorder {
+    pub(crate) fn record_instruction(&mut self, opcode: &Opcode) {
+        self.opcode_counts[usize::from(opcode.encode().0)] += 1;
+    }
+
+    pub(crate) fn record_branch(&mut self, opcode: &Opcode, taken: bool) {
+        match opcode {
+            Opcode::JRZ | Opcode::JRNZ | Opcode::JRC | Opcode::JRO => {
+                if taken {
+                    self.branches_taken += 1;
+                } else {
+                    self.branches_not_taken += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn record_trap(&mut self, trap_id: i64) {
+        *self.traps.entry(trap_id).or_insert(0) += 1;
+    }
+
+    pub(crate) fn export(&self) -> ExecutionStatistics {
+        let instructions_by_opcode = Opcode::all_variants()
+            .iter()
+            .zip(self.opcode_counts.iter())
+            .filter(|(_, count)| **count > 0)
+            .map(|(opcode, count)| (opcode.mnemonic().to_string(), *count))
+            .collect();
+        ExecutionStatistics {
+            total_instructions: self.opcode_counts.iter().sum(),
+            instructions_by_opcode,
+            branches_taken: self.branches_taken,
+            branches_not_taken: self.branches_not_taken,
+            traps: self.traps.clone(),
+        }
+    }
+}
+
+/// The deepest each stack got, and the most cells allocated, during a run
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HighWaterMarks {
+    pub number_stack: usize,
+    pub scratch_stack: usize,
+    pub return_stack: usize,
+    pub loop_stack: usize,
+    pub cells: usize,
+}
+
+impl HighWaterMarks {
+    pub(crate) fn update(&mut self, st: &StackMachineState) {
+        self.number_stack = self.number_stack.max(st.number_stack().len());
+        self.scratch_stack = self.scratch_stack.max(st.scratch_stack().len());
+        self.return_stack = self.return_stack.max(st.return_stack.len());
+        self.loop_stack = self.loop_stack.max(st.loop_stack.len());
+        self.cells = self.cells.max(st.cells.len());
+    }
+}
+
+/// How one trap handler has behaved, it is called for every TRAP that the handlers
+/// before it did not handle
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrapHandlerStats {
+    pub calls: u64,
+    pub handled: u64,
+    pub errors: u64,
+    /// Wall clock time spent in the handler
+    pub time: Duration,
+}
+
+impl TrapHandlerStats {
+    pub(crate) fn record(
+        &mut self,
+        result: &Result<TrapHandled, StackMachineError>,
+        time: Duration,
+    ) {
+        self.calls += 1;
+        match result {
+            Ok(TrapHandled::Handled) => self.handled += 1,
+            Ok(TrapHandled::NotHandled) => {}
+            Err(_) => self.errors += 1,
+        }
+        self.time += time;
+    }
+}
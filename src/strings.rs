@@ -0,0 +1,185 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use super::dictionary::cell_index;
+use super::{ErrorKind, HandleTrap, StackMachineError, StackMachineState, TrapHandled};
+
+/// Strings with compact ids, so that text can cross between the host and a program as
+/// values, such as the message_id of ASSERT or the values logged by LOG.
+///
+/// Interning the same string again gives the same id. Ids start at 1, so 0 never names a
+/// string
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StringTable {
+    strings: Vec<Arc<str>>,
+    ids: HashMap<Arc<str>, i64>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        StringTable::default()
+    }
+
+    /// The id of a string, interning it first if it is new
+    pub fn intern(&mut self, string: &str) -> i64 {
+        if let Some(id) = self.ids.get(string) {
+            return *id;
+        }
+        let string: Arc<str> = string.into();
+        self.strings.push(string.clone());
+        let id = self.strings.len() as i64;
+        self.ids.insert(string, id);
+        id
+    }
+
+    /// The id of a string, None if it has not been interned
+    pub fn id(&self, string: &str) -> Option<i64> {
+        self.ids.get(string).copied()
+    }
+
+    /// The string with an id
+    pub fn get(&self, id: i64) -> Option<&str> {
+        let index = usize::try_from(id).ok()?.checked_sub(1)?;
+        self.strings.get(index).map(|string| &**string)
+    }
+
+    /// The strings and their ids, in the order they were interned
+    pub fn iter(&self) -> impl Iterator<Item = (i64, &str)> + '_ {
+        self.strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (index as i64 + 1, &**string))
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+impl StackMachineState {
+    /// Intern a string in the machine's string table, returning its id
+    pub fn intern(&mut self, string: &str) -> i64 {
+        self.strings.intern(string)
+    }
+
+    /// The interned string with an id
+    pub fn string(&self, id: i64) -> Option<&str> {
+        self.strings.get(id)
+    }
+
+    pub fn strings(&self) -> &StringTable {
+        &self.strings
+    }
+
+    pub fn strings_mut(&mut self) -> &mut StringTable {
+        &mut self.strings
+    }
+
+    /// Store a string in the cells from address, one character to a cell, returning the
+    /// number of cells used. The cells must all have been allocated
+    pub fn write_string(
+        &mut self,
+        address: usize,
+        string: &str,
+    ) -> Result<usize, StackMachineError> {
+        let length = string.chars().count();
+        let range = self.cell_range(address, length)?;
+        for (cell, c) in self.cells[range].iter_mut().zip(string.chars()) {
+            *cell = i64::from(u32::from(c));
+        }
+        Ok(length)
+    }
+
+    fn pop_string(&mut self) -> Result<Arc<str>, StackMachineError> {
+        let id = self.pop_number()?;
+        let index = usize::try_from(id).ok().and_then(|id| id.checked_sub(1));
+        index
+            .and_then(|index| self.strings.strings.get(index).cloned())
+            .ok_or_else(|| ErrorKind::UnknownString { id }.into())
+    }
+}
+
+/// Traps working with interned strings, by id, the least needed for a Forth TYPE and
+/// ACCEPT layer. Strings in cells are one character to a cell:
+///
+/// - first_trap_id, INTERN ( address length -- id ) the string in cells
+/// - first_trap_id + 1, STORE ( id address -- length ) into cells
+/// - first_trap_id + 2, CONCAT ( id1 id2 -- id )
+/// - first_trap_id + 3, COMPARE ( id1 id2 -- n ) -1, 0 or 1 as id1 sorts before, the same
+///   as or after id2
+/// - first_trap_id + 4, PARSE ( id -- n true | false ) a decimal number
+/// - first_trap_id + 5, FORMAT ( n -- id ) in decimal
+/// - first_trap_id + 6, LENGTH ( id -- length ) in characters
+///
+/// An id that is not interned gives an UnknownString error
+pub struct StringTraps {
+    first_trap_id: i64,
+}
+
+impl StringTraps {
+    pub fn new(first_trap_id: i64) -> Self {
+        StringTraps { first_trap_id }
+    }
+}
+
+impl HandleTrap for StringTraps {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id.checked_sub(self.first_trap_id) {
+            Some(0) => {
+                let [address, length] = st.pop_n()?;
+                let string = st.read_string(cell_index(address)?, cell_index(length)?)?;
+                let id = st.intern(&string);
+                st.push_number(id);
+            }
+            Some(1) => {
+                let address = cell_index(st.pop_number()?)?;
+                let string = st.pop_string()?;
+                let length = st.write_string(address, &string)?;
+                st.push_number(i64::try_from(length)?);
+            }
+            Some(2) => {
+                let second = st.pop_string()?;
+                let first = st.pop_string()?;
+                let id = st.intern(&format!("{}{}", first, second));
+                st.push_number(id);
+            }
+            Some(3) => {
+                let second = st.pop_string()?;
+                let first = st.pop_string()?;
+                st.push_number(match first.cmp(&second) {
+                    Ordering::Less => -1,
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 1,
+                });
+            }
+            Some(4) => match st.pop_string()?.trim().parse::<i64>() {
+                Ok(n) => {
+                    st.push_number(n);
+                    st.push_number(-1);
+                }
+                Err(_) => st.push_number(0),
+            },
+            Some(5) => {
+                let n = st.pop_number()?;
+                let id = st.intern(&n.to_string());
+                st.push_number(id);
+            }
+            Some(6) => {
+                let length = st.pop_string()?.chars().count();
+                st.push_number(i64::try_from(length)?);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Names for addresses in a program, usually the labels it was assembled from.
+///
+/// An address can have several names, when labels alias each other, and each is kept. Where
+/// one name is wanted for an address, the first of them in alphabetical order is used
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    by_address: BTreeMap<usize, BTreeSet<String>>,
+    by_name: BTreeMap<String, usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// Name an address, keeping any other names it has. A name belongs to one address, so
+    /// naming another address with it moves it there
+    pub fn insert(&mut self, address: usize, name: &str) {
+        if let Some(old) = self.by_name.insert(name.to_string(), address) {
+            if let Some(names) = self.by_address.get_mut(&old) {
+                names.remove(name);
+                if names.is_empty() {
+                    self.by_address.remove(&old);
+                }
+            }
+        }
+        self.by_address
+            .entry(address)
+            .or_default()
+            .insert(name.to_string());
+    }
+
+    /// The name given to exactly this address, the first in alphabetical order when it has
+    /// several
+    pub fn name_at(&self, address: usize) -> Option<&str> {
+        self.names_at(address).next()
+    }
+
+    /// Every name given to exactly this address, in alphabetical order
+    pub fn names_at(&self, address: usize) -> impl Iterator<Item = &str> {
+        self.by_address
+            .get(&address)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// The closest symbol at or before the address, with its address
+    pub fn containing(&self, address: usize) -> Option<(usize, &str)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .and_then(|(a, names)| names.iter().next().map(|name| (*a, name.as_str())))
+    }
+
+    /// The address of a symbol
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// A readable name for an address, `NAME`, `NAME+offset`, or `@address` when no symbol
+    /// precedes it
+    pub fn describe(&self, address: usize) -> String {
+        match self.containing(address) {
+            Some((a, name)) if a == address => name.to_string(),
+            Some((a, name)) => format!("{}+{}", name, address - a),
+            None => format!("@{}", address),
+        }
+    }
+
+    /// Every name with its address, by address and then in alphabetical order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.by_address
+            .iter()
+            .flat_map(|(a, names)| names.iter().map(move |name| (*a, name.as_str())))
+    }
+
+    /// The number of names
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+}
@@ -0,0 +1,631 @@
+//! Bounded symbolic execution, to find inputs that make a program fail.
+//!
+//! The program is run from address 0 with a number of symbolic input values on
+//! the number stack. Every conditional branch whose flag depends on the inputs
+//! forks the path, and at each instruction that could fail the constraints of
+//! the path are searched for concrete inputs that trigger the failure.
+//!
+//! Inputs are searched for among interesting values, the boundaries of i64 and
+//! the constants used by the program and their neighbours, rather than with a
+//! solver, so a finding always comes with inputs that really trigger it but
+//! failures that need other inputs can be missed.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use super::constprop::evaluate;
+use super::{Opcode, WordSize};
+
+/// A value on one of the stacks, in terms of the inputs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(i64),
+    /// The nth input, input 0 is the deepest on the stack
+    Input(usize),
+    /// A single result opcode applied to its arguments, TOS first
+    Op(Opcode, Vec<Rc<Expr>>),
+    /// Quotient and remainder of floored division, dividend then divisor
+    FlooredQuotient(Rc<Expr>, Rc<Expr>),
+    FlooredRemainder(Rc<Expr>, Rc<Expr>),
+    /// 1 if the first value is greater than or equal to the second, as CMPLOOP
+    GreaterOrEqual(Rc<Expr>, Rc<Expr>),
+}
+
+impl Expr {
+    /// The value of the expression for concrete inputs, None where evaluation would fail
+    pub fn evaluate(&self, inputs: &[i64]) -> Option<i64> {
+        match self {
+            Expr::Const(v) => Some(*v),
+            Expr::Input(i) => inputs.get(*i).copied(),
+            Expr::Op(op, args) => {
+                let values = args
+                    .iter()
+                    .map(|a| a.evaluate(inputs))
+                    .collect::<Option<Vec<i64>>>()?;
+                evaluate(op, &values, WordSize::Bits64)
+            }
+            Expr::FlooredQuotient(a, b) | Expr::FlooredRemainder(a, b) => {
+                let (dividend, divisor) = (a.evaluate(inputs)?, b.evaluate(inputs)?);
+                let (q, r) = floored_div_mod(dividend, divisor)?;
+                Some(if let Expr::FlooredQuotient(..) = self {
+                    q
+                } else {
+                    r
+                })
+            }
+            Expr::GreaterOrEqual(a, b) => Some(if a.evaluate(inputs)? >= b.evaluate(inputs)? {
+                1
+            } else {
+                0
+            }),
+        }
+    }
+
+    fn constant(&self) -> Option<i64> {
+        match self {
+            Expr::Const(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+fn floored_div_mod(dividend: i64, divisor: i64) -> Option<(i64, i64)> {
+    let mut quotient = dividend.checked_div(divisor)?;
+    let mut remainder = dividend % divisor;
+    if remainder != 0 && (remainder < 0) != (divisor < 0) {
+        quotient -= 1;
+        remainder += divisor;
+    }
+    Some((quotient, remainder))
+}
+
+/// Build an expression, folding it to a constant when all of its arguments are constant
+fn apply(op: Opcode, args: Vec<Rc<Expr>>) -> Rc<Expr> {
+    let constants = args
+        .iter()
+        .map(|a| a.constant())
+        .collect::<Option<Vec<i64>>>();
+    if let Some(value) = constants.and_then(|c| evaluate(&op, &c, WordSize::Bits64)) {
+        return Rc::new(Expr::Const(value));
+    }
+    Rc::new(Expr::Op(op, args))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FindingKind {
+    DivisionByZero,
+    /// Arithmetic whose result does not fit, an error for the checked opcodes, a wrap for
+    /// the others
+    Overflow,
+    NumberStackUnderflow,
+    ScratchStackUnderflow,
+    LoopStackUnderflow,
+    /// An ASSERT popped a false flag
+    AssertionFailed,
+}
+
+/// A failure found at pc, with inputs, deepest first, that trigger it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub pc: usize,
+    pub inputs: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymexecOptions {
+    /// Number of symbolic values on the number stack at the start
+    pub inputs: usize,
+    /// Instructions executed along a single path before it is abandoned
+    pub max_steps: usize,
+    /// Paths explored before the search stops
+    pub max_paths: usize,
+    /// Input combinations tried when searching for a witness
+    pub max_candidates: usize,
+}
+
+impl Default for SymexecOptions {
+    fn default() -> Self {
+        SymexecOptions {
+            inputs: 2,
+            max_steps: 1_000,
+            max_paths: 256,
+            max_candidates: 20_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymexecReport {
+    pub findings: Vec<Finding>,
+    pub paths_explored: usize,
+    /// Paths abandoned because they ran too long, reached a TRAP, or used a value that
+    /// depends on the inputs as an address or jump target
+    pub paths_abandoned: usize,
+}
+
+#[derive(Clone)]
+struct Path {
+    pc: usize,
+    steps: usize,
+    number_stack: Vec<Rc<Expr>>,
+    scratch_stack: Vec<Rc<Expr>>,
+    loop_stack: Vec<(Rc<Expr>, Rc<Expr>)>,
+    return_stack: Vec<usize>,
+    cells: Vec<Rc<Expr>>,
+    /// Expressions that are known to be zero (true) or non-zero (false) on this path
+    constraints: Vec<(Rc<Expr>, bool)>,
+}
+
+enum Flow {
+    Continue,
+    Finished,
+    Abandoned,
+    Failed(FindingKind),
+}
+
+struct Explorer<'a> {
+    opcodes: &'a [Opcode],
+    options: SymexecOptions,
+    candidates: Vec<i64>,
+    report: SymexecReport,
+    seen: BTreeSet<(usize, FindingKind)>,
+}
+
+impl<'a> Explorer<'a> {
+    /// Search for inputs satisfying the path constraints and a condition
+    fn witness(&self, path: &Path, condition: &dyn Fn(&[i64]) -> bool) -> Option<Vec<i64>> {
+        let n = self.options.inputs;
+        let mut indices = vec![0_usize; n];
+        for _ in 0..self.options.max_candidates {
+            let inputs: Vec<i64> = indices.iter().map(|i| self.candidates[*i]).collect();
+            let satisfied = path
+                .constraints
+                .iter()
+                .all(|(e, zero)| e.evaluate(&inputs).is_some_and(|v| (v == 0) == *zero));
+            if satisfied && condition(&inputs) {
+                return Some(inputs);
+            }
+            // Next combination, like an odometer
+            let mut position = 0;
+            loop {
+                if position == n {
+                    return None;
+                }
+                indices[position] += 1;
+                if indices[position] < self.candidates.len() {
+                    break;
+                }
+                indices[position] = 0;
+                position += 1;
+            }
+        }
+        None
+    }
+
+    fn record(
+        &mut self,
+        path: &Path,
+        kind: FindingKind,
+        condition: &dyn Fn(&[i64]) -> bool,
+    ) -> bool {
+        if self.seen.contains(&(path.pc, kind)) {
+            return false;
+        }
+        match self.witness(path, condition) {
+            Some(inputs) => {
+                self.seen.insert((path.pc, kind));
+                self.report.findings.push(Finding {
+                    kind,
+                    pc: path.pc,
+                    inputs,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn explore(mut self) -> SymexecReport {
+        let mut pending = vec![Path {
+            pc: 0,
+            steps: 0,
+            number_stack: (0..self.options.inputs)
+                .map(|i| Rc::new(Expr::Input(i)))
+                .collect(),
+            scratch_stack: vec![],
+            loop_stack: vec![],
+            return_stack: vec![],
+            cells: vec![],
+            constraints: vec![],
+        }];
+
+        while let Some(mut path) = pending.pop() {
+            if self.report.paths_explored >= self.options.max_paths {
+                break;
+            }
+            loop {
+                if path.steps >= self.options.max_steps {
+                    self.report.paths_abandoned += 1;
+                    break;
+                }
+                path.steps += 1;
+                match self.step(&mut path, &mut pending) {
+                    Flow::Continue => {}
+                    Flow::Finished => break,
+                    Flow::Abandoned => {
+                        self.report.paths_abandoned += 1;
+                        break;
+                    }
+                    Flow::Failed(kind) => {
+                        self.record(&path, kind, &|_| true);
+                        break;
+                    }
+                }
+            }
+            self.report.paths_explored += 1;
+        }
+        self.report
+    }
+
+    fn step(&mut self, path: &mut Path, pending: &mut Vec<Path>) -> Flow {
+        macro_rules! pop {
+            ($stack:ident, $kind:ident) => {
+                match path.$stack.pop() {
+                    Some(v) => v,
+                    None => return Flow::Failed(FindingKind::$kind),
+                }
+            };
+        }
+        macro_rules! concrete {
+            ($expr:expr) => {
+                match $expr.constant() {
+                    Some(v) => v,
+                    None => return Flow::Abandoned,
+                }
+            };
+        }
+
+        let op = match self.opcodes.get(path.pc) {
+            Some(op) => op.clone(),
+            None => return Flow::Finished,
+        };
+        let mut next = path.pc + 1;
+        match op {
+            Opcode::JMP | Opcode::CALL => {
+                let target = concrete!(pop!(number_stack, NumberStackUnderflow));
+                if op == Opcode::CALL {
+                    path.return_stack.push(next);
+                }
+                next = match usize::try_from(target) {
+                    Ok(t) => t,
+                    Err(_) => return Flow::Finished,
+                };
+            }
+            Opcode::JR | Opcode::JRZ | Opcode::JRNZ => {
+                let offset = concrete!(pop!(number_stack, NumberStackUnderflow));
+                let target = match i64::try_from(path.pc)
+                    .ok()
+                    .and_then(|p| p.checked_add(offset))
+                    .and_then(|t| usize::try_from(t).ok())
+                {
+                    Some(t) => t,
+                    None => return Flow::Finished,
+                };
+                if op == Opcode::JR {
+                    next = target;
+                } else {
+                    let flag = pop!(number_stack, NumberStackUnderflow);
+                    let jump_when_zero = op == Opcode::JRZ;
+                    match flag.constant() {
+                        Some(v) if (v == 0) == jump_when_zero => next = target,
+                        Some(_) => {}
+                        None => {
+                            // Fork, this path falls through and the other jumps
+                            let mut taken = path.clone();
+                            taken.constraints.push((flag.clone(), jump_when_zero));
+                            taken.pc = target;
+                            pending.push(taken);
+                            path.constraints.push((flag, !jump_when_zero));
+                        }
+                    }
+                }
+            }
+            Opcode::JRC
+            | Opcode::JRO
+            | Opcode::TRAP
+            | Opcode::TRAPID(_)
+            | Opcode::READIN
+            | Opcode::NEXTIN
+            | Opcode::DADD
+            | Opcode::DSUB
+            | Opcode::DMUL
+            | Opcode::DDIV
+            | Opcode::DRESCALE => return Flow::Abandoned,
+            Opcode::RET => match path.return_stack.pop() {
+                Some(r) => next = r,
+                None => return Flow::Finished,
+            },
+            Opcode::ASSERT(_) => {
+                let flag = pop!(number_stack, NumberStackUnderflow);
+                let f = flag.clone();
+                self.record(path, FindingKind::AssertionFailed, &|inputs| {
+                    f.evaluate(inputs) == Some(0)
+                });
+                if flag.constant() == Some(0) {
+                    return Flow::Finished;
+                }
+                path.constraints.push((flag, false));
+            }
+            Opcode::LDI(v) => path.number_stack.push(Rc::new(Expr::Const(v))),
+            Opcode::DODOES(data, behavior) => {
+                path.number_stack
+                    .push(Rc::new(Expr::Const(i64::from(data))));
+                next = behavior as usize;
+            }
+            Opcode::LDPC(offset) => match (path.pc as i64).checked_add(offset) {
+                Some(address) => path.number_stack.push(Rc::new(Expr::Const(address))),
+                None => return Flow::Abandoned,
+            },
+            Opcode::DROP => {
+                pop!(number_stack, NumberStackUnderflow);
+            }
+            Opcode::DUP => {
+                let x = pop!(number_stack, NumberStackUnderflow);
+                path.number_stack.push(x.clone());
+                path.number_stack.push(x);
+            }
+            Opcode::SWAP => {
+                let x = pop!(number_stack, NumberStackUnderflow);
+                let y = pop!(number_stack, NumberStackUnderflow);
+                path.number_stack.push(x);
+                path.number_stack.push(y);
+            }
+            Opcode::DUP2 | Opcode::SWAP2 | Opcode::OVER2 => {
+                let count = if op == Opcode::DUP2 { 2 } else { 4 };
+                if path.number_stack.len() < count {
+                    return Flow::Failed(FindingKind::NumberStackUnderflow);
+                }
+                let top = path.number_stack.split_off(path.number_stack.len() - count);
+                let order: &[usize] = match op {
+                    Opcode::DUP2 => &[0, 1, 0, 1],
+                    Opcode::SWAP2 => &[2, 3, 0, 1],
+                    _ => &[0, 1, 2, 3, 0, 1],
+                };
+                path.number_stack
+                    .extend(order.iter().map(|i| top[*i].clone()));
+            }
+            Opcode::GtR => {
+                let x = pop!(number_stack, NumberStackUnderflow);
+                path.scratch_stack.push(x);
+            }
+            Opcode::RGt => {
+                let x = pop!(scratch_stack, ScratchStackUnderflow);
+                path.number_stack.push(x);
+            }
+            Opcode::RAt => match path.scratch_stack.last() {
+                Some(x) => path.number_stack.push(x.clone()),
+                None => return Flow::Failed(FindingKind::ScratchStackUnderflow),
+            },
+            Opcode::GtR2 => {
+                let x = pop!(number_stack, NumberStackUnderflow);
+                let y = pop!(number_stack, NumberStackUnderflow);
+                path.scratch_stack.push(y);
+                path.scratch_stack.push(x);
+            }
+            Opcode::RGt2 | Opcode::RAt2 => {
+                let x = pop!(scratch_stack, ScratchStackUnderflow);
+                let y = pop!(scratch_stack, ScratchStackUnderflow);
+                if op == Opcode::RAt2 {
+                    path.scratch_stack.push(y.clone());
+                    path.scratch_stack.push(x.clone());
+                }
+                path.number_stack.push(y);
+                path.number_stack.push(x);
+            }
+            Opcode::PUSHLP => {
+                let index = pop!(number_stack, NumberStackUnderflow);
+                let max = pop!(number_stack, NumberStackUnderflow);
+                path.loop_stack.push((index, max));
+            }
+            Opcode::INCLP | Opcode::ADDLP => {
+                let increment = if op == Opcode::ADDLP {
+                    pop!(number_stack, NumberStackUnderflow)
+                } else {
+                    Rc::new(Expr::Const(1))
+                };
+                match path.loop_stack.last_mut() {
+                    Some((index, _)) => *index = apply(Opcode::ADD, vec![increment, index.clone()]),
+                    None => return Flow::Failed(FindingKind::LoopStackUnderflow),
+                }
+            }
+            Opcode::GETLP | Opcode::GETLP2 | Opcode::CMPLOOP => {
+                let depth = if op == Opcode::GETLP2 { 2 } else { 1 };
+                if path.loop_stack.len() < depth {
+                    return Flow::Failed(FindingKind::LoopStackUnderflow);
+                }
+                let (index, max) = path.loop_stack[path.loop_stack.len() - depth].clone();
+                let value = if op == Opcode::CMPLOOP {
+                    match (index.constant(), max.constant()) {
+                        (Some(i), Some(m)) => Rc::new(Expr::Const(if i >= m { 1 } else { 0 })),
+                        _ => Rc::new(Expr::GreaterOrEqual(index, max)),
+                    }
+                } else {
+                    index
+                };
+                path.number_stack.push(value);
+            }
+            Opcode::DROPLP => {
+                if path.loop_stack.pop().is_none() {
+                    return Flow::Failed(FindingKind::LoopStackUnderflow);
+                }
+            }
+            Opcode::NEWCELLS => {
+                let count = concrete!(pop!(number_stack, NumberStackUnderflow));
+                let count = match usize::try_from(count) {
+                    Ok(c) if c <= 1 << 16 => c,
+                    _ => return Flow::Abandoned,
+                };
+                let address = path.cells.len();
+                path.cells.resize(address + count, Rc::new(Expr::Const(0)));
+                path.number_stack.push(Rc::new(Expr::Const(address as i64)));
+            }
+            Opcode::FREECELLS => {
+                let count = concrete!(pop!(number_stack, NumberStackUnderflow));
+                match usize::try_from(count)
+                    .ok()
+                    .and_then(|c| path.cells.len().checked_sub(c))
+                {
+                    Some(len) => path.cells.truncate(len),
+                    None => return Flow::Abandoned,
+                }
+            }
+            Opcode::HERE => {
+                let here = path.cells.len() as i64;
+                path.number_stack.push(Rc::new(Expr::Const(here)));
+            }
+            Opcode::ALLOT => {
+                let count = concrete!(pop!(number_stack, NumberStackUnderflow));
+                let here = (path.cells.len() as i64).checked_add(count);
+                match here.and_then(|here| usize::try_from(here).ok()) {
+                    Some(here) if here <= path.cells.len() + (1 << 16) => {
+                        path.cells.resize(here, Rc::new(Expr::Const(0)))
+                    }
+                    _ => return Flow::Abandoned,
+                }
+            }
+            Opcode::MOVETOCELLS | Opcode::MOVEFROMCELLS => {
+                let count = concrete!(pop!(number_stack, NumberStackUnderflow));
+                let address = concrete!(pop!(number_stack, NumberStackUnderflow));
+                let range = match (usize::try_from(address), usize::try_from(count)) {
+                    (Ok(a), Ok(c))
+                        if c >= 1
+                            && a.checked_add(c).is_some_and(|end| end <= path.cells.len()) =>
+                    {
+                        a..a + c
+                    }
+                    _ => return Flow::Abandoned,
+                };
+                if op == Opcode::MOVETOCELLS {
+                    for i in range {
+                        path.cells[i] = pop!(number_stack, NumberStackUnderflow);
+                    }
+                } else {
+                    for i in range.rev() {
+                        path.number_stack.push(path.cells[i].clone());
+                    }
+                }
+            }
+            Opcode::SPILL(n, address) | Opcode::UNSPILL(n, address) => {
+                let (n, address) = (n as usize, address as usize);
+                if address + n > path.cells.len() {
+                    return Flow::Abandoned;
+                }
+                if let Opcode::SPILL(..) = op {
+                    if path.number_stack.len() < n {
+                        return Flow::Failed(FindingKind::NumberStackUnderflow);
+                    }
+                    let first = path.number_stack.len() - n;
+                    let values = path.number_stack.split_off(first);
+                    path.cells.splice(address..address + n, values);
+                } else {
+                    let values = path.cells[address..address + n].to_vec();
+                    path.number_stack.extend(values);
+                }
+            }
+            Opcode::FMDIVMOD => {
+                let divisor = pop!(number_stack, NumberStackUnderflow);
+                let dividend = pop!(number_stack, NumberStackUnderflow);
+                if self.check_division(path, &dividend, &divisor) {
+                    return Flow::Finished;
+                }
+                path.number_stack.push(Rc::new(Expr::FlooredRemainder(
+                    dividend.clone(),
+                    divisor.clone(),
+                )));
+                path.number_stack
+                    .push(Rc::new(Expr::FlooredQuotient(dividend, divisor)));
+            }
+            _ => {
+                let metadata = op.metadata();
+                let mut args = Vec::new();
+                for _ in 0..metadata.pops {
+                    args.push(pop!(number_stack, NumberStackUnderflow));
+                }
+                if op == Opcode::DIV && self.check_division(path, &args[1], &args[0]) {
+                    return Flow::Finished;
+                }
+                self.check_overflow(path, &op, &args);
+                if metadata.pushes == 1 {
+                    path.number_stack.push(apply(op, args));
+                }
+            }
+        }
+        path.pc = next;
+        Flow::Continue
+    }
+
+    /// Report division by zero, returning true when the divisor is always zero
+    fn check_division(&mut self, path: &mut Path, dividend: &Rc<Expr>, divisor: &Rc<Expr>) -> bool {
+        let d = divisor.clone();
+        self.record(path, FindingKind::DivisionByZero, &|inputs| {
+            d.evaluate(inputs) == Some(0)
+        });
+        if divisor.constant() == Some(0) {
+            return true;
+        }
+        let (a, b) = (dividend.clone(), divisor.clone());
+        self.record(path, FindingKind::Overflow, &|inputs| {
+            matches!((a.evaluate(inputs), b.evaluate(inputs)), (Some(x), Some(y)) if y != 0 && x.checked_div(y).is_none())
+        });
+        path.constraints.push((divisor.clone(), false));
+        false
+    }
+
+    fn check_overflow(&mut self, path: &Path, op: &Opcode, args: &[Rc<Expr>]) {
+        let checked: fn(i64, i64) -> Option<i64> = match op {
+            Opcode::ADD => |x, y| x.checked_add(y),
+            Opcode::SUB => |x, y| x.checked_sub(y),
+            Opcode::MUL => |x, y| x.checked_mul(y),
+            Opcode::IPOW => |x, y| u32::try_from(x).ok().and_then(|e| y.checked_pow(e)),
+            _ => return,
+        };
+        let (a, b) = (args[0].clone(), args[1].clone());
+        self.record(path, FindingKind::Overflow, &|inputs| {
+            matches!((a.evaluate(inputs), b.evaluate(inputs)), (Some(x), Some(y)) if checked(x, y).is_none())
+        });
+    }
+}
+
+/// Explore the paths of a program, reporting inputs that make it fail
+pub fn explore(opcodes: &[Opcode], options: SymexecOptions) -> SymexecReport {
+    let mut candidates: BTreeSet<i64> = [0, 1, -1, 2, -2, i64::MAX, i64::MIN]
+        .iter()
+        .copied()
+        .collect();
+    for op in opcodes {
+        if let Opcode::LDI(v) = op {
+            for c in &[
+                Some(*v),
+                v.checked_add(1),
+                v.checked_sub(1),
+                v.checked_neg(),
+            ] {
+                candidates.extend(c);
+            }
+        }
+    }
+    // Try the small values first, they make the most readable findings
+    let mut candidates: Vec<i64> = candidates.into_iter().collect();
+    candidates.sort_by_key(|v| (v.unsigned_abs(), *v < 0));
+
+    Explorer {
+        opcodes,
+        options,
+        candidates,
+        report: SymexecReport::default(),
+        seen: BTreeSet::new(),
+    }
+    .explore()
+}
@@ -0,0 +1,89 @@
+//! Proptest strategies and invariant checks for property testing programs.
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn never_panics(opcodes in arb_program(32), stack in arb_number_stack(4)) {
+//!         check_invariants(&opcodes, &stack, 1_000)?;
+//!     }
+//! }
+//! ```
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+use super::{ErrorKind, ExitStatus, GasLimit, Opcode, StackMachine, StackMachineError};
+
+/// Immediate values, mostly small so that they make sense as addresses, counts and offsets
+pub fn arb_immediate() -> impl Strategy<Value = i64> {
+    prop_oneof![
+        8 => -16_i64..=16,
+        1 => Just(i64::MIN),
+        1 => Just(i64::MAX),
+        1 => any::<i64>(),
+    ]
+}
+
+/// Any opcode, with LDI immediates from arb_immediate
+pub fn arb_opcode() -> impl Strategy<Value = Opcode> {
+    let others: Vec<Opcode> = Opcode::all_variants()
+        .iter()
+        .filter(|op| !matches!(op, Opcode::LDI(_)))
+        .cloned()
+        .collect();
+    prop_oneof![
+        arb_immediate().prop_map(Opcode::LDI),
+        proptest::sample::select(others),
+    ]
+}
+
+/// Programs of up to max_len opcodes
+pub fn arb_program(max_len: usize) -> impl Strategy<Value = Vec<Opcode>> {
+    proptest::collection::vec(arb_opcode(), 0..=max_len)
+}
+
+/// Initial number stacks of up to max_len values
+pub fn arb_number_stack(max_len: usize) -> impl Strategy<Value = Vec<i64>> {
+    proptest::collection::vec(arb_immediate(), 0..=max_len)
+}
+
+/// Check that a machine that ran with a limit stopped within it, allowing for the
+/// instruction that ran out of gas
+pub fn assert_gas_within_limit(
+    sm: &StackMachine,
+    result: &Result<ExitStatus, StackMachineError>,
+    limit: u64,
+) -> Result<(), TestCaseError> {
+    let allowed = match result {
+        Err(e) if e.kind() == ErrorKind::RanOutOfGas => limit.saturating_add(1),
+        _ => limit,
+    };
+    prop_assert!(
+        sm.st.gas_used() <= allowed,
+        "used {} gas with a limit of {}",
+        sm.st.gas_used(),
+        limit
+    );
+    Ok(())
+}
+
+/// Run a program from address 0 and check that it neither panics nor overruns its gas
+pub fn check_invariants(
+    opcodes: &[Opcode],
+    number_stack: &[i64],
+    gas_limit: u64,
+) -> Result<StackMachine, TestCaseError> {
+    let mut sm = StackMachine::default();
+    sm.st.load_program(opcodes);
+    sm.st.set_number_stack(number_stack.to_vec());
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        sm.execute(0, GasLimit::Limited(gas_limit))
+    }))
+    .map_err(|_| TestCaseError::fail(format!("execution panicked: {:?}", opcodes)))?;
+
+    assert_gas_within_limit(&sm, &result, gas_limit)?;
+    Ok(sm)
+}
@@ -1,3 +1,6 @@
+// The tests set up and inspect the stacks directly
+#![allow(deprecated)]
+
 use super::*;
 
 #[test]
@@ -649,7 +652,7 @@ fn test_handle_trap_1() {
         .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
             st.number_stack
                 .pop()
-                .ok_or(StackMachineError::NumberStackUnderflow)?;
+                .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
             st.number_stack.push(200);
             Ok(TrapHandled::Handled)
         })));
@@ -675,7 +678,7 @@ fn test_handle_trap_2() {
         .push(Box::from(TrapHandler::new(-100, |_trap_id, st| {
             st.number_stack
                 .pop()
-                .ok_or(StackMachineError::NumberStackUnderflow)?;
+                .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
             st.number_stack.push(-100);
             Ok(TrapHandled::Handled)
         })));
@@ -683,7 +686,7 @@ fn test_handle_trap_2() {
         .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
             st.number_stack
                 .pop()
-                .ok_or(StackMachineError::NumberStackUnderflow)?;
+                .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
             st.number_stack.push(200);
             Ok(TrapHandled::Handled)
         })));
@@ -691,7 +694,7 @@ fn test_handle_trap_2() {
         .push(Box::from(TrapHandler::new(-200, |_trap_id, st| {
             st.number_stack
                 .pop()
-                .ok_or(StackMachineError::NumberStackUnderflow)?;
+                .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
             st.number_stack.push(-200);
             Ok(TrapHandled::Handled)
         })));
@@ -722,8 +725,8 @@ fn test_unhandled_trap_1() {
         .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
 
     // Execute the instructions
-    match sm.execute(0, GasLimit::Limited(100)) {
-        Err(StackMachineError::UnhandledTrap) => (),
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::UnhandledTrap { .. }) => (),
         r => panic!("Incorrect error type returned {:?}", r),
     }
 }
@@ -743,7 +746,7 @@ fn test_execute_pushlp() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321]);
-    assert_eq!(sm.st.loop_stack, vec![(0, 39483)]);
+    assert_eq!(sm.st.loop_stack(), vec![LoopFrame::new(0, 39483)]);
 }
 
 #[test]
@@ -761,7 +764,7 @@ fn test_execute_inclp() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321]);
-    assert_eq!(sm.st.loop_stack, vec![(1, 39483)]);
+    assert_eq!(sm.st.loop_stack(), vec![LoopFrame::new(1, 39483)]);
 }
 
 #[test]
@@ -778,8 +781,8 @@ fn test_execute_addlp() {
     // Execute the instructions
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-    assert_eq!(sm.st.number_stack, vec![]);
-    assert_eq!(sm.st.loop_stack, vec![(321, 39483)]);
+    assert_eq!(sm.st.number_stack, Vec::<i64>::new());
+    assert_eq!(sm.st.loop_stack(), vec![LoopFrame::new(321, 39483)]);
 }
 
 #[test]
@@ -791,7 +794,7 @@ fn test_execute_getlp() {
     // Populate the loop stack
     sm.st
         .loop_stack
-        .extend_from_slice(&[(3210, 0), (394836, 0)]);
+        .extend_from_slice(&[LoopFrame::new(3210, 0), LoopFrame::new(394836, 0)]);
     // Put the opcodes into the *memory*
     sm.st
         .opcodes
@@ -801,7 +804,10 @@ fn test_execute_getlp() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321, 39483, 394836]);
-    assert_eq!(sm.st.loop_stack, vec![(3210, 0), (394836, 0)]);
+    assert_eq!(
+        sm.st.loop_stack(),
+        vec![LoopFrame::new(3210, 0), LoopFrame::new(394836, 0)]
+    );
 }
 
 #[test]
@@ -818,8 +824,8 @@ fn test_execute_getlp_fail_1() {
 
     // Execute the instructions
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::LoopStackUnderflow) => 1,
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::LoopStackUnderflow { .. }) => 1,
             _ => 0,
         },
         1
@@ -835,7 +841,7 @@ fn test_execute_getlp2() {
     // Populate the loop stack
     sm.st
         .loop_stack
-        .extend_from_slice(&[(3210, 0), (394836, 0)]);
+        .extend_from_slice(&[LoopFrame::new(3210, 0), LoopFrame::new(394836, 0)]);
     // Put the opcodes into the *memory*
     sm.st
         .opcodes
@@ -845,7 +851,10 @@ fn test_execute_getlp2() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321, 39483, 3210]);
-    assert_eq!(sm.st.loop_stack, vec![(3210, 0), (394836, 0)]);
+    assert_eq!(
+        sm.st.loop_stack(),
+        vec![LoopFrame::new(3210, 0), LoopFrame::new(394836, 0)]
+    );
 }
 
 #[test]
@@ -856,7 +865,9 @@ fn test_execute_getlp2_fail_2() {
     sm.st.number_stack.extend_from_slice(&[321, 39483]);
 
     // Populate the loop stack
-    sm.st.loop_stack.extend_from_slice(&[(3210, 0)]);
+    sm.st
+        .loop_stack
+        .extend_from_slice(&[LoopFrame::new(3210, 0)]);
 
     // Put the opcodes into the *memory*
     sm.st
@@ -865,8 +876,8 @@ fn test_execute_getlp2_fail_2() {
 
     // Execute the instructions
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::LoopStackUnderflow) => 1,
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::LoopStackUnderflow { .. }) => 1,
             _ => 0,
         },
         1
@@ -882,7 +893,7 @@ fn test_execute_cmpgelp_eq() {
     // Populate the loop stack
     sm.st
         .loop_stack
-        .extend_from_slice(&[(3210, 0), (39483, 39483)]);
+        .extend_from_slice(&[LoopFrame::new(3210, 0), LoopFrame::new(39483, 39483)]);
     // Put the opcodes into the *memory*
     sm.st
         .opcodes
@@ -892,7 +903,10 @@ fn test_execute_cmpgelp_eq() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321, 39583, 1]);
-    assert_eq!(sm.st.loop_stack, vec![(3210, 0), (39483, 39483)]);
+    assert_eq!(
+        sm.st.loop_stack(),
+        vec![LoopFrame::new(3210, 0), LoopFrame::new(39483, 39483)]
+    );
 }
 
 #[test]
@@ -904,7 +918,7 @@ fn test_execute_cmpgelp_gt() {
     // Populate the loop stack
     sm.st
         .loop_stack
-        .extend_from_slice(&[(3210, 0), (39484, 39483)]);
+        .extend_from_slice(&[LoopFrame::new(3210, 0), LoopFrame::new(39484, 39483)]);
     // Put the opcodes into the *memory*
     sm.st
         .opcodes
@@ -914,7 +928,10 @@ fn test_execute_cmpgelp_gt() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321, 39583, 1]);
-    assert_eq!(sm.st.loop_stack, vec![(3210, 0), (39484, 39483)]);
+    assert_eq!(
+        sm.st.loop_stack(),
+        vec![LoopFrame::new(3210, 0), LoopFrame::new(39484, 39483)]
+    );
 }
 
 #[test]
@@ -926,7 +943,7 @@ fn test_execute_cmpgelp_lt() {
     // Populate the loop stack
     sm.st
         .loop_stack
-        .extend_from_slice(&[(3210, 0), (39482, 39483)]);
+        .extend_from_slice(&[LoopFrame::new(3210, 0), LoopFrame::new(39482, 39483)]);
     // Put the opcodes into the *memory*
     sm.st
         .opcodes
@@ -936,7 +953,10 @@ fn test_execute_cmpgelp_lt() {
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
     assert_eq!(sm.st.number_stack, vec![321, 39583, 0]);
-    assert_eq!(sm.st.loop_stack, vec![(3210, 0), (39482, 39483)]);
+    assert_eq!(
+        sm.st.loop_stack(),
+        vec![LoopFrame::new(3210, 0), LoopFrame::new(39482, 39483)]
+    );
 }
 
 #[test]
@@ -970,10 +990,45 @@ fn test_execute_newcells_1() {
     // Execute the instructions
     sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-    assert_eq!(sm.st.number_stack, vec![0]);
+    assert_eq!(sm.st.number_stack, vec![0, 0]);
     assert_eq!(sm.st.cells, vec![0, 0]);
 }
 
+#[test]
+fn test_execute_newcells_pushes_address() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI 2, NEWCELLS, LDI 3, NEWCELLS, RET];
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[0, 2]);
+    assert_eq!(sm.st.cells(), &[0; 5]);
+}
+
+#[test]
+fn test_execute_freecells() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI 4, NEWCELLS, DROP, LDI 3, FREECELLS, LDI 2, FREECELLS, RET];
+
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::InvalidCellOperation) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.cells(), &[0]);
+}
+
+#[test]
+fn test_trim_cells() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI 100, NEWCELLS, RET];
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    sm.st.trim_cells(10);
+    assert_eq!(sm.st.cells().len(), 10);
+    sm.st.trim_cells(20);
+    assert_eq!(sm.st.cells().len(), 10);
+}
+
 #[test]
 fn test_execute_newcells_2() {
     let mut sm = StackMachine::default();
@@ -987,8 +1042,8 @@ fn test_execute_newcells_2() {
 
     // Execute the instructions
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
             _ => 0,
         },
         1
@@ -996,214 +1051,4620 @@ fn test_execute_newcells_2() {
 }
 
 #[test]
-fn test_execute_movetocells_1() {
+fn test_execute_here_allot() {
     let mut sm = StackMachine::default();
+    sm.st.opcodes = program![HERE, LDI 3, ALLOT, HERE, LDI -2, ALLOT, HERE, RET];
 
-    // Populate the number stack
-    // 2 is the number of values to move to cells
-    // 0 is the location to start moving values to
-    // 3 2 1 are the values to use when moving to cells
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-    // Setup the cells we will be storing to
-    sm.st.cells.extend_from_slice(&[0, 0]);
+    assert_eq!(sm.st.number_stack(), &[0, 3, 1]);
+    assert_eq!(sm.st.cells(), &[0]);
 
-    // Execute the instructions
-    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    sm.st.opcodes = program![LDI - 2, ALLOT, RET];
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::InvalidCellOperation) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
 
-    assert_eq!(sm.st.number_stack, vec![0_i64, 1]);
-    assert_eq!(sm.st.cells, vec![3, 2]);
+#[test]
+fn test_load_cells() {
+    let mut sm = StackMachine::default();
+    sm.load_cells(2, &[5, 6]).unwrap();
+    sm.load_cells(1, &[7]).unwrap();
+    assert_eq!(sm.st.cells(), &[0, 7, 5, 6]);
+
+    match sm.load_cells(usize::MAX, &[1]).map_err(|e| e.kind()) {
+        Err(ErrorKind::InvalidCellOperation) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
 }
 
 #[test]
-fn test_execute_movetocells_2() {
+fn test_load_image() {
     let mut sm = StackMachine::default();
+    let image = Image::new(program![LDI 1, LDI 1, MOVEFROMCELLS, RET])
+        .with_data(0, vec![10, 20, 30])
+        .with_data(2, vec![40]);
 
-    // Populate the number stack
-    // -2 Use an invalid number for the number of cells to cause a fault
-    // 0 is the start location to start
-    // 0 is the location to start moving values to
-    // 3 2 1 are the values to use when moving to cells
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, -2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+    sm.load_image(&image).unwrap();
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-    // Execute the instructions
+    assert_eq!(sm.st.number_stack(), &[20]);
+    assert_eq!(sm.st.cells(), &[10, 20, 40]);
+}
+
+#[test]
+fn test_image_round_trip() {
+    let image = Image::new(program![LDI -5, LDI 300, ADD, FREECELLS, RET])
+        .with_data(3, vec![i64::MIN, 2])
+        .with_data(0, vec![]);
+
+    let bytes = image.to_bytes();
+    assert_eq!(&bytes[..5], b"SSPI\x01");
+    // LDI -5 is encoded as opcode number 7 and then the little-endian immediate
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
+        &bytes[13..22],
+        &[7, 0xfb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
     );
+    assert_eq!(Image::from_bytes(&bytes), Ok(image));
 }
 
 #[test]
-fn test_execute_movetocells_3() {
-    let mut sm = StackMachine::default();
+fn test_image_decode_errors() {
+    let bytes = Image::new(program![LDI 1, RET])
+        .with_data(0, vec![1])
+        .to_bytes();
+
+    assert_eq!(Image::from_bytes(b"SSPX\x01"), Err(ImageError::BadMagic));
+    let mut future = bytes.clone();
+    future[4] = IMAGE_VERSION + 1;
+    assert_eq!(
+        Image::from_bytes(&future),
+        Err(ImageError::UnsupportedVersion(IMAGE_VERSION + 1))
+    );
+    assert_eq!(
+        Image::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(ImageError::Truncated)
+    );
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(Image::from_bytes(&trailing), Err(ImageError::TrailingBytes));
+    let mut unknown = bytes;
+    unknown[22] = 255;
+    assert_eq!(
+        Image::from_bytes(&unknown),
+        Err(ImageError::Opcode(DecodeOpcodeError::UnknownCode(255)))
+    );
+}
 
-    // Populate the number stack
-    // 2 is the number of values to move to cells
-    // -5 is an invalid start location to cause a fault
-    // 0 is the location to start moving values to
-    // 3 2 1 are the values to use when moving to cells
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, -5, 2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+#[test]
+fn test_image_checksum() {
+    // The standard CRC-32 check value
+    assert_eq!(Checksum::crc32(b"123456789"), Checksum::Crc32(0xCBF4_3926));
 
-    // Execute the instructions
+    let bytes = Image::new(program![LDI 1, RET]).to_bytes();
+    let checksum = Checksum::crc32(&bytes);
+    assert!(Image::from_bytes_checked(&bytes, &checksum).is_ok());
+
+    let mut tampered = bytes;
+    tampered[13] ^= 1;
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
+        Image::from_bytes_checked(&tampered, &checksum),
+        Err(ImageError::ChecksumMismatch)
     );
 }
 
+#[cfg(feature = "sha2")]
 #[test]
-fn test_execute_movetocells_4() {
-    let mut sm = StackMachine::default();
+fn test_image_sha256() {
+    let bytes = Image::new(program![LDI 1, RET]).to_bytes();
+    let checksum = Checksum::sha256(&bytes);
+    assert!(checksum.matches(&bytes));
+    assert!(!checksum.matches(&bytes[1..]));
+    assert!(matches!(
+        Checksum::sha256(b"abc"),
+        Checksum::Sha256(digest) if digest[..4] == [0xba, 0x78, 0x16, 0xbf]
+    ));
+}
 
-    // Populate the number stack
-    // 3 is the number of values to move to cells, it should cause a fault
-    // 0 is the location to start moving values to
-    // 3 2 1 are the values to use when moving to cells
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 3]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_image_signature() {
+    use ed25519_dalek::{Signer, SigningKey};
 
-    // Execute the instructions
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let bytes = Image::new(program![LDI 1, RET]).to_bytes();
+    let signature = key.sign(&bytes);
+    assert!(Image::from_bytes_signed(&bytes, &signature, &key.verifying_key()).is_ok());
+
+    let other = SigningKey::from_bytes(&[8; 32]);
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
+        Image::from_bytes_signed(&bytes, &signature, &other.verifying_key()),
+        Err(ImageError::BadSignature)
     );
 }
 
 #[test]
-fn test_execute_movefromcells_1() {
-    let mut sm = StackMachine::default();
-
-    // Populate the number stack
-    // 2 is the number of values to move to cells
-    // 0 is the location to start moving values to
-    // 3 2 1 are the values to use when moving to cells
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+fn test_program_cache() {
+    let mut cache = ProgramCache::new();
+    let program = program![LDI 2, LDI 3, ADD, RET];
+
+    let prepared = cache
+        .prepare(&program, &[0], WordSize::Bits64, false)
+        .unwrap();
+    assert_eq!(&*prepared, &program![NOP, NOP, LDI 5, RET][..]);
+    assert!(Arc::ptr_eq(
+        &prepared,
+        &cache
+            .prepare(&program, &[0], WordSize::Bits64, false)
+            .unwrap()
+    ));
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    assert!(cache.contains(&program, &[0], WordSize::Bits64, false));
+
+    // Entered part way through, the program adds to what is already on the stack, so
+    // nothing an entry point lands inside is folded
+    let run = |opcodes: &[Opcode]| {
+        let mut sm = StackMachine::default();
+        sm.st.load_program(opcodes.to_vec());
+        sm.execute_with_stack(1, vec![10], GasLimit::Limited(100))
+            .unwrap()
+    };
+    let mut entered_cache = ProgramCache::new();
+    let entered = entered_cache
+        .prepare(&program, &[0, 1], WordSize::Bits64, false)
+        .unwrap();
+    assert_eq!(&*entered, &program[..]);
+    assert_eq!(run(&entered), vec![13]);
+    assert_eq!(run(&program), vec![13]);
+    assert!(!entered_cache.contains(&program, &[0], WordSize::Bits64, false));
+    let longer = program![LDI 1, DROP, LDI 2, LDI 3, ADD, RET];
+    let folded = entered_cache
+        .prepare(&longer, &[0, 2], WordSize::Bits64, false)
+        .unwrap();
+    assert_eq!(&*folded, &program![LDI 1, DROP, NOP, NOP, LDI 5, RET][..]);
+
+    // Another word size or flags setting is prepared separately
+    assert!(!cache.contains(&program, &[0], WordSize::Bits32, false));
+    assert!(!cache.contains(&program, &[0], WordSize::Bits64, true));
+    let max = i64::from(i32::MAX);
+    let saturating = program![LDI max, LDI 1, ADDSAT, RET];
+    let wide = cache
+        .prepare(&saturating, &[0], WordSize::Bits64, false)
+        .unwrap();
+    let narrow = cache
+        .prepare(&saturating, &[0], WordSize::Bits32, false)
+        .unwrap();
+    assert_eq!(wide[2], Opcode::LDI(max + 1));
+    assert_eq!(narrow[2], Opcode::LDI(max));
+    let flagged = cache
+        .prepare(&program, &[0], WordSize::Bits64, true)
+        .unwrap();
+    assert_eq!(&*flagged, &program[..]);
+    assert_eq!((cache.hits(), cache.misses()), (1, 4));
+    assert_eq!(cache.len(), 4);
+    cache.clear();
+    cache
+        .prepare(&program, &[0], WordSize::Bits64, false)
+        .unwrap();
+    assert_eq!(
+        content_hash(&program),
+        content_hash(&program![LDI 2, LDI 3, ADD, RET])
+    );
+    assert_ne!(content_hash(&program), content_hash(&program![LDI 2, RET]));
 
-    // Setup the cells we will be storing to
-    sm.st.cells.extend_from_slice(&[5, 4]);
+    assert_eq!(
+        cache.prepare(&[], &[0], WordSize::Bits64, false),
+        Err(ValidationError::EmptyProgram)
+    );
+    assert!(!cache.contains(&[], &[0], WordSize::Bits64, false));
+    assert_eq!(cache.len(), 1);
 
-    // Execute the instructions
+    let mut sm = StackMachine::default();
+    sm.st.load_shared_program(prepared);
     sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[5]);
+}
 
-    assert_eq!(sm.st.number_stack, vec![0_i64, 1, 2, 3, 4, 5]);
-    assert_eq!(sm.st.cells, vec![5, 4]);
+#[test]
+fn test_breakpoint_resume() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI 1, LDI 2, ADD, LDI 3, MUL, RET];
+    sm.set_breakpoint(2);
+    sm.set_breakpoint(4);
+
+    sm.execute(0, GasLimit::Limited(5)).unwrap();
+    assert_eq!(sm.paused_at(), Some(2));
+    assert_eq!(sm.st.number_stack(), &[1, 2]);
+
+    sm.resume(GasLimit::Limited(5)).unwrap();
+    assert_eq!(sm.paused_at(), Some(4));
+    sm.resume(GasLimit::Limited(5)).unwrap();
+    assert_eq!(sm.paused_at(), None);
+    assert_eq!(sm.st.number_stack(), &[9]);
+    assert_eq!(sm.st.gas_used(), 5);
+
+    match sm.resume(GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::NotPaused) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
 }
 
 #[test]
-fn test_execute_movefromcells_2() {
+fn test_patch_while_paused() {
     let mut sm = StackMachine::default();
+    let shared: Arc<[Opcode]> = program![LDI 1, LDI 2, ADD, RET].into();
+    sm.st.load_shared_program(shared.clone());
+    sm.set_breakpoint(2);
+    sm.execute(0, GasLimit::Unlimited).unwrap();
 
-    // Populate the number stack
-    // -2 Use an invalid number for the number of cells to cause a fault
-    // 0 is the start location to start
-    // 0 is the location to start moving values from
-    // 3 2 1 are the values left on the stack
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, -2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+    sm.patch(2, &[Opcode::SUB]).unwrap();
+    assert_eq!(&*shared, &program![LDI 1, LDI 2, ADD, RET][..]);
+    sm.resume(GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.st.number_stack(), &[1]);
 
-    // Execute the instructions
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
+        sm.patch(3, &[Opcode::RET, Opcode::RET]),
+        Err(PatchError::OutOfRange { start: 3, len: 2 })
     );
+    assert_eq!(
+        sm.patch(1, &[Opcode::LDI(9), Opcode::JR]),
+        Err(PatchError::Invalid(ValidationError::TargetOutOfRange {
+            pc: 2,
+            target: 11
+        }))
+    );
+    assert_eq!(sm.st.opcodes(), &program![LDI 1, LDI 2, SUB, RET][..]);
+    // Falling through the end halts, so a program need not end in RET
+    sm.patch(3, &[Opcode::NOP]).unwrap();
+    assert_eq!(sm.st.opcodes(), &program![LDI 1, LDI 2, SUB, NOP][..]);
 }
 
 #[test]
-fn test_execute_movefromcells_3() {
+fn test_frozen_code() {
     let mut sm = StackMachine::default();
+    sm.trap_handlers.push(Box::new(TrapHandler::new(1, |_, st| {
+        st.opcodes.push(Opcode::NOP);
+        Ok(TrapHandled::Handled)
+    })));
+    sm.st.opcodes = program![LDI 1, TRAP];
+    sm.st.freeze_code();
+
+    match sm.execute(0, GasLimit::Unlimited) {
+        Err(e) => {
+            assert_eq!(e.kind(), ErrorKind::CodeModified);
+            assert_eq!(e.pc(), Some(1));
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.patch(0, &[Opcode::LDI(2)]), Err(PatchError::Frozen));
 
-    // Populate the number stack
-    // 2 is the number of values to move from cells
-    // -5 is an invalid start location to cause a fault
-    // 0 is the location to start moving values from
-    // 3 2 1 are the values left on the stack
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, -5, 2]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+    sm.st.load_program(program![LDI 2, RET]);
+    match sm.execute(0, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::CodeModified) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
 
-    // Execute the instructions
-    assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
-    );
+    sm.st.thaw_code();
+    sm.execute(0, GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.st.number_stack(), &[2]);
 }
 
 #[test]
-fn test_execute_movefromcells_4() {
+fn test_state_hash() {
+    let mut a = StackMachine::default();
+    a.st.opcodes = program![LDI 2, NEWCELLS, LDI 3, RET];
+    let mut b = StackMachine::default();
+    b.st.load_shared_program(program![LDI 2, NEWCELLS, LDI 3, RET].into());
+    b.enable_statistics();
+
+    assert_eq!(a.st.state_hash(), b.st.state_hash());
+    a.execute(0, GasLimit::Unlimited).unwrap();
+    b.execute(0, GasLimit::Unlimited).unwrap();
+    assert_eq!(a.st.state_hash(), b.st.state_hash());
+
+    // The same values in a different place
+    let mut c = a.st.clone();
+    c.set_number_stack(vec![0]);
+    c.push_scratch(3);
+    assert_ne!(a.st.state_hash(), c.state_hash());
+}
+
+#[test]
+fn test_single_step() {
     let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI 1, LDI 2, ADD, RET];
+
+    sm.start_paused(0);
+    assert_eq!(sm.paused_at(), Some(0));
+    sm.step(GasLimit::Unlimited).unwrap();
+    sm.step(GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.paused_at(), Some(2));
+    assert_eq!(sm.st.number_stack(), &[1, 2]);
+    sm.resume(GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.paused_at(), None);
+    assert_eq!(sm.st.number_stack(), &[3]);
+}
 
-    // Populate the number stack
-    // 3 is the number of values to move from cells, it should cause a fault
-    // 0 is a start location
-    // 0 is the location to start moving values from
-    // 3 2 1 are the values left on the stack
-    sm.st
-        .number_stack
-        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 3]);
-    // Put the opcodes into the *memory*
-    sm.st
-        .opcodes
-        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+#[test]
+fn test_run_lockstep() {
+    let mut a = StackMachine::default();
+    a.st.opcodes = program![LDI 6, LDI 2, DIV, LDI 1, ADD, RET];
+    let mut b = StackMachine::default();
+    b.st.load_shared_program(a.st.opcodes.clone().into());
+
+    assert_eq!(run_lockstep(&mut a, &mut b, 0, GasLimit::Unlimited), None);
+    assert_eq!(a.st.number_stack(), &[4]);
+
+    b.patch(4, &[Opcode::SUB]).unwrap();
+    let divergence = run_lockstep(&mut a, &mut b, 0, GasLimit::Unlimited).unwrap();
+    assert_eq!(divergence.step, 4);
+    assert_eq!(divergence.pcs, (4, 4));
+    assert_eq!(divergence.errors, (None, None));
+
+    b.st.set_number_stack(vec![]);
+    let divergence = run_lockstep(&mut a, &mut b, 0, GasLimit::Unlimited).unwrap();
+    assert_eq!(divergence.step, 0);
+}
 
-    // Execute the instructions
+#[test]
+fn test_cell_limit() {
+    let mut sm = StackMachine::default();
+    sm.st.set_cell_limit(Some(4));
+    sm.st.opcodes = program![LDI 3, NEWCELLS, LDI 2, NEWCELLS, RET];
+
+    match sm.execute(0, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::CellLimitExceeded) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.cells().len(), 3);
+    assert!(sm.load_cells(3, &[1, 2]).is_err());
+    sm.load_cells(3, &[1]).unwrap();
+}
+
+#[test]
+fn test_deterministic_profile() {
+    let profile = DeterministicProfile::new(1000, 16);
+    let program: Arc<[Opcode]> = program![LDI 10, LDI 4, NEWCELLS, LDI 1, MOVETOCELLS, RET].into();
+    let mut replicas: Vec<StackMachine> = (0..2)
+        .map(|_| {
+            let mut sm = StackMachine::default();
+            sm.st.load_shared_program(program.clone());
+            sm.set_deterministic_profile(profile);
+            sm
+        })
+        .collect();
+    for sm in replicas.iter_mut() {
+        sm.execute(0, GasLimit::Unlimited).unwrap();
+    }
+    assert_eq!(replicas[0].st.state_hash(), replicas[1].st.state_hash());
+
+    // The profile's gas limit applies to unlimited runs
+    let mut sm = StackMachine::default();
+    sm.set_deterministic_profile(profile);
+    sm.st.opcodes = program![LDI - 1, JR];
+    match sm.execute(0, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::RanOutOfGas) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.gas_used(), 1001);
+
+    sm.enable_trap_handler_stats();
+    match sm.execute(0, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::NondeterministicFeature) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    sm.disable_trap_handler_stats();
+    sm.st.word_size = WordSize::Bits32;
+    match sm.execute(0, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::NondeterministicFeature) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_step_limit() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![LDI - 1, JR];
+    sm.st.set_step_limit(Some(10));
+
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => {
+            assert_eq!(e.kind(), ErrorKind::StepLimitExceeded);
+            assert_eq!(e.pc(), Some(0));
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.steps(), 11);
+
+    sm.st.set_step_limit(None);
+    sm.st.opcodes = program![LDI 1, RET];
+    sm.execute(0, GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.st.steps(), 1);
+}
+
+#[test]
+fn test_exit_status() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.push(Box::new(TrapHandler::new(7, |_, _| {
+        Ok(TrapHandled::Handled)
+    })));
+
+    sm.st.opcodes = program![LDI 1, RET];
     assert_eq!(
-        match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::InvalidCellOperation) => 1,
-            _ => 0,
-        },
-        1
+        sm.execute(0, GasLimit::Unlimited).unwrap(),
+        ExitStatus::Returned
     );
+    sm.st.opcodes = program![LDI 1, LDI 2];
+    assert_eq!(
+        sm.execute(0, GasLimit::Unlimited).unwrap(),
+        ExitStatus::Halted
+    );
+    sm.st.opcodes = program![LDI 7, TRAP, RET];
+    assert_eq!(
+        sm.execute(0, GasLimit::Unlimited).unwrap(),
+        ExitStatus::TrapExit(7)
+    );
+
+    sm.set_breakpoint(1);
+    assert_eq!(
+        sm.execute(0, GasLimit::Unlimited).unwrap(),
+        ExitStatus::BreakpointHit(1)
+    );
+    sm.clear_breakpoint(1);
+    sm.start_paused(0);
+    assert_eq!(sm.step(GasLimit::Unlimited).unwrap(), ExitStatus::Yielded);
+
+    match sm.execute(4, GasLimit::Unlimited).map_err(|e| e.kind()) {
+        Err(ErrorKind::PcOutOfRange) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_call_within_trap() {
+    let mut sm = StackMachine::default();
+    // Trap 1 applies the callback whose address is on top of the stack to each of the values
+    // under it
+    sm.trap_handlers.push(Box::new(TrapHandler::new(1, |_, st| {
+        let callback = usize::try_from(st.pop_number()?)?;
+        let values = std::mem::take(&mut st.number_stack);
+        for value in values {
+            st.push_number(value);
+            call_within_trap(st, callback, GasLimit::Limited(10))?;
+        }
+        Ok(TrapHandled::Handled)
+    })));
+    sm.st.opcodes = program![
+        LDI @main,
+        CALL,
+        RET,
+        main:
+        LDI 3,
+        LDI 4,
+        LDI @square,
+        LDI 1,
+        TRAP,
+        square:
+        DUP,
+        MUL,
+        RET,
+    ];
+
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::TrapExit(1)
+    );
+    assert_eq!(sm.st.number_stack(), &[9, 16]);
+    assert_eq!(sm.st.pc, 7);
+    // The handler was called from inside main
+    assert_eq!(sm.st.return_stack, vec![2]);
+    assert_eq!(sm.st.gas_used(), 10);
+}
+
+#[test]
+fn test_sandbox_trap() {
+    let limits = SandboxLimits {
+        gas_limit: 50,
+        step_limit: 50,
+        cell_limit: 8,
+        max_inputs: 4,
+        max_results: 4,
+        max_depth: 1,
+    };
+    let run = |entry: usize| {
+        let mut sm = StackMachine::default();
+        sm.trap_handlers
+            .register(Box::new(SandboxTrap::new(9, limits)));
+        sm.st.opcodes = program![
+            LDI 3,
+            LDI 4,
+            LDI 2,
+            LDI @entry,
+            LDI 1,
+            LDI 9,
+            TRAP,
+            add:
+            ADD,
+            RET,
+            spin:
+            LDI -1,
+            JR,
+            nested:
+            LDI 1,
+            LDI @add,
+            LDI 1,
+            LDI 9,
+            TRAP,
+            entry:
+        ];
+        sm.st.opcodes[3] = Opcode::LDI(entry as i64);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        sm.st.number_stack().to_vec()
+    };
+
+    assert_eq!(run(7), vec![7, -1]);
+    // Children that run out of gas fail
+    assert_eq!(run(9), vec![0]);
+    // A child can use a sandbox of its own, here the grandchild fails as ADD gets one input,
+    // and its false flag is the child's result
+    assert_eq!(run(11), vec![0, -1]);
+}
+
+#[test]
+fn test_sandbox_trap_charges_parent_gas() {
+    let limits = SandboxLimits {
+        gas_limit: 10_000,
+        step_limit: 10_000,
+        cell_limit: 8,
+        max_inputs: 4,
+        max_results: 4,
+        max_depth: 0,
+    };
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(SandboxTrap::new(9, limits)));
+    // The child counts down from 400, five gas a time
+    sm.st.load_program(program![
+        LDI 0,
+        LDI @child,
+        LDI 0,
+        LDI 9,
+        TRAP,
+        child:
+        LDI 400,
+        top:
+        LDI -1,
+        ADD,
+        DUP,
+        LDI %top,
+        JRNZ,
+        DROP,
+        RET,
+    ]);
+    sm.execute(5, GasLimit::Unlimited).unwrap();
+    let child_gas = sm.st.gas_used();
+    assert_eq!(child_gas, 2002);
+
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(10_000)).unwrap(),
+        ExitStatus::TrapExit(9)
+    );
+    assert_eq!(sm.st.number_stack(), &[-1]);
+    assert_eq!(sm.st.gas_used(), 4 + child_gas);
+
+    // The child's gas takes the parent over its limit, and over the trap's budget
+    match sm.execute(0, GasLimit::Limited(10)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::RanOutOfGas),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    sm.st.set_trap_budget(9, TrapBudget::gas(100));
+    match sm.execute(0, GasLimit::Limited(10_000)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::TrapBudgetExceeded { trap_id: 9 }),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_spill() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = program![
+        LDI 4, NEWCELLS, DROP, LDI 1, LDI 2, LDI 3, SPILL 2 1, LDI 9, UNSPILL 2 1, RET
+    ];
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.cells(), &[0, 2, 3, 0]);
+    assert_eq!(sm.st.number_stack(), &[1, 9, 2, 3]);
+
+    const CODE: &[Opcode] = ssp_asm! { SPILL 2 1; UNSPILL 2 1 };
+    assert_eq!(CODE, &[Opcode::SPILL(2, 1), Opcode::UNSPILL(2, 1)]);
+    assert_eq!("SPILL 2 1".parse::<Opcode>(), Ok(Opcode::SPILL(2, 1)));
+    assert_eq!(Opcode::UNSPILL(2, 1).to_string(), "UNSPILL 2 1");
+    let encoded = Opcode::SPILL(u32::MAX, 7).encode();
+    assert_eq!(Opcode::try_from(encoded), Ok(Opcode::SPILL(u32::MAX, 7)));
+
+    for (op, expected) in [
+        (
+            Opcode::SPILL(3, 0),
+            ErrorKind::StackUnderflow(StackKind::Number),
+        ),
+        (Opcode::SPILL(1, 4), ErrorKind::InvalidCellOperation),
+        (Opcode::UNSPILL(2, 3), ErrorKind::InvalidCellOperation),
+    ] {
+        sm.st.load_program(vec![op, Opcode::RET]);
+        sm.st.number_stack_mut().truncate(2);
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(kind) if kind == expected => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_ldpc() {
+    let code = program![LDPC @square, LDI 7, SWAP, CALL, RET, square: DUP, MUL, RET];
+    assert_eq!(code[0], Opcode::LDPC(5));
+    assert_eq!(
+        ssp_asm! { LDPC @square; LDI 7; SWAP; CALL; RET; square: DUP; MUL; RET },
+        &code[..]
+    );
+    assert_eq!(crate::validate(&code), Ok(()));
+
+    // The same code loaded after a prefix still calls its own subroutine
+    let mut sm = StackMachine::default();
+    let mut relocated = vec![Opcode::NOP; 3];
+    relocated.extend(code);
+    sm.st.load_program(relocated);
+
+    sm.execute(3, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[49]);
+}
+
+#[test]
+fn test_load_library() {
+    let mut builder = ProgramBuilder::new();
+    builder
+        .label("square")
+        .op(Opcode::DUP)
+        .op(Opcode::MUL)
+        .op(Opcode::RET)
+        .label("cube")
+        .op(Opcode::DUP)
+        .ldi_address("square")
+        .op(Opcode::CALL)
+        .op(Opcode::MUL)
+        .op(Opcode::RET);
+    let library = builder.build_library().unwrap();
+    assert_eq!(library.relocations, vec![4]);
+
+    let mut sm = StackMachine::default();
+    sm.st.load_program(program![LDI 3, RET]);
+    assert_eq!(sm.st.load_library(&library), Ok(2));
+    let cube = sm.st.exports().address_of("cube").unwrap();
+    assert_eq!(cube, 5);
+
+    let result = sm.execute_with_stack(cube, vec![3], GasLimit::Limited(100));
+
+    assert_eq!(result.unwrap(), vec![27]);
+    assert_eq!(
+        sm.st.load_library(&library),
+        Err(LinkError::DuplicateExport("square".to_string()))
+    );
+    assert_eq!(sm.st.opcodes().len(), 10);
+
+    // Relocated code that jumps outside the program is refused, as is an export that does
+    let mut builder = ProgramBuilder::new();
+    builder.label("far").op(Opcode::LDI(40)).op(Opcode::JMP);
+    let far = builder.build_library().unwrap();
+    assert_eq!(
+        sm.st.load_library(&far),
+        Err(LinkError::Invalid(ValidationError::TargetOutOfRange {
+            pc: 11,
+            target: 40
+        }))
+    );
+    let mut hidden = Library {
+        opcodes: program![RET, LDI 40, JMP],
+        ..Library::default()
+    };
+    hidden.exports.insert(1, "hidden");
+    assert!(matches!(
+        sm.st.load_library(&hidden),
+        Err(LinkError::Invalid(_))
+    ));
+    assert_eq!(sm.st.opcodes().len(), 10);
+    assert_eq!(sm.st.exports().address_of("hidden"), None);
+
+    // A shared program is copied once, then extended in place
+    let shared = sm.st.share_program();
+    let mut builder = ProgramBuilder::new();
+    builder
+        .label("twice")
+        .op(Opcode::DUP)
+        .op(Opcode::ADD)
+        .op(Opcode::RET);
+    assert_eq!(
+        sm.st.load_library(&builder.build_library().unwrap()),
+        Ok(10)
+    );
+    assert_eq!(shared.len(), 10);
+    assert_eq!(sm.st.opcodes().len(), 13);
+    let result = sm.execute_with_stack(10, vec![4], GasLimit::Limited(100));
+    assert_eq!(result.unwrap(), vec![8]);
+}
+
+#[test]
+fn test_aliased_labels() {
+    let mut builder = ProgramBuilder::new();
+    builder
+        .op(Opcode::RET)
+        .label("square")
+        .label("sq")
+        .op(Opcode::DUP)
+        .op(Opcode::MUL)
+        .op(Opcode::RET);
+    let library = builder.build_library().unwrap();
+    assert_eq!(
+        library.exports.iter().collect::<Vec<_>>(),
+        vec![(1, "sq"), (1, "square")]
+    );
+    assert_eq!(library.exports.name_at(1), Some("sq"));
+    assert_eq!(library.exports.describe(2), "sq+1");
+    assert_eq!(library.exports.len(), 2);
+
+    // Every alias is exported
+    let mut sm = StackMachine::default();
+    sm.st.load_program(program![LDI 3, RET]);
+    assert_eq!(sm.st.load_library(&library), Ok(2));
+    assert_eq!(sm.st.exports().address_of("square"), Some(3));
+    assert_eq!(sm.st.exports().address_of("sq"), Some(3));
+    assert_eq!(
+        sm.st.exports().names_at(3).collect::<Vec<_>>(),
+        vec!["sq", "square"]
+    );
+
+    // Naming another address moves the name
+    let mut symbols = library.exports;
+    symbols.insert(0, "sq");
+    assert_eq!(symbols.address_of("sq"), Some(0));
+    assert_eq!(symbols.name_at(1), Some("square"));
+    assert_eq!(symbols.len(), 2);
+}
+
+#[test]
+fn test_dictionary_traps() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(DictionaryTraps::new(100)));
+    let name: Vec<i64> = "sq".chars().map(|c| c as i64).collect();
+    sm.load_cells(0, &name).unwrap();
+    sm.st.load_program(program![LDI 0, LDI 2, LDI 101, TRAP]);
+
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::DictionaryNotEnabled) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    sm.enable_dictionary();
+    sm.st.dictionary_mut().unwrap().create("dup", 3);
+    sm.st.number_stack_mut().clear();
+    sm.st
+        .load_program(program![LDI 7, LDI 0, LDI 2, LDI 100, TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.dictionary().unwrap().find("sq"), Some(7));
+
+    sm.st.load_program(program![LDI 0, LDI 2, LDI 101, TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[7, -1]);
+
+    sm.st.dictionary_mut().unwrap().create("sq", 9);
+    assert_eq!(sm.st.dictionary().unwrap().find("sq"), Some(9));
+    assert!(sm.st.dictionary_mut().unwrap().forget("sq"));
+    assert_eq!(sm.st.dictionary().unwrap().find("sq"), Some(7));
+    sm.st.load_program(program![LDI 0, LDI 2, LDI 102, TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[7, -1, -1]);
+    assert_eq!(
+        sm.st.dictionary().unwrap().iter().collect::<Vec<_>>(),
+        vec![("dup", 3)]
+    );
+}
+
+#[test]
+fn test_dodoes() {
+    // Two words made by a CONSTANT defining word, sharing the behavior at 8
+    const CODE: &[Opcode] = ssp_asm! {
+        LDI 6; CALL; LDI 7; CALL; ADD; RET;
+        DODOES 0 8;
+        DODOES 1 8;
+        LDI 1; MOVEFROMCELLS; RET
+    };
+    assert_eq!(CODE[6], Opcode::DODOES(0, 8));
+    assert_eq!(crate::validate(CODE), Ok(()));
+
+    let mut sm = StackMachine::default();
+    sm.load_cells(0, &[5, 7]).unwrap();
+    sm.st.load_program(CODE.to_vec());
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[12]);
+}
+
+#[test]
+fn test_trapid() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(42, |_, st| {
+            st.push_number(7);
+            Ok(TrapHandled::Handled)
+        })));
+    sm.st.load_program(program![LDI 1, TRAPID 42]);
+
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::TrapExit(42)
+    );
+
+    assert_eq!(sm.st.number_stack(), &[1, 7]);
+    assert_eq!(analyze(sm.st.opcodes()).trap_count, 1);
+    assert_eq!("TRAPID -3".parse::<Opcode>(), Ok(Opcode::TRAPID(-3)));
+}
+
+#[test]
+fn test_trap_usage() {
+    let usage = trap_usage(&program![
+        LDI %other,
+        JRZ,
+        TRAPID 3,
+        other:
+        LDI 2,
+        LDI 5,
+        ADD,
+        TRAP
+    ]);
+    assert_eq!(usage.trap_ids, [3, 7].iter().copied().collect());
+    assert!(usage.only_uses(|trap_id| trap_id < 10));
+    assert!(!usage.only_uses(|trap_id| trap_id < 5));
+
+    // The id of the TRAP depends on the number stack the program is given
+    let usage = trap_usage(&program![TRAP, RET]);
+    assert_eq!(usage.dynamic_traps, vec![0]);
+    assert!(!usage.only_uses(|_| true));
+}
+
+#[test]
+fn test_trap_permissions() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::any(|_, _| Ok(TrapHandled::Handled))));
+    let permissions = TrapPermissions::new().allow(1).allow_range(10..=19);
+    sm.st.set_trap_permissions(Some(permissions.clone()));
+
+    for trap_id in [1, 10, 19] {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute(0, GasLimit::Limited(10)).unwrap();
+    }
+    for trap_id in [2, 20] {
+        sm.st.load_program(vec![Opcode::LDI(trap_id), Opcode::TRAP]);
+        match sm.execute(0, GasLimit::Limited(10)).map_err(|e| e.kind()) {
+            Err(ErrorKind::PermissionDenied { trap_id: denied }) if denied == trap_id => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    let usage = trap_usage(&[Opcode::TRAPID(1), Opcode::RET]);
+    assert!(usage.only_uses(|trap_id| permissions.permits(trap_id)));
+}
+
+#[test]
+fn test_trap_quotas() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::any(|trap_id, st| {
+            st.charge("bytes_out", trap_id as u64)?;
+            Ok(TrapHandled::Handled)
+        })));
+    let permissions = TrapPermissions::new()
+        .allow_range(0..=9)
+        .limit_traps("file_io", 5..=9, 2)
+        .limit_resource("bytes_out", 20);
+    sm.st.set_trap_permissions(Some(permissions));
+    let mut raise = |trap_id| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute(0, GasLimit::Limited(10)).map_err(|e| e.kind())
+    };
+
+    assert!(raise(5).is_ok());
+    assert!(raise(6).is_ok());
+    assert_eq!(raise(7), Err(ErrorKind::QuotaExceeded { quota: "file_io" }));
+    assert!(raise(4).is_ok());
+    assert!(raise(3).is_ok());
+    assert_eq!(
+        raise(3),
+        Err(ErrorKind::QuotaExceeded { quota: "bytes_out" })
+    );
+
+    let permissions = sm.st.trap_permissions_mut().unwrap();
+    assert_eq!(permissions.usage("file_io"), Some(2));
+    assert_eq!(permissions.usage("bytes_out"), Some(18));
+    permissions.reset_usage();
+    assert_eq!(permissions.usage("file_io"), Some(0));
+}
+
+#[test]
+fn test_input_and_output_queues() {
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(program![READIN, READIN, ADD, WRITEOUT, RET]);
+    sm.st.input_queue_mut().push_back(3);
+
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Yielded
+    );
+    assert_eq!(sm.paused_at(), Some(1));
+
+    sm.st.input_queue_mut().push_back(4);
+    assert_eq!(
+        sm.resume(GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Returned
+    );
+    assert_eq!(sm.st.take_output(), vec![7]);
+    assert!(sm.st.output_queue().is_empty());
+    // The READIN that waited for input is only charged once
+    assert_eq!(sm.st.gas_used(), 4);
+}
+
+#[test]
+fn test_input_stream() {
+    // Sum the stream
+    let mut sm = StackMachine::default();
+    sm.st.load_program(program![
+        LDI 0,
+        top:
+        NEXTIN,
+        LDI %done,
+        JRZ,
+        ADD,
+        LDI %top,
+        JR,
+        done:
+        RET
+    ]);
+    sm.attach_input_stream(1..=100);
+
+    sm.execute(0, GasLimit::Limited(1000)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[5050]);
+    assert!(sm.detach_input_stream().unwrap().next().is_none());
+    // Nothing is left to keep going with once the gas runs out
+    sm.attach_input_stream(std::iter::repeat(1));
+    match sm.execute(0, GasLimit::Limited(1000)).map_err(|e| e.kind()) {
+        Err(ErrorKind::RanOutOfGas) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_writer() {
+    #[derive(Clone, Default)]
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let output = Shared::default();
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(program![LDI 104, WRITEBYTE, LDI 105, WRITEBYTE, LDI 258, WRITECELL, RET]);
+    sm.set_writer(output.clone());
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    // Flushed at the end of the run
+    assert_eq!(&output.0.borrow()[..2], b"hi");
+    assert_eq!(&output.0.borrow()[2..], &258i64.to_le_bytes());
+
+    sm.st.load_program(program![LDI 256, WRITEBYTE, RET]);
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::NumericOverflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    assert!(sm.take_writer().unwrap().is_some());
+    sm.st.load_program(program![LDI 1, WRITEBYTE, RET]);
+    let error = sm.execute(0, GasLimit::Limited(100)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::IoError);
+    assert_eq!(error.pc(), Some(1));
+}
+
+#[test]
+fn test_log() {
+    #[derive(Clone, Default)]
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<LogRecord>>>);
+
+    impl LogSink for Shared {
+        fn log(&mut self, record: &LogRecord) {
+            self.0.borrow_mut().log(record);
+        }
+    }
+
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(program![LDI 7, LDI 42, LOG 3, LDI 1, ADD, LOG 5, RET]);
+
+    // Dropped without a sink
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert!(sm.st.number_stack().is_empty());
+
+    let records = Shared::default();
+    sm.set_log_sink(records.clone());
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(
+        *records.0.borrow(),
+        vec![
+            LogRecord {
+                level: 3,
+                pc: 2,
+                value: 42
+            },
+            LogRecord {
+                level: 5,
+                pc: 5,
+                value: 8
+            }
+        ]
+    );
+    assert_eq!("LOG 3".parse::<Opcode>(), Ok(Opcode::LOG(3)));
+}
+
+#[test]
+fn test_assert() {
+    let mut sm = StackMachine::default();
+    // ( a b -- ) asserting that a and b differ
+    let opcodes = program![SUB, ASSERT 17, RET];
+    sm.st.load_program(opcodes.clone());
+
+    sm.execute_with_stack(0, vec![5, 6], GasLimit::Limited(100))
+        .unwrap();
+
+    match sm
+        .execute_with_stack(0, vec![5, 5], GasLimit::Limited(100))
+        .map_err(|e| e.kind())
+    {
+        Err(ErrorKind::AssertionFailed {
+            pc: 1,
+            message_id: 17,
+        }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    #[cfg(feature = "symexec")]
+    {
+        use symexec::{explore, FindingKind, SymexecOptions};
+
+        let report = explore(&opcodes, SymexecOptions::default());
+        let finding = report
+            .findings
+            .iter()
+            .find(|f| f.kind == FindingKind::AssertionFailed)
+            .unwrap();
+        assert_eq!(finding.pc, 1);
+        assert_eq!(finding.inputs[0], finding.inputs[1]);
+    }
+}
+
+#[test]
+fn test_bad_operands_are_errors() {
+    use Opcode::*;
+
+    let mut sm = StackMachine::default();
+    let cases = [
+        (vec![LDI(-5), JR], ErrorKind::PcOutOfRange),
+        (vec![LDI(i64::MIN), JR], ErrorKind::PcOutOfRange),
+        (vec![LDI(1), LDI(-5), JRNZ], ErrorKind::PcOutOfRange),
+        (vec![LDI(-1), JMP], ErrorKind::PcOutOfRange),
+        (vec![LDI(-1), CALL], ErrorKind::PcOutOfRange),
+        (
+            vec![LDI(1), NEWCELLS, LDI(1), LDI(i64::MAX), MOVETOCELLS],
+            ErrorKind::InvalidCellOperation,
+        ),
+        (
+            vec![LDI(1), NEWCELLS, LDI(i64::MAX), MOVEFROMCELLS],
+            ErrorKind::InvalidCellOperation,
+        ),
+    ];
+    for (opcodes, kind) in cases {
+        sm.st.load_program(opcodes);
+        sm.st.set_number_stack(vec![]);
+        assert_eq!(
+            sm.execute(0, GasLimit::Limited(100)).unwrap_err().kind(),
+            kind
+        );
+        // A failed CALL pushes no return address
+        assert!(sm.st.return_stack().is_empty());
+    }
+}
+
+#[test]
+fn test_newcells_too_large_to_allocate() {
+    let mut sm = StackMachine::default();
+    // No cell limit, the allocation itself fails
+    sm.st
+        .load_program(vec![Opcode::LDI(i64::MAX), Opcode::NEWCELLS, Opcode::RET]);
+
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::CellLimitExceeded) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert!(sm.st.cells().is_empty());
+}
+
+#[test]
+fn test_take_results() {
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(program![LDI 2, NEWCELLS, LDI 7, LDI 8, RET]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    let stack = sm.st.number_stack().as_ptr();
+    let taken = sm.st.take_number_stack();
+    // Moved, not copied
+    assert_eq!(taken.as_ptr(), stack);
+    assert_eq!(taken, vec![0, 7, 8]);
+    assert!(sm.st.number_stack().is_empty());
+
+    assert_eq!(sm.st.take_cells(), vec![0, 0]);
+    assert!(sm.st.cells().is_empty());
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    let mut state = sm.into_state();
+    assert_eq!(state.take_number_stack(), vec![0, 7, 8]);
+    assert_eq!(state.cells(), &[0, 0]);
+}
+
+#[test]
+fn test_string_table() {
+    let mut sm = StackMachine::default();
+    let message = sm.st.intern("x must not be zero");
+    assert_eq!(sm.st.intern("x must not be zero"), message);
+    assert_ne!(message, 0);
+    let name = sm.st.intern("x");
+
+    sm.st.load_program(vec![
+        Opcode::LDI(name),
+        Opcode::LOG(3),
+        Opcode::ASSERT(message),
+        Opcode::RET,
+    ]);
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<LogRecord>>>);
+
+    impl LogSink for Shared {
+        fn log(&mut self, record: &LogRecord) {
+            self.0.borrow_mut().push(*record);
+        }
+    }
+
+    let records = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    sm.set_log_sink(Shared(records.clone()));
+
+    let error = sm
+        .execute_with_stack(0, vec![0], GasLimit::Limited(100))
+        .unwrap_err();
+    assert_eq!(sm.st.string(records.borrow()[0].value), Some("x"));
+    match error.kind() {
+        ErrorKind::AssertionFailed { message_id, .. } => {
+            assert_eq!(sm.st.string(message_id), Some("x must not be zero"))
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.string(0), None);
+    assert_eq!(
+        sm.st.strings().iter().collect::<Vec<_>>(),
+        vec![(1, "x must not be zero"), (2, "x")]
+    );
+}
+
+#[test]
+fn test_string_traps() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(StringTraps::new(200)));
+    let answer = sm.st.intern("answer: ");
+    let name: Vec<i64> = " 42".chars().map(|c| c as i64).collect();
+    sm.load_cells(0, &name).unwrap();
+
+    // INTERN the number in cells, then PARSE it
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+            .unwrap()
+    };
+    let id = run(&mut sm, vec![0, 3], 200)[0];
+    assert_eq!(run(&mut sm, vec![id], 204), vec![42, -1]);
+    assert_eq!(run(&mut sm, vec![answer], 204), vec![0]);
+
+    // FORMAT, CONCAT, LENGTH and COMPARE
+    let formatted = run(&mut sm, vec![-7], 205)[0];
+    let joined = run(&mut sm, vec![answer, formatted], 202)[0];
+    assert_eq!(sm.st.string(joined), Some("answer: -7"));
+    assert_eq!(run(&mut sm, vec![joined], 206), vec![10]);
+    assert_eq!(run(&mut sm, vec![answer, joined], 203), vec![-1]);
+    assert_eq!(run(&mut sm, vec![joined, joined], 203), vec![0]);
+
+    // STORE is UTF-8 aware, one character to a cell
+    let snowman = sm.st.intern("☃!");
+    assert_eq!(run(&mut sm, vec![snowman, 1], 201), vec![2]);
+    assert_eq!(sm.st.read_string(0, 3).unwrap(), " ☃!");
+
+    sm.st.load_program(vec![Opcode::TRAPID(206)]);
+    match sm
+        .execute_with_stack(0, vec![99], GasLimit::Limited(100))
+        .map_err(|e| e.kind())
+    {
+        Err(ErrorKind::UnknownString { id: 99 }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[cfg(feature = "fs-traps")]
+#[test]
+fn test_file_traps() {
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("ssp-fs-in-{}", std::process::id()));
+    let output = dir.join(format!("ssp-fs-out-{}", std::process::id()));
+    std::fs::write(&input, "hi").unwrap();
+
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(
+        FileTraps::new(300)
+            .allow("in", &input, FileAccess::Read)
+            .allow("out", &output, FileAccess::Write),
+    ));
+    sm.load_cells(0, &[0; 4]).unwrap();
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+    };
+
+    let secret = sm.st.intern("/etc/passwd");
+    assert_eq!(run(&mut sm, vec![secret], 300).unwrap(), vec![0]);
+
+    let name = sm.st.intern("in");
+    let opened = run(&mut sm, vec![name], 300).unwrap();
+    assert_eq!(opened, vec![1, -1]);
+    assert_eq!(run(&mut sm, vec![1, 0, 4], 301).unwrap(), vec![2]);
+    assert_eq!(run(&mut sm, vec![1, 0, 4], 301).unwrap(), vec![0]);
+    assert_eq!(&sm.st.cells()[..2], &[104, 105]);
+    // Opened for reading only
+    assert_eq!(
+        run(&mut sm, vec![1, 0, 2], 302).unwrap_err().kind(),
+        ErrorKind::IoError
+    );
+    run(&mut sm, vec![1], 303).unwrap();
+    assert_eq!(
+        run(&mut sm, vec![1, 0, 2], 301).unwrap_err().kind(),
+        ErrorKind::BadFileHandle { handle: 1 }
+    );
+
+    let name = sm.st.intern("out");
+    assert_eq!(run(&mut sm, vec![name], 300).unwrap(), vec![1, -1]);
+    run(&mut sm, vec![1, 0, 2], 302).unwrap();
+    run(&mut sm, vec![1], 303).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"hi");
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_kv_traps() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    let store = Rc::new(RefCell::new(HashMap::new()));
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(KvTraps::with_backend(400, store.clone())));
+    let count = sm.st.intern("count");
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+            .unwrap()
+    };
+
+    assert_eq!(run(&mut sm, vec![count], 400), vec![0]);
+    run(&mut sm, vec![1, count], 401);
+    assert_eq!(store.borrow().get("count"), Some(&1));
+
+    // The host changes the value between runs
+    store.borrow_mut().insert("count".to_string(), 5);
+    assert_eq!(run(&mut sm, vec![count], 400), vec![5, -1]);
+    assert_eq!(run(&mut sm, vec![count], 402), vec![-1]);
+    assert_eq!(run(&mut sm, vec![count], 402), vec![0]);
+    assert!(store.borrow().is_empty());
+
+    let mut traps = KvTraps::new(0);
+    traps.backend_mut().set("x", 3).unwrap();
+    assert_eq!(traps.backend()["x"], 3);
+}
+
+#[test]
+fn test_session() {
+    let mut sm = StackMachine::default();
+    sm.enable_dictionary();
+    sm.st.dictionary_mut().unwrap().create("square", 4);
+    let greeting = sm.st.intern("hello");
+    sm.load_cells(0, &[1, 2, 3]).unwrap();
+    sm.st.set_number_stack(vec![9]);
+
+    let bytes = sm.save_session().to_bytes();
+    let session = Session::from_bytes(&bytes).unwrap();
+    assert_eq!(session, sm.save_session());
+
+    let mut resumed = StackMachine::default();
+    resumed.enable_cell_allocator(1, 0);
+    resumed.restore_session(session);
+    assert_eq!(resumed.st.cells(), &[1, 2, 3]);
+    assert_eq!(
+        resumed.cell_allocations(),
+        Some(vec![Allocation { address: 0, len: 3 }])
+    );
+    assert_eq!(resumed.st.dictionary().unwrap().find("square"), Some(4));
+    assert_eq!(resumed.st.string(greeting), Some("hello"));
+    // The stacks are not part of a session
+    assert!(resumed.st.number_stack().is_empty());
+
+    assert_eq!(
+        Session::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(ImageError::Truncated)
+    );
+}
+
+#[test]
+fn test_context() {
+    // EVALUATE, running the code at TOS in a context of its own and pushing what it leaves
+    struct Evaluate;
+
+    impl HandleTrap for Evaluate {
+        fn handle_trap(
+            &mut self,
+            trap_id: i64,
+            st: &mut StackMachineState,
+        ) -> Result<TrapHandled, StackMachineError> {
+            if trap_id != 5 {
+                return Ok(TrapHandled::NotHandled);
+            }
+            let address = usize::try_from(st.pop_number()?)?;
+            let outer = st.take_context();
+            call_within_trap(st, address, GasLimit::Limited(100))?;
+            let inner = st.restore_context(outer);
+            for value in inner.number_stack {
+                st.push_number(value);
+            }
+            Ok(TrapHandled::Handled)
+        }
+    }
+
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(Evaluate));
+    // The evaluated code at 5 sees none of the caller's stack
+    sm.st
+        .load_program(program![LDI 1, LDI 5, LDI 5, TRAP, RET, LDI 40, LDI 2, ADD, RET]);
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[1, 42]);
+    assert_eq!(sm.st.save_context().pc, 3);
+
+    let saved = sm.st.save_context();
+    sm.st.push_number(7);
+    assert_eq!(
+        sm.st.restore_context(saved.clone()).number_stack,
+        &[1, 42, 7]
+    );
+    assert_eq!(sm.st.save_context(), saved);
+}
+
+#[test]
+fn test_append_code() {
+    let mut sm = StackMachine::default();
+    let square = sm.append_code(&program![DUP, MUL, RET]).unwrap();
+    assert_eq!(square, 0);
+
+    // A word calling the one before it twice
+    let fourth = sm
+        .append_code(&program![LDI 0, CALL, LDI 0, CALL, RET])
+        .unwrap();
+    assert_eq!(fourth, 3);
+    assert_eq!(
+        sm.execute_with_stack(fourth, vec![3], GasLimit::Limited(100))
+            .unwrap(),
+        vec![81]
+    );
+
+    // Rejected code leaves the program as it was
+    match sm.append_code(&program![LDI 50, JMP]) {
+        Err(PatchError::Invalid(ValidationError::TargetOutOfRange { pc: 9, target: 50 })) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(sm.st.opcodes().len(), 8);
+    match sm.append_code(&[]) {
+        Err(PatchError::Invalid(ValidationError::EmptyProgram)) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+
+    let shared = sm.st.share_program();
+    assert_eq!(sm.append_code(&program![LDI - 8, JR]), Ok(8));
+    assert_eq!(shared.len(), 8);
+    sm.st.freeze_code();
+    assert_eq!(sm.append_code(&program![RET]), Err(PatchError::Frozen));
+}
+
+#[test]
+fn test_compact_code() {
+    let mut sm = StackMachine::default();
+    sm.enable_dictionary();
+    let cube = sm.append_code(&program![DUP, MUL, RET]).unwrap();
+    sm.st.dictionary_mut().unwrap().create("cube", cube);
+    let cube = sm.append_code(&program![DUP, DUP, MUL, MUL, RET]).unwrap();
+    sm.st.dictionary_mut().unwrap().create("cube", cube);
+    let twice = sm
+        .append_code(&program![LDI 3, CALL, LDI 2, JR, NOP, LDI 3, CALL, RET])
+        .unwrap();
+    sm.st.dictionary_mut().unwrap().create("twice", twice);
+    sm.set_breakpoint(1);
+    sm.set_breakpoint(twice);
+
+    let compaction = sm.compact_code(&[]).unwrap();
+    assert_eq!(compaction.removed, 3);
+    assert_eq!(compaction.new_address(0), None);
+    assert_eq!(compaction.new_address(cube), Some(0));
+    assert_eq!(compaction.new_address(twice), Some(5));
+    assert_eq!(
+        sm.st.opcodes(),
+        &program![
+            DUP, DUP, MUL, MUL, RET, LDI 0, CALL, LDI 2, JR, NOP, LDI 0, CALL, RET
+        ][..]
+    );
+    assert_eq!(
+        sm.st.dictionary().unwrap().iter().collect::<Vec<_>>(),
+        vec![("cube", 0), ("twice", 5)]
+    );
+    assert_eq!(sm.breakpoints().collect::<Vec<_>>(), vec![5]);
+    sm.clear_breakpoint(5);
+    assert_eq!(
+        sm.execute_with_stack(5, vec![2], GasLimit::Limited(100))
+            .unwrap(),
+        vec![512]
+    );
+
+    // Nothing left to remove
+    assert_eq!(sm.compact_code(&[]).unwrap().removed, 0);
+
+    // Calls through an address on the stack could reach any code
+    sm.append_code(&program![DUP, CALL, RET]).unwrap();
+    assert_eq!(
+        sm.compact_code(&[13]),
+        Err(CompactError::DynamicJump { pc: 14 })
+    );
+    assert_eq!(sm.st.opcodes().len(), 16);
+}
+
+#[test]
+fn test_instruction_set_json() {
+    let json = instruction_set_json();
+    assert!(json.starts_with("{\"opcode_count\":68,\"opcodes\":[{\"mnemonic\":\"JMP\",\"code\":0,"));
+    assert!(json.contains(concat!(
+        "{\"mnemonic\":\"LDI\",\"code\":7,\"immediates\":1,\"pops\":0,\"pushes\":1,",
+        "\"variable_stack_effect\":false,\"gas_class\":\"Stack\",",
+        "\"description\":\"Push the immediate value\"}"
+    )));
+    assert_eq!(
+        json.matches("\"mnemonic\":").count(),
+        usize::from(OPCODE_COUNT)
+    );
+    for opcode in Opcode::all_variants() {
+        assert!(!opcode.description().is_empty());
+    }
+
+    #[cfg(feature = "golden")]
+    {
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let opcodes = parsed["opcodes"].as_array().unwrap();
+        assert_eq!(opcodes.len(), usize::from(OPCODE_COUNT));
+        assert_eq!(opcodes[62]["mnemonic"], "ASSERT");
+        assert_eq!(opcodes[62]["gas_class"], "Control");
+        assert_eq!(opcodes[19]["variable_stack_effect"], true);
+    }
+}
+
+#[test]
+fn test_dump() {
+    let mut sm = StackMachine::default();
+    sm.enable_touched_cells(3);
+    sm.st.load_program(program![
+        LDI 4, NEWCELLS, GtR, LDI 7, LDI 8, RAt, LDI 2, MOVETOCELLS, LDI 1, LDI 2, PUSHLP,
+        RAt, LDI 2, MOVEFROMCELLS, ADD, LDI 0, TRAP, RET
+    ]);
+    sm.trap_handlers.push(Box::new(TrapHandler::new(0, |_, _| {
+        Ok(TrapHandled::Handled)
+    })));
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::TrapExit(0)
+    );
+    assert_eq!(sm.touched_cells(), Some(vec![1, 0]));
+    assert_eq!(
+        sm.dump(&DumpOptions::default()),
+        "pc 16, gas used 16, steps 16
+      13  MOVEFROMCELLS
+      14  ADD
+      15  LDI 0
+=>    16  TRAP
+      17  RET
+number stack (1): 15
+scratch stack (1): 0
+return stack (0):
+loop stack (1): 2/1
+cells (4): [1]=7 [0]=8
+"
+    );
+
+    let options = DumpOptions {
+        code_before: 0,
+        code_after: 0,
+        stack_depth: 0,
+        cells: 1,
+    };
+    assert_eq!(
+        sm.dump(&options),
+        "pc 16, gas used 16, steps 16
+=>    16  TRAP
+number stack (1): ...
+scratch stack (1): ...
+return stack (0):
+loop stack (1): ...
+cells (4): [1]=7
+"
+    );
+}
+
+#[test]
+fn test_trap_handler_stack_helpers() {
+    let mut sm = StackMachine::default();
+    sm.st.word_size = WordSize::Bits32;
+    // ( a b c -- c-a b*2 )
+    sm.trap_handlers.push(Box::new(TrapHandler::new(0, |_, st| {
+        let [a, b, c] = st.pop_n()?;
+        st.push_all(&[c - a, b * 2]);
+        Ok(TrapHandled::Handled)
+    })));
+    sm.st.load_program(program![LDI 0, TRAP]);
+    assert_eq!(
+        sm.execute_with_stack(0, vec![7, 1, 2, 10], GasLimit::Limited(100))
+            .unwrap(),
+        vec![7, 9, 4]
+    );
+
+    // An underflow pops nothing
+    sm.st.set_number_stack(vec![2, 10]);
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StackUnderflow(StackKind::Number)),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(sm.st.number_stack(), &[2, 10]);
+
+    sm.st.push_all(&[i64::from(i32::MAX) + 1]);
+    assert_eq!(sm.st.pop().unwrap(), i64::from(i32::MIN));
+    assert_eq!(sm.st.pop_n::<0>().unwrap(), [0i64; 0]);
+    assert_eq!(sm.st.pop_n().unwrap(), [2, 10]);
+    match sm.st.pop() {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StackUnderflow(StackKind::Number)),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_run_plan() {
+    let mut sm = StackMachine::default();
+    // init pushes 10, main doubles it, teardown adds 1
+    sm.st
+        .load_program(program![LDI 10, RET, DUP, ADD, RET, LDI 1, ADD, RET, LDI 8, JMP]);
+    let steps = sm
+        .run_plan(&[
+            (0, GasLimit::Limited(1)),
+            (2, GasLimit::Limited(2)),
+            (5, GasLimit::Unlimited),
+        ])
+        .unwrap();
+    assert_eq!(
+        steps,
+        vec![
+            PlanStep {
+                entry: 0,
+                status: ExitStatus::Returned,
+                gas_used: 1
+            },
+            PlanStep {
+                entry: 2,
+                status: ExitStatus::Returned,
+                gas_used: 2
+            },
+            PlanStep {
+                entry: 5,
+                status: ExitStatus::Returned,
+                gas_used: 2
+            },
+        ]
+    );
+    assert_eq!(sm.st.number_stack(), &[21]);
+
+    // Each entry has its own budget, the loop at 8 runs out of it
+    let e = sm
+        .run_plan(&[
+            (2, GasLimit::Limited(2)),
+            (8, GasLimit::Limited(50)),
+            (5, GasLimit::Unlimited),
+        ])
+        .unwrap_err();
+    assert_eq!((e.index, e.entry), (1, 8));
+    assert_eq!(e.completed.len(), 1);
+    assert_eq!(e.error.kind(), ErrorKind::RanOutOfGas);
+
+    // A breakpoint ends the plan
+    sm.st.set_number_stack(vec![3]);
+    sm.set_breakpoint(3);
+    let steps = sm
+        .run_plan(&[(2, GasLimit::Unlimited), (5, GasLimit::Unlimited)])
+        .unwrap();
+    assert_eq!(steps.len(), 1);
+    assert_eq!(steps[0].status, ExitStatus::BreakpointHit(3));
+    assert_eq!(sm.paused_at(), Some(3));
+}
+
+#[test]
+fn test_start_and_exit_hooks() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let harvested = Rc::new(RefCell::new(Vec::new()));
+    let mut sm = StackMachine::default();
+    sm.on_start(|st| {
+        st.input_queue_mut().extend([2, 3]);
+        Ok(())
+    });
+    let outputs = harvested.clone();
+    sm.on_exit(move |st, result| {
+        outputs
+            .borrow_mut()
+            .push((result.is_ok(), st.take_output()));
+        if st.number_stack().is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::Host.into())
+        }
+    });
+
+    sm.st
+        .load_program(program![READIN, READIN, ADD, WRITEOUT, RET]);
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Returned
+    );
+    assert_eq!(*harvested.borrow(), vec![(true, vec![5])]);
+
+    // The exit hook fails a run that leaves values on the stack
+    sm.st.load_program(program![READIN, READIN, ADD, RET]);
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Host),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+
+    // but keeps the error of a failed run, and runs when a paused run ends
+    sm.st.set_number_stack(Vec::new());
+    sm.st
+        .load_program(program![READIN, READIN, READIN, ADD, WRITEOUT, RET]);
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Yielded
+    );
+    assert_eq!(harvested.borrow().len(), 2);
+    sm.st.set_number_stack(Vec::new());
+    sm.st.input_queue_mut().push_back(7);
+    match sm.resume(GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StackUnderflow(StackKind::Number)),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(harvested.borrow()[2], (false, vec![]));
+
+    sm.on_start(|_| Err(ErrorKind::Host.into()));
+    sm.st.set_number_stack(Vec::new());
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Host),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(harvested.borrow().len(), 3);
+
+    sm.clear_hooks();
+    sm.st.input_queue_mut().extend([1, 2, 3]);
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Returned
+    );
+    assert_eq!(sm.st.take_output(), vec![5]);
+}
+
+#[test]
+fn test_call_chain() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert(0, "MAIN");
+    symbols.insert(5, "SQUARE");
+    symbols.insert(9, "HELPER");
+    let mut sm = StackMachine::default();
+    sm.st.load_program(program![
+        LDI 3, LDI 5, CALL, RET, NOP, LDI 9, CALL, RET, NOP, DUP, MUL, DIV, RET
+    ]);
+    let error = sm.execute(0, GasLimit::Limited(100)).unwrap_err();
+
+    let chain = sm.st.call_chain(&symbols);
+    assert_eq!(chain.to_string(), "MAIN > SQUARE > HELPER @ pc 11");
+    assert_eq!(error.call_chain(&symbols), Some(chain.clone()));
+    assert_eq!(
+        StackMachineError::from(ErrorKind::NotPaused).call_chain(&symbols),
+        None
+    );
+    assert_eq!(chain.pc(), 11);
+    assert_eq!(
+        chain.frames[1],
+        CallFrame {
+            pc: 6,
+            function: Some((5, "SQUARE".to_string()))
+        }
+    );
+    assert_eq!(
+        unwind(sm.st.return_stack(), 11, &SymbolTable::new()).to_string(),
+        "@2 > @6 > @11 @ pc 11"
+    );
+    assert_eq!(unwind(&[], 4, &symbols).to_string(), "MAIN @ pc 4");
+
+    sm.st.exports = symbols;
+    let options = DumpOptions {
+        code_before: 0,
+        code_after: 0,
+        stack_depth: 2,
+        cells: 0,
+    };
+    assert_eq!(
+        sm.dump(&options),
+        "pc 11, gas used 7, steps 7
+=>    11  DIV
+number stack (0):
+scratch stack (0):
+return stack (2): 7 3
+loop stack (0):
+calls: MAIN > SQUARE > HELPER @ pc 11
+"
+    );
+
+    // The debugger names the frames of a paused run the same way
+    let symbols = sm.st.exports.clone();
+    assert_eq!(sm.paused_call_chain(&symbols), None);
+    sm.st.return_stack.clear();
+    sm.set_breakpoint(10);
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::BreakpointHit(10)
+    );
+    assert_eq!(
+        sm.paused_call_chain(&symbols).unwrap().to_string(),
+        "MAIN > SQUARE > HELPER @ pc 10"
+    );
+}
+
+#[test]
+fn test_soft_gas_limit() {
+    let mut sm = StackMachine::default();
+    // Count up forever, 4 gas a time round
+    sm.st.load_program(program![LDI 1, ADD, LDI - 3, JR]);
+    sm.st.set_number_stack(vec![0]);
+    assert_eq!(
+        sm.execute(0, GasLimit::Soft { soft: 10, hard: 30 })
+            .unwrap(),
+        ExitStatus::Yielded
+    );
+    assert_eq!(sm.st.gas_used(), 11);
+    assert_eq!(sm.paused_at(), Some(3));
+    assert_eq!(sm.st.number_stack(), &[3, -3]);
+
+    // The host grants more gas
+    assert_eq!(
+        sm.resume(GasLimit::Soft { soft: 20, hard: 30 }).unwrap(),
+        ExitStatus::Yielded
+    );
+    assert_eq!(sm.st.gas_used(), 21);
+    assert_eq!(sm.st.number_stack(), &[5, 1]);
+
+    // and then refuses
+    match sm.resume(GasLimit::Limited(30)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::RanOutOfGas),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(sm.st.gas_used(), 31);
+    assert_eq!(sm.paused_at(), None);
+
+    // The hard limit wins when it is lower
+    sm.st.set_number_stack(vec![0]);
+    match sm.execute(0, GasLimit::Soft { soft: 10, hard: 5 }) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::RanOutOfGas),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(sm.st.gas_used(), 6);
+}
+
+#[test]
+fn test_trap_budget() {
+    use std::time::Duration;
+
+    let mut sm = StackMachine::default();
+    // Trap 1 calls the subroutine whose address is on top of the stack
+    sm.trap_handlers.push(Box::new(TrapHandler::new(1, |_, st| {
+        let address = usize::try_from(st.pop_number()?)?;
+        call_within_trap(st, address, GasLimit::Limited(100))?;
+        Ok(TrapHandled::Handled)
+    })));
+    sm.trap_handlers.push(Box::new(TrapHandler::new(2, |_, _| {
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(TrapHandled::Handled)
+    })));
+    sm.st
+        .load_program(program![LDI 3, LDI 1, TRAP, DUP, ADD, DUP, ADD, RET]);
+    sm.st.set_trap_budget(1, TrapBudget::gas(4));
+    assert_eq!(
+        sm.execute_with_stack(0, vec![5], GasLimit::Limited(100))
+            .unwrap(),
+        vec![20]
+    );
+
+    sm.st.set_trap_budget(1, TrapBudget::gas(3));
+    sm.st.set_number_stack(vec![5]);
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::TrapBudgetExceeded { trap_id: 1 }),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(sm.st.trap_budget(1), Some(TrapBudget::gas(3)));
+    assert_eq!(sm.st.remove_trap_budget(1), Some(TrapBudget::gas(3)));
+    assert_eq!(sm.st.trap_budget(1), None);
+
+    sm.st.load_program(program![LDI 2, TRAP]);
+    sm.st
+        .set_trap_budget(2, TrapBudget::time(Duration::from_millis(1)));
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::TrapBudgetExceeded { trap_id: 2 }),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    sm.st
+        .set_trap_budget(2, TrapBudget::time(Duration::from_secs(60)));
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::TrapExit(2)
+    );
+
+    // Time budgets measure wall-clock time
+    sm.set_deterministic_profile(DeterministicProfile::new(1000, 16));
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::NondeterministicFeature),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_loop_stack_from_trap_handler() {
+    let mut sm = StackMachine::default();
+    // Trap 1 starts a loop over the cells from the address on the stack, trap 2 pushes the
+    // cell at the index and steps on, pushing whether the loop has finished
+    sm.trap_handlers.push(Box::new(TrapHandler::new(1, |_, st| {
+        let [address, len] = st.pop_n()?;
+        st.push_loop(LoopFrame::new(address, address + len));
+        Ok(TrapHandled::Handled)
+    })));
+    sm.trap_handlers.push(Box::new(TrapHandler::new(2, |_, st| {
+        let word_size = st.word_size;
+        let frame = st.loop_frame(0)?;
+        let index = frame.index;
+        frame.advance(1, word_size);
+        let finished = frame.is_finished();
+        let value = st.cells[usize::try_from(index)?];
+        st.push_all(&[value, i64::from(finished)]);
+        if finished {
+            st.pop_loop()?;
+        }
+        Ok(TrapHandled::Handled)
+    })));
+
+    sm.load_cells(0, &[4, 5, 6]).unwrap();
+    sm.st.set_number_stack(vec![1, 2]);
+    sm.st.load_program(program![TRAPID 1]);
+    sm.execute(0, GasLimit::Limited(10)).unwrap();
+    assert_eq!(sm.st.loop_stack(), &[LoopFrame::new(1, 3)]);
+
+    sm.st.load_program(program![TRAPID 2]);
+    sm.execute(0, GasLimit::Limited(10)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[5, 0]);
+    sm.execute(0, GasLimit::Limited(10)).unwrap();
+    assert_eq!(sm.st.number_stack(), &[5, 0, 6, 1]);
+    assert!(sm.st.loop_stack().is_empty());
+
+    match sm.execute(0, GasLimit::Limited(10)) {
+        Err(e) => assert_eq!(
+            e.kind(),
+            ErrorKind::LoopStackUnderflow { frame: 0, depth: 0 }
+        ),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match sm.st.pop_loop() {
+        Err(e) => assert_eq!(
+            e.kind(),
+            ErrorKind::LoopStackUnderflow { frame: 0, depth: 0 }
+        ),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_decimal_opcodes() {
+    let mut sm = StackMachine::default();
+    let mut run = |program: Vec<Opcode>, mode: RoundingMode| {
+        sm.st.rounding_mode = mode;
+        sm.st.load_program(program);
+        sm.execute_with_stack(0, vec![], GasLimit::Limited(100))
+    };
+    let half_even = RoundingMode::default();
+    // 1.25 + 0.1, 1.00 - 0.25 and 1.5 * 2.5
+    assert_eq!(
+        run(program![LDI 125, LDI -2, LDI 1, LDI -1, DADD], half_even).unwrap(),
+        vec![135, -2]
+    );
+    assert_eq!(
+        run(program![LDI 100, LDI -2, LDI 25, LDI -2, DSUB], half_even).unwrap(),
+        vec![75, -2]
+    );
+    assert_eq!(
+        run(program![LDI 15, LDI -1, LDI 25, LDI -1, DMUL], half_even).unwrap(),
+        vec![375, -2]
+    );
+    // 2.00 / 3 and 2.00 / 0.3 round to cents
+    assert_eq!(
+        run(program![LDI 200, LDI -2, LDI 3, LDI 0, DDIV], half_even).unwrap(),
+        vec![67, -2]
+    );
+    assert_eq!(
+        run(program![LDI 200, LDI -2, LDI 3, LDI -1, DDIV], half_even).unwrap(),
+        vec![667, -2]
+    );
+    assert_eq!(
+        run(
+            program![LDI 1, LDI 0, LDI 1, LDI 40, DDIV],
+            RoundingMode::Ceiling
+        )
+        .unwrap(),
+        vec![1, 0]
+    );
+
+    for (mode, up, down) in &[
+        (RoundingMode::HalfEven, 2, -2),
+        (RoundingMode::HalfUp, 3, -3),
+        (RoundingMode::TowardZero, 2, -2),
+        (RoundingMode::Floor, 2, -3),
+        (RoundingMode::Ceiling, 3, -2),
+    ] {
+        assert_eq!(
+            run(program![LDI 25, LDI -1, LDI 0, DRESCALE], *mode).unwrap(),
+            vec![*up, 0]
+        );
+        assert_eq!(
+            run(program![LDI -25, LDI -1, LDI 0, DRESCALE], *mode).unwrap(),
+            vec![*down, 0]
+        );
+    }
+    assert_eq!(
+        run(program![LDI 35, LDI -1, LDI 0, DRESCALE], half_even).unwrap(),
+        vec![4, 0]
+    );
+    assert_eq!(
+        run(program![LDI 7, LDI 0, LDI -3, DRESCALE], half_even).unwrap(),
+        vec![7000, -3]
+    );
+    assert_eq!(
+        run(
+            program![LDI -7, LDI 0, LDI 50, DRESCALE],
+            RoundingMode::Floor
+        )
+        .unwrap(),
+        vec![-1, 50]
+    );
+
+    match run(program![LDI 1, LDI 0, LDI 0, LDI -2, DDIV], half_even) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::DivisionByZero),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match run(program![LDI 1, LDI 0, LDI 1, LDI -30, DADD], half_even) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::NumericOverflow),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match run(
+        program![LDI 4000000000, LDI 0, LDI 4000000000, LDI 0, DMUL],
+        half_even,
+    ) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::NumericOverflow),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_array_traps() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(ArrayTraps::new(300)));
+    sm.load_cells(0, &[1, 2, 3, 10, 20, 30]).unwrap();
+    sm.load_cells(6, &[0; 6]).unwrap();
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+    };
+
+    // ADD, SUB, MUL and SCALE into cells 6 to 8
+    run(&mut sm, vec![0, 3, 6, 3], 300).unwrap();
+    assert_eq!(&sm.st.cells()[6..9], &[11, 22, 33]);
+    run(&mut sm, vec![3, 0, 6, 3], 301).unwrap();
+    assert_eq!(&sm.st.cells()[6..9], &[9, 18, 27]);
+    run(&mut sm, vec![0, 3, 6, 3], 302).unwrap();
+    assert_eq!(&sm.st.cells()[6..9], &[10, 40, 90]);
+    run(&mut sm, vec![0, -2, 6, 3], 303).unwrap();
+    assert_eq!(&sm.st.cells()[6..9], &[-2, -4, -6]);
+
+    // DOT and SUM
+    assert_eq!(run(&mut sm, vec![0, 3, 3], 304).unwrap(), vec![140]);
+    assert_eq!(run(&mut sm, vec![3, 3], 305).unwrap(), vec![60]);
+
+    // RAMP, then MATMUL of [[1 2 3] [10 20 30]] by [[5] [6] [7]]
+    run(&mut sm, vec![6, 3, 5, 1], 306).unwrap();
+    assert_eq!(&sm.st.cells()[6..9], &[5, 6, 7]);
+    run(&mut sm, vec![0, 6, 9, 2, 3, 1], 307).unwrap();
+    assert_eq!(&sm.st.cells()[9..11], &[38, 380]);
+
+    // The destination may overlap the source
+    run(&mut sm, vec![0, 1, 1, 3], 300).unwrap();
+    assert_eq!(&sm.st.cells()[0..5], &[1, 3, 5, 13, 20]);
+
+    match run(&mut sm, vec![0, 3, 10, 3], 300) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidCellOperation),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match run(&mut sm, vec![0, i64::MAX, 1, 0], 306) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidCellOperation),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(&sm.st.cells()[10..12], &[380, 0]);
+}
+
+#[test]
+fn test_simd_kernels() {
+    // Lengths around the lane widths, with values that wrap
+    for len in 0..12 {
+        let a: Vec<i64> = (0..len).map(|i| i64::MAX - i).collect();
+        let b: Vec<i64> = (0..len).map(|i| i * 3 - 5).collect();
+        let added: Vec<i64> = a.iter().zip(&b).map(|(x, y)| x.wrapping_add(*y)).collect();
+        let subtracted: Vec<i64> = a.iter().zip(&b).map(|(x, y)| x.wrapping_sub(*y)).collect();
+        assert_eq!(simd::add(&a, &b), added);
+        assert_eq!(simd::sub(&a, &b), subtracted);
+        assert_eq!(
+            simd::sum(&a),
+            a.iter().fold(0i64, |sum, x| sum.wrapping_add(*x))
+        );
+    }
+    assert_eq!(simd::add(&[1, 2, 3], &[10]), vec![11]);
+}
+
+#[test]
+fn test_memory_stats() {
+    let mut sm = StackMachine::default();
+    assert_eq!(sm.memory_stats(), MemoryStats::default());
+
+    sm.st
+        .load_program(program![LDI 100, NEWCELLS, DROP, LDI 1, LDI 2, GtR, RET]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    let stats = sm.memory_stats();
+    assert!(stats.number_stack >= 8);
+    assert!(stats.scratch_stack >= 8);
+    assert!(stats.cells >= 800);
+    assert!(stats.program >= 7 * std::mem::size_of::<Opcode>());
+    assert!(!stats.program_shared);
+    assert_eq!(stats.total(), stats.stacks() + stats.cells + stats.program);
+
+    sm.st.trim_cells(0);
+    let program = sm.st.share_program();
+    let stats = sm.memory_stats();
+    assert_eq!(stats.cells, 0);
+    assert_eq!(stats.program, program.len() * std::mem::size_of::<Opcode>());
+    assert!(stats.program_shared);
+}
+
+#[test]
+fn test_engine_budgets() {
+    let mut engine = Engine::new(EngineLimits {
+        gas: Some(25),
+        memory: None,
+    });
+    let square = engine.add_program(program![DUP, MUL, RET]);
+    let a = engine.spawn(square).unwrap();
+    let b = engine.spawn(square).unwrap();
+    assert!(Arc::ptr_eq(
+        &engine.machine_mut(a).unwrap().st.share_program(),
+        engine.program(square).unwrap()
+    ));
+
+    // Each run uses 2 gas, charged to the engine
+    engine.machine_mut(a).unwrap().st.set_number_stack(vec![7]);
+    assert_eq!(
+        engine.execute(a, 0, GasLimit::Limited(100)).unwrap(),
+        ExitStatus::Returned
+    );
+    assert_eq!(engine.machine(a).unwrap().st.number_stack(), &[49]);
+    assert_eq!(engine.gas_used(), 2);
+    assert_eq!(engine.gas_remaining(), Some(23));
+
+    // A machine's own gas limit is still its own error
+    let looping = engine.add_program(program![LDI 0, JMP]);
+    let c = engine.spawn(looping).unwrap();
+    match engine.execute(c, 0, GasLimit::Limited(9)) {
+        Err(EngineError::Machine(e)) => assert_eq!(e.kind(), ErrorKind::RanOutOfGas),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match engine.execute(c, 0, GasLimit::Unlimited) {
+        Err(EngineError::GasBudgetExhausted) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert!(engine.gas_remaining().unwrap() == 0);
+    match engine.execute(b, 0, GasLimit::Unlimited) {
+        Err(EngineError::GasBudgetExhausted) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match engine.execute(c, 0, GasLimit::Unlimited) {
+        Err(EngineError::GasBudgetExhausted) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert!(engine.remove(c).is_some());
+    match engine.execute(c, 0, GasLimit::Unlimited) {
+        Err(EngineError::UnknownMachine(id)) => assert_eq!(id, c),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(engine.machines().collect::<Vec<_>>(), vec![a, b]);
+}
+
+#[test]
+fn test_engine_memory_budget() {
+    let mut engine = Engine::new(EngineLimits {
+        gas: None,
+        memory: Some(4096),
+    });
+    let allocate = engine.add_program(program![NEWCELLS, DROP, RET]);
+    let a = engine.spawn(allocate).unwrap();
+    let b = engine.spawn(allocate).unwrap();
+    engine
+        .machine_mut(a)
+        .unwrap()
+        .st
+        .set_number_stack(vec![200]);
+    engine.execute(a, 0, GasLimit::Unlimited).unwrap();
+    let used = engine.memory_used();
+    assert!(used >= 1600);
+
+    // The second machine only gets what the first left
+    engine
+        .machine_mut(b)
+        .unwrap()
+        .st
+        .set_number_stack(vec![400]);
+    match engine.execute(b, 0, GasLimit::Unlimited) {
+        Err(EngineError::MemoryBudgetExceeded) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    assert_eq!(engine.machine(b).unwrap().st.cell_limit(), None);
+    engine
+        .machine_mut(b)
+        .unwrap()
+        .st
+        .set_number_stack(vec![100]);
+    engine.execute(b, 0, GasLimit::Unlimited).unwrap();
+
+    // Removing a machine frees its memory for the others
+    engine.remove(a);
+    assert!(engine.memory_used() < used);
+    engine
+        .machine_mut(b)
+        .unwrap()
+        .st
+        .set_number_stack(vec![200]);
+    engine.execute(b, 0, GasLimit::Unlimited).unwrap();
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_engine_execute_all_parallel() {
+    // Count down from TOS, five gas a time, then double the record with a trap
+    let countdown = program![
+        DUP,
+        top:
+        LDI -1,
+        ADD,
+        DUP,
+        LDI %top,
+        JRNZ,
+        DROP,
+        TRAPID 7,
+    ];
+    let setup = |sm: &mut StackMachine| {
+        sm.trap_handlers
+            .register(Box::new(TrapHandler::new(7, |_, st| {
+                let record = st.pop()?;
+                st.push_number(record * 2);
+                Ok(TrapHandled::Handled)
+            })));
+    };
+
+    let mut engine = Engine::new(EngineLimits {
+        gas: Some(100_000),
+        memory: None,
+    });
+    let program = engine.add_program(countdown);
+    let records: Vec<i64> = (1..=8).map(|i| i * 100).collect();
+    let machines: Vec<MachineId> = records
+        .iter()
+        .map(|&record| {
+            let id = engine.spawn(program).unwrap();
+            engine
+                .machine_mut(id)
+                .unwrap()
+                .st
+                .set_number_stack(vec![record]);
+            engine.queue(id, 0, GasLimit::Unlimited).unwrap();
+            id
+        })
+        .collect();
+    let reports = engine.execute_all_parallel(setup);
+    assert_eq!(reports.len(), 8);
+    for ((report, id), record) in reports.iter().zip(&machines).zip(&records) {
+        assert_eq!(report.machine, *id);
+        assert_eq!(report.result.as_ref().unwrap(), &ExitStatus::TrapExit(7));
+        assert_eq!(report.gas_used, 1 + 5 * *record as u64 + 1);
+        assert_eq!(
+            engine.machine(*id).unwrap().st.number_stack(),
+            &[record * 2]
+        );
+    }
+    let total: u64 = reports.iter().map(|report| report.gas_used).sum();
+    assert_eq!(engine.gas_used(), total);
+
+    // A budget too small for every run stops some of them, without going over
+    let mut engine = Engine::new(EngineLimits {
+        gas: Some(10_000),
+        memory: None,
+    });
+    let program = engine.add_program(program![LDI 0, JMP]);
+    for _ in 0..4 {
+        let id = engine.spawn(program).unwrap();
+        engine.queue(id, 0, GasLimit::Limited(3_000)).unwrap();
+    }
+    let reports = engine.execute_all_parallel(|_| {});
+    let exhausted = reports
+        .iter()
+        .filter(|report| matches!(report.result, Err(EngineError::GasBudgetExhausted)))
+        .count();
+    // Gas is reserved a slice at a time, so at least one run gets the 3001 it needs
+    assert!((1..4).contains(&exhausted));
+    for report in &reports {
+        if let Err(EngineError::Machine(e)) = &report.result {
+            assert_eq!(e.kind(), ErrorKind::RanOutOfGas);
+        }
+    }
+    assert!(engine.gas_used() <= 10_000);
+    assert!(engine.execute_all_parallel(|_| {}).is_empty());
+
+    // Gas reserved but not used goes back to the budget for the other runs
+    let mut engine = Engine::new(EngineLimits {
+        gas: Some(2048),
+        memory: None,
+    });
+    let program = engine.add_program(program![LDI 1, DROP, RET]);
+    for _ in 0..3 {
+        let id = engine.spawn(program).unwrap();
+        engine.queue(id, 0, GasLimit::Unlimited).unwrap();
+    }
+    for report in engine.execute_all_parallel(|_| {}) {
+        assert_eq!(report.result.unwrap(), ExitStatus::Returned);
+        assert_eq!(report.gas_used, 2);
+    }
+    assert_eq!(engine.gas_used(), 6);
+    assert_eq!(engine.gas_remaining(), Some(2042));
+}
+
+#[test]
+fn test_stack_machine_future() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Poll the future until it is ready, returning its output and the times it was pending
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut pending = 0;
+        loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(output) => {
+                    // Every pending poll woke the task to be polled again
+                    assert_eq!(counter.0.load(Ordering::SeqCst), pending);
+                    return (output, pending);
+                }
+                Poll::Pending => pending += 1,
+            }
+        }
+    }
+
+    // Count down from TOS, five gas a time
+    let countdown = program![DUP, top: LDI -1, ADD, DUP, LDI %top, JRNZ, DROP, RET];
+    let mut sm = StackMachine::default();
+    sm.st.load_program(countdown);
+
+    // 502 gas in slices of 100
+    sm.st.set_number_stack(vec![100]);
+    let (result, pending) = block_on(sm.execute_async(0, GasLimit::Unlimited).with_slice(100));
+    assert_eq!(result.unwrap(), ExitStatus::Returned);
+    assert_eq!(pending, 5);
+    assert_eq!(sm.st.number_stack(), &[100]);
+    assert_eq!(sm.st.gas_used(), 502);
+
+    // Running within a single slice never yields
+    let (result, pending) = block_on(sm.execute_async(0, GasLimit::Limited(1000)));
+    assert_eq!(result.unwrap(), ExitStatus::Returned);
+    assert_eq!(pending, 0);
+
+    // The run's own soft limit finishes the future, to resume like any other pause
+    let soft = GasLimit::Soft {
+        soft: 250,
+        hard: 1000,
+    };
+    let (result, pending) = block_on(sm.execute_async(0, soft).with_slice(100));
+    assert_eq!(result.unwrap(), ExitStatus::Yielded);
+    assert_eq!(pending, 2);
+    assert_eq!(
+        sm.resume(GasLimit::Limited(1000)).unwrap(),
+        ExitStatus::Returned
+    );
+    assert_eq!(sm.st.number_stack(), &[100]);
+
+    // As does running out of gas
+    let (result, _) = block_on(sm.execute_async(0, GasLimit::Limited(300)).with_slice(100));
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::RanOutOfGas);
+}
+
+#[test]
+fn test_env_traps() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(EnvTraps::new(500)));
+    assert_eq!(sm.st.set_env("retries", 3), None);
+    sm.st.set_env("mode", "fast");
+    let retries = sm.st.intern("retries");
+    let mode = sm.st.intern("mode");
+    let missing = sm.st.intern("missing");
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+    };
+
+    assert_eq!(run(&mut sm, vec![retries], 500).unwrap(), vec![3, -1]);
+    assert_eq!(run(&mut sm, vec![missing], 500).unwrap(), vec![0]);
+    // Strings are read as interned ids
+    let stack = run(&mut sm, vec![mode], 500).unwrap();
+    assert_eq!(stack.len(), 2);
+    assert_eq!(sm.st.string(stack[0]), Some("fast"));
+    assert_eq!(run(&mut sm, vec![mode], 500).unwrap(), stack);
+
+    assert_eq!(run(&mut sm, vec![missing], 501).unwrap(), vec![0]);
+    assert_eq!(run(&mut sm, vec![retries], 501).unwrap(), vec![1]);
+    assert_eq!(run(&mut sm, vec![mode], 501).unwrap(), vec![2]);
+
+    // The host changes the environment between runs
+    assert_eq!(sm.st.set_env("retries", 5), Some(EnvValue::Number(3)));
+    assert_eq!(run(&mut sm, vec![retries], 500).unwrap(), vec![5, -1]);
+    assert_eq!(sm.st.remove_env("mode"), Some(EnvValue::from("fast")));
+    assert_eq!(run(&mut sm, vec![mode], 501).unwrap(), vec![0]);
+    assert_eq!(sm.st.environment().len(), 1);
+    sm.st.clear_env();
+    assert_eq!(sm.st.env("retries"), None);
+
+    match run(&mut sm, vec![999], 500) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::UnknownString { id: 999 }),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_argv_traps() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers.register(Box::new(ArgvTraps::new(600)));
+    let run = |sm: &mut StackMachine, stack: Vec<i64>, trap_id: i64| {
+        sm.st.load_program(vec![Opcode::TRAPID(trap_id)]);
+        sm.execute_with_stack(0, stack, GasLimit::Limited(100))
+    };
+
+    sm.set_argv(&[3, 4, 5]);
+    assert_eq!(sm.st.argv(), &[3, 4, 5]);
+    assert_eq!(run(&mut sm, vec![], 600).unwrap(), vec![3]);
+    assert_eq!(run(&mut sm, vec![0], 601).unwrap(), vec![3]);
+    assert_eq!(run(&mut sm, vec![2], 601).unwrap(), vec![5]);
+    sm.set_argv(&[]);
+    assert_eq!(run(&mut sm, vec![], 600).unwrap(), vec![0]);
+
+    sm.set_argv(&[7]);
+    for index in [1, -1] {
+        match run(&mut sm, vec![index], 601) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::NoSuchArgument { index, argc: 1 }),
+            r => panic!("Incorrect result returned {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_execution_report() {
+    let mut sm = StackMachine::default();
+    // Leave a result and an exit code of 0 on top
+    sm.st.load_program(program![DUP, MUL, LDI 0, RET]);
+    let report = sm
+        .execute_with_report(0, vec![1, 7], GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(report.status, ExitStatus::Returned);
+    assert_eq!(report.gas_used, 3);
+    assert_eq!(report.number_stack, vec![1, 49, 0]);
+    assert_eq!(report.return_values(2).unwrap(), &[49, 0]);
+    assert_eq!(report.return_values(0).unwrap(), &[] as &[i64]);
+    assert_eq!(report.exit_code().unwrap(), 0);
+    assert!(report.succeeded());
+    assert!(!report.return_bool().unwrap());
+    assert!(sm.st.number_stack().is_empty());
+
+    match report.return_values(4) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StackUnderflow(StackKind::Number)),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+
+    let report = sm
+        .execute_with_report(0, vec![-3], GasLimit::Limited(100))
+        .unwrap();
+    let report = ExecutionReport {
+        number_stack: vec![-1],
+        ..report
+    };
+    assert!(report.return_bool().unwrap());
+    assert_eq!(
+        report.return_u32().unwrap_err().kind(),
+        ErrorKind::NumericOverflow
+    );
+    assert!(!report.succeeded());
+    let report = ExecutionReport {
+        number_stack: vec![1 << 40],
+        ..report
+    };
+    assert_eq!(
+        report.exit_code().unwrap_err().kind(),
+        ErrorKind::NumericOverflow
+    );
+    assert!(!report.succeeded());
+    let report = ExecutionReport {
+        number_stack: vec![],
+        ..report
+    };
+    assert!(!report.succeeded());
+    assert_eq!(
+        ExecutionReport {
+            number_stack: vec![40_000],
+            ..report
+        }
+        .return_u32()
+        .unwrap(),
+        40_000
+    );
+}
+
+#[test]
+fn test_typed_results() {
+    let mut st = StackMachineState::default();
+    st.set_number_stack(vec![-1, 300, 7]);
+    assert_eq!(st.pop_typed::<u8>().unwrap(), 7);
+    // A value that does not fit is left on the stack
+    assert_eq!(
+        st.pop_typed::<u8>().unwrap_err().kind(),
+        ErrorKind::ConversionFailed {
+            position: 0,
+            value: 300
+        }
+    );
+    assert_eq!(st.pop_typed::<u16>().unwrap(), 300);
+    assert_eq!(st.pop_typed::<i8>().unwrap(), -1);
+    assert_eq!(
+        st.pop_typed::<i64>().unwrap_err().kind(),
+        ErrorKind::StackUnderflow(StackKind::Number)
+    );
+
+    st.set_number_stack(vec![9, 1, 300, -2]);
+    assert_eq!(st.results::<(u8, u16, i32)>().unwrap(), (1, 300, -2));
+    assert_eq!(st.results::<(i64,)>().unwrap(), (-2,));
+    assert_eq!(st.number_stack(), &[9, 1, 300, -2]);
+    // The first position that does not fit is reported
+    assert_eq!(
+        st.results::<(u8, u8, u32)>().unwrap_err().kind(),
+        ErrorKind::ConversionFailed {
+            position: 1,
+            value: 300
+        }
+    );
+    assert_eq!(
+        st.results::<(u8, u8, u8, u8, u8)>().unwrap_err().kind(),
+        ErrorKind::StackUnderflow(StackKind::Number)
+    );
+    // Called directly, the values must be exactly as many as the tuple takes
+    assert_eq!(<(u8, i8)>::from_stack_values(&[1, -1]).unwrap(), (1, -1));
+    for values in [&[1][..], &[1, 2, 3][..]] {
+        assert_eq!(
+            <(u8, i8)>::from_stack_values(values).unwrap_err().kind(),
+            ErrorKind::StackUnderflow(StackKind::Number)
+        );
+    }
+
+    let mut sm = StackMachine::default();
+    sm.st.load_program(program![DUP, MUL, LDI 0, RET]);
+    let report = sm
+        .execute_with_report(0, vec![12], GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(report.results::<(u32, u8)>().unwrap(), (144, 0));
+    assert_eq!(
+        report.results::<(i8, u8)>().unwrap_err().to_string(),
+        "value 144 at position 0 does not fit the type asked for"
+    );
+}
+
+#[test]
+fn test_cell_allocator() {
+    let mut sm = StackMachine::default();
+    sm.enable_cell_allocator(4, 1);
+    sm.st.opcodes =
+        program![LDI 2, NEWCELLS, LDI 3, NEWCELLS, LDI 3, FREECELLS, LDI 5, NEWCELLS, RET];
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[4, 8, 8]);
+    assert_eq!(
+        sm.cell_allocations(),
+        Some(vec![
+            Allocation { address: 4, len: 2 },
+            Allocation { address: 8, len: 5 },
+        ])
+    );
+    assert_eq!(sm.st.cells().len(), 14);
+}
+
+#[test]
+fn test_cell_allocator_guard_cells() {
+    let mut sm = StackMachine::default();
+    sm.enable_cell_allocator(1, 2);
+    sm.st.opcodes = program![
+        LDI 7, LDI 8, LDI 9, LDI 2, NEWCELLS, LDI 3, MOVETOCELLS, RET
+    ];
+
+    match sm.execute(0, GasLimit::Limited(100)) {
+        Err(e) => {
+            assert_eq!(e.kind(), ErrorKind::GuardCellAccess { address: 4 });
+            assert_eq!(e.pc(), Some(6));
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    // The guard cells before the allocation
+    sm.st.set_number_stack(vec![5, 0, 1]);
+    match sm.execute(6, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::GuardCellAccess { address: 0 }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_execute_movetocells_1() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 2 is the number of values to move to cells
+    // 0 is the location to start moving values to
+    // 3 2 1 are the values to use when moving to cells
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+
+    // Setup the cells we will be storing to
+    sm.st.cells.extend_from_slice(&[0, 0]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![0_i64, 1]);
+    assert_eq!(sm.st.cells, vec![3, 2]);
+}
+
+#[test]
+fn test_execute_movetocells_2() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // -2 Use an invalid number for the number of cells to cause a fault
+    // 0 is the start location to start
+    // 0 is the location to start moving values to
+    // 3 2 1 are the values to use when moving to cells
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, -2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_movetocells_3() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 2 is the number of values to move to cells
+    // -5 is an invalid start location to cause a fault
+    // 0 is the location to start moving values to
+    // 3 2 1 are the values to use when moving to cells
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, -5, 2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_movetocells_4() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 3 is the number of values to move to cells, it should cause a fault
+    // 0 is the location to start moving values to
+    // 3 2 1 are the values to use when moving to cells
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 3]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVETOCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_movefromcells_1() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 2 is the number of values to move to cells
+    // 0 is the location to start moving values to
+    // 3 2 1 are the values to use when moving to cells
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+
+    // Setup the cells we will be storing to
+    sm.st.cells.extend_from_slice(&[5, 4]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![0_i64, 1, 2, 3, 4, 5]);
+    assert_eq!(sm.st.cells, vec![5, 4]);
+}
+
+#[test]
+fn test_execute_movefromcells_2() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // -2 Use an invalid number for the number of cells to cause a fault
+    // 0 is the start location to start
+    // 0 is the location to start moving values from
+    // 3 2 1 are the values left on the stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, -2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_movefromcells_3() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 2 is the number of values to move from cells
+    // -5 is an invalid start location to cause a fault
+    // 0 is the location to start moving values from
+    // 3 2 1 are the values left on the stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, -5, 2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_movefromcells_4() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    // 3 is the number of values to move from cells, it should cause a fault
+    // 0 is a start location
+    // 0 is the location to start moving values from
+    // 3 2 1 are the values left on the stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[0_i64, 1, 2, 3, 0, 3]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::MOVEFROMCELLS, Opcode::RET]);
+
+    // Execute the instructions
+    assert_eq!(
+        match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+            Err(ErrorKind::InvalidCellOperation) => 1,
+            _ => 0,
+        },
+        1
+    );
+}
+
+#[test]
+fn test_execute_addsat() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[i64::MAX - 1, 5, 123, 321]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::ADDSAT,
+        Opcode::GtR,
+        Opcode::ADDSAT,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![i64::MAX, 444]);
+}
+
+#[test]
+fn test_execute_subsat() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[5, i64::MIN + 1, 321, 444]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::SUBSAT,
+        Opcode::GtR,
+        Opcode::SUBSAT,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![i64::MIN, 123]);
+}
+
+#[test]
+fn test_execute_mulsat() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[i64::MAX / 2, -3, 321, 123]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::MULSAT,
+        Opcode::GtR,
+        Opcode::MULSAT,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![i64::MIN, 39483]);
+}
+
+#[test]
+fn test_execute_isqrt() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[1, 99, i64::MAX]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::ISQRT,
+        Opcode::GtR,
+        Opcode::ISQRT,
+        Opcode::GtR,
+        Opcode::ISQRT,
+        Opcode::RGt,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![1, 9, 3037000499]);
+}
+
+#[test]
+fn test_execute_isqrt_negative() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[-4]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::ISQRT, Opcode::RET]);
+
+    // Execute the instructions
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::NumericOverflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_execute_gcd() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[0, 7, -12, 18]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::GCD,
+        Opcode::GtR,
+        Opcode::GCD,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![7, 6]);
+}
+
+#[test]
+fn test_execute_ipow() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[-3, 3]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::IPOW, Opcode::RET]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![-27]);
+}
+
+#[test]
+fn test_execute_ipow_overflow() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[2, 63]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::IPOW, Opcode::RET]);
+
+    // Execute the instructions
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::NumericOverflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_execute_32bit_wrapping() {
+    let mut sm = StackMachine::default();
+    sm.st.word_size = WordSize::Bits32;
+
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::LDI(i64::from(i32::MAX)),
+        Opcode::LDI(1),
+        Opcode::ADD,
+        Opcode::LDI(0x1_0000_0005),
+        Opcode::LDI(65536),
+        Opcode::LDI(65536),
+        Opcode::MUL,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![i64::from(i32::MIN), 5, 0]);
+}
+
+#[test]
+fn test_execute_32bit_saturating_and_checked() {
+    let mut sm = StackMachine::default();
+    sm.st.word_size = WordSize::Bits32;
+
+    // Populate the number stack
+    sm.st
+        .number_stack
+        .extend_from_slice(&[i64::from(i32::MAX), 1, 2, 31]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::GtR,
+        Opcode::GtR,
+        Opcode::ADDSAT,
+        Opcode::RGt,
+        Opcode::RGt,
+        Opcode::IPOW,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::NumericOverflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(sm.st.number_stack, vec![i64::from(i32::MAX)]);
+}
+
+#[test]
+fn test_execute_flags_carry() {
+    let mut sm = StackMachine::default();
+    sm.st.flags = Some(Flags::default());
+
+    // Add the low words of two double width numbers, then use the carry to
+    // decide whether to increment the sum of the high words
+    sm.st.number_stack.extend_from_slice(&[1, 2, -1, 1]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::ADD,
+        Opcode::GtR,
+        Opcode::LDI(4),
+        Opcode::JRC,
+        Opcode::ADD,
+        Opcode::RGt,
+        Opcode::RET,
+        Opcode::ADD,
+        Opcode::LDI(1),
+        Opcode::ADD,
+        Opcode::RGt,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![4, 0]);
+    assert_eq!(
+        sm.st.flags,
+        Some(Flags {
+            carry: false,
+            overflow: false,
+            zero: false,
+            negative: false
+        })
+    );
+}
+
+#[test]
+fn test_execute_flags_overflow() {
+    let mut sm = StackMachine::default();
+    sm.st.flags = Some(Flags::default());
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[i64::MAX, 1]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::ADD,
+        Opcode::LDI(2),
+        Opcode::JRO,
+        Opcode::LDI(0),
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![i64::MIN]);
+    assert_eq!(
+        sm.st.flags,
+        Some(Flags {
+            carry: false,
+            overflow: true,
+            zero: false,
+            negative: true
+        })
+    );
+}
+
+#[test]
+fn test_execute_flags_not_enabled() {
+    let mut sm = StackMachine::default();
+
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(2), Opcode::JRC, Opcode::RET]);
+
+    // Execute the instructions
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::FlagsNotEnabled) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_execute_div_by_zero() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[10, 0]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[Opcode::DIV, Opcode::RET]);
+
+    // Execute the instructions
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::DivisionByZero) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_execute_fmdivmod() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[-7, 2]);
+    // Put the opcodes into the *memory*
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::FMDIVMOD, Opcode::RET]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![1, -4]);
+}
+
+#[test]
+fn test_execute_fmdivmod_negative_divisor() {
+    let mut sm = StackMachine::default();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[7, -2, -6, 3]);
+    // Put the opcodes into the *memory*
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::FMDIVMOD,
+        Opcode::GtR2,
+        Opcode::FMDIVMOD,
+        Opcode::RGt2,
+        Opcode::RET,
+    ]);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![-1, -4, 0, -2]);
+}
+
+#[test]
+fn test_opcode_display() {
+    assert_eq!(Opcode::ADD.to_string(), "ADD");
+    assert_eq!(Opcode::GtR2.to_string(), "GtR2");
+    assert_eq!(Opcode::LDI(-5).to_string(), "LDI -5");
+}
+
+#[test]
+fn test_opcode_from_str() {
+    assert_eq!("RET".parse::<Opcode>(), Ok(Opcode::RET));
+    assert_eq!("  LDI   42 ".parse::<Opcode>(), Ok(Opcode::LDI(42)));
+    assert_eq!(
+        "LDI".parse::<Opcode>(),
+        Err(ParseOpcodeError::MissingImmediate)
+    );
+    assert_eq!(
+        "LDI x".parse::<Opcode>(),
+        Err(ParseOpcodeError::InvalidImmediate("x".to_string()))
+    );
+    assert_eq!(
+        "ADD 1".parse::<Opcode>(),
+        Err(ParseOpcodeError::UnexpectedOperand("1".to_string()))
+    );
+    assert_eq!(
+        "FOO".parse::<Opcode>(),
+        Err(ParseOpcodeError::UnknownMnemonic("FOO".to_string()))
+    );
+}
+
+#[test]
+fn test_opcode_display_round_trip() {
+    for op in &[
+        Opcode::JMP,
+        Opcode::LDI(i64::MIN),
+        Opcode::LDI(i64::MAX),
+        Opcode::RAt2,
+        Opcode::MOVEFROMCELLS,
+        Opcode::FMDIVMOD,
+    ] {
+        assert_eq!(op.to_string().parse::<Opcode>().as_ref(), Ok(op));
+    }
+}
+
+#[test]
+fn test_opcode_encoding() {
+    assert_eq!(Opcode::JMP.encode(), (0, None));
+    assert_eq!(Opcode::LDI(-9).encode(), (7, Some(-9)));
+    assert_eq!(Opcode::try_from((12, None)), Ok(Opcode::ADD));
+    assert_eq!(
+        Opcode::try_from((7, None)),
+        Err(DecodeOpcodeError::MissingImmediate(7))
+    );
+    assert_eq!(
+        Opcode::try_from((12, Some(1))),
+        Err(DecodeOpcodeError::UnexpectedImmediate(12))
+    );
+    assert_eq!(
+        Opcode::try_from((OPCODE_COUNT, None)),
+        Err(DecodeOpcodeError::UnknownCode(OPCODE_COUNT))
+    );
+}
+
+#[test]
+fn test_opcode_encoding_round_trip() {
+    for code in 0..OPCODE_COUNT {
+        let immediate = if matches!(code, 7 | 51..=55 | 61 | 62) {
+            Some(1234)
+        } else {
+            None
+        };
+        let op = Opcode::try_from((code, immediate)).unwrap();
+        assert_eq!(op.encode(), (code, immediate));
+    }
+}
+
+#[test]
+fn test_opcode_metadata() {
+    let add = Opcode::ADD.metadata();
+    assert_eq!(add.mnemonic, "ADD");
+    assert_eq!((add.immediates, add.pops, add.pushes), (0, 2, 1));
+    assert_eq!(add.gas_class, GasClass::Arithmetic);
+    assert!(!add.variable_stack_effect);
+
+    let ldi = Opcode::LDI(3).metadata();
+    assert_eq!((ldi.immediates, ldi.pops, ldi.pushes), (1, 0, 1));
+
+    assert!(Opcode::MOVETOCELLS.metadata().variable_stack_effect);
+    assert_eq!(Opcode::TRAP.metadata().gas_class, GasClass::Host);
+}
+
+#[test]
+fn test_opcode_all_variants() {
+    let all = Opcode::all_variants();
+    assert_eq!(all.len(), usize::from(OPCODE_COUNT));
+    for (code, op) in all.iter().enumerate() {
+        let (encoded, immediate) = op.encode();
+        assert_eq!(usize::from(encoded), code);
+        assert_eq!(Opcode::try_from((encoded, immediate)).as_ref(), Ok(op));
+        assert_eq!(op.to_string().parse::<Opcode>().as_ref(), Ok(op));
+        assert_eq!(op.metadata().mnemonic, op.mnemonic());
+        // SPILL and UNSPILL pack their two immediates into one
+        assert_eq!(op.metadata().immediates > 0, immediate.is_some());
+    }
+}
+
+#[test]
+fn test_program_macro() {
+    let opcodes = program![LDI 5, LDI 6, ADD, RET];
+
+    assert_eq!(
+        opcodes,
+        vec![Opcode::LDI(5), Opcode::LDI(6), Opcode::ADD, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_program_macro_labels() {
+    let mut sm = StackMachine::default();
+
+    // Count down from 3, calling a subroutine that doubles a running total each time
+    sm.st.opcodes = program![
+        LDI 1,
+        LDI 3,
+        top:
+        SWAP,
+        LDI @double,
+        CALL,
+        SWAP,
+        LDI -1,
+        ADD,
+        DUP,
+        LDI %top,
+        JRNZ,
+        DROP,
+        RET,
+        double:
+        DUP,
+        ADD,
+        RET,
+    ];
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![8]);
+}
+
+#[test]
+#[should_panic]
+fn test_program_macro_undefined_label() {
+    let _ = program![LDI @nowhere, JMP];
+}
+
+#[test]
+fn test_ssp_asm() {
+    const CODE: &[Opcode] = ssp_asm! {
+        LDI 3;
+        top: LDI -1;
+        ADD;
+        DUP;
+        JRNZ @top;
+        CALL @done;
+        done: RET
+    };
+
+    assert_eq!(
+        CODE,
+        &[
+            Opcode::LDI(3),
+            Opcode::LDI(-1),
+            Opcode::ADD,
+            Opcode::DUP,
+            Opcode::LDI(-4),
+            Opcode::JRNZ,
+            Opcode::LDI(8),
+            Opcode::CALL,
+            Opcode::RET,
+        ]
+    );
+
+    let mut sm = StackMachine::default();
+    sm.st.opcodes.extend_from_slice(CODE);
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![0]);
+}
+
+#[test]
+fn test_examples_factorial() {
+    for (n, expected) in &[(0, 1), (1, 1), (5, 120), (20, 2432902008176640000)] {
+        let mut sm = StackMachine::default();
+        sm.st.opcodes = examples_lib::factorial();
+        sm.st.number_stack.push(*n);
+
+        sm.execute(0, GasLimit::Limited(1000)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![*expected]);
+    }
+}
+
+#[test]
+fn test_examples_fibonacci() {
+    for (n, expected) in &[(0, 0), (1, 1), (2, 1), (10, 55), (50, 12586269025)] {
+        let mut sm = StackMachine::default();
+        sm.st.opcodes = examples_lib::fibonacci();
+        sm.st.number_stack.push(*n);
+
+        sm.execute(0, GasLimit::Limited(1000)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![*expected]);
+    }
+}
+
+#[test]
+fn test_examples_memcpy() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = examples_lib::memcpy();
+    sm.st.cells.extend_from_slice(&[1, 2, 3, 4, 0, 0, 0]);
+    sm.st.number_stack.extend_from_slice(&[0, 3, 4]);
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, Vec::<i64>::new());
+    assert_eq!(sm.st.cells, vec![1, 2, 3, 1, 2, 3, 4]);
+
+    // A zero length copy is allowed
+    sm.st.number_stack.extend_from_slice(&[0, 3, 0]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, Vec::<i64>::new());
+}
+
+#[test]
+fn test_examples_string_length() {
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = examples_lib::string_length();
+    sm.st.cells.extend_from_slice(&[9, 104, 105, 33, 0, 0]);
+    sm.st.number_stack.push(1);
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack, vec![3]);
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn test_bench_workloads() {
+    for workload in bench::all() {
+        assert!(workload.run().unwrap() > 10_000, "{}", workload.name);
+    }
+    assert_eq!(bench::call_heavy(10).machine().st.number_stack, vec![10]);
+}
+
+#[test]
+fn test_execution_statistics() {
+    let mut sm = StackMachine::default();
+    sm.enable_statistics();
+    sm.trap_handlers
+        .push(Box::from(TrapHandler::new(7, |_trap_id, _st| {
+            Ok(TrapHandled::Handled)
+        })));
+
+    // Count down from 2, then raise trap 7
+    sm.st.opcodes = program![
+        LDI 2,
+        top:
+        LDI -1,
+        ADD,
+        DUP,
+        LDI %top,
+        JRNZ,
+        LDI 7,
+        TRAP,
+    ];
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    let statistics = sm.statistics().unwrap();
+    assert_eq!(statistics.total_instructions, 13);
+    assert_eq!(statistics.instructions_by_opcode["LDI"], 6);
+    assert_eq!(statistics.instructions_by_opcode["JRNZ"], 2);
+    assert!(!statistics.instructions_by_opcode.contains_key("RET"));
+    assert_eq!(statistics.branches_taken, 1);
+    assert_eq!(statistics.branches_not_taken, 1);
+    assert_eq!(statistics.traps.get(&7), Some(&1));
+
+    // Statistics are per run
+    sm.st.number_stack.clear();
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.statistics().unwrap().total_instructions, 13);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_execution_statistics_serializable() {
+    fn assert_serializable<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+    assert_serializable::<ExecutionStatistics>();
+}
+
+#[test]
+fn test_call_graph_profiler() {
+    let mut sm = StackMachine::default();
+    sm.enable_profiler();
+
+    let mut builder = ProgramBuilder::new();
+    builder
+        .label("MAIN")
+        .op(Opcode::LDI(3))
+        .ldi_address("SQUARE")
+        .op(Opcode::CALL)
+        .ldi_address("TWICE")
+        .op(Opcode::CALL)
+        .op(Opcode::RET)
+        .label("TWICE")
+        .ldi_address("SQUARE")
+        .op(Opcode::CALL)
+        .ldi_address("SQUARE")
+        .op(Opcode::CALL)
+        .op(Opcode::RET)
+        .label("SQUARE")
+        .op(Opcode::DUP)
+        .op(Opcode::MUL)
+        .op(Opcode::RET);
+    let (opcodes, symbols) = builder.build_with_symbols().unwrap();
+    sm.st.opcodes = opcodes;
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![6561]);
+
+    let profile = sm.profile().unwrap();
+    let square = profile
+        .function(symbols.address_of("SQUARE").unwrap())
+        .unwrap();
+    assert_eq!(square.calls, 3);
+    assert_eq!(square.inclusive_gas, 9);
+    assert_eq!(square.exclusive_gas, 9);
+    let twice = profile
+        .function(symbols.address_of("TWICE").unwrap())
+        .unwrap();
+    assert_eq!(
+        (twice.calls, twice.inclusive_gas, twice.exclusive_gas),
+        (1, 11, 5)
+    );
+    let main = profile.function(0).unwrap();
+    assert_eq!(
+        (main.calls, main.inclusive_gas, main.exclusive_gas),
+        (1, sm.st.gas_used(), 5)
+    );
+
+    let report = profile.report(&symbols);
+    assert!(report.contains("TWICE -> SQUARE (2 calls)"));
+    assert!(report.contains("MAIN -> TWICE (1 calls)"));
+}
+
+#[test]
+fn test_high_water_marks() {
+    let mut sm = StackMachine::default();
+    sm.enable_high_water_marks();
+
+    // Populate the number stack
+    sm.st.number_stack.extend_from_slice(&[1, 2]);
+    sm.st.opcodes = program![
+        LDI 3,
+        LDI 4,
+        GtR2,
+        DROP,
+        DROP,
+        LDI 5,
+        LDI 0,
+        PUSHLP,
+        DROPLP,
+        LDI 3,
+        NEWCELLS,
+        LDI @sub,
+        CALL,
+        RGt2,
+        RET,
+        sub:
+        RET,
+    ];
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(
+        sm.high_water_marks(),
+        Some(HighWaterMarks {
+            number_stack: 4,
+            scratch_stack: 2,
+            return_stack: 1,
+            loop_stack: 1,
+            cells: 3,
+        })
+    );
+}
+
+#[test]
+fn test_gas_by_call_target() {
+    let mut sm = StackMachine::default();
+    sm.enable_call_gas();
+
+    sm.st.opcodes = program![
+        LDI 3,
+        LDI @square,
+        CALL,
+        LDI @twice,
+        CALL,
+        RET,
+        twice:
+        LDI @square,
+        CALL,
+        LDI @square,
+        CALL,
+        RET,
+        square:
+        DUP,
+        MUL,
+        RET,
+    ];
+
+    // Execute the instructions
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    let gas = sm.gas_by_call_target().unwrap();
+    assert_eq!(gas.len(), 2);
+    assert_eq!(gas[&11], CallTargetGas { calls: 3, gas: 9 });
+    assert_eq!(gas[&6], CallTargetGas { calls: 1, gas: 11 });
+}
+
+#[test]
+fn test_analyze_loop_free() {
+    let opcodes = program![
+        LDI 3,
+        LDI 0,
+        LDI %skip,
+        JRZ,
+        LDI @square,
+        CALL,
+        LDI @square,
+        CALL,
+        skip:
+        RET,
+        square:
+        PUSHLP,
+        DROPLP,
+        DUP,
+        MUL,
+        RET,
+    ];
+
+    let metrics = analyze(&opcodes);
+    assert_eq!(metrics.instruction_count, 14);
+    assert_eq!(metrics.branch_count, 3);
+    assert_eq!(metrics.call_count, 2);
+    assert_eq!(metrics.trap_count, 0);
+    assert_eq!(metrics.max_loop_nesting, 1);
+    // Both calls taken, 9 instructions in the main line and 5 in each call
+    assert_eq!(metrics.worst_case_gas, Some(19));
+}
+
+#[test]
+fn test_analyze_unbounded() {
+    let looping = program![top: LDI 1, LDI %top, JRNZ, RET];
+    assert_eq!(analyze(&looping).worst_case_gas, None);
+
+    let dynamic = program![LDI 1, LDI 2, ADD, JMP, RET];
+    assert_eq!(analyze(&dynamic).worst_case_gas, None);
+
+    let recursive = program![start: LDI @start, CALL, RET];
+    assert_eq!(analyze(&recursive).worst_case_gas, None);
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!(validate(&[]), Err(ValidationError::EmptyProgram));
+    // Running past the end halts, by falling through or jumping just past the end
+    assert_eq!(validate(&[Opcode::LDI(1), Opcode::DROP]), Ok(()));
+    assert_eq!(validate(&[Opcode::LDI(1), Opcode::JR]), Ok(()));
+    assert_eq!(validate(&[Opcode::LDI(2), Opcode::JMP]), Ok(()));
+    let mut sm = StackMachine::default();
+    sm.st.load_program(vec![Opcode::LDI(2), Opcode::JMP]);
+    assert_eq!(
+        sm.execute(0, GasLimit::Limited(10)).unwrap(),
+        ExitStatus::Halted
+    );
+    assert_eq!(
+        validate(&[Opcode::LDI(3), Opcode::JMP]),
+        Err(ValidationError::TargetOutOfRange { pc: 1, target: 3 })
+    );
+    assert_eq!(
+        validate(&[Opcode::LDI(5), Opcode::JR]),
+        Err(ValidationError::TargetOutOfRange { pc: 1, target: 6 })
+    );
+    assert_eq!(
+        validate(&[Opcode::LDI(-3), Opcode::JR]),
+        Err(ValidationError::TargetOutOfRange { pc: 1, target: -2 })
+    );
+    assert_eq!(validate(&examples_lib::factorial()), Ok(()));
+}
+
+#[test]
+fn test_estimate_cost() {
+    // Ten iterations of a three instruction body, plus the loop overhead
+    let counted = program![
+        LDI 10,
+        LDI 0,
+        PUSHLP,
+        top:
+        LDI 1,
+        LDI 2,
+        ADD,
+        INCLP,
+        CMPLOOP,
+        LDI %top,
+        JRZ,
+        DROPLP,
+        RET,
+    ];
+    assert_eq!(
+        estimate_cost(&counted, 0, 100),
+        Ok(CostEstimate::WithinBudget { worst_case_gas: 75 })
+    );
+    assert_eq!(
+        estimate_cost(&counted, 0, 50),
+        Ok(CostEstimate::ExceedsBudget { worst_case_gas: 75 })
+    );
+
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = counted;
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert!(sm.st.gas_used() <= 75);
+
+    assert_eq!(
+        estimate_cost(&examples_lib::factorial(), 0, 1_000_000),
+        Ok(CostEstimate::NeedsMetering)
+    );
+
+    // A body balancing the loop stack, but resetting the counter every pass, never ends
+    let resetting = program![
+        LDI 10,
+        LDI 0,
+        PUSHLP,
+        top:
+        DROPLP,
+        LDI 10,
+        LDI 0,
+        PUSHLP,
+        INCLP,
+        CMPLOOP,
+        LDI %top,
+        JRZ,
+        DROPLP,
+        RET,
+    ];
+    assert_eq!(
+        estimate_cost(&resetting, 0, 1_000_000),
+        Ok(CostEstimate::NeedsMetering)
+    );
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = resetting;
+    assert!(sm.execute(0, GasLimit::Limited(10_000)).is_err());
+
+    // A nested counted loop is still bounded
+    let nested = program![
+        LDI 3,
+        LDI 0,
+        PUSHLP,
+        outer:
+        LDI 2,
+        LDI 0,
+        PUSHLP,
+        inner:
+        NOP,
+        INCLP,
+        CMPLOOP,
+        LDI %inner,
+        JRZ,
+        DROPLP,
+        INCLP,
+        CMPLOOP,
+        LDI %outer,
+        JRZ,
+        DROPLP,
+        RET,
+    ];
+    let worst_case_gas = match estimate_cost(&nested, 0, 1_000) {
+        Ok(CostEstimate::WithinBudget { worst_case_gas }) => worst_case_gas,
+        other => panic!("{:?}", other),
+    };
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = nested;
+    sm.execute(0, GasLimit::Limited(1_000)).unwrap();
+    assert!(sm.st.gas_used() <= worst_case_gas);
+    assert_eq!(
+        estimate_cost(&[Opcode::NOP], 0, 10),
+        Ok(CostEstimate::WithinBudget { worst_case_gas: 1 })
+    );
+}
+
+#[test]
+fn test_propagate_constants() {
+    let opcodes = program![
+        LDI 2,
+        LDI 3,
+        ADD,
+        JR,
+        NOP,
+        NOP,
+        NOP,
+        NOP,
+        LDI 0,
+        LDI 4,
+        JRNZ,
+        RET,
+    ];
+
+    let analysis = propagate_constants(&opcodes);
+    assert_eq!(analysis.constant_results.get(&2), Some(&5));
+    assert_eq!(analysis.jump_targets.get(&3), Some(&8));
+    assert!(!analysis.is_reachable(4));
+    assert!(analysis.is_reachable(8));
+    // A zero flag never takes a JRNZ
+    assert_eq!(analysis.constant_branches.get(&10), Some(&false));
+    assert_eq!(analysis.jump_targets.get(&10), None);
+    assert!(!analysis.has_dynamic_jumps);
+    assert_eq!(
+        analysis.stack_at(10),
+        Some(&[AbstractValue::Known(0), AbstractValue::Known(4)][..])
+    );
+}
+
+#[test]
+fn test_fold_constants() {
+    let opcodes = program![LDI 2, LDI 3, ADD, LDI 4, MUL, GtR, RET];
+
+    let folded = fold_constants(&opcodes, &[0], WordSize::Bits64, false);
+    assert_eq!(
+        folded,
+        vec![
+            Opcode::NOP,
+            Opcode::NOP,
+            Opcode::NOP,
+            Opcode::NOP,
+            Opcode::LDI(20),
+            Opcode::GtR,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_fold_constants_word_size_and_flags() {
+    let run = |opcodes: Vec<Opcode>, word_size: WordSize, flags: Option<Flags>| {
+        let mut sm = StackMachine::default();
+        sm.st.word_size = word_size;
+        sm.st.flags = flags;
+        sm.st.load_program(opcodes);
+        sm.execute(0, GasLimit::Limited(100))
+            .map(|_| (sm.st.number_stack().to_vec(), sm.st.flags))
+    };
+
+    // Folding for 32-bit words gives the results a 32-bit machine computes
+    let max = i64::from(i32::MAX);
+    let programs = vec![
+        program![LDI max, LDI 1, ADDSAT, RET],
+        program![LDI max, LDI 2, MULSAT, RET],
+        program![LDI max, LDI 1, ADD, RET],
+        program![LDI 2, LDI 40, IPOW, RET],
+        program![LDI 4_294_967_296, LDI 5, ADD, RET],
+        program![LDI 4_294_967_296, CMPZ, RET],
+        program![LDI 6, LDI 4_294_967_298, GCD, RET],
+    ];
+    for opcodes in programs {
+        let folded = fold_constants(&opcodes, &[0], WordSize::Bits32, false);
+        assert_eq!(
+            run(folded, WordSize::Bits32, None).ok(),
+            run(opcodes, WordSize::Bits32, None).ok()
+        );
+    }
+    let folded = fold_constants(
+        &program![LDI max, LDI 1, ADDSAT, RET],
+        &[0],
+        WordSize::Bits32,
+        false,
+    );
+    assert_eq!(folded[2], Opcode::LDI(max));
+    // 2^40 does not fit in 32 bits, so IPOW is left to fail at run time
+    let opcodes = program![LDI 2, LDI 40, IPOW, RET];
+    assert_eq!(
+        fold_constants(&opcodes, &[0], WordSize::Bits32, false),
+        opcodes
+    );
+
+    // With flags the instructions that set them are kept, so JRC still sees the carry
+    let opcodes = program![LDI -1, LDI 1, ADD, LDI 3, JRC, LDI 7, RET, LDI 8, RET];
+    let folded = fold_constants(&opcodes, &[0], WordSize::Bits64, true);
+    assert_eq!(folded, opcodes);
+    assert_eq!(
+        run(folded, WordSize::Bits64, Some(Flags::default()))
+            .unwrap()
+            .0,
+        vec![0, 8]
+    );
+    assert_ne!(
+        fold_constants(&opcodes, &[0], WordSize::Bits64, false),
+        opcodes
+    );
+}
+
+#[test]
+fn test_validate_computed_target() {
+    assert_eq!(
+        validate(&[Opcode::LDI(40), Opcode::LDI(2), Opcode::ADD, Opcode::JMP]),
+        Err(ValidationError::TargetOutOfRange { pc: 3, target: 42 })
+    );
+}
+
+#[cfg(feature = "symexec")]
+#[test]
+fn test_symexec_division_by_zero() {
+    use symexec::{explore, FindingKind, SymexecOptions};
+
+    // ( a b -- a/(b-5) ) unless b is zero
+    let opcodes = program![
+        DUP,
+        LDI %zero,
+        JRZ,
+        LDI -5,
+        SWAP,
+        ADD,
+        DIV,
+        RET,
+        zero:
+        DROP,
+        RET,
+    ];
+
+    let report = explore(&opcodes, SymexecOptions::default());
+    assert_eq!(report.paths_explored, 2);
+    let division: Vec<_> = report
+        .findings
+        .iter()
+        .filter(|f| f.kind == FindingKind::DivisionByZero)
+        .collect();
+    assert_eq!(division.len(), 1);
+    assert_eq!(division[0].pc, 6);
+    assert_eq!(division[0].inputs[1], 5);
+
+    // The witness really does fail
+    let mut sm = StackMachine::default();
+    sm.st.opcodes = opcodes;
+    sm.st.number_stack = division[0].inputs.clone();
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::DivisionByZero) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[cfg(feature = "symexec")]
+#[test]
+fn test_symexec_overflow_and_underflow() {
+    use symexec::{explore, FindingKind, SymexecOptions};
+
+    let report = explore(
+        &[Opcode::MUL, Opcode::DROP, Opcode::DROP, Opcode::RET],
+        SymexecOptions::default(),
+    );
+    let kinds: Vec<_> = report.findings.iter().map(|f| (f.kind, f.pc)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (FindingKind::Overflow, 0),
+            (FindingKind::NumberStackUnderflow, 2)
+        ]
+    );
+    let inputs = &report.findings[0].inputs;
+    assert!(inputs[0].checked_mul(inputs[1]).is_none());
+}
+
+#[cfg(feature = "proptest")]
+mod property_tests {
+    use super::*;
+    use crate::testing::{arb_number_stack, arb_opcode, arb_program, check_invariants};
+    use proptest::prelude::*;
+
+    // Straight line code ending in RET
+    fn straight_line_op() -> impl Strategy<Value = Opcode> {
+        arb_opcode().prop_filter("jump", |op| !analysis::is_branch(op) && *op != Opcode::CALL)
+    }
+
+    proptest! {
+        #[test]
+        fn test_programs_never_panic(
+            opcodes in arb_program(32),
+            stack in arb_number_stack(4),
+        ) {
+            check_invariants(&opcodes, &stack, 64)?;
+        }
+
+        #[test]
+        fn test_straight_line_programs_stay_within_gas(
+            body in proptest::collection::vec(straight_line_op(), 0..24),
+            stack in arb_number_stack(4),
+        ) {
+            let mut opcodes = body;
+            opcodes.push(Opcode::RET);
+            check_invariants(&opcodes, &stack, 16)?;
+        }
+    }
+}
+
+#[cfg(feature = "golden")]
+#[test]
+fn test_golden_update_then_check() {
+    use golden::{run_golden, GoldenError, GoldenMode};
+
+    let path = std::env::temp_dir().join(format!("ssp-golden-{}.json", std::process::id()));
+    let opcodes = [Opcode::LDI(3), Opcode::ADD, Opcode::RET];
+
+    run_golden(
+        &opcodes,
+        &[4],
+        GasLimit::Limited(100),
+        &path,
+        GoldenMode::Update,
+    )
+    .unwrap();
+    run_golden(
+        &opcodes,
+        &[4],
+        GasLimit::Limited(100),
+        &path,
+        GoldenMode::Check,
+    )
+    .unwrap();
+
+    match run_golden(
+        &opcodes,
+        &[5],
+        GasLimit::Limited(100),
+        &path,
+        GoldenMode::Check,
+    ) {
+        Err(GoldenError::Mismatch {
+            expected, actual, ..
+        }) => {
+            assert_eq!(expected.number_stack, vec![7]);
+            assert_eq!(actual.number_stack, vec![8]);
+        }
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "golden")]
+#[test]
+fn test_trace_golden() {
+    use golden::{check_trace, record_trace, run_trace_golden, GoldenError, GoldenMode};
+
+    let path = std::env::temp_dir().join(format!("ssp-trace-{}.txt", std::process::id()));
+    let opcodes = program![LDI 0, LDI 2, JRZ, NOP, LDI - 5, ADD, RET];
+
+    run_trace_golden(
+        &opcodes,
+        &[4],
+        GasLimit::Limited(100),
+        &path,
+        GoldenMode::Update,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "0 LDI 0\n1 LDI 2\n2 JRZ\n4 LDI -5\n5 ADD\n"
+    );
+    run_trace_golden(
+        &opcodes,
+        &[4],
+        GasLimit::Limited(100),
+        &path,
+        GoldenMode::Check,
+    )
+    .unwrap();
+
+    // Not taking the branch runs the NOP
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(program![LDI 1, LDI 2, JRZ, NOP, LDI - 5, ADD, RET]);
+    sm.st.set_number_stack(vec![4]);
+    let (trace, result) = record_trace(&mut sm, 0, GasLimit::Limited(100));
+    assert_eq!(result.unwrap(), ExitStatus::Returned);
+    assert!(sm.event_sinks.is_empty());
+    match check_trace(&trace, &path, GoldenMode::Check) {
+        Err(GoldenError::TraceMismatch {
+            index: 0,
+            expected: Some((0, Opcode::LDI(0))),
+            actual: Some((0, Opcode::LDI(1))),
+            ..
+        }) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    match check_trace(&trace[..0], &path, GoldenMode::Check) {
+        Err(GoldenError::TraceMismatch {
+            index: 0,
+            actual: None,
+            ..
+        }) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+
+    std::fs::write(&path, "0 LDI 0\nLDI 2\n").unwrap();
+    match check_trace(&trace, &path, GoldenMode::Check) {
+        Err(GoldenError::BadTraceLine { line: 2, .. }) => (),
+        r => panic!("Incorrect result returned {:?}", r),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_event_sink() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<Event>>>);
+    impl EventSink for Shared {
+        fn event(&mut self, event: &Event) {
+            self.0.borrow_mut().event(event);
+        }
+    }
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut sm = StackMachine::default();
+    sm.event_sinks.push(Box::new(Shared(events.clone())));
+    sm.trap_handlers.push(Box::new(TrapHandler::new(7, |_, _| {
+        Ok(TrapHandled::Handled)
+    })));
+
+    // Call the RET at 9, branch over the NOPs, then finish with a handled TRAP
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::LDI(9),
+        Opcode::CALL,
+        Opcode::LDI(0),
+        Opcode::LDI(3),
+        Opcode::JRZ,
+        Opcode::NOP,
+        Opcode::NOP,
+        Opcode::LDI(7),
+        Opcode::TRAP,
+        Opcode::RET,
+    ]);
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    let events = events.borrow();
+    let interesting: Vec<&Event> = events
+        .iter()
+        .filter(|e| !matches!(e, Event::InstructionRetired { .. }))
+        .collect();
+    assert_eq!(
+        interesting,
+        vec![
+            &Event::CallEntered { from: 1, target: 9 },
+            &Event::CallReturned { from: 9, to: 2 },
+            &Event::BranchTaken { from: 4, to: 7 },
+            &Event::TrapRaised { pc: 8, trap_id: 7 },
+        ]
+    );
+    assert_eq!(
+        events.last(),
+        Some(&Event::TrapRaised { pc: 8, trap_id: 7 })
+    );
+    let retired = events.len() - interesting.len();
+    assert_eq!(retired as u64, sm.st.gas_used());
+}
+
+#[test]
+fn test_trap_handler_stats() {
+    let mut sm = StackMachine::default();
+    let failing = sm
+        .trap_handlers
+        .register(Box::new(TrapHandler::new(2, |_, _| {
+            Err(ErrorKind::Unknown.into())
+        })));
+    let working = sm
+        .trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, _| {
+            Ok(TrapHandled::Handled)
+        })));
+    sm.enable_trap_handler_stats();
+
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(1), Opcode::TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    let stats = sm.trap_handler_stats().unwrap();
+    let (f, w) = (stats[&failing], stats[&working]);
+    assert_eq!((f.calls, f.handled, f.errors), (1, 0, 0));
+    assert_eq!((w.calls, w.handled, w.errors), (1, 1, 0));
+
+    sm.st.opcodes[0] = Opcode::LDI(2);
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::Unknown) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    let stats = sm.trap_handler_stats().unwrap();
+    let f = stats[&failing];
+    assert_eq!((f.calls, f.handled, f.errors), (1, 0, 1));
+    assert_eq!(stats[&working], TrapHandlerStats::default());
+}
+
+#[test]
+fn test_trap_handler_registration() {
+    let mut sm = StackMachine::default();
+    let first = sm
+        .trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, st| {
+            st.number_stack.push(10);
+            Ok(TrapHandled::Handled)
+        })));
+    let second = sm
+        .trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, st| {
+            st.number_stack.push(20);
+            Ok(TrapHandled::Handled)
+        })));
+    assert_ne!(first, second);
+
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(1), Opcode::TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![10]);
+
+    // Replacing keeps the handler's place in the chain
+    let replacement = Box::new(TrapHandler::new(1, |_, st| {
+        st.number_stack.push(30);
+        Ok(TrapHandled::Handled)
+    }));
+    assert!(sm.trap_handlers.replace(first, replacement).is_some());
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![10, 30]);
+
+    assert!(sm.trap_handlers.unregister(first).is_some());
+    assert!(sm.trap_handlers.unregister(first).is_none());
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![10, 30, 20]);
+    assert_eq!(sm.trap_handlers.ids().collect::<Vec<_>>(), vec![second]);
+}
+
+#[test]
+fn test_fallback_trap_handler() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, _| {
+            Ok(TrapHandled::Handled)
+        })));
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(5), Opcode::TRAP]);
+
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::UnhandledTrap { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    assert!(sm
+        .trap_handlers
+        .set_fallback(Box::new(TrapHandler::any(|trap_id, st| {
+            st.number_stack.push(trap_id * 10);
+            Ok(TrapHandled::Handled)
+        })))
+        .is_none());
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![50]);
+
+    // Registered handlers still come first
+    sm.st.number_stack.clear();
+    sm.st.opcodes[0] = Opcode::LDI(1);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert!(sm.st.number_stack.is_empty());
+}
+
+#[test]
+fn test_trap_frame() {
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, st| {
+            let mut frame = TrapFrame::new(st);
+            assert_eq!(frame.arg(0)?, 3);
+            assert_eq!(frame.arg(1)?, 10);
+            let [a, b] = frame.args()?;
+            frame.ret(&[a + b, a - b]);
+            Ok(TrapHandled::Handled)
+        })));
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(2, |_, st| {
+            let [_, _, _] = TrapFrame::new(st).args()?;
+            Ok(TrapHandled::Handled)
+        })));
+
+    sm.st.opcodes.extend_from_slice(&[
+        Opcode::LDI(10),
+        Opcode::LDI(3),
+        Opcode::LDI(1),
+        Opcode::TRAP,
+    ]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![13, 7]);
+
+    sm.st.number_stack.clear();
+    sm.st.opcodes[2] = Opcode::LDI(2);
+    match sm.execute(0, GasLimit::Limited(100)).map_err(|e| e.kind()) {
+        Err(ErrorKind::StackUnderflow(StackKind::Number)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    // A failed args leaves the stack alone
+    assert_eq!(sm.st.number_stack, vec![10, 3]);
+}
+
+#[test]
+fn test_stack_abi() {
+    #[derive(StackAbi, Debug, PartialEq)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+
+    #[derive(StackAbi, Debug, PartialEq)]
+    struct Rect {
+        origin: Point,
+        size: [i64; 2],
+    }
+
+    assert_eq!(<Rect as StackAbi>::WIDTH, 4);
+
+    // Move a rectangle by (dx, dy), passed as ( x y w h dx dy -- x y w h )
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, st| {
+            let mut frame = TrapFrame::new(st);
+            let delta: Point = frame.arg_struct()?;
+            let mut rect: Rect = frame.arg_struct()?;
+            rect.origin.x += delta.x;
+            rect.origin.y += delta.y;
+            frame.ret_struct(&rect);
+            Ok(TrapHandled::Handled)
+        })));
+    sm.st.number_stack.extend_from_slice(&[1, 2, 30, 40, 5, 6]);
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(1), Opcode::TRAP]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert_eq!(sm.st.number_stack, vec![6, 8, 30, 40]);
+
+    let rect = Rect {
+        origin: Point { x: 1, y: 2 },
+        size: [3, 4],
+    };
+    sm.st.cells.resize(6, 0);
+    sm.st.store_struct(2, &rect).unwrap();
+    assert_eq!(sm.st.cells, vec![0, 0, 1, 2, 3, 4]);
+    assert_eq!(sm.st.load_struct::<Rect>(2).unwrap(), rect);
+    match sm.st.load_struct::<Rect>(3).map_err(|e| e.kind()) {
+        Err(ErrorKind::InvalidCellOperation) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    // Stores wrap to the word size and touch the cells, as MOVETOCELLS does
+    sm.st.word_size = WordSize::Bits32;
+    sm.enable_touched_cells(8);
+    let wide = Rect {
+        origin: Point {
+            x: (1 << 32) + 5,
+            y: 2,
+        },
+        size: [3, 4],
+    };
+    sm.st.store_struct(2, &wide).unwrap();
+    assert_eq!(sm.st.cells, vec![0, 0, 5, 2, 3, 4]);
+    let mut touched = sm.touched_cells().unwrap();
+    touched.sort_unstable();
+    assert_eq!(touched, vec![2, 3, 4, 5]);
+
+    // The cell allocator's guard cells cannot be read or written from the host either
+    let mut sm = StackMachine::default();
+    sm.enable_cell_allocator(1, 2);
+    sm.st.load_program(program![LDI 4, NEWCELLS, RET]);
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    let address = usize::try_from(sm.st.pop_number().unwrap()).unwrap();
+    sm.st.store_struct(address, &rect).unwrap();
+    assert_eq!(sm.st.load_struct::<Rect>(address).unwrap(), rect);
+    let cells = sm.st.cells().to_vec();
+    for address in [address - 1, address + 1] {
+        assert!(matches!(
+            sm.st.store_struct(address, &rect).map_err(|e| e.kind()),
+            Err(ErrorKind::GuardCellAccess { .. })
+        ));
+        assert!(matches!(
+            sm.st.load_struct::<Rect>(address).map_err(|e| e.kind()),
+            Err(ErrorKind::GuardCellAccess { .. })
+        ));
+    }
+    assert_eq!(sm.st.cells(), &cells[..]);
+}
+
+#[test]
+fn test_host_error() {
+    use std::error::Error;
+    use std::io;
+
+    let mut sm = StackMachine::default();
+    sm.trap_handlers
+        .register(Box::new(TrapHandler::new(1, |_, _| {
+            Err(StackMachineError::host(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such device",
+            )))
+        })));
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::LDI(1), Opcode::TRAP]);
+
+    let err = sm.execute(0, GasLimit::Limited(100)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Host);
+    assert_eq!(err.to_string(), "host error: no such device at 1 (TRAP)");
+    let io_error = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+    assert_eq!(io_error.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_error_location() {
+    let mut sm = StackMachine::default();
+    sm.st.number_stack.extend_from_slice(&[1, 0]);
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::NOP, Opcode::DIV, Opcode::RET]);
+
+    let err = sm.execute(0, GasLimit::Limited(100)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DivisionByZero);
+    assert_eq!(err.pc(), Some(1));
+    assert_eq!(err.opcode(), Some(&Opcode::DIV));
+
+    // Running out of gas is blamed on the instruction that used the last of it
+    sm.st.opcodes = vec![Opcode::NOP, Opcode::NOP, Opcode::NOP, Opcode::RET];
+    let err = sm.execute(0, GasLimit::Limited(1)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::RanOutOfGas);
+    assert_eq!(err.pc(), Some(1));
+
+    // Errors made outside a run have no location
+    let err = StackMachineError::from(ErrorKind::StackUnderflow(StackKind::Scratch));
+    assert_eq!(err.pc(), None);
+    assert_eq!(err.to_string(), "scratch stack underflow");
+}
+
+#[test]
+fn test_unlimited_gas_is_still_counted() {
+    let mut sm = StackMachine::default();
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::NOP, Opcode::NOP, Opcode::RET]);
+    sm.execute(0, GasLimit::Unlimited).unwrap();
+    assert_eq!(sm.st.gas_used(), 2);
+
+    match sm.execute(0, GasLimit::Limited(1)).map_err(|e| e.kind()) {
+        Err(ErrorKind::RanOutOfGas) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_loop_stack_underflow_names_the_frame() {
+    let mut sm = StackMachine::default();
+    sm.st.loop_stack.push(LoopFrame::new(0, 10));
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::GETLP2, Opcode::RET]);
+
+    let err = sm.execute(0, GasLimit::Limited(100)).unwrap_err();
+    assert_eq!(
+        err.kind(),
+        ErrorKind::LoopStackUnderflow { frame: 1, depth: 1 }
+    );
+    assert_eq!(
+        err.to_string(),
+        "loop stack underflow, needed frame 1 with 1 on the loop stack at 0 (GETLP2)"
+    );
+    assert!(!sm.st.loop_stack()[0].is_finished());
+}
+
+#[test]
+fn test_execute_with_stack() {
+    let mut sm = StackMachine::default();
+    sm.st.number_stack.push(99);
+    sm.st
+        .opcodes
+        .extend_from_slice(&[Opcode::ADD, Opcode::LDI(2), Opcode::MUL, Opcode::RET]);
+
+    let result = sm
+        .execute_with_stack(0, vec![3, 4], GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(result, vec![14]);
+    assert!(sm.st.number_stack.is_empty());
+
+    match sm
+        .execute_with_stack(0, vec![3], GasLimit::Limited(100))
+        .map_err(|e| e.kind())
+    {
+        Err(ErrorKind::StackUnderflow(StackKind::Number)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_state_accessors() {
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(vec![Opcode::GtR, Opcode::ADD, Opcode::RET]);
+    assert_eq!(sm.st.opcodes().len(), 3);
+
+    sm.st.set_number_stack(vec![1, 2]);
+    sm.st.push_number(3);
+    assert_eq!(sm.st.peek_number(), Some(3));
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(sm.st.number_stack(), &[3]);
+    assert_eq!(sm.st.peek_scratch(), Some(3));
+    assert_eq!(sm.st.pop_scratch().unwrap(), 3);
+    assert_eq!(sm.st.pop_number().unwrap(), 3);
+    match sm.st.pop_number().map_err(|e| e.kind()) {
+        Err(ErrorKind::StackUnderflow(StackKind::Number)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    sm.st.word_size = WordSize::Bits32;
+    sm.st.push_number(i64::from(i32::MAX) + 1);
+    assert_eq!(sm.st.number_stack(), &[i64::from(i32::MIN)]);
+}
+
+#[test]
+fn test_state_clone_and_eq() {
+    let mut sm = StackMachine::default();
+    sm.st
+        .load_program(vec![Opcode::LDI(1), Opcode::ADD, Opcode::RET]);
+    sm.st.set_number_stack(vec![10]);
+    let snapshot = sm.st.clone();
+    assert!(snapshot == sm.st);
+
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert!(snapshot != sm.st);
+    assert_eq!(sm.st.number_stack(), &[11]);
+
+    // Restore and run again from the snapshot, instrumentation does not affect equality
+    let first_run = sm.st.clone();
+    sm.st = snapshot;
+    sm.enable_statistics();
+    sm.execute(0, GasLimit::Limited(100)).unwrap();
+    assert!(first_run == sm.st);
+}
+
+#[test]
+fn test_shared_program() {
+    use std::sync::Arc;
+
+    let mut first = StackMachine::default();
+    first
+        .st
+        .load_program(vec![Opcode::LDI(2), Opcode::MUL, Opcode::RET]);
+    let program = first.st.share_program();
+
+    let mut machines: Vec<StackMachine> = (0..3)
+        .map(|i| {
+            let mut sm = StackMachine::default();
+            sm.st.load_shared_program(Arc::clone(&program));
+            sm.st.set_number_stack(vec![i]);
+            sm
+        })
+        .collect();
+    assert_eq!(Arc::strong_count(&program), 5);
+
+    for sm in machines.iter_mut() {
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+    }
+    let results: Vec<&[i64]> = machines.iter().map(|sm| sm.st.number_stack()).collect();
+    assert_eq!(results, vec![&[0][..], &[2], &[4]]);
+
+    // Loading an owned program drops the shared one
+    machines[0].st.load_program(vec![Opcode::RET]);
+    assert_eq!(Arc::strong_count(&program), 4);
+
+    // Cloning a state shares its program too
+    let snapshot = machines[1].st.clone();
+    assert_eq!(Arc::strong_count(&program), 5);
+    assert_eq!(snapshot.opcodes(), &program[..]);
 }
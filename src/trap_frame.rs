@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+
+use super::{ErrorKind, StackKind, StackMachineError, StackMachineState};
+
+/// Typed access to the arguments and results of a trap.
+///
+/// The calling convention is that a program pushes the arguments of a trap in order, then
+/// the trap id, so when a handler runs the last argument is on top of the number stack.
+/// Results are pushed in order, leaving the last on top.
+///
+/// ```ignore
+/// TrapHandler::new(ADD_AND_DIFF, |_, st| {
+///     let mut frame = TrapFrame::new(st);
+///     let [a, b] = frame.args()?;
+///     frame.ret(&[a + b, a - b]);
+///     Ok(TrapHandled::Handled)
+/// })
+/// ```
+pub struct TrapFrame<'a> {
+    st: &'a mut StackMachineState,
+}
+
+impl<'a> TrapFrame<'a> {
+    pub fn new(st: &'a mut StackMachineState) -> Self {
+        TrapFrame { st }
+    }
+
+    /// Look at an argument without removing it, arg(0) is the last argument pushed
+    pub fn arg(&self, i: usize) -> Result<i64, StackMachineError> {
+        let stack = self.st.number_stack();
+        stack
+            .len()
+            .checked_sub(i + 1)
+            .map(|index| stack[index])
+            .ok_or_else(|| ErrorKind::StackUnderflow(StackKind::Number).into())
+    }
+
+    /// Remove the last N arguments, returning them in the order they were pushed
+    pub fn args<const N: usize>(&mut self) -> Result<[i64; N], StackMachineError> {
+        let stack = self.st.number_stack_mut();
+        let start = stack
+            .len()
+            .checked_sub(N)
+            .ok_or(ErrorKind::StackUnderflow(StackKind::Number))?;
+        let args: Vec<i64> = stack.drain(start..).collect();
+        Ok(args.try_into().expect("drained exactly N arguments"))
+    }
+
+    /// Push results in order, wrapped to the word size like any other push
+    pub fn ret(&mut self, values: &[i64]) {
+        for value in values {
+            self.st.push_number(*value);
+        }
+    }
+
+    /// The state, for anything the frame does not cover
+    pub fn state(&mut self) -> &mut StackMachineState {
+        self.st
+    }
+}
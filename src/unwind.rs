@@ -0,0 +1,76 @@
+use std::fmt;
+
+use super::{StackMachineState, SymbolTable};
+
+/// A call in progress, named by the symbol at or before its address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    /// The CALL the frame is running, or the pc for the innermost frame
+    pub pc: usize,
+    /// The symbol containing pc and its address, None when no symbol precedes it
+    pub function: Option<(usize, String)>,
+}
+
+impl CallFrame {
+    /// The frame running pc, named by the symbol at or before it
+    pub fn new(pc: usize, symbols: &SymbolTable) -> CallFrame {
+        CallFrame {
+            pc,
+            function: symbols
+                .containing(pc)
+                .map(|(address, name)| (address, name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some((_, name)) => f.write_str(name),
+            None => write!(f, "@{}", self.pc),
+        }
+    }
+}
+
+/// The calls in progress, outermost first, displayed as `MAIN > SQUARE > HELPER @ pc 142`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallChain {
+    pub frames: Vec<CallFrame>,
+}
+
+impl CallChain {
+    /// The pc of the innermost frame
+    pub fn pc(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.pc)
+    }
+}
+
+impl fmt::Display for CallChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" > ")?;
+            }
+            write!(f, "{}", frame)?;
+        }
+        write!(f, " @ pc {}", self.pc())
+    }
+}
+
+/// The call chain of a machine stopped at pc with return_stack, each return address
+/// standing for the CALL just before it
+pub fn unwind(return_stack: &[usize], pc: usize, symbols: &SymbolTable) -> CallChain {
+    let mut frames: Vec<CallFrame> = return_stack
+        .iter()
+        .map(|&address| CallFrame::new(address.saturating_sub(1), symbols))
+        .collect();
+    frames.push(CallFrame::new(pc, symbols));
+    CallChain { frames }
+}
+
+impl StackMachineState {
+    /// The calls in progress at the pc, such as after a run failed or while it is paused
+    pub fn call_chain(&self, symbols: &SymbolTable) -> CallChain {
+        unwind(&self.return_stack, self.pc, symbols)
+    }
+}
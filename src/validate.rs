@@ -0,0 +1,56 @@
+use std::fmt;
+
+use super::constprop::propagate_constants_from;
+use super::{Opcode, WordSize};
+
+/// Reasons a program is rejected by `validate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    EmptyProgram,
+    /// A jump or call at pc whose target is a constant outside the program, and not just
+    /// past its end where the run halts
+    TargetOutOfRange {
+        pc: usize,
+        target: i64,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::EmptyProgram => write!(f, "program is empty"),
+            ValidationError::TargetOutOfRange { pc, target } => {
+                write!(f, "jump at {} targets {}, outside the program", pc, target)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that a program is well formed before running it.
+///
+/// Every reachable jump or call whose target is a known constant, as found by
+/// `propagate_constants`, must land inside the program or just past its end. Running past
+/// the end, by falling through the last instruction or jumping there, halts the run.
+pub fn validate(opcodes: &[Opcode]) -> Result<(), ValidationError> {
+    validate_from(opcodes, 0)
+}
+
+/// As validate, for the code from start, which must not be empty. Jumps before start that
+/// the code does not reach are not checked
+pub(crate) fn validate_from(opcodes: &[Opcode], start: usize) -> Result<(), ValidationError> {
+    if start >= opcodes.len() {
+        return Err(ValidationError::EmptyProgram);
+    }
+    let analysis = propagate_constants_from(opcodes, start, WordSize::Bits64);
+    for (pc, target) in &analysis.jump_targets {
+        if *target < 0 || *target as u64 > opcodes.len() as u64 {
+            return Err(ValidationError::TargetOutOfRange {
+                pc: *pc,
+                target: *target,
+            });
+        }
+    }
+    Ok(())
+}